@@ -0,0 +1,27 @@
+//! Model tests for the kernel's lock-free code, which run on the host under loom.
+//! Loom runs each test over and over, trying every way the threads' atomic operations can interleave (and every value a weakly-ordered load may see),
+//!     so these catch races that the debug shell's stress tests would only hit by luck.
+//! The kernel's modules are compiled here as-is (with cfg(loom) swapping in loom's atomics), alongside stand-ins for the parts of the kernel they use.
+//! Modules with no concurrency to speak of (e.g. the timer wheel and run queues) are tested here too, as ordinary tests, so that they don't need a kernel to run in.
+//!
+//! Run them with `make modeltest` (or `RUSTFLAGS="--cfg loom" cargo test --release` in this directory).
+#![cfg(loom)]
+
+extern crate alloc;
+
+#[path = "../../rust/src/descriptors.rs"]
+#[allow(dead_code, unused_imports, mismatched_lifetime_syntaxes)]
+pub mod descriptors;
+#[path = "../../rust/src/multitasking/timerwheel.rs"]
+pub mod timerwheel;
+
+/// Stand-ins for the parts of the kernel that the modules above use, and the multitasking modules that are tested here
+pub mod multitasking {
+    /* There are no interrupts on the host */
+    pub fn disable_interruptions() {}
+
+    #[path = "../../../rust/src/multitasking/runqueue.rs"]
+    pub mod runqueue;
+
+    pub mod task;
+}
@@ -0,0 +1,29 @@
+//! A stand-in for the kernel's tasks, with just what the run queues use
+
+use super::runqueue::TaskPriority;
+
+/// A set of CPUs (as in the kernel's, but with only what the run queues use)
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct CpuSet(u64);
+impl CpuSet {
+    pub const ALL: Self = Self(u64::MAX);
+    pub const fn single(cpu: usize) -> Self { Self(1<<cpu) }
+    pub const fn contains(&self, cpu: usize) -> bool {
+        cpu < 64 && self.0 & (1<<cpu) != 0
+    }
+}
+
+/// A task that's only ever queued, with just the fields the run queues use
+#[derive(Debug)]
+pub struct Task {
+    pub task_id: usize,
+    pub priority: TaskPriority,
+    pub affinity: CpuSet,
+    pub sched_level: usize,
+    pub sched_used: usize,
+}
+impl Task {
+    pub fn new(task_id: usize, priority: TaskPriority) -> Self {
+        Self { task_id, priority, affinity: CpuSet::ALL, sched_level: 0, sched_used: 0 }
+    }
+}
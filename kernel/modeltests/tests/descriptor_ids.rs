@@ -0,0 +1,87 @@
+//! Tests for how descriptor IDs are split between slot and generation (DescriptorIdLayout), and for tables whose IDs only have room for a few slots.
+#![cfg(loom)]
+
+use kernel_modeltests::descriptors::*;
+
+/* The slot goes in the low bits and the generation above it, and both can be read back out */
+#[test]
+fn compose_round_trips() {
+    let layout = DescriptorIdLayout::new(16, 16);
+    let id = layout.compose(5, 7);
+    assert_eq!(id, (7<<16) | 5);
+    assert_eq!(layout.slot_of(id), 5);
+    assert_eq!(layout.generation_of(id), 7);
+    assert!(layout.generation_matches(id, 7));
+    assert!(!layout.generation_matches(id, 8));
+}
+
+/* Generations wrap around to fit, and IDs never have any bits set above slot_bits+generation_bits */
+#[test]
+fn generations_are_truncated() {
+    let layout = DescriptorIdLayout::new(3, 13);
+    let id = layout.compose(6, (1<<13) + 2);
+    assert_eq!(layout.generation_of(id), 2);
+    assert!(layout.generation_matches(id, 2));
+    assert!(layout.generation_matches(id, (1<<13) + 2));
+    assert!(!layout.generation_matches(id, (1<<13) + 3));
+    assert!(layout.compose(layout.max_slot(), u64::MAX) < 1<<16);
+    // (a slot number that doesn't fit is truncated too, rather than spilling into the generation)
+    assert_eq!(layout.generation_of(layout.compose(9, 0)), 0);
+}
+
+/* The largest slot number is every slot bit set */
+#[test]
+fn max_slot() {
+    assert_eq!(DescriptorIdLayout::new(3, 13).max_slot(), 7);
+    assert_eq!(DescriptorIdLayout::DEFAULT.max_slot(), u32::MAX as u64);
+    assert_eq!(DescriptorIdLayout::new(64, 0).max_slot(), u64::MAX);
+}
+
+/* Layouts with no room for a generation (or no spare bits at all) don't overflow their shifts */
+#[test]
+fn full_width_layouts() {
+    let slots_only = DescriptorIdLayout::new(64, 0);
+    assert_eq!(slots_only.compose(u64::MAX, 5), u64::MAX);
+    assert_eq!(slots_only.generation_of(u64::MAX), 0);
+    assert!(slots_only.generation_matches(u64::MAX, 5));
+
+    let split = DescriptorIdLayout::new(1, 63);
+    let id = split.compose(1, u64::MAX);
+    assert_eq!(id, u64::MAX);
+    assert_eq!(split.generation_of(id), u64::MAX >> 1);
+}
+
+#[test]
+#[should_panic(expected = "Invalid descriptor ID layout")]
+fn no_slot_bits() {
+    DescriptorIdLayout::new(0, 16);
+}
+#[test]
+#[should_panic(expected = "Invalid descriptor ID layout")]
+fn too_many_bits() {
+    DescriptorIdLayout::new(40, 40);
+}
+
+#[derive(Default)]
+struct TestT;
+// (tiny tables, so that it has to grow several levels deep before it runs out of slot numbers)
+type TestTable = DescriptorTable<TestT,(),(),2,2>;
+
+/* A table whose IDs only have room for 7 slots must say so once it can't find one (rather than panicking), and have room again once one is freed */
+#[test]
+fn full_table() {
+    loom::model(||{
+        let table = TestTable::with_id_layout(DescriptorIdLayout::new(3, 13));
+        let mut handles = Vec::new();
+        while let Ok(init) = table.create_new_descriptor() {
+            handles.push(init.commit((), ()));
+            assert!(handles.len() <= 7, "created {} descriptors in a table with 7 slots", handles.len());
+        }
+        // (the root table's slots are on every path)
+        let freed_id = handles.remove(0).get_id();
+        let handle = table.create_new_descriptor().expect("table was still full after freeing a descriptor").commit((), ());
+        assert_eq!(table.id_layout().slot_of(handle.get_id()), table.id_layout().slot_of(freed_id));
+        assert_ne!(handle.get_id(), freed_id);
+        assert!(matches!(table.acquire_a(freed_id), Err(DescriptorAcquireError::Stale)));
+    });
+}
@@ -0,0 +1,130 @@
+//! Model tests for descriptor handles: every interleaving of looking a descriptor up, upgrading a handle, and creating and freeing descriptors, against each other.
+#![cfg(loom)]
+
+use loom::sync::Arc;
+use loom::sync::atomic::{AtomicUsize,Ordering};
+use loom::thread;
+use kernel_modeltests::descriptors::*;
+
+#[derive(Default)]
+struct TestT;
+/// A or B section. Remembers which descriptor it belongs to, and counts how many sections are alive.
+struct Section { id: DescriptorID, live: Arc<AtomicUsize> }
+impl Drop for Section {
+    fn drop(&mut self){ self.live.fetch_sub(1, Ordering::Relaxed); }
+}
+
+// (one descriptor per table, so that freed slots are re-used straight away, and new descriptors go into sub-tables while the slot is busy)
+type TestTable = DescriptorTable<TestT,Section,Section,1,1>;
+type TestHandleB = DescriptorHandleB<'static,TestT,Section,Section>;
+
+/* A new table, leaked so that handles to it can be moved into threads */
+fn new_table() -> &'static TestTable {
+    Box::leak(Box::new(TestTable::new()))
+}
+fn create(table: &'static TestTable, live: &Arc<AtomicUsize>) -> TestHandleB {
+    let init = table.create_new_descriptor().unwrap();
+    let id = init.id();
+    live.fetch_add(2, Ordering::Relaxed);
+    init.commit(Section { id, live: Arc::clone(live) }, Section { id, live: Arc::clone(live) })
+}
+
+/* Looking up a descriptor as its last handle is dropped, and its slot is re-used, must either find it intact or report it as gone - never the new descriptor */
+#[test]
+fn lookup_races_with_free_and_reuse() {
+    loom::model(||{
+        let live = Arc::new(AtomicUsize::new(0));
+        let table = new_table();
+        let handle = create(table, &live);
+        let id = handle.get_id();
+        let lookup = thread::spawn(move ||match table.acquire_b(id) {
+            Ok(b) => { assert_eq!(b.get_a().id, id); assert_eq!(b.get_b().id, id); },
+            // (BSlotNotAvailable if we get in after its B section has been dropped, but before the rest of it has)
+            Err(e) => assert!(matches!(e, DescriptorAcquireError::Stale | DescriptorAcquireError::DescriptorReserved | DescriptorAcquireError::BSlotNotAvailable), "{:?}", e),
+        });
+        drop(handle);
+        let new = create(table, &live);
+        assert_ne!(new.get_id(), id);
+        lookup.join().unwrap();
+        assert!(matches!(table.acquire_a(id), Err(DescriptorAcquireError::Stale)));
+        drop(new);
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    });
+}
+
+/* Upgrading an A-handle as the last B-handle is dropped must either get a B-handle (keeping the B section alive) or fail, and the B section must be dropped exactly once */
+#[test]
+fn upgrade_races_with_last_b_drop() {
+    loom::model(||{
+        let live = Arc::new(AtomicUsize::new(0));
+        let table = new_table();
+        let b = create(table, &live);
+        let id = b.get_id();
+        let a = b.clone_a_ref();
+        let upgrader = thread::spawn(move ||match a.upgrade() {
+            Ok(b) => { assert_eq!(b.get_b().id, id); true },
+            Err(e) => { assert_eq!(e, DescriptorAcquireError::BSlotNotAvailable); false },
+        });
+        drop(b);
+        let upgraded = upgrader.join().unwrap();
+        // (whichever way it went, every handle is gone now)
+        assert_eq!(live.load(Ordering::Relaxed), 0, "upgraded={}", upgraded);
+        assert!(matches!(table.acquire_a(id), Err(DescriptorAcquireError::Stale)));
+    });
+}
+
+/* Looking up a descriptor as its last (A-)handle is dropped must either keep it alive until we're done with it, or fail */
+#[test]
+fn lookup_races_with_last_a_drop() {
+    loom::model(||{
+        let live = Arc::new(AtomicUsize::new(0));
+        let table = new_table();
+        let a = create(table, &live).downgrade();
+        let id = a.get_id();
+        assert_eq!(live.load(Ordering::Relaxed), 1);
+        let lookup = thread::spawn(move ||match table.acquire_a(id) {
+            Ok(a) => assert_eq!(a.get_a().id, id),
+            Err(e) => assert!(matches!(e, DescriptorAcquireError::Stale | DescriptorAcquireError::DescriptorReserved), "{:?}", e),
+        });
+        drop(a);
+        lookup.join().unwrap();
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    });
+}
+
+/* Looking up a descriptor while it's being created must either fail, or see it fully initialised */
+#[test]
+fn lookup_races_with_commit() {
+    loom::model(||{
+        let live = Arc::new(AtomicUsize::new(0));
+        let table = new_table();
+        // (the first descriptor in a new table always goes in slot 1, at generation 0)
+        let id = table.id_layout().compose(1, 0);
+        let lookup = thread::spawn(move ||match table.acquire_b(id) {
+            Ok(b) => { assert_eq!(b.get_a().id, id); assert_eq!(b.get_b().id, id); },
+            Err(e) => assert!(matches!(e, DescriptorAcquireError::Stale | DescriptorAcquireError::DescriptorReserved), "{:?}", e),
+        });
+        let handle = create(table, &live);
+        assert_eq!(handle.get_id(), id);
+        lookup.join().unwrap();
+        drop(handle);
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    });
+}
+
+/* Descriptors created at the same time must get different slots */
+#[test]
+fn concurrent_creates() {
+    loom::model(||{
+        let live = Arc::new(AtomicUsize::new(0));
+        let table = new_table();
+        let creator = { let live = Arc::clone(&live); thread::spawn(move ||create(table, &live)) };
+        let mine = create(table, &live);
+        let theirs = creator.join().unwrap();
+        assert_ne!(mine.get_id(), theirs.get_id());
+        assert_eq!(table.acquire_a(mine.get_id()).unwrap().get_a().id, mine.get_id());
+        assert_eq!(table.acquire_a(theirs.get_id()).unwrap().get_a().id, theirs.get_id());
+        drop((mine, theirs));
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+    });
+}
@@ -0,0 +1,116 @@
+//! Tests for the run queues: the order the MLFQ and FIFO policies pick tasks in (including MLFQ demotion, priority boosts and preemption), and which tasks load balancing steals.
+#![cfg(loom)]
+
+use kernel_modeltests::multitasking::runqueue::*;
+use kernel_modeltests::multitasking::task::{Task,CpuSet};
+
+/* Make a task for each priority, numbered in order */
+fn tasks<const N: usize>(priorities: [TaskPriority; N]) -> [Task; N] {
+    let mut id = 0;
+    priorities.map(|priority|{ id += 1; Task::new(id, priority) })
+}
+/* Pop every task, returning their IDs in the order they'd run */
+fn drain(queue: &mut impl RunQueue) -> Vec<usize> {
+    core::iter::from_fn(||queue.pop()).map(|task|task.task_id).collect()
+}
+
+/* Higher priorities always run first, and tasks of the same priority take turns */
+#[test]
+fn mlfq_runs_by_priority() {
+    let mut queue = MlfqRunQueue::default();
+    for task in tasks([TaskPriority::Background, TaskPriority::Normal, TaskPriority::High, TaskPriority::Normal]) { queue.push(task, EnqueueReason::New); }
+    assert_eq!(queue.len(), 4);
+    assert_eq!(drain(&mut queue), [3, 2, 4, 1]);
+}
+
+/* A task that keeps yielding is demoted once it's used up its allotment, and then runs after tasks that are still at its old level */
+#[test]
+fn mlfq_demotes_yielding_tasks() {
+    let mut queue = MlfqRunQueue::default();
+    let [mut hog, other] = tasks([TaskPriority::High, TaskPriority::High]);
+    for yields in 1..=MLFQ_ALLOTMENT[0] {
+        queue.push(hog, EnqueueReason::Yielded);
+        hog = queue.pop().unwrap();
+        assert_eq!(hog.sched_level, if yields < MLFQ_ALLOTMENT[0] { 0 } else { 1 }, "after yielding {} times", yields);
+    }
+    // (the other task hasn't used any of its allotment, so it should preempt the demoted one)
+    queue.push(other, EnqueueReason::New);
+    assert!(queue.should_preempt(&hog));
+    queue.push(hog, EnqueueReason::Woken);
+    assert_eq!(drain(&mut queue), [2, 1]);
+}
+
+/* Blocking doesn't use up the allotment */
+#[test]
+fn mlfq_keeps_blocking_tasks_level() {
+    let mut queue = MlfqRunQueue::default();
+    let [mut sleeper] = tasks([TaskPriority::High]);
+    for _ in 0..MLFQ_ALLOTMENT[0]*2 {
+        queue.push(sleeper, EnqueueReason::Woken);
+        sleeper = queue.pop().unwrap();
+    }
+    assert_eq!(sleeper.sched_level, 0);
+}
+
+/* A background task isn't starved by a busy Normal one (which keeps its level by blocking): it gets a turn at the top level at the next boost, and then drops back down */
+#[test]
+fn mlfq_boost_runs_background_tasks() {
+    let mut queue = MlfqRunQueue::default();
+    let [busy, background] = tasks([TaskPriority::Normal, TaskPriority::Background]);
+    queue.push(busy, EnqueueReason::New);
+    queue.push(background, EnqueueReason::New);
+    let mut decisions = 0;
+    let background = loop {
+        let task = queue.pop().unwrap();
+        decisions += 1;
+        if task.task_id == 2 { break task; }
+        assert!(decisions <= MLFQ_BOOST_INTERVAL, "the background task hadn't run after {} decisions", decisions);
+        queue.push(task, EnqueueReason::Woken);
+    };
+    assert_eq!(decisions, MLFQ_BOOST_INTERVAL+1);  // (the busy task was boosted too, and ran first)
+    assert_eq!(background.sched_level, 0);
+
+    queue.push(background, EnqueueReason::Woken);
+    let levels: Vec<(usize,usize)> = queue.iter().map(|task|(task.task_id, task.sched_level)).collect();
+    assert_eq!(levels, [(1, 1), (2, MLFQ_LEVELS-1)]);
+}
+
+/* Demoted tasks are boosted too, and go back to their base level afterwards rather than the level they were demoted to */
+#[test]
+fn mlfq_boost_resets_demotion() {
+    let mut queue = MlfqRunQueue::default();
+    let [mut hog] = tasks([TaskPriority::Normal]);
+    queue.push(hog, EnqueueReason::New);
+    for _ in 1..MLFQ_BOOST_INTERVAL {
+        hog = queue.pop().unwrap();
+        queue.push(hog, EnqueueReason::Yielded);
+    }
+    assert_eq!(queue.iter().next().unwrap().sched_level, MLFQ_LEVELS-1);
+    hog = queue.pop().unwrap();
+    assert_eq!(hog.sched_level, 0);
+    queue.push(hog, EnqueueReason::Yielded);
+    assert_eq!(queue.iter().next().unwrap().sched_level, 1);
+}
+
+/* Tasks run in the order they were queued, whatever their priority, and yielding sends them to the back */
+#[test]
+fn fifo_runs_in_order() {
+    let mut queue = FifoRunQueue::default();
+    for task in tasks([TaskPriority::Background, TaskPriority::High, TaskPriority::Normal]) { queue.push(task, EnqueueReason::New); }
+    let first = queue.pop().unwrap();
+    queue.push(first, EnqueueReason::Yielded);
+    assert!(!queue.iter().any(|task|queue.should_preempt(task)));
+    assert_eq!(drain(&mut queue), [2, 3, 1]);
+}
+
+/* Stealing takes the task that would run last, skipping any that aren't allowed to run on the thief's CPU */
+#[test]
+fn steal_takes_last_allowed_task() {
+    let mut queue = MlfqRunQueue::default();
+    let [high, mut pinned, normal] = tasks([TaskPriority::High, TaskPriority::Background, TaskPriority::Normal]);
+    pinned.affinity = CpuSet::single(0);
+    for task in [high, pinned, normal] { queue.push(task, EnqueueReason::New); }
+    let stolen: Vec<usize> = core::iter::from_fn(||queue.steal(1)).map(|task|task.task_id).collect();
+    assert_eq!(stolen, [3, 1]);
+    assert_eq!(drain(&mut queue), [2]);
+}
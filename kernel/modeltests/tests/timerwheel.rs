@@ -0,0 +1,72 @@
+//! Tests for the scheduler's timer wheel: every timer must expire on exactly its deadline tick, in order, however far away it was (i.e. whichever level it started on).
+#![cfg(loom)]
+
+use kernel_modeltests::timerwheel::TimerWheel;
+
+const TOP: usize = 1 << (6*4);  // (the first deadline that starts in the overflow list)
+
+/* Advance the wheel tick by tick up to `to`, returning (tick, deadline, item) for each timer that expired */
+fn advance_by_ticks<T>(wheel: &mut TimerWheel<T>, to: usize) -> Vec<(usize,usize,T)> {
+    let mut fired = Vec::new();
+    for tick in wheel.now()+1..=to {
+        wheel.advance(tick, |deadline, item|fired.push((tick, deadline, item)));
+    }
+    fired
+}
+
+/* Timers either side of each level boundary (inserted out of order) cascade down and expire on their deadlines, in order */
+#[test]
+fn expires_on_deadline_across_levels() {
+    let deadlines = [4097, 1, 63, TOP+3, 65, 4095, 64, 200, 4096, 2, 64*64*5+17, TOP-1, 130];
+    let mut wheel = TimerWheel::new();
+    for (index, deadline) in deadlines.iter().enumerate() { wheel.insert(*deadline, index); }
+    assert_eq!(wheel.len(), deadlines.len());
+
+    // (tick by tick up to the last level boundary in the list, and then straight to each remaining deadline)
+    let mut fired = advance_by_ticks(&mut wheel, 64*64*5+17);
+    for deadline in [TOP-2, TOP-1, TOP+2, TOP+3] {
+        wheel.advance(deadline, |d, index|fired.push((deadline, d, index)));
+    }
+    for (tick, deadline, index) in fired.iter() {
+        assert_eq!(tick, deadline, "timer {} expired on the wrong tick", index);
+        assert_eq!(*deadline, deadlines[*index], "timer {} was given the wrong deadline", index);
+    }
+    let mut sorted = deadlines; sorted.sort();
+    assert_eq!(fired.iter().map(|(_, deadline, _)|*deadline).collect::<Vec<_>>(), sorted);
+    assert_eq!(wheel.len(), 0);
+}
+
+/* Deadlines that have already passed expire on the next tick (which is then reported as their deadline) */
+#[test]
+fn late_timers_expire_next_tick() {
+    let mut wheel = TimerWheel::new();
+    advance_by_ticks(&mut wheel, 100);
+    wheel.insert(90, "late");
+    wheel.insert(100, "now");
+    let fired = advance_by_ticks(&mut wheel, 101);
+    assert_eq!(fired, [(101, 101, "late"), (101, 101, "now")]);
+}
+
+/* A wheel that starts part-way through (as a CPU's does when it comes online after the clock has started) expires timers on their deadlines too, including ones that cascade */
+#[test]
+fn starting_part_way() {
+    let start = 64*64*3 + 60;
+    let deadlines = [start+1, start+4, start+64, start+100, start+4096, start+5000];
+    let mut wheel = TimerWheel::starting_at(start);
+    for deadline in deadlines { wheel.insert(deadline, ()); }
+    let fired = advance_by_ticks(&mut wheel, start+5000);
+    assert_eq!(fired.iter().map(|(tick, deadline, _)|{ assert_eq!(tick, deadline); *deadline }).collect::<Vec<_>>(), deadlines);
+}
+
+/* Jumping straight to a much later tick expires everything due in between, in order */
+#[test]
+fn advancing_many_ticks_at_once() {
+    let mut wheel = TimerWheel::new();
+    let deadlines = [5000, 3, 70, 64*64+1, 1000];
+    for deadline in deadlines { wheel.insert(deadline, deadline); }
+    let mut fired = Vec::new();
+    wheel.advance(10_000, |deadline, item|{ assert_eq!(deadline, item); fired.push(deadline); });
+    let mut sorted = deadlines; sorted.sort();
+    assert_eq!(fired, sorted);
+    assert_eq!(wheel.now(), 10_000);
+}
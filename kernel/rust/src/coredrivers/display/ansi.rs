@@ -0,0 +1,117 @@
+//! A minimal parser for the ANSI escape sequences used by log formatters, shared by the text consoles.
+//! Only colours are supported (SGR: 0, 1, 2, 22, 30-37, 39, 40-47, 49, 90-97, 100-107). Any other sequence is silently dropped.
+
+use alloc::format;
+use alloc::string::String;
+use crate::logging::{LogFormatter,LogLevel};
+
+/// A 16-colour foreground/background pair, using VGA colour numbers (0-7 = black, blue, green, cyan, red, magenta, brown, light grey. Add 8 for the bright version)
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct TextColour {
+    pub foreground: u8,
+    pub background: u8,
+}
+impl TextColour {
+    /// Light grey on black
+    pub const DEFAULT: Self = Self { foreground: 7, background: 0 };
+    /// Bright white on red
+    pub const PANIC: Self = Self { foreground: 15, background: 4 };
+
+    /// Convert an ANSI colour number (0-7, i.e. black, red, green, yellow, blue, magenta, cyan, white) to the VGA equivalent
+    fn from_ansi(n: u8) -> u8 {
+        const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        ANSI_TO_VGA[(n & 0b0111) as usize]
+    }
+    /* Apply an ANSI SGR ("select graphic rendition") parameter */
+    pub fn apply_sgr(self, param: u8, default: Self) -> Self {
+        let Self { foreground, background } = self;
+        match param {
+            0 => default,
+            1 => Self { foreground: foreground | 8, background },
+            2 | 22 => Self { foreground: foreground & 7, background },
+            30..=37 => Self { foreground: (foreground & 8) | Self::from_ansi(param-30), background },
+            39 => Self { foreground: default.foreground, background },
+            40..=47 => Self { foreground, background: Self::from_ansi(param-40) },
+            49 => Self { foreground, background: default.background },
+            90..=97 => Self { foreground: 8 | Self::from_ansi(param-90), background },
+            100..=107 => Self { foreground, background: 8 | Self::from_ansi(param-100) },
+            _ => self,
+        }
+    }
+    /// The RGB value of each colour number, from the standard VGA palette
+    pub const PALETTE: [(u8,u8,u8); 16] = [
+        (0x00,0x00,0x00), (0x00,0x00,0xAA), (0x00,0xAA,0x00), (0x00,0xAA,0xAA), (0xAA,0x00,0x00), (0xAA,0x00,0xAA), (0xAA,0x55,0x00), (0xAA,0xAA,0xAA),
+        (0x55,0x55,0x55), (0x55,0x55,0xFF), (0x55,0xFF,0x55), (0x55,0xFF,0xFF), (0xFF,0x55,0x55), (0xFF,0x55,0xFF), (0xFF,0xFF,0x55), (0xFF,0xFF,0xFF),
+    ];
+    pub fn foreground_rgb(&self) -> (u8,u8,u8) { Self::PALETTE[(self.foreground & 0xF) as usize] }
+    pub fn background_rgb(&self) -> (u8,u8,u8) { Self::PALETTE[(self.background & 0xF) as usize] }
+}
+
+#[derive(Debug,Clone,Copy)]
+enum ParserState {
+    Ground,
+    /// Got ESC
+    Escape,
+    /// Got ESC [, followed by the given parameters so far
+    Csi { params: [u8; 4], count: usize },
+}
+
+pub struct AnsiParser {
+    state: ParserState,
+}
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self { state: ParserState::Ground }
+    }
+    /// Abandon any escape sequence that is in progress
+    pub fn reset(&mut self) {
+        self.state = ParserState::Ground;
+    }
+
+    /* Feed a byte to the parser. Colour changes are applied to `colour` (with `default` being the colour to reset to).
+        Returns the byte if it is not part of an escape sequence, so that the caller can display it (or handle it, if it's a control character). */
+    pub fn feed(&mut self, byte: u8, colour: &mut TextColour, default: TextColour) -> Option<u8> {
+        use ParserState::*;
+        match (self.state, byte) {
+            (Ground, 0x1B) => self.state = Escape,
+            (Ground, byte) => return Some(byte),
+
+            (Escape, b'[') => self.state = Csi { params: [0; 4], count: 0 },
+            (Escape, _) => self.state = Ground,
+            (Csi { mut params, count }, b'0'..=b'9') => {
+                let i = count.saturating_sub(1);
+                params[i] = params[i].saturating_mul(10).saturating_add(byte-b'0');
+                self.state = Csi { params, count: count.max(1) };
+            },
+            (Csi { params, count }, b';') => self.state = Csi { params, count: (count.max(1)+1).min(params.len()) },
+            (Csi { params, count }, b'm') => {
+                self.state = Ground;
+                // (ESC [ m is the same as ESC [ 0 m)
+                for param in params.iter().take(count.max(1)) { *colour = colour.apply_sgr(*param, default); }
+            },
+            (Csi { .. }, _) => self.state = Ground,  // unsupported sequence
+        }
+        None
+    }
+}
+
+/// A short log format for the screen consoles, with the level coloured according to its severity
+pub struct ScreenLogFormatter();
+impl ScreenLogFormatter {
+    fn level_colour(level: LogLevel) -> &'static str {
+        use LogLevel::*;
+        match level {
+            Debug    => "\x1b[90m",     // dark grey
+            Info     => "\x1b[92m",     // light green
+            Warning  => "\x1b[93m",     // yellow
+            Severe   => "\x1b[91m",     // light red
+            Critical => "\x1b[97;41m",  // white on red
+            Fatal    => "\x1b[97;41m",  // white on red
+        }
+    }
+}
+impl LogFormatter for ScreenLogFormatter {
+    fn format_log_message(&self, level: LogLevel, component: &str, msg: &str, file: &str, line: u32, column: u32) -> String {
+        format!("{}[{}]\x1b[0m {}: {}", Self::level_colour(level), level.name(), component, msg)
+    }
+}
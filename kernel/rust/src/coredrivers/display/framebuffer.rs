@@ -0,0 +1,303 @@
+//! Text console drawn onto the linear framebuffer set up by the bootloader (see parse_multiboot::MULTIBOOT_FRAMEBUFFER), using an 8x8 bitmap font.
+//! Like the VGA console, it understands ANSI colour codes (see display_ansi).
+//! The framebuffer is mapped write-combining, so drawing is fast but reading it back is slow - a copy of the text on screen is kept instead, and used for scrolling.
+
+use alloc::vec::Vec;
+
+use crate::logging::klog;
+use super::display_ansi::{AnsiParser,TextColour};
+use super::display_font8x8::{self as font,GLYPH_WIDTH,GLYPH_HEIGHT};
+use crate::coredrivers::parse_multiboot::{MULTIBOOT_FRAMEBUFFER,FramebufferInfo,FramebufferColourInfo};
+use crate::memory::paging::global_pages;
+use crate::sync::kspin::KMutex;
+
+/// Screens at least this wide have their text drawn at double size, so that it's still readable
+const DOUBLE_SIZE_MIN_WIDTH: usize = 1600;
+
+/// How to encode colours into pixels
+#[derive(Debug,Clone,Copy)]
+struct PixelFormat {
+    bytes_per_pixel: usize,
+    red: (u8,u8), green: (u8,u8), blue: (u8,u8),  // (position, size)
+}
+impl PixelFormat {
+    fn from_info(info: &FramebufferInfo) -> Option<Self> {
+        let FramebufferColourInfo::Rgb { red_position, red_size, green_position, green_size, blue_position, blue_size } = info.colour_info else { return None; };
+        let bytes_per_pixel = match info.bpp { 15 | 16 => 2, 24 => 3, 32 => 4, _ => return None };
+        Some(Self { bytes_per_pixel, red: (red_position, red_size), green: (green_position, green_size), blue: (blue_position, blue_size) })
+    }
+    /* Convert an 8-bit-per-channel colour to a pixel value */
+    fn encode(&self, (r,g,b): (u8,u8,u8)) -> u32 {
+        let field = |value: u8, (position, size): (u8,u8)| -> u32 {
+            if size == 0 { return 0; }
+            ((value as u32) >> 8u8.saturating_sub(size)) << position
+        };
+        field(r, self.red) | field(g, self.green) | field(b, self.blue)
+    }
+}
+
+/// The mapped framebuffer
+struct Framebuffer {
+    base: usize,
+    pitch: usize,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+}
+impl Framebuffer {
+    /* Set a single pixel. x and y must be within the framebuffer. */
+    fn put_pixel(&mut self, x: usize, y: usize, value: u32) {
+        debug_assert!(x < self.width && y < self.height);
+        let addr = self.base + y*self.pitch + x*self.format.bytes_per_pixel;
+        // Safety: the framebuffer is mapped for its full size (see map_framebuffer), and x/y are within it
+        unsafe { match self.format.bytes_per_pixel {
+            4 => core::ptr::write_volatile(addr as *mut u32, value),
+            2 => core::ptr::write_volatile(addr as *mut u16, value as u16),
+            _ => for (i, byte) in value.to_le_bytes().iter().take(self.format.bytes_per_pixel).enumerate() {
+                core::ptr::write_volatile((addr+i) as *mut u8, *byte);
+            },
+        }}
+    }
+    /* Fill the whole framebuffer with one pixel value */
+    fn fill(&mut self, value: u32) {
+        for y in 0..self.height { for x in 0..self.width { self.put_pixel(x, y, value); } }
+    }
+}
+
+/* Map the framebuffer into MMIO_PTABLE (write-combining). Returns None if it couldn't be mapped, or uses a pixel format we can't draw. */
+fn map_framebuffer(info: &FramebufferInfo) -> Option<Framebuffer> {
+    use crate::memory::paging::{PageAlignedAddressT,PageAllocationSizeT,PageAlignedValue,pageFlags};
+    let format = PixelFormat::from_info(info)?;
+    // (the framebuffer isn't necessarily page-aligned)
+    let page_offset = info.phys_addr % 4096;
+    let phys_start = info.phys_addr - page_offset;
+    let size = (info.pitch as usize) * (info.height as usize) + page_offset;
+
+    let vaddr = global_pages::MMIO_PTABLE_VADDR + phys_start;
+    let buf = global_pages::MMIO_PTABLE.allocate_at(PageAlignedAddressT::new(vaddr), PageAllocationSizeT::new_rounded(size))?;
+    buf.set_base_addr(phys_start, pageFlags!(t:WRITEABLE,m:PINNED,m:CACHE_WRITE_COMBINING));
+    buf.leak();  // (the framebuffer stays mapped forever)
+    Some(Framebuffer { base: vaddr + page_offset, pitch: info.pitch as usize, width: info.width as usize, height: info.height as usize, format })
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+struct Cell {
+    c: u8,
+    colour: TextColour,
+}
+
+pub struct FramebufferConsole {
+    /// None until the framebuffer has been mapped (anything written before that is discarded)
+    fb: Option<Framebuffer>,
+    /// The text currently on screen, row by row
+    cells: Vec<Cell>,
+    columns: usize,
+    rows: usize,
+    /// Each pixel of the font is drawn as a scale*scale square
+    scale: usize,
+
+    column_pos: usize,
+    row_pos: usize,
+    /// Where the cursor was last drawn (so it can be removed when it moves)
+    cursor_drawn: Option<(usize,usize)>,
+    colour: TextColour,
+    default_colour: TextColour,
+    ansi: AnsiParser,
+}
+impl FramebufferConsole {
+    const fn new() -> Self {
+        Self {
+            fb: None, cells: Vec::new(), columns: 0, rows: 0, scale: 1,
+            column_pos: 0, row_pos: 0, cursor_drawn: None,
+            colour: TextColour::DEFAULT, default_colour: TextColour::DEFAULT,
+            ansi: AnsiParser::new(),
+        }
+    }
+    pub fn is_mapped(&self) -> bool { self.fb.is_some() }
+    /// The size of the console, in characters (columns, rows)
+    pub fn size(&self) -> (usize,usize) { (self.columns, self.rows) }
+
+    /* Set up the console on the given framebuffer */
+    fn attach(&mut self, fb: Framebuffer) {
+        self.scale = if fb.width >= DOUBLE_SIZE_MIN_WIDTH { 2 } else { 1 };
+        self.columns = fb.width / (GLYPH_WIDTH*self.scale);
+        self.rows = fb.height / (GLYPH_HEIGHT*self.scale);
+        self.cells = alloc::vec![Cell { c: b' ', colour: self.colour }; self.columns*self.rows];
+        self.fb = Some(fb);
+    }
+
+    /* Draw the given cell (optionally with the cursor, which is an underline along the bottom of the cell) */
+    fn draw_cell(&mut self, x: usize, y: usize, with_cursor: bool) {
+        let Cell { c, colour } = self.cells[y*self.columns + x];
+        let scale = self.scale;
+        let Some(fb) = self.fb.as_mut() else { return };
+        let fg = fb.format.encode(colour.foreground_rgb());
+        let bg = fb.format.encode(colour.background_rgb());
+        let glyph = font::glyph(c);
+        let (left, top) = (x*GLYPH_WIDTH*scale, y*GLYPH_HEIGHT*scale);
+        for gy in 0..GLYPH_HEIGHT*scale {
+            let row = if with_cursor && gy >= (GLYPH_HEIGHT-1)*scale { 0xFF } else { glyph[gy/scale] };
+            for gx in 0..GLYPH_WIDTH*scale {
+                let set = (row >> (gx/scale)) & 1 != 0;
+                fb.put_pixel(left+gx, top+gy, if set { fg } else { bg });
+            }
+        }
+    }
+    /* Set the given cell, redrawing it only if it has changed */
+    fn put_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        let i = y*self.columns + x;
+        if self.cells[i] == cell { return; }
+        self.cells[i] = cell;
+        self.draw_cell(x, y, false);
+        if self.cursor_drawn == Some((x,y)) { self.cursor_drawn = None; }
+    }
+
+    pub fn advance_right(&mut self){
+        self.column_pos += 1;
+        if self.column_pos >= self.columns {
+            self.new_line();
+        }
+    }
+    pub fn advance_down(&mut self){
+        self.row_pos += 1;
+        if self.row_pos >= self.rows {
+            self.scroll(1);
+        }
+    }
+    pub fn return_to_left(&mut self){
+        self.column_pos = 0;
+    }
+
+    pub fn new_line(&mut self){
+        self.return_to_left();
+        self.advance_down();
+    }
+
+    pub fn colour(&self) -> TextColour { self.colour }
+    pub fn set_colour(&mut self, colour: TextColour){
+        self.colour = colour
+    }
+    /* Set the colour used after an ANSI reset (ESC [ 0 m), as well as the current colour */
+    pub fn set_default_colour(&mut self, colour: TextColour){
+        self.default_colour = colour; self.colour = colour;
+    }
+
+    // Scroll the screen up to make space for new text
+    pub fn scroll(&mut self, nlines: usize){
+        let nlines = nlines.min(self.rows);
+        if self.is_mapped() {
+            // Move text up (only redrawing the cells which actually change, as drawing is comparatively slow)
+            for newy in 0..(self.rows-nlines) {
+                let oldy = newy+nlines;
+                for x in 0..self.columns {
+                    let old = self.cells[oldy*self.columns + x];
+                    self.put_cell(x, newy, old);
+                }
+            }
+
+            // Clear bottom lines
+            let blank = Cell { c: b' ', colour: self.colour };
+            for y in (self.rows-nlines)..self.rows {
+                for x in 0..self.columns {
+                    self.put_cell(x, y, blank);
+                }
+            }
+        }
+
+        // Move cursor to correct position
+        if self.row_pos < nlines { self.row_pos = 0; }
+        else { self.row_pos -= nlines; }
+    }
+    /* Fill the screen with the current background colour, and move the cursor to the top-left */
+    pub fn clear(&mut self){
+        let blank = Cell { c: b' ', colour: self.colour };
+        self.cells.fill(blank);
+        if let Some(fb) = self.fb.as_mut() {
+            // (this also clears the margins to the right and bottom, which aren't part of any cell)
+            let bg = fb.format.encode(blank.colour.background_rgb());
+            fb.fill(bg);
+        }
+        self.cursor_drawn = None;
+        self.column_pos = 0; self.row_pos = 0;
+        self.update_cursor();
+    }
+
+    /* Draw the cursor where the next character will be written (removing it from where it was before) */
+    fn update_cursor(&mut self){
+        if !self.is_mapped() { return; }
+        let pos = (self.column_pos, self.row_pos);
+        if self.cursor_drawn == Some(pos) { return; }
+        if let Some((x, y)) = self.cursor_drawn.take() { self.draw_cell(x, y, false); }
+        self.draw_cell(pos.0, pos.1, true);
+        self.cursor_drawn = Some(pos);
+    }
+
+    pub fn write_byte(&mut self, byte: u8){
+        let default = self.default_colour;
+        match self.ansi.feed(byte, &mut self.colour, default) {
+            None => {},
+            Some(b'\n') => self.new_line(),
+            Some(b'\r') => self.return_to_left(),
+            Some(byte) => {
+                if self.is_mapped() {
+                    let (x, y) = (self.column_pos, self.row_pos);
+                    self.put_cell(x, y, Cell { c: byte, colour: self.colour });
+                }
+                self.advance_right();
+            },
+        }
+    }
+
+    pub fn write_string(&mut self, s: &str){
+        for c in s.bytes(){
+            // Characters outside of ASCII aren't in the font, so they're shown as the font's replacement glyph
+            self.write_byte(if c.is_ascii() { c } else { 0xFE });
+        }
+        self.update_cursor();
+    }
+}
+impl core::fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_string(s); Ok(())
+    }
+}
+
+pub static FB_CONSOLE: KMutex<FramebufferConsole> = KMutex::new(FramebufferConsole::new());
+
+/* Map the bootloader's framebuffer and clear the screen. Returns false if there is no (usable) framebuffer, e.g. if we were booted in VGA text mode. */
+pub fn init_framebuffer_console() -> bool {
+    let Some(info) = *MULTIBOOT_FRAMEBUFFER else { return false; };
+    if let FramebufferColourInfo::EgaText = info.colour_info { return false; }  // (handled by display_vga instead)
+    let Some(fb) = map_framebuffer(&info) else {
+        klog!(Warning, COREDRIVERS_FRAMEBUFFER, "Unable to use framebuffer at {:#x} ({}x{}, {}bpp, {:?}).", info.phys_addr, info.width, info.height, info.bpp, info.colour_info);
+        return false;
+    };
+    let mut console = FB_CONSOLE.lock();
+    console.attach(fb);
+    console.clear();
+    let (columns, rows) = console.size();
+    drop(console);
+    klog!(Info, COREDRIVERS_FRAMEBUFFER, "Using {}x{} {}bpp framebuffer at {:#x} as a {}x{} text console.", info.width, info.height, info.bpp, info.phys_addr, columns, rows);
+    true
+}
+
+/// A handle to FB_CONSOLE, usable as a log destination (each write locks the console)
+pub struct FramebufferConsoleWriter;
+impl core::fmt::Write for FramebufferConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        FB_CONSOLE.lock().write_str(s)
+    }
+}
+
+/* Paint the panic screen: the whole screen turns red, and the given message is written onto it.
+    Safety: Bypasses the console's lock (which may be held forever by whoever panicked). Only for use by the panic handler. */
+pub unsafe fn show_panic_screen(message: core::fmt::Arguments){
+    use core::fmt::Write;
+    if FB_CONSOLE.is_locked() { FB_CONSOLE.force_unlock(); }
+    let mut console = FB_CONSOLE.lock();
+    if !console.is_mapped() { return; }
+    console.ansi.reset();
+    console.set_default_colour(TextColour::PANIC);
+    console.clear();
+    let _ = console.write_fmt(message);
+}
@@ -0,0 +1,275 @@
+//! VGA text-mode console (80x25), mapped through MMIO_PTABLE
+//! The console understands ANSI colour codes (see display_ansi), so that log formatters can colour their output.
+
+use volatile::Volatile;
+use x86_64::instructions::port::Port;
+
+use crate::logging::klog;
+use super::display_ansi::{AnsiParser,TextColour};
+use crate::memory::paging::global_pages;
+use crate::sync::kspin::KMutex;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BaseColour{
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+}
+impl BaseColour {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b0111 {
+            0 => BaseColour::Black,
+            1 => BaseColour::Blue,
+            2 => BaseColour::Green,
+            3 => BaseColour::Cyan,
+            4 => BaseColour::Red,
+            5 => BaseColour::Magenta,
+            6 => BaseColour::Brown,
+            7 => BaseColour::LightGray,
+            _ => unreachable!(),
+        }
+    }
+}
+
+const FLAG_BLINK: u8 = 0b1000_0000;
+const FLAG_LIGHT: u8 = 0b0000_1000;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct VGAColour(u8);
+impl VGAColour {
+    pub const DEFAULT: Self = Self::new(BaseColour::LightGray, BaseColour::Black, false, false);
+    /// The colour of the panic screen (bright white on red)
+    pub const PANIC: Self = Self::new(BaseColour::LightGray, BaseColour::Red, true, false);
+
+    pub const fn new(foreground: BaseColour, background: BaseColour, bright: bool, blink: bool) -> Self {
+        let bright_flag = if bright { FLAG_LIGHT } else { 0 };
+        let blink_flag = if blink { FLAG_BLINK } else { 0 };
+        VGAColour((background as u8)<<4 | (foreground as u8) | bright_flag | blink_flag)
+    }
+
+    pub fn is_blinking(&self) -> bool {
+        (self.0 & FLAG_BLINK) != 0
+    }
+    pub fn is_bright(&self) -> bool {
+        (self.0 & FLAG_LIGHT) != 0
+    }
+    pub fn foreground(&self) -> BaseColour {
+        BaseColour::from_bits(self.0)
+    }
+    pub fn background(&self) -> BaseColour {
+        BaseColour::from_bits(self.0 >> 4)
+    }
+
+}
+// (TextColour uses the same colour numbers as VGA, so these are simple conversions. Bright backgrounds aren't available, as that bit is used for blinking)
+impl From<TextColour> for VGAColour {
+    fn from(colour: TextColour) -> Self { Self((colour.background & 0b0111) << 4 | (colour.foreground & 0b1111)) }
+}
+impl From<VGAColour> for TextColour {
+    fn from(colour: VGAColour) -> Self { Self { foreground: colour.0 & 0b1111, background: (colour.0 >> 4) & 0b0111 } }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct VGAChar {
+    ascii_code: u8,
+    colour: VGAColour,
+}
+
+const VGA_HEIGHT: usize = 25;
+const VGA_WIDTH: usize = 80;
+#[repr(transparent)]
+pub struct VGABuffer {
+    chars: [[Volatile<VGAChar>; VGA_WIDTH]; VGA_HEIGHT],
+}
+impl VGABuffer {
+    pub fn put_byte(&mut self, x: usize, y: usize, c: u8, colour: VGAColour) {
+        self.chars[y][x].write(VGAChar { ascii_code: c, colour: colour });
+    }
+    pub fn put_vgachar(&mut self, x: usize, y: usize, chr: VGAChar){
+        self.chars[y][x].write(chr);
+    }
+
+    pub fn get_vgachar(&self, x: usize, y: usize) -> VGAChar { self.chars[y][x].read() }
+}
+
+pub const VGA_BUFFER_PHYSICAL: usize = 0xb8000;
+pub const VGA_BUFFER_ADDR: usize = global_pages::MMIO_PTABLE_VADDR + VGA_BUFFER_PHYSICAL;
+pub const VGA_BUFFER_SIZE: usize = VGA_HEIGHT * VGA_WIDTH * 2;
+
+/* Map the VGA text buffer into MMIO_PTABLE. Returns None if it couldn't be mapped. */
+fn map_vga_mmio() -> Option<&'static mut VGABuffer> {
+    use crate::memory::paging::{PageAlignedAddressT,PageAllocationSizeT,PageAlignedValue,pageFlags};
+    let buf = global_pages::MMIO_PTABLE.allocate_at(PageAlignedAddressT::new(VGA_BUFFER_ADDR), PageAllocationSizeT::new_rounded(VGA_BUFFER_SIZE))?;
+    buf.set_base_addr(VGA_BUFFER_PHYSICAL, pageFlags!(t:WRITEABLE,m:PINNED,m:CACHE_WRITE_THROUGH));
+    buf.leak();  // (the buffer stays mapped forever)
+    Some(unsafe { &mut *(VGA_BUFFER_ADDR as *mut VGABuffer) })
+}
+
+pub struct VGAConsole {
+    /// None until the buffer has been mapped (anything written before that is discarded)
+    buffer: Option<&'static mut VGABuffer>,
+    column_pos: usize,
+    row_pos: usize,
+    colour: VGAColour,
+    default_colour: VGAColour,
+    ansi: AnsiParser,
+}
+impl VGAConsole {
+    const fn new() -> Self {
+        Self {
+            buffer: None,
+            column_pos: 0, row_pos: 0,
+            colour: VGAColour::DEFAULT, default_colour: VGAColour::DEFAULT,
+            ansi: AnsiParser::new(),
+        }
+    }
+    pub fn is_mapped(&self) -> bool { self.buffer.is_some() }
+
+    pub fn advance_right(&mut self){
+        self.column_pos += 1;
+        if self.column_pos >= VGA_WIDTH {
+            self.new_line();
+        }
+    }
+    pub fn advance_down(&mut self){
+        self.row_pos += 1;
+        if self.row_pos >= VGA_HEIGHT {
+            self.scroll(1);
+        }
+    }
+    pub fn return_to_left(&mut self){
+        self.column_pos = 0;
+    }
+
+    pub fn new_line(&mut self){
+        self.return_to_left();
+        self.advance_down();
+    }
+
+    pub fn colour(&self) -> VGAColour { self.colour }
+    pub fn set_colour(&mut self, colour: VGAColour){
+        self.colour = colour
+    }
+    /* Set the colour used after an ANSI reset (ESC [ 0 m), as well as the current colour */
+    pub fn set_default_colour(&mut self, colour: VGAColour){
+        self.default_colour = colour; self.colour = colour;
+    }
+
+    // Scroll the screen up to make space for new text
+    pub fn scroll(&mut self, nlines: usize){
+        let nlines = nlines.min(VGA_HEIGHT);
+        if let Some(buffer) = self.buffer.as_mut() {
+            // Move text up
+            for newy in 0..(VGA_HEIGHT-nlines) {
+                let oldy = newy+nlines;
+                for x in 0..VGA_WIDTH {
+                    let old = buffer.get_vgachar(x, oldy);
+                    buffer.put_vgachar(x, newy, old);
+                }
+            }
+
+            // Clear bottom lines
+            for y in (VGA_HEIGHT-nlines)..VGA_HEIGHT {
+                for x in 0..VGA_WIDTH {
+                    buffer.put_byte(x,y, b' ', self.colour);
+                }
+            }
+        }
+
+        // Move cursor to correct position
+        if self.row_pos < nlines { self.row_pos = 0; }
+        else { self.row_pos -= nlines; }
+    }
+    /* Fill the screen with spaces in the current colour, and move the cursor to the top-left */
+    pub fn clear(&mut self){
+        if let Some(buffer) = self.buffer.as_mut() {
+            for y in 0..VGA_HEIGHT { for x in 0..VGA_WIDTH { buffer.put_byte(x, y, b' ', self.colour); } }
+        }
+        self.column_pos = 0; self.row_pos = 0;
+        self.update_cursor();
+    }
+
+    /* Move the hardware (blinking) cursor to where the next character will be written */
+    fn update_cursor(&self){
+        if self.buffer.is_none() { return; }
+        let pos = (self.row_pos * VGA_WIDTH + self.column_pos) as u16;
+        unsafe {
+            let mut index: Port<u8> = Port::new(0x3D4);
+            let mut data: Port<u8> = Port::new(0x3D5);
+            index.write(0x0F); data.write((pos & 0xFF) as u8);
+            index.write(0x0E); data.write((pos >> 8) as u8);
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8){
+        let mut colour = TextColour::from(self.colour);
+        let byte = self.ansi.feed(byte, &mut colour, TextColour::from(self.default_colour));
+        self.colour = VGAColour::from(colour);
+        match byte {
+            None => {},
+            Some(b'\n') => self.new_line(),
+            Some(b'\r') => self.return_to_left(),
+            Some(byte) => {
+                let (x, y, colour) = (self.column_pos, self.row_pos, self.colour);
+                if let Some(buffer) = self.buffer.as_mut() { buffer.put_byte(x, y, byte, colour); }
+                self.advance_right();
+            },
+        }
+    }
+
+    pub fn write_string(&mut self, s: &str){
+        for c in s.bytes(){
+            // Characters outside of ASCII don't exist in the VGA font (or at least, not in the same place), so show them as a block
+            self.write_byte(if c.is_ascii() { c } else { 0xFE });
+        }
+        self.update_cursor();
+    }
+}
+impl core::fmt::Write for VGAConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_string(s); Ok(())
+    }
+}
+
+pub static VGA_CONSOLE: KMutex<VGAConsole> = KMutex::new(VGAConsole::new());
+
+/* Map the VGA text buffer and clear the screen. Returns false if the buffer couldn't be mapped. */
+pub fn init_vga_console() -> bool {
+    let Some(buffer) = map_vga_mmio() else {
+        klog!(Warning, COREDRIVERS_VGA, "Unable to map VGA text buffer at {:#x}.", VGA_BUFFER_ADDR);
+        return false;
+    };
+    let mut console = VGA_CONSOLE.lock();
+    console.buffer = Some(buffer);
+    console.clear();
+    true
+}
+
+/// A handle to VGA_CONSOLE, usable as a log destination (each write locks the console)
+pub struct VGAConsoleWriter;
+impl core::fmt::Write for VGAConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        VGA_CONSOLE.lock().write_str(s)
+    }
+}
+
+/* Paint the panic screen: the whole screen turns red, and the given message is written onto it.
+    Safety: Bypasses the console's lock (which may be held forever by whoever panicked). Only for use by the panic handler. */
+pub unsafe fn show_panic_screen(message: core::fmt::Arguments){
+    use core::fmt::Write;
+    if VGA_CONSOLE.is_locked() { VGA_CONSOLE.force_unlock(); }
+    let mut console = VGA_CONSOLE.lock();
+    if !console.is_mapped() { return; }
+    console.ansi.reset();
+    console.set_default_colour(VGAColour::PANIC);
+    console.clear();
+    let _ = console.write_fmt(message);
+}
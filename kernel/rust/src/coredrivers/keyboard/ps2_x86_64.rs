@@ -0,0 +1,370 @@
+//! Driver for a keyboard on the first port of the PS/2 (8042) controller.
+//! init() resets and tests the controller, checks which ports it has, resets the keyboard and selects scancode set 2 (falling back to the controller's set 1 translation if the keyboard refuses).
+//! Once IRQ1 is enabled, scancodes are decoded (using pc-keyboard) and placed into a WQueue of KeyEvents for tasks to read from.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use x86_64::instructions::port::Port;
+use pc_keyboard::{DecodedKey,HandleControl,KeyCode,KeyState,Keyboard,ScancodeSet1,ScancodeSet2};
+use pc_keyboard::layouts::Us104Key;
+
+use crate::sync::kspin::KMutex;
+use crate::sync::WQueue;
+use crate::cpu::interrupts::{register_irq_handler,IrqHandlerId};
+use crate::logging::klog;
+
+/// The maximum number of key events that are kept for readers. Anything received past this point is dropped.
+pub const EVENT_BUFFER_LIMIT: usize = 256;
+/// The IRQ line used by the first PS/2 port
+const KEYBOARD_IRQ: u8 = 1;
+/// How many times to poll the status register before giving up on the controller/keyboard
+const POLL_TIMEOUT: usize = 100_000;
+/// How many times to re-send a command the keyboard asked us to resend
+const MAX_RESENDS: usize = 3;
+
+const PORT_DATA: u16 = 0x60;
+const PORT_STATUS_COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1<<0;
+const STATUS_INPUT_FULL: u8 = 1<<1;
+
+// Controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+
+const CONFIG_PORT1_IRQ: u8 = 1<<0;
+const CONFIG_PORT2_IRQ: u8 = 1<<1;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1<<5;
+const CONFIG_PORT1_TRANSLATION: u8 = 1<<6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Keyboard commands and responses
+const KB_SET_LEDS: u8 = 0xED;
+const KB_SCANCODE_SET: u8 = 0xF0;
+const KB_ENABLE_SCANNING: u8 = 0xF4;
+const KB_DISABLE_SCANNING: u8 = 0xF5;
+const KB_RESET: u8 = 0xFF;
+const KB_ACK: u8 = 0xFA;
+const KB_RESEND: u8 = 0xFE;
+const KB_SELF_TEST_PASSED: u8 = 0xAA;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PS2Error {
+    /// The controller didn't respond in time (most likely because there isn't one)
+    Timeout,
+    /// The controller failed its self-test, returning the given code
+    ControllerSelfTestFailed(u8),
+    /// The first port failed its interface test, returning the given code
+    PortTestFailed(u8),
+    /// Nothing responded to a reset on the first port, or it failed its self-test
+    NoKeyboard,
+}
+
+/// A key being pressed or released
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// The character (or raw key) that the key press produced, taking modifiers and the layout into account. None for releases and modifier keys.
+    pub decoded: Option<DecodedKey>,
+}
+
+/// Counters for the keyboard
+#[derive(Debug,Default)]
+pub struct KeyboardStats {
+    pub bytes_received: AtomicUsize,
+    pub events: AtomicUsize,
+    /// Events that were discarded because nobody was reading them (see EVENT_BUFFER_LIMIT)
+    pub events_dropped: AtomicUsize,
+    /// Bytes that couldn't be decoded as part of a scancode
+    pub decode_errors: AtomicUsize,
+}
+impl KeyboardStats {
+    const fn new() -> Self {
+        Self { bytes_received: AtomicUsize::new(0), events: AtomicUsize::new(0), events_dropped: AtomicUsize::new(0), decode_errors: AtomicUsize::new(0) }
+    }
+}
+
+/// The scancode decoder, for whichever set the keyboard ended up using
+enum Decoder {
+    Set1(Keyboard<Us104Key,ScancodeSet1>),
+    Set2(Keyboard<Us104Key,ScancodeSet2>),
+}
+impl Decoder {
+    fn new(set: u8) -> Self {
+        match set {
+            1 => Self::Set1(Keyboard::new(ScancodeSet1::new(), Us104Key, HandleControl::Ignore)),
+            _ => Self::Set2(Keyboard::new(ScancodeSet2::new(), Us104Key, HandleControl::Ignore)),
+        }
+    }
+    fn add_byte(&mut self, byte: u8) -> Result<Option<pc_keyboard::KeyEvent>,pc_keyboard::Error> {
+        match self { Self::Set1(kb) => kb.add_byte(byte), Self::Set2(kb) => kb.add_byte(byte) }
+    }
+    fn process_keyevent(&mut self, event: pc_keyboard::KeyEvent) -> Option<DecodedKey> {
+        match self { Self::Set1(kb) => kb.process_keyevent(event), Self::Set2(kb) => kb.process_keyevent(event) }
+    }
+}
+
+struct ControllerState {
+    /// None until the controller and keyboard have been (successfully) initialised
+    decoder: Option<Decoder>,
+    irq_handler: Option<IrqHandlerId>,
+    /// Whether the controller has a second port (we don't drive it, but it's kept disabled)
+    dual_channel: bool,
+
+    /// Command bytes waiting to be sent to the keyboard (sent one at a time, as each is acknowledged)
+    commands: VecDeque<u8>,
+    /// The byte we're waiting for the keyboard to acknowledge, and how many times it has been resent
+    awaiting_ack: Option<(u8,usize)>,
+    /// The lock LEDs (bit 0 = scroll lock, 1 = num lock, 2 = caps lock)
+    leds: u8,
+}
+impl ControllerState {
+    unsafe fn read_status(&self) -> u8 {
+        Port::<u8>::new(PORT_STATUS_COMMAND).read()
+    }
+    fn wait_output_full(&self) -> Result<(),PS2Error> {
+        for _ in 0..POLL_TIMEOUT {
+            if unsafe { self.read_status() } & STATUS_OUTPUT_FULL != 0 { return Ok(()); }
+            core::hint::spin_loop();
+        }
+        Err(PS2Error::Timeout)
+    }
+    fn wait_input_empty(&self) -> Result<(),PS2Error> {
+        for _ in 0..POLL_TIMEOUT {
+            if unsafe { self.read_status() } & STATUS_INPUT_FULL == 0 { return Ok(()); }
+            core::hint::spin_loop();
+        }
+        Err(PS2Error::Timeout)
+    }
+
+    /// Read a byte from the data port, waiting for one to arrive
+    fn read_data(&mut self) -> Result<u8,PS2Error> {
+        self.wait_output_full()?;
+        Ok(unsafe { Port::<u8>::new(PORT_DATA).read() })
+    }
+    /// Write a byte to the data port (i.e. to the keyboard, unless it's the argument to a controller command)
+    fn write_data(&mut self, byte: u8) -> Result<(),PS2Error> {
+        self.wait_input_empty()?;
+        unsafe { Port::<u8>::new(PORT_DATA).write(byte); }
+        Ok(())
+    }
+    fn controller_command(&mut self, command: u8) -> Result<(),PS2Error> {
+        self.wait_input_empty()?;
+        unsafe { Port::<u8>::new(PORT_STATUS_COMMAND).write(command); }
+        Ok(())
+    }
+    /// Discard anything left in the output buffer
+    fn flush_output(&mut self) {
+        while unsafe { self.read_status() } & STATUS_OUTPUT_FULL != 0 {
+            unsafe { Port::<u8>::new(PORT_DATA).read(); }
+        }
+    }
+
+    fn read_config(&mut self) -> Result<u8,PS2Error> {
+        self.controller_command(CMD_READ_CONFIG)?;
+        self.read_data()
+    }
+    fn write_config(&mut self, config: u8) -> Result<(),PS2Error> {
+        self.controller_command(CMD_WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /* Send a command byte to the keyboard and wait for it to be acknowledged (re-sending it if asked). Only for use while IRQ1 is disabled. */
+    fn keyboard_command_polled(&mut self, byte: u8) -> Result<(),PS2Error> {
+        for _ in 0..=MAX_RESENDS {
+            self.write_data(byte)?;
+            match self.read_data()? {
+                KB_ACK => return Ok(()),
+                KB_RESEND => continue,
+                _ => return Err(PS2Error::NoKeyboard),
+            }
+        }
+        Err(PS2Error::NoKeyboard)
+    }
+
+    /* Reset and configure the controller and keyboard. Returns the scancode set that the decoder should use. */
+    fn init_controller(&mut self) -> Result<u8,PS2Error> {
+        // Disable both ports while we're setting things up, so the keyboard doesn't send anything in the middle of it
+        self.controller_command(CMD_DISABLE_PORT1)?;
+        self.controller_command(CMD_DISABLE_PORT2)?;
+        self.flush_output();
+
+        // Disable IRQs and translation for now
+        let config = self.read_config()? & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_PORT1_TRANSLATION);
+        self.write_config(config)?;
+
+        // Self-test (this may reset the controller on some hardware, so the config is written again afterwards)
+        self.controller_command(CMD_SELF_TEST)?;
+        let result = self.read_data()?;
+        if result != SELF_TEST_PASSED { return Err(PS2Error::ControllerSelfTestFailed(result)); }
+        self.write_config(config)?;
+
+        // Check for a second port: enabling it should start its clock
+        self.controller_command(CMD_ENABLE_PORT2)?;
+        self.dual_channel = self.read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+        if self.dual_channel {
+            self.controller_command(CMD_DISABLE_PORT2)?;
+            self.controller_command(CMD_TEST_PORT2)?;
+            let result = self.read_data()?;
+            if result != PORT_TEST_PASSED { klog!(Debug, COREDRIVERS_PS2, "Second PS/2 port failed its interface test ({:#x}).", result); }
+        }
+
+        // Test and enable the first port
+        self.controller_command(CMD_TEST_PORT1)?;
+        let result = self.read_data()?;
+        if result != PORT_TEST_PASSED { return Err(PS2Error::PortTestFailed(result)); }
+        self.controller_command(CMD_ENABLE_PORT1)?;
+
+        // Reset the keyboard (it sends ACK, followed by the result of its self-test)
+        self.keyboard_command_polled(KB_RESET).map_err(|_|PS2Error::NoKeyboard)?;
+        if self.read_data().map_err(|_|PS2Error::NoKeyboard)? != KB_SELF_TEST_PASSED { return Err(PS2Error::NoKeyboard); }
+
+        // Select scancode set 2 (which all keyboards should support). If the keyboard won't switch, have the controller translate to set 1 instead.
+        self.keyboard_command_polled(KB_DISABLE_SCANNING)?;
+        let set = match self.keyboard_command_polled(KB_SCANCODE_SET).and_then(|_|self.keyboard_command_polled(2)) {
+            Ok(()) => 2,
+            Err(_) => {
+                self.flush_output();
+                self.write_config(config | CONFIG_PORT1_TRANSLATION)?;
+                1
+            },
+        };
+        self.keyboard_command_polled(KB_ENABLE_SCANNING)?;
+        self.flush_output();
+        Ok(set)
+    }
+
+    /// Start sending the next queued command, if the keyboard isn't busy with another one
+    fn send_next_command(&mut self) {
+        if self.awaiting_ack.is_some() { return; }
+        let Some(byte) = self.commands.pop_front() else { return };
+        if self.write_data(byte).is_ok() { self.awaiting_ack = Some((byte, 0)); }
+    }
+    fn queue_command(&mut self, bytes: &[u8]) {
+        self.commands.extend(bytes);
+        self.send_next_command();
+    }
+
+    /* Handle a byte from the keyboard. Returns a KeyEvent if it completed a scancode. */
+    fn receive(&mut self, byte: u8, stats: &KeyboardStats) -> Option<KeyEvent> {
+        // Responses to commands
+        if let Some((command, resends)) = self.awaiting_ack {
+            match byte {
+                KB_ACK => { self.awaiting_ack = None; self.send_next_command(); return None; },
+                KB_RESEND if resends < MAX_RESENDS => {
+                    if self.write_data(command).is_ok() { self.awaiting_ack = Some((command, resends+1)); }
+                    return None;
+                },
+                KB_RESEND => {
+                    klog!(Warning, COREDRIVERS_PS2, "Keyboard rejected command byte {:#x}.", command);
+                    self.awaiting_ack = None; self.commands.clear();
+                    return None;
+                },
+                _ => {},  // (a scancode that arrived before the response)
+            }
+        }
+
+        let decoder = self.decoder.as_mut()?;
+        let event = match decoder.add_byte(byte) {
+            Ok(Some(event)) => event,
+            Ok(None) => return None,  // (part of a multi-byte scancode)
+            Err(_) => { stats.decode_errors.fetch_add(1, Ordering::Relaxed); return None; },
+        };
+        let (code, state) = (event.code, event.state);
+        let decoded = decoder.process_keyevent(event);
+
+        // Keep the LEDs in sync with the lock keys
+        if state == KeyState::Down {
+            let led = match code { KeyCode::ScrollLock => 1<<0, KeyCode::NumpadLock => 1<<1, KeyCode::CapsLock => 1<<2, _ => 0 };
+            if led != 0 {
+                self.leds ^= led;
+                let leds = self.leds;
+                self.queue_command(&[KB_SET_LEDS, leds]);
+            }
+        }
+        Some(KeyEvent { code, state, decoded })
+    }
+}
+
+pub struct PS2Keyboard {
+    state: KMutex<ControllerState>,
+    events: WQueue<KeyEvent>,
+    stats: KeyboardStats,
+}
+impl PS2Keyboard {
+    const fn new() -> Self {
+        Self {
+            state: KMutex::new(ControllerState { decoder: None, irq_handler: None, dual_channel: false, commands: VecDeque::new(), awaiting_ack: None, leds: 0 }),
+            events: WQueue::new(),
+            stats: KeyboardStats::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &KeyboardStats { &self.stats }
+    pub fn is_initialised(&self) -> bool { self.state.lock().decoder.is_some() }
+
+    /* Initialise the controller and keyboard, and start receiving key events through IRQ1.
+        Returns an error if there is no working controller or keyboard, in which case no events will ever be received. Does nothing if already initialised. */
+    pub fn init(&'static self) -> Result<(),PS2Error> {
+        let mut state = self.state.lock();
+        if state.decoder.is_some() { return Ok(()); }
+        let set = state.init_controller()?;
+        state.decoder = Some(Decoder::new(set));
+        // (the handler registry is a KRwLock, so it's fine to register while holding our state lock)
+        state.irq_handler = Some(register_irq_handler(KEYBOARD_IRQ, move ||self.handle_interrupt()));
+        let config = state.read_config()?;
+        state.write_config(config | CONFIG_PORT1_IRQ)?;
+        let dual_channel = state.dual_channel;
+        drop(state);
+        klog!(Info, COREDRIVERS_PS2, "PS/2 keyboard initialised (scancode set {}{}).", set, if dual_channel { ", dual-channel controller" } else { "" });
+        Ok(())
+    }
+
+    fn handle_interrupt(&self){
+        let mut state = self.state.lock();
+        // (the interrupt may be spurious, so check there is actually something to read)
+        if unsafe { state.read_status() } & STATUS_OUTPUT_FULL == 0 { return; }
+        let byte = unsafe { Port::<u8>::new(PORT_DATA).read() };
+        self.stats.bytes_received.fetch_add(1, Ordering::Relaxed);
+        let event = state.receive(byte, &self.stats);
+        drop(state);
+        if let Some(event) = event {
+            self.stats.events.fetch_add(1, Ordering::Relaxed);
+            if self.events.len() >= EVENT_BUFFER_LIMIT { self.stats.events_dropped.fetch_add(1, Ordering::Relaxed); return; }
+            self.events.push(event);
+        }
+    }
+
+    /* Read a key event, blocking until one is available. */
+    pub fn read_event(&self) -> KeyEvent {
+        self.events.get()
+    }
+    /* Read a key event if one is available. */
+    pub fn try_read_event(&self) -> Option<KeyEvent> {
+        self.events.get_if_available()
+    }
+    /// The number of key events waiting to be read
+    pub fn events_available(&self) -> usize {
+        self.events.len()
+    }
+
+    /* Set the keyboard's lock LEDs (bit 0 = scroll lock, 1 = num lock, 2 = caps lock). They are otherwise kept in sync with the lock keys automatically. */
+    pub fn set_leds(&self, leds: u8){
+        let mut state = self.state.lock();
+        if state.decoder.is_none() { return; }
+        state.leds = leds & 0b111;
+        state.queue_command(&[KB_SET_LEDS, leds & 0b111]);
+    }
+}
+
+pub static KEYBOARD: PS2Keyboard = PS2Keyboard::new();
@@ -9,17 +9,26 @@ By the time the OS has finished booting, these should have been replaced with no
 // #[path = "base/mmio32.rs"]
 // pub mod util_mmio32;
 
-// #[cfg_attr(target_arch = "x86_64", path = "keyboard/ps2_x86_64.rs")]
-// pub mod keyboard_ps2;
+#[cfg_attr(target_arch = "x86_64", path = "keyboard/ps2_x86_64.rs")]
+pub mod keyboard_ps2;
 
 #[cfg_attr(target_arch = "x86_64", path = "serial/uart_x86_64.rs")]
 pub mod serial_uart;
 
-// #[cfg_attr(target_arch = "x86_64", path = "system/xapic_x86_64.rs")]
-// pub mod system_apic;
+#[cfg_attr(target_arch = "x86_64", path = "timer/pit_x86_64.rs")]
+pub mod timer_pit;
 
-// #[cfg_attr(target_arch = "x86_64", path = "display/vga_x86.rs")]
-// pub mod display_vga;
+#[cfg_attr(target_arch = "x86_64", path = "system/xapic_x86_64.rs")]
+pub mod system_apic;
+
+#[path = "display/ansi.rs"]
+pub mod display_ansi;
+#[cfg_attr(target_arch = "x86_64", path = "display/vga_x86.rs")]
+pub mod display_vga;
+#[path = "display/font8x8.rs"]
+pub mod display_font8x8;
+#[path = "display/framebuffer.rs"]
+pub mod display_framebuffer;
 
 // #[path = "parser/acpi_tables.rs"]
 // pub mod parse_acpi_tables;
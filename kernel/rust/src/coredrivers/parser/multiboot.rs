@@ -20,6 +20,19 @@ union MBTagContentRaw {
     mem_map: (u32,u32,MemoryMapEntry),  // the first MemoryMapEntry is a stand in for the start of the list of entries
     rsdp_v1: u8,  // The u8 is a stand in for the actual content
     rsdp_v2: u8,
+    framebuffer: FramebufferTagRaw,
+}
+#[derive(Debug,Clone,Copy)]
+#[repr(C,packed)]
+struct FramebufferTagRaw {
+    addr: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    fb_type: u8,
+    reserved: u16,
+    colour_info: [u8; 6],  // (the RGB field positions/sizes, or the number of palette entries for indexed framebuffers)
 }
 
 #[derive(Debug,Clone,Copy)]
@@ -42,6 +55,8 @@ pub enum MBTagContents {
     AcpiRsdpV1 { rsdp_virt_addr: usize },  // const pointers are not Sync???
     AcpiRsdpV2 { rsdp_virt_addr: usize },
     
+    Framebuffer(FramebufferInfo),
+    
     // Terminates the list of tags
     EndOfTags,
 }
@@ -86,6 +101,20 @@ impl MBTag {
                 14 => AcpiRsdpV1 { rsdp_virt_addr: addr_of!(tag_raw.rsdp_v1) as usize },
                 15 => AcpiRsdpV2 { rsdp_virt_addr: addr_of!(tag_raw.rsdp_v2) as usize },
                 
+                8 => Framebuffer({
+                    let raw = tag_raw.framebuffer;
+                    let ci = raw.colour_info;
+                    FramebufferInfo {
+                        phys_addr: raw.addr.try_into().unwrap(), pitch: raw.pitch, width: raw.width, height: raw.height, bpp: raw.bpp,
+                        colour_info: match raw.fb_type {
+                            0 => FramebufferColourInfo::Indexed { num_colours: u16::from_le_bytes([ci[0],ci[1]]) },
+                            1 => FramebufferColourInfo::Rgb { red_position: ci[0], red_size: ci[1], green_position: ci[2], green_size: ci[3], blue_position: ci[4], blue_size: ci[5] },
+                            2 => FramebufferColourInfo::EgaText,
+                            _ => Err(header)?,
+                        },
+                    }
+                }),
+                
                 0 => EndOfTags,
                 _ => Err(header)?,
             },
@@ -109,6 +138,28 @@ impl MemoryMapEntry {
     }
 }
 
+/// The framebuffer set up by the bootloader (multiboot2 tag type 8)
+#[derive(Debug,Clone,Copy)]
+pub struct FramebufferInfo {
+    pub phys_addr: usize,
+    /// The number of bytes in each row (which may be more than width * bytes per pixel)
+    pub pitch: u32,
+    /// In pixels (or characters, for EGA text mode)
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub colour_info: FramebufferColourInfo,
+}
+#[derive(Debug,Clone,Copy)]
+pub enum FramebufferColourInfo {
+    /// Each pixel is an index into a palette (the palette itself isn't kept)
+    Indexed { num_colours: u16 },
+    /// Each pixel contains red, green and blue fields, at the given bit positions and sizes
+    Rgb { red_position: u8, red_size: u8, green_position: u8, green_size: u8, blue_position: u8, blue_size: u8 },
+    /// The "framebuffer" is the VGA text buffer (see display_vga)
+    EgaText,
+}
+
 lazy_static! {
     pub static ref MULTIBOOT_TAGS: Vec<MBTag> = { unsafe {
         // SAFETY: This requires the multiboot_info_ptr (and the information it points to)
@@ -138,6 +189,10 @@ lazy_static! {
         if let MBTagContents::MemoryMap { ref entries, .. } = tag.content { return Some(entries); }
     }; None};
     
+    pub static ref MULTIBOOT_FRAMEBUFFER: Option<FramebufferInfo> = { for tag in &*MULTIBOOT_TAGS {
+        if let MBTagContents::Framebuffer(info) = tag.content { return Some(info); }
+    }; None};
+    
     pub static ref ACPI_RSDP_V1_PHYSADDR: Option<usize> = { for tag in &*MULTIBOOT_TAGS {
             if let MBTagContents::AcpiRsdpV1 { rsdp_virt_addr } = tag.content {
                 let rsdp_phys_addr = crate::memory::paging::ptaddr_virt_to_phys(rsdp_virt_addr);
@@ -1,17 +1,429 @@
+//! Driver for the 16550 UARTs behind the standard PC COM ports (COM1-COM4)
+//! Output is polled until enable_interrupts is called, after which it is buffered and sent from the THRE interrupt.
+//! Input is only received once interrupts are enabled, and is placed into a WQueue for tasks to read from.
 
+use core::fmt;
+use core::sync::atomic::{AtomicUsize,Ordering};
 use lazy_static::lazy_static;
-use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
 
-fn init_serial_1() -> SerialPort {
-    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-    serial_port.init();
-    serial_port
+use crate::sync::kspin::KMutex;
+use crate::sync::WQueue;
+use crate::cpu::interrupts::{register_irq_handler,unregister_irq_handler,IrqHandlerId};
+use crate::logging::klog;
+
+/// The size of each port's transmit buffer, in bytes. If it fills up, writers will wait for it to drain.
+pub const TX_BUFFER_SIZE: usize = 4096;
+/// The maximum number of received bytes that are kept for readers. Anything received past this point is dropped.
+pub const RX_BUFFER_LIMIT: usize = 4096;
+/// The UART's internal clock, divided by 16. Baud rates must divide evenly into this.
+const UART_BASE_BAUD: u32 = 115200;
+/// The size of the transmit FIFO (only filled when it's completely empty)
+const TX_FIFO_SIZE: usize = 16;
+
+// Registers (offset from the port's base)
+const REG_DATA: u16 = 0;  // (DLAB=1: divisor low byte)
+const REG_IER: u16 = 1;  // (DLAB=1: divisor high byte)
+const REG_IIR_FCR: u16 = 2;
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
+const REG_MSR: u16 = 6;
+
+const IER_RX_AVAILABLE: u8 = 1<<0;
+const IER_TX_EMPTY: u8 = 1<<1;
+const IER_LINE_STATUS: u8 = 1<<2;
+const LCR_DLAB: u8 = 1<<7;
+const MCR_DTR: u8 = 1<<0;
+const MCR_RTS: u8 = 1<<1;
+const MCR_OUT2: u8 = 1<<3;  // (gates the UART's interrupt line on PCs)
+const MCR_LOOPBACK: u8 = 1<<4;
+const LSR_DATA_READY: u8 = 1<<0;
+const LSR_OVERRUN: u8 = 1<<1;
+const LSR_PARITY_ERROR: u8 = 1<<2;
+const LSR_FRAMING_ERROR: u8 = 1<<3;
+const LSR_TX_EMPTY: u8 = 1<<5;
+const FCR_ENABLE_AND_CLEAR: u8 = 0b111;
+const FCR_TRIGGER_14: u8 = 0b11<<6;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ComPort { COM1, COM2, COM3, COM4 }
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::COM1, ComPort::COM2, ComPort::COM3, ComPort::COM4];
+    /// The base I/O port of this COM port
+    pub const fn base(self) -> u16 {
+        match self { Self::COM1 => 0x3F8, Self::COM2 => 0x2F8, Self::COM3 => 0x3E8, Self::COM4 => 0x2E8 }
+    }
+    /// The IRQ line used by this COM port (COM1/COM3 and COM2/COM4 share lines)
+    pub const fn irq(self) -> u8 {
+        match self { Self::COM1 | Self::COM3 => 4, Self::COM2 | Self::COM4 => 3 }
+    }
+    const fn index(self) -> usize {
+        match self { Self::COM1 => 0, Self::COM2 => 1, Self::COM3 => 2, Self::COM4 => 3 }
+    }
 }
-pub type SerialPortType = SerialPort;
 
-// This writer uses spinlocks and without_interrupts(...) to ensure that no deadlocks or race conditions occur
-use crate::sync::kspin::KMutex;
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Parity { None, Odd, Even, Mark, Space }
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum StopBits { One, Two }
+
+/// Baud rate and line settings for a port. The default is 115200 8N1.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct LineConfig {
+    pub baud_rate: u32,
+    /// 5-8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+impl LineConfig {
+    pub const DEFAULT: Self = Self { baud_rate: 115200, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One };
+
+    fn divisor(&self) -> Result<u16,SerialError> {
+        if self.baud_rate == 0 || UART_BASE_BAUD % self.baud_rate != 0 { return Err(SerialError::UnsupportedConfig); }
+        Ok((UART_BASE_BAUD / self.baud_rate) as u16)
+    }
+    fn lcr(&self) -> Result<u8,SerialError> {
+        if !(5..=8).contains(&self.data_bits) { return Err(SerialError::UnsupportedConfig); }
+        let data = self.data_bits - 5;
+        let stop = match self.stop_bits { StopBits::One => 0, StopBits::Two => 1<<2 };
+        let parity = match self.parity {
+            Parity::None => 0b000, Parity::Odd => 0b001, Parity::Even => 0b011,
+            Parity::Mark => 0b101, Parity::Space => 0b111,
+        } << 3;
+        Ok(data | stop | parity)
+    }
+}
+impl core::default::Default for LineConfig {
+    fn default() -> Self { Self::DEFAULT }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SerialError {
+    /// The UART did not respond (most likely because there isn't one at this port)
+    NotPresent,
+    /// The requested baud rate or line settings can't be used
+    UnsupportedConfig,
+}
+
+/// Counters for a port. Errors are those reported by the UART's line status register.
+#[derive(Debug,Default)]
+pub struct SerialStats {
+    pub bytes_sent: AtomicUsize,
+    pub bytes_received: AtomicUsize,
+    /// Received bytes that were discarded because nobody was reading them (see RX_BUFFER_LIMIT)
+    pub rx_dropped: AtomicUsize,
+    pub overrun_errors: AtomicUsize,
+    pub parity_errors: AtomicUsize,
+    pub framing_errors: AtomicUsize,
+}
+impl SerialStats {
+    const fn new() -> Self {
+        Self { bytes_sent: AtomicUsize::new(0), bytes_received: AtomicUsize::new(0), rx_dropped: AtomicUsize::new(0),
+               overrun_errors: AtomicUsize::new(0), parity_errors: AtomicUsize::new(0), framing_errors: AtomicUsize::new(0) }
+    }
+}
+
+struct UartState {
+    base: u16,
+    /// The current config, or None if the port hasn't been (successfully) initialised
+    config: Option<LineConfig>,
+    /// Set once the IRQ handler is registered. Until then, everything is polled.
+    irq_handler: Option<IrqHandlerId>,
+
+    // Transmit ring buffer
+    tx_buf: [u8; TX_BUFFER_SIZE],
+    tx_head: usize,
+    tx_len: usize,
+}
+impl UartState {
+    unsafe fn read_reg(&self, reg: u16) -> u8 {
+        Port::<u8>::new(self.base + reg).read()
+    }
+    unsafe fn write_reg(&mut self, reg: u16, value: u8) {
+        Port::<u8>::new(self.base + reg).write(value)
+    }
+
+    fn tx_ready(&self) -> bool {
+        unsafe { self.read_reg(REG_LSR) & LSR_TX_EMPTY != 0 }
+    }
+    /// Send a byte directly, waiting until the UART is ready for it
+    fn send_polled(&mut self, byte: u8) {
+        while !self.tx_ready() { core::hint::spin_loop(); }
+        unsafe { self.write_reg(REG_DATA, byte); }
+    }
+    fn tx_pop(&mut self) -> Option<u8> {
+        if self.tx_len == 0 { return None; }
+        let byte = self.tx_buf[self.tx_head];
+        self.tx_head = (self.tx_head + 1) % TX_BUFFER_SIZE;
+        self.tx_len -= 1;
+        Some(byte)
+    }
+    fn tx_push(&mut self, byte: u8) {
+        debug_assert!(self.tx_len < TX_BUFFER_SIZE);
+        self.tx_buf[(self.tx_head + self.tx_len) % TX_BUFFER_SIZE] = byte;
+        self.tx_len += 1;
+    }
+    /// Move buffered bytes into the transmit FIFO, if it is empty. Returns the number of bytes sent.
+    fn fill_fifo(&mut self) -> usize {
+        if !self.tx_ready() { return 0; }
+        let mut sent = 0;
+        while sent < TX_FIFO_SIZE {
+            let Some(byte) = self.tx_pop() else { break };
+            unsafe { self.write_reg(REG_DATA, byte); }
+            sent += 1;
+        }
+        sent
+    }
+    /// Send everything in the transmit buffer, waiting for the UART as necessary. Returns the number of bytes sent.
+    fn drain_polled(&mut self) -> usize {
+        let mut sent = 0;
+        while let Some(byte) = self.tx_pop() { self.send_polled(byte); sent += 1; }
+        sent
+    }
+}
+
+pub struct SerialPort {
+    port: ComPort,
+    state: KMutex<UartState>,
+    rx: WQueue<u8>,
+    stats: SerialStats,
+}
+impl SerialPort {
+    const fn new(port: ComPort) -> Self {
+        Self {
+            port,
+            state: KMutex::new(UartState { base: port.base(), config: None, irq_handler: None, tx_buf: [0; TX_BUFFER_SIZE], tx_head: 0, tx_len: 0 }),
+            rx: WQueue::new(),
+            stats: SerialStats::new(),
+        }
+    }
+
+    pub fn port(&self) -> ComPort { self.port }
+    pub fn stats(&self) -> &SerialStats { &self.stats }
+    /// The port's current line settings, or None if it hasn't been initialised
+    pub fn config(&self) -> Option<LineConfig> { self.state.lock().config }
+    pub fn is_initialised(&self) -> bool { self.state.lock().config.is_some() }
+
+    /* (Re-)initialise the UART with the given line settings. Anything still in the transmit buffer is sent first (using the old settings).
+        Returns Err(NotPresent) if the UART fails its loopback test, in which case the port is left uninitialised and all writes to it are discarded. */
+    pub fn init(&self, config: LineConfig) -> Result<(),SerialError> {
+        let divisor = config.divisor()?;
+        let lcr = config.lcr()?;
+
+        let mut state = self.state.lock();
+        if state.config.is_some() {
+            let sent = state.drain_polled();
+            self.stats.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        }
+        state.config = None;
+        unsafe {
+            state.write_reg(REG_IER, 0);
+            state.write_reg(REG_LCR, LCR_DLAB);
+            state.write_reg(REG_DATA, divisor as u8);
+            state.write_reg(REG_IER, (divisor >> 8) as u8);
+            state.write_reg(REG_LCR, lcr);
+            state.write_reg(REG_IIR_FCR, FCR_ENABLE_AND_CLEAR | FCR_TRIGGER_14);
+
+            // Check that the UART is actually there, by sending a byte to ourselves
+            let old_mcr = state.read_reg(REG_MCR);
+            state.write_reg(REG_MCR, MCR_LOOPBACK | MCR_RTS | MCR_OUT2);
+            state.write_reg(REG_DATA, 0xAE);
+            if state.read_reg(REG_DATA) != 0xAE {
+                // (take it back out of loopback mode, so that whatever is on the other end isn't left cut off)
+                state.write_reg(REG_MCR, old_mcr & !MCR_LOOPBACK);
+                return Err(SerialError::NotPresent);
+            }
+
+            state.write_reg(REG_MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+            if state.irq_handler.is_some() { state.write_reg(REG_IER, IER_RX_AVAILABLE | IER_TX_EMPTY | IER_LINE_STATUS); }
+        }
+        state.config = Some(config);
+        Ok(())
+    }
+
+    /* Switch the port over to interrupt-driven I/O: output is buffered and sent in the background, and input is received into a queue for read_byte & co.
+        The port must have been initialised first. Does nothing if interrupts are already enabled. */
+    pub fn enable_interrupts(&'static self){
+        {
+            let state = self.state.lock();
+            if state.irq_handler.is_some() { return; }
+            assert!(state.config.is_some(), "Attempted to enable interrupts on uninitialised serial port {:?}!", self.port);
+        }
+        // The handler is registered without our state lock held, as dispatch_irq takes the handler registry's lock and then (in our handler) our state lock
+        // (until IER is set below, the UART won't raise any interrupts for it to handle)
+        let handler = register_irq_handler(self.port.irq(), move ||self.handle_interrupt());
+        let mut state = self.state.lock();
+        if state.irq_handler.is_some() {
+            // Someone else enabled them in the meantime
+            drop(state);
+            unregister_irq_handler(handler);
+            return;
+        }
+        state.irq_handler = Some(handler);
+        unsafe { state.write_reg(REG_IER, IER_RX_AVAILABLE | IER_TX_EMPTY | IER_LINE_STATUS); }
+        drop(state);
+        klog!(Info, COREDRIVERS_SERIAL, "Enabled interrupts for serial port {:?}.", self.port);
+    }
+
+    fn handle_interrupt(&self){
+        let mut state = self.state.lock();
+        if state.config.is_none() { return; }
+        loop {
+            let iir = unsafe { state.read_reg(REG_IIR_FCR) };
+            if iir & 1 != 0 { break; }  // nothing pending (or the interrupt was for another port sharing the line)
+            match (iir >> 1) & 0b111 {
+                // Line status
+                0b011 => { let lsr = unsafe { state.read_reg(REG_LSR) }; self.record_line_status(lsr); },
+                // Received data / character timeout
+                0b010 | 0b110 => loop {
+                    let lsr = unsafe { state.read_reg(REG_LSR) };
+                    if lsr & LSR_DATA_READY == 0 { break; }
+                    self.record_line_status(lsr);
+                    let byte = unsafe { state.read_reg(REG_DATA) };
+                    self.receive(byte);
+                },
+                // Transmitter empty
+                0b001 => { let sent = state.fill_fifo(); self.stats.bytes_sent.fetch_add(sent, Ordering::Relaxed); },
+                // Modem status (we don't use it, but it must be read to clear the interrupt)
+                _ => { unsafe { state.read_reg(REG_MSR); } },
+            }
+        }
+    }
+    fn record_line_status(&self, lsr: u8){
+        if lsr & LSR_OVERRUN != 0 { self.stats.overrun_errors.fetch_add(1, Ordering::Relaxed); }
+        if lsr & LSR_PARITY_ERROR != 0 { self.stats.parity_errors.fetch_add(1, Ordering::Relaxed); }
+        if lsr & LSR_FRAMING_ERROR != 0 { self.stats.framing_errors.fetch_add(1, Ordering::Relaxed); }
+    }
+    fn receive(&self, byte: u8){
+        self.stats.bytes_received.fetch_add(1, Ordering::Relaxed);
+        if self.rx.len() >= RX_BUFFER_LIMIT { self.stats.rx_dropped.fetch_add(1, Ordering::Relaxed); return; }
+        self.rx.push(byte);
+    }
+
+    /* Write the given bytes to the port.
+        Before interrupts are enabled, this waits for each byte to be sent. Afterwards, bytes are buffered and this only waits if the buffer is full. */
+    pub fn write_bytes(&self, bytes: &[u8]){
+        let mut state = self.state.lock();
+        if state.config.is_none() { return; }
+        let mut sent = 0;
+        if state.irq_handler.is_none() {
+            for byte in bytes { state.send_polled(*byte); }
+            sent = bytes.len();
+        } else {
+            for byte in bytes {
+                if state.tx_len == TX_BUFFER_SIZE { sent += state.drain_polled(); }
+                state.tx_push(*byte);
+            }
+            // Kick off the transmission (if the UART is idle, the THRE interrupt won't fire on its own)
+            sent += state.fill_fifo();
+        }
+        self.stats.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+    }
+    /* Wait until everything in the transmit buffer has been handed to the UART. */
+    pub fn flush(&self){
+        let mut state = self.state.lock();
+        let sent = state.drain_polled();
+        self.stats.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    /* Write to the port, bypassing its lock. Anything still in the transmit buffer is sent first.
+        Safety: Only for use in emergencies (e.g. kernel panics), as whoever was holding the lock may be in the middle of using the UART.
+                Interruptions must be disabled. */
+    pub unsafe fn emergency_write(&self, bytes: &[u8]){
+        if self.state.is_locked() { self.state.force_unlock(); }
+        let mut state = self.state.lock();
+        // (the port may not have been initialised yet, but it's worth a shot anyway)
+        state.drain_polled();
+        for byte in bytes { state.send_polled(*byte); }
+    }
+
+    /* Read a byte, blocking until one is available. */
+    pub fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() { return byte; }
+            if self.state.lock().irq_handler.is_some() { return self.rx.get(); }
+            crate::multitasking::spin_yield();
+        }
+    }
+    /* Read a byte if one is available. */
+    pub fn try_read_byte(&self) -> Option<u8> {
+        self.try_read_byte_inner(false)
+    }
+    /* Like try_read_byte, but checks the UART directly if nothing has been received, even if interrupts are enabled.
+        For use by things that run with interruptions disabled for long periods (e.g. a debugger stub), where the IRQ handler can't run. */
+    pub fn poll_read_byte(&self) -> Option<u8> {
+        self.try_read_byte_inner(true)
+    }
+    fn try_read_byte_inner(&self, always_poll: bool) -> Option<u8> {
+        if let Some(byte) = self.rx.get_if_available() { return Some(byte); }
+        // If interrupts aren't enabled yet, check the UART directly
+        let state = self.state.lock();
+        if (state.irq_handler.is_some() && !always_poll) || state.config.is_none() { return None; }
+        unsafe {
+            if state.read_reg(REG_LSR) & LSR_DATA_READY == 0 { return None; }
+            let byte = state.read_reg(REG_DATA);
+            self.stats.bytes_received.fetch_add(1, Ordering::Relaxed);
+            Some(byte)
+        }
+    }
+    /* Read into the given buffer, blocking until at least one byte is available. Returns the number of bytes read. */
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() { return 0; }
+        buf[0] = self.read_byte();
+        let mut n = 1;
+        while n < buf.len() {
+            let Some(byte) = self.try_read_byte() else { break };
+            buf[n] = byte; n += 1;
+        }
+        n
+    }
+    /// The number of received bytes waiting to be read
+    pub fn bytes_available(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Get a fmt::Write implementation for this port
+    pub fn writer(&'static self) -> SerialWriter {
+        SerialWriter(self)
+    }
+}
+
+/// Writes to a serial port (see SerialPort::write_bytes)
+pub struct SerialWriter(&'static SerialPort);
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+/// Writes to a serial port using SerialPort::emergency_write. For use by emergency_kernel_log!.
+pub struct EmergencySerialWriter(&'static SerialPort);
+impl EmergencySerialWriter {
+    /* Safety: see SerialPort::emergency_write */
+    pub unsafe fn new(port: ComPort) -> Self {
+        Self(get_port(port))
+    }
+}
+impl fmt::Write for EmergencySerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { self.0.emergency_write(s.as_bytes()); }
+        Ok(())
+    }
+}
+
+static PORTS: [SerialPort; 4] = [SerialPort::new(ComPort::COM1), SerialPort::new(ComPort::COM2), SerialPort::new(ComPort::COM3), SerialPort::new(ComPort::COM4)];
+/* Get the given port. It must be initialised with SerialPort::init before it can be used (except for SERIAL1, which is initialised on first use). */
+pub fn get_port(port: ComPort) -> &'static SerialPort {
+    &PORTS[port.index()]
+}
 
 lazy_static! {
-    pub static ref SERIAL1: KMutex<SerialPortType> = KMutex::new(init_serial_1());
-}
\ No newline at end of file
+    /// COM1, initialised with the default config. Used for kernel logging.
+    pub static ref SERIAL1: &'static SerialPort = {
+        let port = get_port(ComPort::COM1);
+        let _ = port.init(LineConfig::DEFAULT);
+        port
+    };
+}
@@ -0,0 +1,120 @@
+//! The local APIC (in xAPIC mode), used to send inter-processor interrupts (e.g. to wake up a halted CPU when it's given a task).
+//! Hardware IRQs still come from the legacy PICs (through LINT0, as set up by the firmware), so the local APIC's own timer and LVT entries are left alone.
+
+use core::sync::atomic::{AtomicBool,AtomicU32,AtomicUsize,Ordering};
+use x86_64::registers::model_specific::Msr;
+
+use crate::logging::klog;
+use crate::memory::paging::global_pages;
+use crate::multitasking::{get_cpu_num,disable_interruptions};
+use crate::cpu::interrupts::{WAKEUP_VECTOR,SPURIOUS_VECTOR};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1<<11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const APIC_MMIO_SIZE: usize = 0x1000;
+
+// Register offsets
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+const SVR_ENABLE: u32 = 1<<8;
+const ICR_DELIVERY_PENDING: u32 = 1<<12;
+/// ICR: fixed delivery, physical destination, assert, edge-triggered, no shorthand
+const ICR_FIXED: u32 = 1<<14;
+
+/// The most CPUs we can address (the same as CpuSet)
+const MAX_CPUS: usize = 64;
+const NO_APIC: u32 = u32::MAX;
+
+/// The virtual address the local APIC's registers are mapped at (0 until the BSP's has been mapped). Every CPU's local APIC is at the same physical address, so one mapping does for all of them.
+static APIC_VADDR: AtomicUsize = AtomicUsize::new(0);
+/// Each CPU's local APIC ID, or NO_APIC until init_local_apic has been called on it.
+/// (this is a fixed array rather than a CpuLocal, so that sending an IPI never allocates - it's done from interrupt handlers)
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(NO_APIC) }; MAX_CPUS];
+static MAP_FAILED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ApicInitError {
+    /// The CPU number is too large to be stored (see MAX_CPUS)
+    TooManyCPUs,
+    /// The firmware has disabled the local APIC
+    Disabled,
+    /// The registers couldn't be mapped into MMIO_PTABLE
+    MappingFailed,
+}
+
+#[inline]
+fn read_register(base: usize, reg: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((base + reg) as *const u32) }
+}
+#[inline]
+fn write_register(base: usize, reg: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u32, value) }
+}
+
+/* Map the local APIC's registers (the first time this is called), returning their virtual address */
+fn map_apic_mmio(physical: usize) -> Option<usize> {
+    use crate::memory::paging::{PageAlignedAddressT,PageAllocationSizeT,PageAlignedValue,pageFlags};
+    let vaddr = APIC_VADDR.load(Ordering::Acquire);
+    if vaddr != 0 { return Some(vaddr); }
+    if MAP_FAILED.load(Ordering::Relaxed) { return None; }
+    let vaddr = global_pages::MMIO_PTABLE_VADDR + physical;
+    let Some(buf) = global_pages::MMIO_PTABLE.allocate_at(PageAlignedAddressT::new(vaddr), PageAllocationSizeT::new_rounded(APIC_MMIO_SIZE)) else {
+        MAP_FAILED.store(true, Ordering::Relaxed);
+        return None;
+    };
+    buf.set_base_addr(physical, pageFlags!(t:WRITEABLE,m:PINNED,m:CACHE_DISABLE));
+    buf.leak();  // (the registers stay mapped forever)
+    APIC_VADDR.store(vaddr, Ordering::Release);
+    Some(vaddr)
+}
+
+/* Enable the current CPU's local APIC, so that it can send and receive IPIs. Must be called on each CPU (after its IDT has been loaded). */
+pub fn init_local_apic() -> Result<(), ApicInitError> {
+    let cpu = get_cpu_num();
+    if cpu >= MAX_CPUS { return Err(ApicInitError::TooManyCPUs); }
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if apic_base & APIC_BASE_ENABLE == 0 { return Err(ApicInitError::Disabled); }
+    let base = map_apic_mmio((apic_base & APIC_BASE_ADDR_MASK) as usize).ok_or(ApicInitError::MappingFailed)?;
+
+    // Software-enable it (with the spurious vector pointed at a handler that ignores it)
+    let svr = read_register(base, REG_SVR);
+    write_register(base, REG_SVR, (svr & !0xFF) | SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    let id = read_register(base, REG_ID) >> 24;
+    APIC_IDS[cpu].store(id, Ordering::Release);
+    klog!(Info, COREDRIVERS_XAPIC, "Local APIC enabled on CPU {} (APIC ID {}).", cpu, id);
+    Ok(())
+}
+/// True if init_local_apic has been called on the given CPU
+pub fn is_local_apic_initialised(cpu: usize) -> bool {
+    cpu < MAX_CPUS && APIC_IDS[cpu].load(Ordering::Acquire) != NO_APIC
+}
+
+/* Send an IPI with the given vector to the given CPU. Returns false if it couldn't be sent (because either CPU's local APIC hasn't been initialised).
+    This doesn't allocate or take any locks, so it may be called from interrupt handlers. */
+pub fn send_ipi(cpu: usize, vector: u8) -> bool {
+    let base = APIC_VADDR.load(Ordering::Acquire);
+    if base == 0 || !is_local_apic_initialised(cpu) || !is_local_apic_initialised(get_cpu_num()) { return false; }
+    let destination = APIC_IDS[cpu].load(Ordering::Acquire);
+    // (ICR_HIGH and ICR_LOW must be written together, without another IPI being sent from this CPU in between)
+    let ni = disable_interruptions();
+    while read_register(base, REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 { core::hint::spin_loop(); }
+    write_register(base, REG_ICR_HIGH, destination << 24);
+    write_register(base, REG_ICR_LOW, ICR_FIXED | vector as u32);
+    drop(ni);
+    true
+}
+/* Wake the given CPU (if it's halted), by sending it a WAKEUP_VECTOR IPI. Returns false if it couldn't be sent. */
+pub fn send_wakeup(cpu: usize) -> bool {
+    send_ipi(cpu, WAKEUP_VECTOR)
+}
+
+/* Acknowledge an interrupt that was delivered by the local APIC (i.e. an IPI - IRQs from the legacy PICs are acknowledged there instead) */
+pub fn end_of_interrupt() {
+    let base = APIC_VADDR.load(Ordering::Acquire);
+    if base != 0 { write_register(base, REG_EOI, 0); }
+}
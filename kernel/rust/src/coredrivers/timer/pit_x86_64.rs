@@ -0,0 +1,37 @@
+//! The legacy Programmable Interval Timer, used as the scheduler's clock (see scheduler::_scheduler_tick).
+//! Its IRQ only goes to the bootstrap processor, so other CPUs will need their own timer (e.g. the local APIC's) once they're started.
+
+use x86_64::instructions::port::Port;
+
+use crate::sync::kspin::KMutex;
+use crate::cpu::interrupts::{register_irq_handler,IrqHandlerId};
+use crate::logging::klog;
+
+/// The frequency of the PIT's input clock, in Hz
+const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+/// How many times per second the scheduler's clock ticks
+pub const TICK_RATE_HZ: u32 = 100;
+const PIT_IRQ: u8 = 0;
+
+const PORT_CHANNEL0: u16 = 0x40;
+const PORT_COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary
+const COMMAND_CHANNEL0_RATE_GENERATOR: u8 = 0b00_11_010_0;
+
+static IRQ_HANDLER: KMutex<Option<IrqHandlerId>> = KMutex::new(None);
+
+/* Start the PIT ticking at TICK_RATE_HZ, advancing the scheduler's clock on each tick. Does nothing if it's already running. */
+pub fn init(){
+    let mut handler = IRQ_HANDLER.lock();
+    if handler.is_some() { return; }
+    let divisor = (PIT_BASE_FREQUENCY / TICK_RATE_HZ) as u16;
+    unsafe {
+        Port::<u8>::new(PORT_COMMAND).write(COMMAND_CHANNEL0_RATE_GENERATOR);
+        let mut channel0 = Port::<u8>::new(PORT_CHANNEL0);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+    *handler = Some(register_irq_handler(PIT_IRQ, crate::multitasking::scheduler::_scheduler_tick));
+    drop(handler);
+    klog!(Info, COREDRIVERS_PIT, "PIT started at {}Hz.", TICK_RATE_HZ);
+}
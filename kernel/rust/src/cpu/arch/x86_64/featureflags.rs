@@ -3,6 +3,12 @@ use raw_cpuid::CpuId;
 use x86_64::registers::model_specific::{Efer,EferFlags};
 use x86_64::registers::control::{Cr4,Cr4Flags};
 use x86_64::registers::control::{Cr0,Cr0Flags};
+use x86_64::registers::model_specific::Msr;
+use core::sync::atomic::Ordering;
+use crate::memory::paging::arch::{PAT_MSR_VALUE,WRITE_COMBINING_AVAILABLE};
+use crate::multitasking::arch::idle::MWAIT_AVAILABLE;
+
+const IA32_PAT: u32 = 0x277;
 
 use crate::sync::promise::POnceLock;
 type StoredFlags = (EferFlags,Cr4Flags);
@@ -117,6 +123,13 @@ pub fn init_msr(){
         // Write-Protect
         feature_check!(required name="Ring 0 Write-Protect", true ; set cr0flags |= Cr0Flags::WRITE_PROTECT; else incompatible(failed,fail_reasons));
         
+        // == MSRs
+        // Page Attribute Table - used for write-combining mappings (e.g. framebuffers). If unsupported, these fall back to write-through.
+        feature_check!(required name="PAT (write-combining)", check_cpu_feature!(cpuid_f, has_pat); set {
+            Msr::new(IA32_PAT).write(PAT_MSR_VALUE);
+            WRITE_COMBINING_AVAILABLE.store(true, Ordering::Relaxed);
+        }; else warn);
+        
         // == NO FLAG TO SET (just checks)
         // 1GiB Huge Pages
         feature_check!(feature="1G_huge_pages" name="1GiB Huge Page", check_cpu_feature!(cpuid_epfi, has_1gib_pages); set (); else incompatible(failed,fail_reasons));  // No flag to set here
@@ -124,6 +137,9 @@ pub fn init_msr(){
         // APIC
         feature_check!(required name="APIC", check_cpu_feature!(cpuid_f, has_apic); set (); else incompatible(failed,fail_reasons));  // no flag to set
         
+        // MONITOR/MWAIT - lets idle CPUs be woken by other CPUs writing to memory, rather than only by interrupts
+        feature_check!(required name="MONITOR/MWAIT", check_cpu_feature!(cpuid_f, has_monitor_mwait); set MWAIT_AVAILABLE.store(true, Ordering::Relaxed); else warn);
+        
         // INVLPGB
         feature_check!(feature="enable_amd64_invlpgb" name="INVLPGB Instruction", check_cpu_feature!(cpuid_pcfi, has_invlpgb); set (); else warn);
         
@@ -151,5 +167,7 @@ pub fn init_msr_ap(){
         klog!(Debug, FEATURE_FLAGS, "Writing flags to control registers: EFER={:?} CR4={:?}", eferflags, cr4flags);
         Efer::write(*eferflags);
         Cr4::write(*cr4flags);
+        // All CPUs must agree on the PAT, or mappings will have a different memory type depending on which CPU accesses them
+        if WRITE_COMBINING_AVAILABLE.load(Ordering::Relaxed) { Msr::new(IA32_PAT).write(PAT_MSR_VALUE); }
     }
 }
\ No newline at end of file
@@ -0,0 +1,207 @@
+//! Interrupt handling: the IDT, CPU exceptions, hardware IRQs (currently via the legacy PICs), and IPIs (via the local APIC, see coredrivers::system_apic)
+//! Drivers register handlers for IRQ lines using register_irq_handler. Handlers are called with interruptions disabled,
+//!     so they must not yield to the scheduler (use KMutexes, or things built to be called from interrupt handlers, e.g. WQueue::push or WaitingList::notify_one).
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicPtr,AtomicUsize,Ordering};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::port::Port;
+use pic8259::ChainedPics;
+
+use crate::logging::klog;
+use crate::sync::kspin::{KMutex,KRwLock};
+use crate::multitasking::disable_interruptions;
+use crate::sync::rcu;
+use crate::multitasking::fixedcpulocal::fixed_cpu_local;
+
+// 0x00-0x1F - CPU Exceptions
+// 0x20-0x2F - Legacy PICs (IRQs 0-15)
+pub const PIC_1_OFFSET: u8 = 0x20;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// The number of IRQ lines available
+pub const IRQ_COUNT: u8 = 16;
+/// The IRQ line that the secondary PIC is chained to. This is never delivered as an interrupt itself.
+const CASCADE_IRQ: u8 = 2;
+// 0x30 - Wakeup IPI (sent to a halted CPU when it's given a task)
+pub const WAKEUP_VECTOR: u8 = 0x30;
+// 0xFF - Local APIC spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+lazy_static! {
+    // (the IDT is the same for all CPUs)
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        // Exceptions
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(gp_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        // The GDB stub needs access to all registers, so it uses its own entry points
+        #[cfg(feature = "dbg_gdbstub")]
+        unsafe {
+            use crate::gdbstub::arch::{breakpoint_entry_addr,debug_entry_addr};
+            idt.breakpoint.set_handler_addr(breakpoint_entry_addr());
+            idt.debug.set_handler_addr(debug_entry_addr());
+        }
+        // IRQs
+        for (irq, handler) in IRQ_ENTRY_POINTS.iter().enumerate() {
+            idt[PIC_1_OFFSET + irq as u8].set_handler_fn(*handler);
+        }
+        // IPIs
+        idt[WAKEUP_VECTOR].set_handler_fn(wakeup_ipi_handler);
+        idt[SPURIOUS_VECTOR].set_handler_fn(spurious_apic_handler);
+        idt
+    };
+}
+
+/* Load the IDT on the current CPU. Called during init_bsp/init_ap. (interrupts are not enabled until init_bsp_2/init_ap_2) */
+pub fn init_idt(){
+    IDT.load();
+}
+
+// EXCEPTIONS
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame){
+    panic!("Divide Error!\n{:?}", stack_frame);
+}
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame){
+    // Nothing is listening for breakpoints, so just note it down and carry on
+    klog!(Warning, CPU_MANAGEMENT_INTERRUPTS, "Breakpoint hit at {:?}", stack_frame.instruction_pointer);
+}
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame){
+    panic!("Invalid Opcode!\n{:?}", stack_frame);
+}
+extern "x86-interrupt" fn gp_fault_handler(stack_frame: InterruptStackFrame, error_code: u64){
+    panic!("General Protection Fault! Code={:#x}\n{:?}", error_code, stack_frame);
+}
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode){
+    use x86_64::registers::control::Cr2;
+    let accessed_addr = Cr2::read();
+    // Page faults are always an error at this point in time
+    panic!("Page Fault! Addr={:?} Code={:?}\n{:?}", accessed_addr, error_code, stack_frame);
+}
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    panic!("Double Fault!\n{:?}", stack_frame);
+}
+
+// IRQS
+pub type IrqHandler = Box<dyn Fn() + Send + Sync>;
+/// Identifies a registered IRQ handler, so that it can be unregistered later
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct IrqHandlerId { irq: u8, id: usize }
+
+static IRQ_HANDLERS: KRwLock<[Vec<(usize,IrqHandler)>; IRQ_COUNT as usize]> = KRwLock::new([const { Vec::new() }; IRQ_COUNT as usize]);
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(0);
+static LEGACY_PICS: KMutex<ChainedPics> = KMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+// The function to call once the current CPU's IRQ has been dispatched (see defer_until_after_irq), or null
+// (per-CPU rather than per-IRQ, as IRQs don't nest)
+fixed_cpu_local!(fixedcpulocal static DEFERRED_AFTER_IRQ: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut()));
+
+/* Register a handler for the given IRQ line, and unmask it.
+    Lines may be shared between devices, so each handler must check whether its device actually raised the interrupt. */
+pub fn register_irq_handler(irq: u8, handler: impl Fn() + Send + Sync + 'static) -> IrqHandlerId {
+    assert!(irq < IRQ_COUNT && irq != CASCADE_IRQ, "Invalid IRQ line: {}", irq);
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    IRQ_HANDLERS.write()[irq as usize].push((id, Box::new(handler)));
+    set_irq_masked(irq, false);
+    klog!(Debug, CPU_MANAGEMENT_INTERRUPTS, "Registered handler {} for IRQ {}.", id, irq);
+    IrqHandlerId { irq, id }
+}
+/* Unregister a handler. If no handlers are left for its IRQ line, the line is masked. */
+pub fn unregister_irq_handler(handler: IrqHandlerId) {
+    let mut handlers = IRQ_HANDLERS.write();
+    let line = &mut handlers[handler.irq as usize];
+    line.retain(|(id,_)|*id != handler.id);
+    if line.is_empty() { set_irq_masked(handler.irq, true); }
+}
+/* Call the given function once the IRQ currently being handled has been dispatched, i.e. after every handler has run, the IRQ has been acknowledged, and the handler list has been unlocked (but before returning from the interrupt).
+    For handlers that need to do something which mustn't happen in the middle of dispatching (e.g. stopping in the debugger). Only one function can be deferred per IRQ, so this replaces any earlier one. */
+pub fn defer_until_after_irq(f: fn()) {
+    DEFERRED_AFTER_IRQ.store(f as *mut (), Ordering::Relaxed);
+}
+/* Mask or unmask the given IRQ line */
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let mut pics = LEGACY_PICS.lock();
+    let mut masks = unsafe { pics.read_masks() };
+    let (chip, bit) = ((irq / 8) as usize, irq % 8);
+    if masked { masks[chip] |= 1<<bit; } else { masks[chip] &= !(1<<bit); }
+    unsafe { pics.write_masks(masks[0], masks[1]); }
+}
+
+/// Read the in-service register of the given PIC (0 = primary, 1 = secondary)
+fn read_pic_isr(chip: u8) -> u8 {
+    let mut command: Port<u8> = Port::new(if chip == 0 { 0x20 } else { 0xA0 });
+    unsafe {
+        command.write(0x0B);  // OCW3: read ISR
+        command.read()
+    }
+}
+
+fn dispatch_irq(irq: u8) {
+    // Spurious IRQs show up on the lowest-priority line of either PIC, but aren't actually in service. They must not be acknowledged (although the cascade line on the primary PIC still must be if it came from the secondary)
+    if irq == 7 && read_pic_isr(0) & 0x80 == 0 { return; }
+    if irq == 15 && read_pic_isr(1) & 0x80 == 0 {
+        unsafe { LEGACY_PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ); }
+        return;
+    }
+
+    // Interrupts are already disabled, but this also stops anything in the handlers from trying to yield
+    let ni = disable_interruptions();
+    rcu::rcu_irq_enter();
+    {
+        let handlers = IRQ_HANDLERS.read();
+        for (_, handler) in handlers[irq as usize].iter() { handler(); }
+    }
+    rcu::rcu_irq_exit();
+    unsafe { LEGACY_PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq); }
+    drop(ni);
+
+    let deferred = DEFERRED_AFTER_IRQ.swap(core::ptr::null_mut(), Ordering::Relaxed);
+    if !deferred.is_null() {
+        // SAFETY: Only ever set from a fn() by defer_until_after_irq
+        let deferred: fn() = unsafe { core::mem::transmute::<*mut (), fn()>(deferred) };
+        deferred();
+    }
+}
+
+macro_rules! irq_entry_points {
+    ($($irq:literal => $name:ident),+ $(,)?) => {
+        $(extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame){
+            dispatch_irq($irq);
+        })+
+        const IRQ_ENTRY_POINTS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT as usize] = [$($name),+];
+    }
+}
+irq_entry_points! {
+    0 => irq0_handler, 1 => irq1_handler, 2 => irq2_handler, 3 => irq3_handler,
+    4 => irq4_handler, 5 => irq5_handler, 6 => irq6_handler, 7 => irq7_handler,
+    8 => irq8_handler, 9 => irq9_handler, 10 => irq10_handler, 11 => irq11_handler,
+    12 => irq12_handler, 13 => irq13_handler, 14 => irq14_handler, 15 => irq15_handler,
+}
+
+// IPIS
+extern "x86-interrupt" fn wakeup_ipi_handler(_stack_frame: InterruptStackFrame){
+    // Nothing to do: being interrupted is enough to bring the CPU out of halt_until_woken, which then checks its run queue
+    crate::coredrivers::system_apic::end_of_interrupt();
+}
+extern "x86-interrupt" fn spurious_apic_handler(_stack_frame: InterruptStackFrame){
+    // (spurious interrupts must not be acknowledged)
+}
+
+/* Remap the legacy PICs (so that their IRQs don't overlap with CPU exceptions), with all lines masked until a handler is registered. */
+pub fn init_pics(){
+    let mut pics = LEGACY_PICS.lock();
+    unsafe {
+        pics.initialize();
+        pics.write_masks(!(1<<CASCADE_IRQ), 0xFF);
+    }
+}
+/* Enable interrupts on the current CPU. */
+pub fn enable(){
+    x86_64::instructions::interrupts::enable();
+}
@@ -1,13 +1,19 @@
 mod featureflags;
 mod gdt;
+pub mod interrupts;
 
 pub fn init_bsp() {
     // Init MSR
     featureflags::init_msr();
     // Init GDT
     gdt::init();
+    // Load IDT
+    interrupts::init_idt();
 }
 pub fn init_bsp_2() {
+    // Configure IRQs and enable interrupts
+    interrupts::init_pics();
+    interrupts::enable();
 }
 
 pub fn init_ap() {
@@ -15,7 +21,28 @@ pub fn init_ap() {
     featureflags::init_msr_ap();
     // Init GDT
     gdt::init();
+    // Load IDT
+    interrupts::init_idt();
 }
 pub fn init_ap_2() {
+    // Enable interrupts
+    interrupts::enable();
+}
+
+/* Reset the machine. Pulses the reset line via the keyboard controller, and falls back to a triple fault if that doesn't work. */
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        // Wait for the controller's input buffer to be empty (but not forever, in case there isn't one)
+        let mut status: Port<u8> = Port::new(0x64);
+        for _ in 0..100_000 { if status.read() & 0b10 == 0 { break; } }
+        status.write(0xFE);
+
+        // Still here? Load an empty IDT and trigger an exception, which will triple fault
+        let empty_idt = x86_64::structures::DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::zero() };
+        x86_64::instructions::tables::lidt(&empty_idt);
+        core::arch::asm!("int3", options(noreturn));
+    }
 }
 // TODO
\ No newline at end of file
@@ -1,3 +1,5 @@
 crate::arch_specific_module!(pub mod arch);
 
-pub use arch::{init_bsp,init_ap,init_bsp_2,init_ap_2};
\ No newline at end of file
+pub use arch::{init_bsp,init_ap,init_bsp_2,init_ap_2};
+pub use arch::reboot;
+pub use arch::interrupts;
\ No newline at end of file
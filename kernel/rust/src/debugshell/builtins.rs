@@ -0,0 +1,229 @@
+//! Commands that are always available in the debug shell
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool,Ordering};
+
+use super::{register_command,commands,get_command,tests,get_test,CommandResult};
+use crate::logging::{LogLevel,kmsg,read_logging_pipeline,update_logging_pipeline,formatter_from_name,FORMATTER_NAMES};
+use crate::multitasking::{snapshot_tasks,TaskState,TaskStatus,idle_stats};
+use crate::multitasking::handle::{live_tasks,lookup_task};
+
+fn parse_address(s: &str) -> Result<usize,String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s).replace('_', "");
+    usize::from_str_radix(&digits, 16).map_err(|_|format!("Invalid address: {} (addresses are in hexadecimal)", s))
+}
+// (errors from writing to the shell's output are ignored, as there's nowhere to report them)
+macro_rules! out {
+    ($out:expr, $($arg:tt)*) => { { let _ = write!($out, $($arg)*); } }
+}
+
+fn help(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    if let Some(name) = args.first() {
+        let command = get_command(name).ok_or_else(||format!("Unknown command: {}", name))?;
+        out!(out, "{} {}\n    {}\n", command.name, command.usage, command.description);
+        return Ok(());
+    }
+    for command in commands() {
+        out!(out, "{:10} {:20} {}\n", command.name, command.usage, command.description);
+    }
+    Ok(())
+}
+
+fn tasks_cmd(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    // (the registry has every task, but only the schedulers know the details of the ones they hold)
+    let tasks = live_tasks();
+    let snapshots = snapshot_tasks();
+    out!(out, "{:>6} {:<20} {:>6} {:>4} {:>10} {:>8} {:>14}  STATE\n", "TASK", "NAME", "PARENT", "CPU", "PRIORITY", "SWITCHES", "CYCLES");
+    for task in tasks.iter() {
+        let snapshot = snapshots.iter().find(|s|s.task_id == task.task_id());
+        let cpu = task.last_cpu().map_or(String::from("-"), |cpu|format!("{}", cpu));
+        let priority = snapshot.map_or(String::from("?"), |s|format!("{:?}", s.priority));
+        let parent = task.parent().map_or(String::from("-"), |id|format!("{}", id));
+        out!(out, "{:>6} {:<20} {:>6} {:>4} {:>10} {:>8} {:>14}  ", task.task_id(), task.name(), parent, cpu, priority, task.context_switches(), task.run_cycles());
+        match (task.status(), snapshot.map(|s|s.state)) {
+            (_, Some(TaskState::Sleeping { wake_at })) => out!(out, "sleeping (until t={})", wake_at),
+            (status, _) => out!(out, "{}", format!("{:?}", status).to_lowercase()),
+        }
+        if task.is_kill_requested() { out!(out, " [killing]"); }
+        else if task.is_cancel_requested() { out!(out, " [cancelling]"); }
+        out!(out, "\n");
+    }
+    out!(out, "{} tasks\n", tasks.len());
+    Ok(())
+}
+fn ps(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    let mut processes = crate::multitasking::process::all_processes();
+    processes.sort_by_key(|p|p.id());
+    out!(out, "{:>6} {:<20} {:>6} {:>6}  STATE\n", "PID", "NAME", "PARENT", "TASKS");
+    for process in processes.iter() {
+        let parent = process.parent().map_or(String::from("-"), |id|format!("{}", id));
+        let tasks = process.upgrade().map_or(0, |p|p.tasks().len());
+        out!(out, "{:>6} {:<20} {:>6} {:>6}  ", process.id(), process.name(), parent, tasks);
+        match process.exit_status_if_dead() {
+            None => out!(out, "alive\n"),
+            Some(status) => out!(out, "zombie ({:?})\n", status),
+        }
+    }
+    out!(out, "{} processes\n", processes.len());
+    Ok(())
+}
+fn parse_task(args: &[&str]) -> Result<alloc::sync::Arc<crate::multitasking::handle::TaskControl>,String> {
+    let id = args.first().ok_or("Missing task ID")?;
+    let id: usize = id.parse().map_err(|_|format!("Invalid task ID: {}", id))?;
+    lookup_task(id).ok_or_else(||format!("No such task: {}", id))
+}
+fn cancel(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let task = parse_task(args)?;
+    task.request_cancel();
+    out!(out, "Asked task {} to stop.\n", task.task_id());
+    Ok(())
+}
+fn kill(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let task = parse_task(args)?;
+    task.kill();
+    match task.status() {
+        // (queued tasks are killed as soon as they're picked to run, so it won't be long)
+        TaskStatus::Runnable | TaskStatus::Terminated => { task.wait_for_exit(); out!(out, "Killed task {}.\n", task.task_id()); },
+        // (it's running on another CPU, and only stops once it yields)
+        TaskStatus::Running => out!(out, "Task {} will be killed the next time it yields.\n", task.task_id()),
+        TaskStatus::Sleeping | TaskStatus::Waiting => out!(out, "Task {} will be killed once it wakes up.\n", task.task_id()),
+    }
+    Ok(())
+}
+fn cpustat(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    out!(out, "{:>4} {:>6} {:>10} {:>16}\n", "CPU", "BUSY", "HALTS", "IDLE CYCLES");
+    for stats in idle_stats() {
+        let busy = stats.busy_permille();
+        out!(out, "{:>4} {:>3}.{}% {:>10} {:>16}\n", stats.cpu, busy/10, busy%10, stats.halts, stats.idle_cycles);
+    }
+    Ok(())
+}
+
+fn mem(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    use crate::memory::physical::{amount_free,amount_allocated,amount_total};
+    const KIB: usize = 1024;
+    let (free, allocated, total) = (amount_free(), amount_allocated(), amount_total());
+    out!(out, "Physical memory:\n");
+    out!(out, "  total:     {:>10} KiB\n", total/KIB);
+    out!(out, "  allocated: {:>10} KiB\n", allocated/KIB);
+    out!(out, "  free:      {:>10} KiB ({}%)\n", free/KIB, if total > 0 { free*100/total } else { 0 });
+    Ok(())
+}
+
+fn ptwalk(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let addr = parse_address(args.first().ok_or("Missing address")?)?;
+    let steps = crate::memory::paging::walk_active_page_table(addr);
+    out!(out, "Page table walk for {:#x}:\n", addr);
+    for step in steps.iter() { out!(out, "  {}\n", step); }
+    match steps.last() {
+        Some(step) if step.is_present() && step.is_leaf() => out!(out, "{:#x} -> {:#x}\n", addr, step.addr() + (addr & (step.page_size()-1))),
+        _ => out!(out, "{:#x} is not mapped\n", addr),
+    }
+    Ok(())
+}
+
+const MAX_LISTED_DESTINATIONS: usize = 16;
+fn loglevel(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    match args {
+        [] => {
+            // (copied into a fixed buffer, as we can't allocate while reading the pipeline)
+            let mut levels: [Option<(&'static str,LogLevel)>; MAX_LISTED_DESTINATIONS] = [None; MAX_LISTED_DESTINATIONS];
+            let total = read_logging_pipeline(|p|{
+                for (slot, dest) in levels.iter_mut().zip(p.destinations()) { *slot = Some((dest.name(), dest.min_level())); }
+                p.destinations().count()
+            }).unwrap_or(0);
+            for (name, level) in levels.iter().flatten() { out!(out, "{:12} {}\n", name, level.name()); }
+            if total > MAX_LISTED_DESTINATIONS { out!(out, "(and {} more)\n", total - MAX_LISTED_DESTINATIONS); }
+            Ok(())
+        },
+        [destination, level] => {
+            let level = LogLevel::from_name(level).ok_or_else(||format!("Unknown log level: {}", level))?;
+            let mut found = false;
+            update_logging_pipeline(|p|{
+                if let Some(dest) = p.get_destination_mut(destination) { dest.set_min_level(level); found = true; }
+            });
+            if !found { return Err(format!("Unknown log destination: {}", destination)); }
+            out!(out, "{} now logs messages at {} and above\n", destination, level.name());
+            Ok(())
+        },
+        _ => Err("Usage: loglevel [<destination> <level>]".into()),
+    }
+}
+
+fn logformat(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let [destination, format] = args else { return Err(format!("Usage: logformat <destination> <{}>", FORMATTER_NAMES.join("|"))); };
+    let formatter = formatter_from_name(format).ok_or_else(||format!("Unknown log format: {} (expected one of: {})", format, FORMATTER_NAMES.join(", ")))?;
+    let mut found = false;
+    update_logging_pipeline(|p|{
+        if let Some(dest) = p.get_destination_mut(destination) { dest.set_formatter(formatter); found = true; }
+    });
+    if !found { return Err(format!("Unknown log destination: {}", destination)); }
+    out!(out, "{} now uses the {} format\n", destination, format);
+    Ok(())
+}
+
+fn kmsg_cmd(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let count: u64 = match args.first() {
+        Some(n) => n.parse().map_err(|_|format!("Invalid count: {}", n))?,
+        None => 20,
+    };
+    // (starting at the first record we want, so that only records lost from that window are reported)
+    let mut reader = kmsg::KmsgReader::starting_at(kmsg::next_seq().saturating_sub(count));
+    while let Some(result) = reader.read_next() {
+        match result {
+            Ok(entry) => out!(out, "{}\n", entry),
+            Err(lost) => out!(out, "({} messages lost)\n", lost),
+        }
+    }
+    Ok(())
+}
+
+fn test(out: &mut dyn Write, args: &[&str]) -> CommandResult {
+    let to_run = match args.first() {
+        None => {
+            for test in tests() { out!(out, "{:24} {}\n", test.name, test.description); }
+            return Ok(());
+        },
+        Some(&"all") => tests(),
+        Some(name) => alloc::vec![get_test(name).ok_or_else(||format!("Unknown test: {}", name))?],
+    };
+    let mut failed = 0;
+    for test in to_run.iter() {
+        out!(out, "{} ... ", test.name);
+        match test.run() {
+            Ok(()) => out!(out, "ok\n"),
+            Err(e) => { out!(out, "FAILED: {}\n", e); failed += 1; },
+        }
+    }
+    out!(out, "{} passed, {} failed\n", to_run.len()-failed, failed);
+    if failed > 0 { Err(format!("{} test(s) failed", failed)) } else { Ok(()) }
+}
+
+fn reboot(out: &mut dyn Write, _args: &[&str]) -> CommandResult {
+    out!(out, "Rebooting...\n");
+    // Make sure the message actually gets out before we pull the plug
+    use crate::coredrivers::serial_uart::{ComPort,get_port};
+    ComPort::ALL.iter().for_each(|p|get_port(*p).flush());
+    crate::cpu::reboot()
+}
+
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+pub(super) fn register_builtins(){
+    if REGISTERED.swap(true, Ordering::Relaxed) { return; }
+    register_command("help", "[command]", "List commands, or show help for a command", help);
+    register_command("tasks", "", "List tasks and their states", tasks_cmd);
+    register_command("ps", "", "List processes (including zombies)", ps);
+    register_command("cancel", "<task>", "Ask a task to stop", cancel);
+    register_command("kill", "<task>", "Forcibly terminate a task (this leaks anything it holds, including locks)", kill);
+    register_command("cpustat", "", "Show how busy each CPU has been since it started scheduling", cpustat);
+    register_command("mem", "", "Show physical memory usage", mem);
+    register_command("ptwalk", "<addr>", "Walk the active page table for a (hex) virtual address", ptwalk);
+    register_command("loglevel", "[<dest> <level>]", "Show or change the minimum log level of each log destination", loglevel);
+    register_command("logformat", "<dest> <default|ansi|terse>", "Change how messages sent to a log destination are formatted", logformat);
+    register_command("kmsg", "[count]", "Show the most recent kernel log messages", kmsg_cmd);
+    register_command("test", "[name|all]", "List tests, or run one (or all) of them", test);
+    register_command("reboot", "", "Reset the machine", reboot);
+}
@@ -0,0 +1,128 @@
+//! A minimal line editor for the debug shell (VT100-style terminals)
+//! Supports moving the cursor (left/right/home/end), backspace/delete, Ctrl-C (cancel), Ctrl-U (clear line), and history (up/down).
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+use alloc::format;
+use crate::coredrivers::serial_uart::SerialPort;
+
+/// The number of previous lines that are kept for the history
+const HISTORY_LENGTH: usize = 32;
+/// Lines longer than this are not accepted
+const MAX_LINE_LENGTH: usize = 256;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7F;
+const ESC: u8 = 0x1B;
+
+pub struct LineEditor {
+    port: &'static SerialPort,
+    prompt: &'static str,
+    history: VecDeque<String>,
+    /// Set after reading a \r, so that the \n of a \r\n pair is ignored
+    skip_lf: bool,
+}
+impl LineEditor {
+    pub fn new(port: &'static SerialPort, prompt: &'static str) -> Self {
+        Self { port, prompt, history: VecDeque::new(), skip_lf: false }
+    }
+
+    fn write(&self, s: &str){
+        self.port.write_bytes(s.as_bytes());
+    }
+    /// Redraw the whole line, and put the cursor back where it belongs
+    fn redraw(&self, line: &[u8], cursor: usize){
+        self.write("\r");
+        self.write(self.prompt);
+        self.port.write_bytes(line);
+        self.write("\x1b[K");
+        if cursor < line.len() { self.write(&format!("\x1b[{}D", line.len()-cursor)); }
+    }
+
+    /* Read an escape sequence (the ESC has already been read). Returns the final byte and any numeric parameter (e.g. ESC [ 3 ~ gives ('~', Some(3))) */
+    fn read_escape(&self) -> (u8, Option<u32>) {
+        let first = self.port.read_byte();
+        if first != b'[' && first != b'O' { return (first, None); }
+        let mut param: Option<u32> = None;
+        loop {
+            let b = self.port.read_byte();
+            match b {
+                b'0'..=b'9' => param = Some(param.unwrap_or(0)*10 + (b-b'0') as u32),
+                b';' => {},
+                _ => return (b, param),
+            }
+        }
+    }
+
+    /* Read a line from the port, echoing it back as it's typed. Returns the line without its line ending. */
+    pub fn read_line(&mut self) -> String {
+        let mut line: Vec<u8> = Vec::new();
+        let mut cursor = 0;
+        // history_pos == history.len() means we're editing a new line (saved in `draft` while browsing the history)
+        let mut history_pos = self.history.len();
+        let mut draft: Vec<u8> = Vec::new();
+        self.write(self.prompt);
+
+        loop {
+            let byte = self.port.read_byte();
+            if self.skip_lf { self.skip_lf = false; if byte == b'\n' { continue; } }
+            match byte {
+                b'\r' | b'\n' => {
+                    self.skip_lf = byte == b'\r';
+                    self.write("\r\n");
+                    break;
+                },
+                CTRL_C => {
+                    self.write("^C\r\n");
+                    line.clear(); cursor = 0; history_pos = self.history.len();
+                    self.write(self.prompt);
+                },
+                CTRL_U => {
+                    line.clear(); cursor = 0;
+                    self.redraw(&line, cursor);
+                },
+                BACKSPACE | DEL => if cursor > 0 {
+                    cursor -= 1; line.remove(cursor);
+                    self.redraw(&line, cursor);
+                },
+                ESC => match self.read_escape() {
+                    (b'D', _) => if cursor > 0 { cursor -= 1; self.write("\x1b[D"); },  // left
+                    (b'C', _) => if cursor < line.len() { cursor += 1; self.write("\x1b[C"); },  // right
+                    (b'H', _) | (b'~', Some(1)) => { cursor = 0; self.redraw(&line, cursor); },  // home
+                    (b'F', _) | (b'~', Some(4)) => { cursor = line.len(); self.redraw(&line, cursor); },  // end
+                    (b'~', Some(3)) => if cursor < line.len() { line.remove(cursor); self.redraw(&line, cursor); },  // delete
+                    (b'A', _) => if history_pos > 0 {  // up
+                        if history_pos == self.history.len() { draft = line.clone(); }
+                        history_pos -= 1;
+                        line = self.history[history_pos].as_bytes().to_vec(); cursor = line.len();
+                        self.redraw(&line, cursor);
+                    },
+                    (b'B', _) => if history_pos < self.history.len() {  // down
+                        history_pos += 1;
+                        line = if history_pos == self.history.len() { core::mem::take(&mut draft) } else { self.history[history_pos].as_bytes().to_vec() };
+                        cursor = line.len();
+                        self.redraw(&line, cursor);
+                    },
+                    _ => {},
+                },
+                0x20..=0x7E => if line.len() < MAX_LINE_LENGTH {
+                    line.insert(cursor, byte); cursor += 1;
+                    if cursor == line.len() { self.port.write_bytes(&[byte]); }
+                    else { self.redraw(&line, cursor); }
+                },
+                _ => {},  // ignore anything else (including non-ASCII)
+            }
+        }
+
+        // (only printable ASCII is ever inserted, so this is always valid UTF-8)
+        let line = String::from_utf8(line).unwrap_or_default();
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_LENGTH { self.history.pop_front(); }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+}
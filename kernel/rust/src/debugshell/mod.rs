@@ -0,0 +1,124 @@
+//! An interactive debug shell, run as a kernel task on a serial port
+//! Other subsystems can add their own commands using register_command, and their own tests using register_test (the kernel's own tests are listed in tests.rs).
+//! Commands run inside the shell's task, so they are free to block, allocate, spawn tasks, etc.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use alloc::format;
+use core::fmt;
+use core::fmt::Write;
+
+use crate::coredrivers::serial_uart::{ComPort,SerialPort,SerialError,get_port,LineConfig};
+use crate::logging::klog;
+use crate::sync::YMutex;
+
+mod lineedit;
+mod builtins;
+mod tests;
+
+pub type CommandResult = Result<(),String>;
+type CommandHandler = Box<dyn Fn(&mut dyn fmt::Write, &[&str]) -> CommandResult + Send + Sync>;
+type TestFn = Box<dyn Fn() -> CommandResult + Send + Sync>;
+
+pub struct ShellCommand {
+    pub name: &'static str,
+    /// Arguments, e.g. "<addr> [count]"
+    pub usage: &'static str,
+    pub description: &'static str,
+    handler: CommandHandler,
+}
+pub struct ShellTest {
+    pub name: &'static str,
+    pub description: &'static str,
+    test: TestFn,
+}
+
+static COMMANDS: YMutex<BTreeMap<&'static str, Arc<ShellCommand>>> = YMutex::new(BTreeMap::new());
+static TESTS: YMutex<BTreeMap<&'static str, Arc<ShellTest>>> = YMutex::new(BTreeMap::new());
+
+/* Register a command with the shell (replacing any existing command with the same name).
+    The handler is given somewhere to write its output, and the arguments (not including the command name itself). Any error returned is shown to the user. */
+pub fn register_command(name: &'static str, usage: &'static str, description: &'static str, handler: impl Fn(&mut dyn fmt::Write, &[&str]) -> CommandResult + Send + Sync + 'static){
+    COMMANDS.lock().insert(name, Arc::new(ShellCommand { name, usage, description, handler: Box::new(handler) }));
+}
+/* Remove a command. Returns false if it didn't exist. */
+pub fn unregister_command(name: &str) -> bool {
+    COMMANDS.lock().remove(name).is_some()
+}
+pub fn get_command(name: &str) -> Option<Arc<ShellCommand>> {
+    COMMANDS.lock().get(name).cloned()
+}
+/// A snapshot of all registered commands, sorted by name
+pub fn commands() -> Vec<Arc<ShellCommand>> {
+    COMMANDS.lock().values().cloned().collect()
+}
+
+/* Register a test that can be run from the shell using the "test" command (replacing any existing test with the same name). */
+pub fn register_test(name: &'static str, description: &'static str, test: impl Fn() -> CommandResult + Send + Sync + 'static){
+    TESTS.lock().insert(name, Arc::new(ShellTest { name, description, test: Box::new(test) }));
+}
+pub fn get_test(name: &str) -> Option<Arc<ShellTest>> {
+    TESTS.lock().get(name).cloned()
+}
+/// A snapshot of all registered tests, sorted by name
+pub fn tests() -> Vec<Arc<ShellTest>> {
+    TESTS.lock().values().cloned().collect()
+}
+impl ShellTest {
+    pub fn run(&self) -> CommandResult {
+        (self.test)()
+    }
+}
+
+/* Run a command line, writing its output to the given writer. */
+pub fn run_command_line(out: &mut dyn fmt::Write, line: &str) -> CommandResult {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((name, args)) = args.split_first() else { return Ok(()); };
+    let command = get_command(name).ok_or_else(||format!("Unknown command: {} (try 'help')", name))?;
+    (command.handler)(out, args)
+}
+
+/// Writes to a serial port, converting "\n" into "\r\n" for the terminal
+struct ShellWriter(&'static SerialPort);
+impl fmt::Write for ShellWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 { self.0.write_bytes(b"\r\n"); }
+            self.0.write_bytes(line.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+const PROMPT: &str = "cookie> ";
+
+crate::multitasking::util::def_task_fn! {
+    task fn shell_task(port: &'static SerialPort) {
+        let mut out = ShellWriter(port);
+        let mut editor = lineedit::LineEditor::new(port, PROMPT);
+        let _ = write!(out, "\nCOOKIE debug shell. Type 'help' for a list of commands.\n");
+        loop {
+            let line = editor.read_line();
+            if let Err(e) = run_command_line(&mut out, &line) {
+                let _ = write!(out, "error: {}\n", e);
+            }
+        }
+    }
+}
+
+/* Start the debug shell on the given serial port, initialising the port (with the default line settings) and enabling its interrupts if needed.
+    Returns the ID of the shell's task, or an error if the port is unusable. */
+pub fn spawn_shell(port: ComPort) -> Result<usize,SerialError> {
+    builtins::register_builtins();
+    tests::register_builtin_tests();
+    let serial = get_port(port);
+    if !serial.is_initialised() { serial.init(LineConfig::default())?; }
+    serial.enable_interrupts();
+    // (interactive, so it gets to run ahead of busy tasks)
+    let task_id = shell_task::spawn_with_priority(crate::multitasking::TaskPriority::High, serial).task_id();
+    klog!(Info, DEBUG_SHELL, "Debug shell started on {:?} (task {}).", port, task_id);
+    Ok(task_id)
+}
@@ -0,0 +1,40 @@
+//! The kernel's own tests, which can be run with the shell's "test" command.
+//! Each test lives next to the code it tests (in a `*_test` module with a `run` function), and is listed here, so that they're all registered in one place.
+
+use super::{register_test,CommandResult};
+
+struct BuiltinTest {
+    name: &'static str,
+    description: &'static str,
+    run: fn() -> CommandResult,
+}
+macro_rules! builtin_test {
+    ($name:literal, $run:path, $description:literal) => { BuiltinTest { name: $name, description: $description, run: $run } }
+}
+
+const BUILTIN_TESTS: &[BuiltinTest] = &[
+    builtin_test!("equals_fourty_two", crate::boot_test::equals_fourty_two, "Spawn tasks and wait for their results"),
+    builtin_test!("test_task_2", crate::boot_test::test_task_2, "Spawn a few tasks that yield repeatedly"),
+    builtin_test!("descriptors", crate::descriptors::stress_test::run, "Hammer a small descriptor table from several tasks, checking the handle state machine and stale IDs"),
+    builtin_test!("process", crate::multitasking::process::process_test::run, "Exit and kill processes, and check that they stay zombies (with their exit status) until reaped, including by their parent, and that their address spaces are only active while their tasks run"),
+    builtin_test!("timers", crate::multitasking::timerwheel::timerwheel_test::run, "Check that sleepers wake in order, and that timed waits are cancelled when notified and otherwise time out on time"),
+    builtin_test!("taskhandle", crate::multitasking::handle::handle_test::run, "Join, cancel and kill tasks through their handles, and check the results they report and that the registry only holds live tasks"),
+    builtin_test!("executor", crate::multitasking::executor::executor_test::run, "Run futures waiting on a queue, a promise, a timer and a lock, all on one task, and check that a dropped promise is reported"),
+    builtin_test!("wlock", crate::sync::wlock::wlock_test::run, "Check that WMutex and WRwLock hand the lock over in the order tasks (and futures) asked for it, can't be taken by try_lock while contended, and aren't left locked by futures that give up waiting"),
+    builtin_test!("condvar", crate::sync::condvar::condvar_test::run, "Hand values between tasks through a condvar (with YMutex and WMutex), and check that its timeouts expire"),
+    builtin_test!("semaphore", crate::sync::semaphore::semaphore_test::run, "Check that a semaphore never lets more tasks in than it has permits, and that acquire_timeout expires"),
+    builtin_test!("barrier", crate::sync::barrier::barrier_test::run, "Run tasks in lock-step through a reusable barrier, and check that wait_timeout expires"),
+    builtin_test!("channel", crate::sync::channel::channel_test::run, "Send values between tasks blocked on a full and an empty channel, send through bounded and unbounded channels (checking backpressure, disconnection and timeouts), and receive from both with select"),
+    builtin_test!("rcu", crate::sync::rcu::rcu_test::run, "Check that RcuCell keeps old values alive until readers are done, that callbacks wait for read-side critical sections, and that call_rcu/synchronize_rcu return"),
+    #[cfg(feature="dbg_lockdep")]
+    builtin_test!("lockdep", crate::sync::lockdep::lockdep_test::run, "Take two locks in both orders (one after the other, so nothing actually deadlocks), and yield while holding a YMutex and an LLMutex, and check that lockdep reports the inversion and the LLMutex (the reports printed are expected)"),
+    #[cfg(feature="dbg_spin_timeout")]
+    builtin_test!("spintimeout", crate::sync::spintimeout::spintimeout_test::run, "Wait for a YMutex held by a sleeping task with a short budget in Panic mode, and check the timeout and holder are reported without panicking (the report printed is expected)"),
+];
+
+/* Register all of the above with the shell */
+pub(super) fn register_builtin_tests() {
+    for test in BUILTIN_TESTS {
+        register_test(test.name, test.description, test.run);
+    }
+}
@@ -1,7 +1,10 @@
-use core::sync::atomic::{AtomicU16,AtomicU64,Ordering,AtomicPtr};
+// (under loom, the atomics and slots are loom's, so that kernel/modeltests can check every interleaving of the handle state machine)
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicU16,AtomicU64,AtomicUsize,Ordering,AtomicPtr};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU16,AtomicU64,AtomicUsize,Ordering,AtomicPtr};
 use alloc::sync::Arc;
 use core::ops::Drop;
-use core::cell::SyncUnsafeCell;
 use core::{mem,ptr};
 use core::default::Default;
 use alloc::boxed::Box;
@@ -9,6 +12,7 @@ use alloc::boxed::Box;
 pub type DescriptorID = u64;
 pub type AtomicDescriptorID = AtomicU64;
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum DescriptorAcquireError {
     /// Descriptor is reserved, either being constructed or destructed
     DescriptorReserved,
@@ -16,7 +20,40 @@ pub enum DescriptorAcquireError {
     BSlotNotAvailable,
     /// Descriptor was not found
     NotFound,
+    /// The descriptor has been freed (and its slot may have been re-used for a different one) - i.e. the ID is out of date
+    Stale,
 }
+/// Returned by DescriptorTable::create_new_descriptor if there was no free slot along the new descriptor's path whose slot number fits in the table's ID layout (see DescriptorIdLayout).
+/// Each new descriptor takes a different path through the sub-tables, so creating another may still succeed (especially once some have been freed).
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct DescriptorTableFull;
+
+/* The cell holding a descriptor's A or B section. Accesses are unsynchronised: rc_a/rc_b decide who may access it, and how. */
+struct SlotCell<T>(
+    #[cfg(not(loom))] core::cell::SyncUnsafeCell<T>,
+    #[cfg(loom)] loom::cell::UnsafeCell<T>,
+);
+impl<T> SlotCell<T> {
+    fn new(value: T) -> Self {
+        #[cfg(not(loom))] { Self(core::cell::SyncUnsafeCell::new(value)) }
+        #[cfg(loom)] { Self(loom::cell::UnsafeCell::new(value)) }
+    }
+    /* SAFETY: Nobody may be modifying the value for as long as the reference is held */
+    #[inline]
+    unsafe fn get(&self) -> &T {
+        #[cfg(not(loom))] { &*self.0.get() }
+        #[cfg(loom)] { self.0.with(|p|&*p) }
+    }
+    /* SAFETY: Nobody else may be accessing the value for as long as the reference is held */
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get_mut(&self) -> &mut T {
+        #[cfg(not(loom))] { &mut *self.0.get() }
+        #[cfg(loom)] { self.0.with_mut(|p|&mut *p) }
+    }
+}
+#[cfg(loom)]
+unsafe impl<T: Send+Sync> Sync for SlotCell<T> {}
 
 /** Descriptors are objects with are opened and stored in a descriptor table.
     These are a lot of kernel managed objects, including things such as processes, open files, and more.
@@ -41,33 +78,38 @@ pub struct Descriptor<T,A,B> where T: Default {
     
     // ID
     id: AtomicDescriptorID,
+    /// Incremented every time the descriptor in this slot is freed, so that IDs of old descriptors can be told apart from the current one (see DescriptorIdLayout)
+    generation: AtomicU64,
     
     // Sections
-    // Null pointers mean the value there has been dropped
+    // None means the section has been dropped (or not initialised yet). Slots are re-used once freed, so whether a
+    // section belongs to a given ID is decided by the generation rather than by these (lookups with an old ID fail as Stale)
     slot_t: T,
-    slot_a: SyncUnsafeCell<Option<A>>,
-    slot_b: SyncUnsafeCell<Option<B>>,
+    slot_a: SlotCell<Option<A>>,
+    slot_b: SlotCell<Option<B>>,
 }
 impl<T,A,B> Descriptor<T,A,B> where T: Default {
-    /* Drop the slot_a value if non-null, then decrement rc_a to 0, marking this descriptor as free to be overwritten.
+    /* Drop the slot_a value if there is one, then decrement rc_a to 0, marking this descriptor as free to be overwritten.
         SAFETY: rc_a should be 1. This MUST be the only thread trying to access this descriptor (enforced by rc_a being 1).
                 rc_b must be 0 and slot_b must be freed. slot_t must not be in use (lifetimes should be pinned to the handle, so they do not outlive their A-handles).
                 When clearing the entire descriptor, you should clear slot B (_clear_slot_b) first, then call _clear. */
     #[inline]
     unsafe fn _clear(&self){
         // Drop slot_a if initialised
-        let slot_a = &mut *self.slot_a.get();
+        let slot_a = self.slot_a.get_mut();
         slot_a.take();  // sets slot_a to None and drops the previous value
         
+        // Any IDs referring to the old descriptor are now stale
+        self.generation.fetch_add(1, Ordering::Release);
         // Now that slot_a is cleared, we decrement rc_a to 0. The descriptor is now free to be overwritten.
         self.rc_a.store(0, Ordering::Release);
     }
-    /* Drop the slot_b value if non-null.
+    /* Drop the slot_b value if there is one.
         SAFETY: rc_b should be 0. This MUST be the only thread trying to access this descriptor (enforced by rc_a being 1). */
     #[inline]
     unsafe fn _clear_slot_b(&self){
         // Drop slot_b if initialised
-        let slot_b = &mut *self.slot_b.get();
+        let slot_b = self.slot_b.get_mut();
         slot_b.take();  // sets slot_b to None and drops the previous value
     }
     
@@ -96,38 +138,41 @@ impl<T,A,B> Descriptor<T,A,B> where T: Default {
                 This MUST be the only thread trying to access this descriptor.*/
     unsafe fn _init_slots(&self, a_value: A, b_value: B){
         // Init slot A
-        let slot_a = &mut *self.slot_a.get();
+        let slot_a = self.slot_a.get_mut();
         let _=slot_a.insert(a_value);
         // Init slot B
-        let slot_b = &mut *self.slot_b.get();
+        let slot_b = self.slot_b.get_mut();
         let _=slot_b.insert(b_value);
         // Done :)
     }
     
-    /* Create a new empty descriptor */
-    fn new_empty() -> Self {
+    /* Create a new empty descriptor, starting at the given generation */
+    fn new_empty(generation: u64) -> Self {
         Self {
             rc_a: AtomicU16::new(0),
             rc_b: AtomicU16::new(0),
             
             id: AtomicDescriptorID::new(0),
+            generation: AtomicU64::new(generation),
             
             slot_t: T::default(),
-            slot_a: SyncUnsafeCell::new(None),
-            slot_b: SyncUnsafeCell::new(None),
+            slot_a: SlotCell::new(None),
+            slot_b: SlotCell::new(None),
         }
     }
     
     /* Reserve the descriptor for use.
-       This will increment rc_a from 0 (free) to 1 (reserved).
+       This will increment rc_a from 0 (free) to 1 (reserved). The descriptor's ID is made from its current generation by make_id.
        Returns None if the operation failed (e.g. because the descriptor is already in use). */
     #[inline]
-    fn reserve(&self, id: DescriptorID) -> Option<DescriptorInitialiser<T,A,B>> {
+    fn reserve(&self, make_id: impl FnOnce(u64)->DescriptorID) -> Option<DescriptorInitialiser<T,A,B>> {
         // Attempt to begin initialisation by compare_exchange-ing the rc_a value.
         let r = self.rc_a.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed);
         if let Err(_) = r { return None; }  // If the compare_exchange failed, then the descriptor is already in use, so we return None.
         self.rc_b.store(0, Ordering::Relaxed);  // clear rc_B count
-        self.id.store(id, Ordering::Relaxed);  // save the descriptor ID
+        // (the generation can't change while we hold the reservation, as it's only changed by whoever frees the descriptor)
+        let id = make_id(self.generation.load(Ordering::Acquire));
+        self.id.store(id, Ordering::Release);  // save the descriptor ID
         // rc_a is now equal to 1 (reserved). This therefore signifies that we are the only one currently using it, as all attempts to use it will now fail.
         Some(DescriptorInitialiser(self))
     }
@@ -175,8 +220,9 @@ impl<'r,T,A,B> DescriptorInitialiser<'r,T,A,B> where T: Default {
         // Forget ourselves so that our drop() does not run (as our drop() attempts to free the descriptor)
         mem::forget(self);
         // Create handle
-        descriptor.rc_b.fetch_add(1, Ordering::Acquire);
-        descriptor.rc_a.fetch_add(1, Ordering::Acquire);
+        descriptor.rc_b.fetch_add(1, Ordering::Relaxed);
+        // (Release, so that whoever acquires a handle after this sees the slots and rc_b initialised)
+        descriptor.rc_a.fetch_add(1, Ordering::Release);
         DescriptorHandle(descriptor)
     }
 }
@@ -204,16 +250,11 @@ impl<'r,T,A,B,const IS_B_REF: bool> DescriptorHandle<'r,T,A,B,IS_B_REF> where T:
     
     /* Get a reference to the slot_t data for this descriptor.
         This is bound by the lifetime of the DescriptorHandle so that it only applies to the requested descriptor.
-        If you want one that lives as long as the table itself (instead of only the given allocation of the slot), use get_t_forever. */
+        (there is no way to get one that outlives the handle, as the sub-table the slot lives in may be reclaimed once the slot is free - see DescriptorTable::reclaim_empty_subtables) */
     #[inline]
     pub fn get_t<'a>(&'a self) -> &'a T {
         &self.0.slot_t
     }
-    /* This reference to T will live as long as the slot itself, even if it is freed and then re-used for a different descriptor. */
-    #[inline]
-    pub fn get_t_forever(&self) -> &'r T {
-        &self.0.slot_t
-    }
     
     /* Get a reference to the A-slot in the descriptor.
         Note: it is impossible to mutate the A-slot itself in this state. Please use interior mutability if mutation is required. */
@@ -221,7 +262,7 @@ impl<'r,T,A,B,const IS_B_REF: bool> DescriptorHandle<'r,T,A,B,IS_B_REF> where T:
     pub fn get_a<'a>(&'a self) -> &'a A {
         // SAFETY: Since this A-ref exists, rc_a is >= 2 and will not decrease below that as long as this A-ref is not dropped
         //          Since rc_a is >= 2, A will not be borrowed mutably by the destructor/initialiser (and it cannot be borrowed mutably in any other way).
-        let cellref = unsafe { &*self.0.slot_a.get() };
+        let cellref = unsafe { self.0.slot_a.get() };
         cellref.as_ref().unwrap()
     }
     
@@ -248,7 +289,7 @@ impl<'r,T,A,B> DescriptorHandleB<'r,T,A,B> where T: Default {
     pub fn get_b<'a>(&'a self) -> &'a B {
         // SAFETY: Since this B-ref exists, rc_b is >= 1 and will not decrease below that as long as this B-ref is not dropped
         //          Since rc_b is >= 2, B will not be borrowed mutably by the destructor/initialiser (and it cannot be borrowed mutably in any other way).
-        let cellref = unsafe { &*self.0.slot_b.get() };
+        let cellref = unsafe { self.0.slot_b.get() };
         cellref.as_ref().unwrap()
     }
     
@@ -281,29 +322,133 @@ impl<T,A,B,const IS_B_REF: bool> Drop for DescriptorHandle<'_,T,A,B,IS_B_REF> wh
     }
 }
 
+/** How a table splits its descriptor IDs between the slot and the generation.
+    The low slot_bits bits of an ID say where the descriptor lives in the table (see DescriptorTable), and the generation_bits bits above them
+    say which of the descriptors that have used that slot it refers to. Each slot's generation is incremented whenever its descriptor is freed,
+    so looking up an ID whose descriptor has been freed fails with DescriptorAcquireError::Stale, even if the slot has since been re-used.
+    
+    More slot bits allow a bigger (deeper) table, while more generation bits make it take longer for a slot's generation to wrap around
+    (after which a very old ID would match its slot again). Any bits above slot_bits+generation_bits are always 0, for tables whose IDs must fit in fewer than 64 bits. */
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct DescriptorIdLayout {
+    slot_bits: u32,
+    generation_bits: u32,
+}
+impl DescriptorIdLayout {
+    /// 32 bits for the slot, 32 bits for the generation
+    pub const DEFAULT: Self = Self::new(32, 32);
+    
+    pub const fn new(slot_bits: u32, generation_bits: u32) -> Self {
+        assert!(slot_bits > 0 && slot_bits+generation_bits <= DescriptorID::BITS, "Invalid descriptor ID layout!");
+        Self { slot_bits, generation_bits }
+    }
+    pub const fn slot_bits(&self) -> u32 { self.slot_bits }
+    pub const fn generation_bits(&self) -> u32 { self.generation_bits }
+    
+    #[inline]
+    const fn _mask(bits: u32) -> DescriptorID {
+        if bits >= DescriptorID::BITS { DescriptorID::MAX } else { (1<<bits)-1 }
+    }
+    /// The largest slot number that fits (slot numbers start at 1, so that no ID is 0)
+    #[inline]
+    pub const fn max_slot(&self) -> DescriptorID { Self::_mask(self.slot_bits) }
+    /// The slot part of the given ID
+    #[inline]
+    pub const fn slot_of(&self, id: DescriptorID) -> DescriptorID { id & Self::_mask(self.slot_bits) }
+    /// The generation part of the given ID
+    #[inline]
+    pub const fn generation_of(&self, id: DescriptorID) -> u64 {
+        match id.checked_shr(self.slot_bits) { Some(g) => g & Self::_mask(self.generation_bits), None => 0 }
+    }
+    /// Returns true if the given ID is for the given generation (once truncated to fit)
+    #[inline]
+    pub const fn generation_matches(&self, id: DescriptorID, generation: u64) -> bool {
+        self.generation_of(id) == generation & Self::_mask(self.generation_bits)
+    }
+    /// Make an ID from a slot number and a generation (the generation is truncated to fit)
+    #[inline]
+    pub const fn compose(&self, slot: DescriptorID, generation: u64) -> DescriptorID {
+        let generation = match (generation & Self::_mask(self.generation_bits)).checked_shl(self.slot_bits) { Some(g) => g, None => 0 };
+        generation | self.slot_of(slot)
+    }
+}
+impl core::default::Default for DescriptorIdLayout {
+    fn default() -> Self { Self::DEFAULT }
+}
+
 /* A table of descriptors. N = number per table, M = number of different sub-tables (see below)
 
 Sub Tables:
 When the current table runs out of space, it will allocate one or more sub-tables (should be power of two for best performance).
-To avoid linked-list level performance, the subtable of index ID%M is used. (if that subtable is full, then index (ID/M)%M is used, and so on.
-Sub-tables may not be dropped unless their parent is also dropped, as it is expected that a subtable, once allocated, will remain allocated.
-N and M are "voodoo constants", whose values are chosen by luck and intuition. */
+Each new descriptor is given a path through the sub-tables (see _subtable_index), which is a hash of a sequence number and the depth, so that descriptors spread out over all of the sub-tables at every depth,
+ and the table can keep growing (a level deeper at a time) for as long as there is memory (and slot numbers - see DescriptorIdLayout).
+Slots never move, so growing the table never invalidates existing handles.
+Sub-tables that no longer hold any descriptors can be freed using reclaim_empty_subtables.
+N and M are "voodoo constants", whose values are chosen by luck and intuition.
+
+IDs:
+The slot part of an ID encodes where the slot is, so lookups go straight to it rather than searching: slot numbers 1..=N are the root table's descriptors,
+ and the rest are spread over the sub-tables in turn (slot s > N is in sub-table (s-1-N)%M, at slot (s-1-N)/M + 1 within it).
+The generation part is checked against the slot's current generation, so IDs of freed descriptors are reported as Stale rather than finding whatever now lives in their slot.
+(IDs pointing into a sub-table that has since been reclaimed are reported as NotFound instead) */
 pub struct DescriptorTable<T,A,B, const N: usize, const M: usize> where T: Default {
-    next_id: AtomicDescriptorID,
+    /// Used to pick the path of each new descriptor through the sub-tables
+    next_seq: AtomicU64,
+    layout: DescriptorIdLayout,
+    /// The generation that newly allocated sub-tables start at. This is raised past the generations of any sub-tables that are reclaimed, so that IDs into them don't become valid again if they're re-created.
+    /// (only changed while reclaiming, so it's stable for as long as a TraversalGuard is held)
+    generation_floor: AtomicU64,
+    /// The number of threads currently walking the sub-tables, plus TRAVERSALS_RECLAIMING while sub-tables are being reclaimed (see TraversalGuard)
+    traversals: AtomicUsize,
     table: DescriptorTableInner<T,A,B,N,M>,
 }
+const TRAVERSALS_RECLAIMING: usize = 1<<(usize::BITS-1);
 impl<T,A,B, const N: usize, const M: usize> DescriptorTable<T,A,B,N,M> where T: Default {
     #[inline]
     pub fn new() -> Self {
+        Self::with_id_layout(DescriptorIdLayout::DEFAULT)
+    }
+    /* Create a table whose IDs are split between slot and generation as given (see DescriptorIdLayout) */
+    pub fn with_id_layout(layout: DescriptorIdLayout) -> Self {
         Self {
-            next_id: AtomicDescriptorID::new(1),  // ID 0 is not used as it would confuse people
-            table: DescriptorTableInner::new(),
+            next_seq: AtomicU64::new(0),
+            layout,
+            generation_floor: AtomicU64::new(0),
+            traversals: AtomicUsize::new(0),
+            table: DescriptorTableInner::new(0),
+        }
+    }
+    pub fn id_layout(&self) -> DescriptorIdLayout { self.layout }
+    
+    /* Register the current thread as walking the table, so that no sub-tables are freed from under it.
+        This only waits if the table is currently being reclaimed (which is rare, and quick). */
+    #[inline]
+    fn _begin_traversal(&self) -> TraversalGuard<'_> {
+        loop {
+            let prev = self.traversals.fetch_add(1, Ordering::Acquire);
+            if prev & TRAVERSALS_RECLAIMING == 0 { return TraversalGuard(&self.traversals); }
+            // Reclaiming - back off until it's done
+            self.traversals.fetch_sub(1, Ordering::Relaxed);
+            while self.traversals.load(Ordering::Relaxed) & TRAVERSALS_RECLAIMING != 0 { core::hint::spin_loop(); }
         }
     }
     
     /* Get a handle to the descriptor with the given ID, or an error if it could not be done. */
     fn acquire<const IS_B_REF: bool>(&self, id: DescriptorID) -> Result<DescriptorHandle<T,A,B,IS_B_REF>,DescriptorAcquireError> {
-        self.table.acquire::<IS_B_REF>(id)
+        let _traversal = self._begin_traversal();
+        // (slot numbers start at 1, so that no ID is 0)
+        let slot = self.layout.slot_of(id);
+        if slot == 0 { return Err(DescriptorAcquireError::NotFound); }
+        let descriptor = self.table._locate(slot-1).ok_or(DescriptorAcquireError::NotFound)?;
+        
+        // Check the generation first, so that stale IDs are reported as such even if the slot is currently free or being re-used
+        if !self.layout.generation_matches(id, descriptor.generation.load(Ordering::Acquire)) { return Err(DescriptorAcquireError::Stale); }
+        let desc_ref = descriptor.acquire_ref::<IS_B_REF>()?;
+        // Now that we have a handle, the descriptor will not be erased or replaced
+        // Check the ID to ensure it wasn't freed and re-used between checking the generation and acquiring the handle
+        if desc_ref.get_id() != id { return Err(DescriptorAcquireError::Stale); }
+        // All ok
+        Ok(desc_ref)
     }
     /* Get an A-handle to the descriptor with the given ID, or an error if it could not be done. */
     pub fn acquire_a(&self, id: DescriptorID) -> Result<DescriptorHandleA<T,A,B>,DescriptorAcquireError> {
@@ -313,36 +458,107 @@ impl<T,A,B, const N: usize, const M: usize> DescriptorTable<T,A,B,N,M> where T:
     pub fn acquire_b(&self, id: DescriptorID) -> Result<DescriptorHandleB<T,A,B>,DescriptorAcquireError> {
         self.acquire::<true>(id)
     }
-    /* Create a new descriptor, and return the initialiser, allowing you to initialise slots T, A, and B as necessary before commit()-ing it and opening the descriptor for regular use. */
-    pub fn create_new_descriptor(&self) -> DescriptorInitialiser<T,A,B> {
-        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
-        self.table.allocate_empty(id)
+    /* Create a new descriptor, and return the initialiser, allowing you to initialise slots T, A, and B as necessary before commit()-ing it and opening the descriptor for regular use.
+        Fails if the table has grown too deep for its ID layout (see DescriptorTableFull). */
+    pub fn create_new_descriptor(&self) -> Result<DescriptorInitialiser<T,A,B>,DescriptorTableFull> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let _traversal = self._begin_traversal();
+        let floor = self.generation_floor.load(Ordering::Acquire);
+        self.table._allocate_empty(seq, floor, self.layout, &mut alloc::vec::Vec::new())
+    }
+    
+    /* Iterate over every live descriptor in the table (i.e. every committed descriptor that still has at least one handle), yielding an A-handle to each.
+        Descriptors created or freed during iteration may or may not be included. Sub-tables are not reclaimed while an iterator exists. */
+    pub fn iter(&self) -> DescriptorTableIter<'_,T,A,B,N,M> {
+        DescriptorTableIter { _traversal: self._begin_traversal(), stack: alloc::vec![(&self.table, 0)] }
+    }
+    
+    /* Free any sub-tables that no longer hold any descriptors (or sub-tables), returning how many were freed.
+        If another thread is currently walking the table (or iterating over it), nothing is freed and 0 is returned, so this never blocks. */
+    pub fn reclaim_empty_subtables(&self) -> usize {
+        // (nothing on this CPU may try to walk the table until we're done, as it would spin forever waiting for us)
+        let _ni = crate::multitasking::disable_interruptions();
+        if self.traversals.compare_exchange(0, TRAVERSALS_RECLAIMING, Ordering::Acquire, Ordering::Relaxed).is_err() { return 0; }
+        // SAFETY: Nobody else is walking the table, and nobody can start until we're done, so no references into the sub-tables exist
+        //          apart from handles and initialisers, whose slots have rc_a != 0, which stops their sub-tables from being freed.
+        let mut max_generation = None;
+        let freed = unsafe { self.table._reclaim_empty_subtables(&mut max_generation) };
+        if let Some(max_generation) = max_generation { self.generation_floor.fetch_max(max_generation+1, Ordering::Release); }
+        self.traversals.fetch_sub(TRAVERSALS_RECLAIMING, Ordering::Release);
+        freed
+    }
+    /* The number of sub-tables currently allocated (for statistics) */
+    pub fn subtable_count(&self) -> usize {
+        let _traversal = self._begin_traversal();
+        self.table._subtable_count()
+    }
+}
+
+/* Held while walking a table's sub-tables (see DescriptorTable::_begin_traversal) */
+struct TraversalGuard<'r>(&'r AtomicUsize);
+impl Drop for TraversalGuard<'_> {
+    fn drop(&mut self){
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/* An iterator over every live descriptor in a table (see DescriptorTable::iter) */
+pub struct DescriptorTableIter<'r,T,A,B, const N: usize, const M: usize> where T: Default {
+    _traversal: TraversalGuard<'r>,
+    /// The tables being walked, and the position in each: 0..N are its descriptors, N..N+M are its sub-tables
+    stack: alloc::vec::Vec<(&'r DescriptorTableInner<T,A,B,N,M>, usize)>,
+}
+impl<'r,T,A,B, const N: usize, const M: usize> Iterator for DescriptorTableIter<'r,T,A,B,N,M> where T: Default {
+    type Item = DescriptorHandleA<'r,T,A,B>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (table, pos) = *self.stack.last()?;
+            self.stack.last_mut().unwrap().1 += 1;
+            if pos < N {
+                // (free, reserved, and dying descriptors are skipped, as acquire_ref fails for them)
+                if let Ok(handle) = table.descriptors[pos].acquire_ref::<false>() { return Some(handle); }
+            } else if pos < N+M {
+                if let Some(subtable) = table._get_sub_table_or_none(pos-N) { self.stack.push((subtable, 0)); }
+            } else {
+                self.stack.pop();
+            }
+        }
     }
 }
 
-/* Descriptor tables are multi-level, however some extra stuff has to be stored with the root. This is the multi-level insides or whatever. You know what i mean */
+/* Descriptor tables are multi-level, however some extra stuff has to be stored with the root. This is the multi-level insides or whatever. You know what i mean
+    Note: Walking the sub-tables must only be done while holding a TraversalGuard, as otherwise they may be freed from under you. */
 pub struct DescriptorTableInner<T,A,B, const N: usize, const M: usize> where T: Default {
     descriptors: [Descriptor<T,A,B>; N],
     subtables: [AtomicPtr<Self>; M],
 }
 impl<T,A,B, const N: usize, const M: usize> DescriptorTableInner<T,A,B,N,M> where T: Default {
+    /* Create an empty table, whose descriptors start at the given generation */
     #[inline]
-    pub fn new() -> Self {
+    fn new(generation: u64) -> Self {
         Self {
-            descriptors: core::array::from_fn(|i| Descriptor::new_empty()),
-            subtables: [const { AtomicPtr::new(ptr::null_mut()) }; M],
+            descriptors: core::array::from_fn(|_| Descriptor::new_empty(generation)),
+            subtables: core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
         }
     }
     
-    /* Return the subtable in that slot, or create a new one if one is not already there. */
+    /* The index of the sub-table that the path with the given sequence number goes through at the given depth (0 = the root table) */
+    #[inline]
+    fn _subtable_index(seq: u64, depth: u32) -> usize {
+        // (a simple multiplicative hash, so that consecutive descriptors go to different sub-tables)
+        let hash = seq.wrapping_add(depth as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(depth*7 + 17);
+        (hash % M as u64) as usize
+    }
+    
+    /* Return the subtable in that slot, or create a new one (starting at the given generation) if one is not already there. */
     #[inline]
-    fn _get_or_create_sub_table(&self, idx: usize) -> &Self {
+    fn _get_or_create_sub_table(&self, idx: usize, generation: u64) -> &Self {
         // Optimisation: Only allocate a new subtable if there isn't a sub-table already there
         // we still have to do a compare_exchange if there isn't as otherwise a sub-table could be put there while our back is turned,
         // but it means we don't have to allocate and de-allocate a boxed subtable for every single subtable lookup.
-        let st_pointer = if self.subtables[idx].load(Ordering::Relaxed) == ptr::null_mut() {
-            let new_subtable_ptr = Box::into_raw(Box::new(Self::new()));
-            match self.subtables[idx].compare_exchange(ptr::null_mut(), new_subtable_ptr, Ordering::Relaxed, Ordering::Relaxed) {
+        let st_pointer = if self.subtables[idx].load(Ordering::Acquire) == ptr::null_mut() {
+            let new_subtable_ptr = Box::into_raw(Box::new(Self::new(generation)));
+            match self.subtables[idx].compare_exchange(ptr::null_mut(), new_subtable_ptr, Ordering::AcqRel, Ordering::Acquire) {
                 Ok(_) => new_subtable_ptr,  // All ok
                 Err(existing_ptr) => {
                     // Something is already there!
@@ -352,11 +568,11 @@ impl<T,A,B, const N: usize, const M: usize> DescriptorTableInner<T,A,B,N,M> wher
                     existing_ptr
                 }
             }
-        } else { self.subtables[idx].load(Ordering::Relaxed) };
+        } else { self.subtables[idx].load(Ordering::Acquire) };
         
         // Borrow an immutable reference to the chosen subtable
         // SAFETY: We've already checked for null pointers above (technically twice - once to skip allocating if not needed, and a second time to assign the new table)
-        //          st_pointer MUST always be valid, and subtables MUST NOT be freed. If it wasn't for the atomicity required, I'd be using a regular Box<>.
+        //          st_pointer MUST always be valid. Sub-tables are only freed by _reclaim_empty_subtables, which cannot run while anyone holds a TraversalGuard (which the caller must).
         //          So, treat it like a Box<> which is owned by this table and you can't really go wrong.
         unsafe { &*st_pointer }
     }
@@ -364,62 +580,204 @@ impl<T,A,B, const N: usize, const M: usize> DescriptorTableInner<T,A,B,N,M> wher
     // a version of _get_or_create_sub_table that returns None if none was found instead of creating a new one
     #[inline]
     fn _get_sub_table_or_none(&self, idx: usize) -> Option<&Self> {
-        let ptr = self.subtables[idx].load(Ordering::Relaxed);
+        let ptr = self.subtables[idx].load(Ordering::Acquire);
         if ptr == ptr::null_mut() { None }
         else { Some(unsafe { &*ptr }) }
     }
     
-    /* Find the descriptor with the given ID.
-        No guarantee is made that the descriptor will still be extant and correct when you go to use it.
-        The only guarantee is that the descriptor was there at some point (as descriptor IDs are not re-used and descriptors cannot be moved, the descriptor being non-extant or overwritten implies that it is gone forever. */
-    fn _search(&self, id: DescriptorID, st_indexer: usize) -> Option<&Descriptor<T,A,B>> {
-        // Find descriptor
-        for descriptor in &self.descriptors {
-            if descriptor.id.load(Ordering::Acquire) == id { return Some(descriptor); }
+    /* Find the descriptor at the given (zero-based) slot number, if the sub-table it lives in exists.
+        No guarantee is made about which descriptor (if any) is in the slot - the caller must check its generation and ID. */
+    fn _locate(&self, mut slot: DescriptorID) -> Option<&Descriptor<T,A,B>> {
+        let mut table = self;
+        while slot >= N as DescriptorID {
+            slot -= N as DescriptorID;
+            table = table._get_sub_table_or_none((slot % M as DescriptorID) as usize)?;
+            slot /= M as DescriptorID;
         }
-        // Find sub-table
-        let st_index = st_indexer%M;
-        if let Some(subtable) = self._get_sub_table_or_none(st_index) {
-            subtable._search(id, st_indexer/M)
-        } else { None }
+        Some(&table.descriptors[slot as usize])
     }
-    /* Get a handle to the descriptor with the given ID, or an error if it could not be done. */
-    #[inline]
-    pub fn acquire<const IS_B_REF: bool>(&self, id: DescriptorID) -> Result<DescriptorHandle<T,A,B,IS_B_REF>,DescriptorAcquireError> {
-        // Locate the descriptor and acquire a handle
-        let descriptor = self._search(id, id.try_into().unwrap()).ok_or(DescriptorAcquireError::NotFound)?;
-        let desc_ref = descriptor.acquire_ref::<IS_B_REF>()?;
-        
-        // Now that we have a handle, the descriptor will not be erased or replaced
-        // Check the ID to ensure it wasn't overwritten between finding the descriptor and acquiring the handle
-        if desc_ref.get_id() != id { return Err(DescriptorAcquireError::NotFound); }  // overwritten
-        // All ok
-        Ok(desc_ref)
+    /* The (zero-based) slot number of the given position in the table at the end of the given path of sub-table indices (the reverse of _locate), or None if it's too big to fit in the layout's slot bits */
+    fn _slot_number(pos: usize, path: &[usize], layout: DescriptorIdLayout) -> Option<DescriptorID> {
+        let mut slot = pos as DescriptorID;
+        for &idx in path.iter().rev() {
+            slot = slot.checked_mul(M as DescriptorID)?.checked_add((N+idx) as DescriptorID)?;
+        }
+        if slot < layout.max_slot() { Some(slot) } else { None }
     }
     
-    fn _allocate_empty(&self, id: DescriptorID, st_indexer: usize) -> DescriptorInitialiser<T,A,B> {
+    /* Allocate an empty slot for a new descriptor along the path for the given sequence number, returning the initialiser which can be used to initialise it.
+        `path` holds the sub-table indices taken to reach this table, and new sub-tables start at the given generation.
+        Fails once the slot numbers no longer fit in the layout (any deeper ones would be bigger still). */
+    fn _allocate_empty(&self, seq: u64, generation: u64, layout: DescriptorIdLayout, path: &mut alloc::vec::Vec<usize>) -> Result<DescriptorInitialiser<T,A,B>,DescriptorTableFull> {
         // Find an empty slot
-        for descriptor in &self.descriptors {
-            if let Some(desc) = descriptor.reserve(id) { return desc; }  // we got it!
+        for (pos, descriptor) in self.descriptors.iter().enumerate() {
+            let slot = Self::_slot_number(pos, path, layout).ok_or(DescriptorTableFull)?;
+            if let Some(desc) = descriptor.reserve(|generation|layout.compose(slot+1, generation)) { return Ok(desc); }  // we got it!
         }
         // Find a sub-table and reserve in there
-        let st_index = st_indexer%M;
-        self._get_or_create_sub_table(st_index)._allocate_empty(id, st_indexer/M)
+        let st_index = Self::_subtable_index(seq, path.len() as u32);
+        path.push(st_index);
+        self._get_or_create_sub_table(st_index, generation)._allocate_empty(seq, generation, layout, path)
     }
-    /* Allocate an empty slot for a new descriptor with the given ID, returning the initialiser which can be used to initialise it.
-        Warning: If a descriptor with that ID already exists in the table, then it is undefined which one is returned by methods such as acquire. */
-    #[inline]
-    pub fn allocate_empty(&self, id: DescriptorID) -> DescriptorInitialiser<T,A,B> {
-        self._allocate_empty(id, id.try_into().unwrap())
+    
+    /* Free every sub-table (below this one) that holds no descriptors and no sub-tables. Returns the number freed, and raises max_generation to the highest generation of any descriptor in them.
+        SAFETY: Nobody else may be walking the table (see DescriptorTable::reclaim_empty_subtables) */
+    unsafe fn _reclaim_empty_subtables(&self, max_generation: &mut Option<u64>) -> usize {
+        let mut freed = 0;
+        for st_ptr in &self.subtables {
+            let ptr = st_ptr.load(Ordering::Acquire);
+            if ptr == ptr::null_mut() { continue; }
+            let subtable = &*ptr;
+            freed += subtable._reclaim_empty_subtables(max_generation);
+            if subtable._is_empty() {
+                let generation = subtable.descriptors.iter().map(|d|d.generation.load(Ordering::Relaxed)).max();
+                *max_generation = (*max_generation).max(generation);
+                st_ptr.store(ptr::null_mut(), Ordering::Release);
+                drop(Box::from_raw(ptr));
+                freed += 1;
+            }
+        }
+        freed
+    }
+    /* Returns true if this table has no descriptors in use (including reserved ones), and no sub-tables */
+    fn _is_empty(&self) -> bool {
+        self.descriptors.iter().all(|d|d.rc_a.load(Ordering::Acquire) == 0)
+            && self.subtables.iter().all(|st|st.load(Ordering::Acquire) == ptr::null_mut())
+    }
+    fn _subtable_count(&self) -> usize {
+        (0..M).filter_map(|i|self._get_sub_table_or_none(i)).map(|st|1+st._subtable_count()).sum()
     }
 }
 impl<T,A,B, const N: usize, const M: usize> Drop for DescriptorTableInner<T,A,B,N,M> where T: Default {
     fn drop(&mut self){
         // Drop sub-tables (as they're stored as pointers and wouldn't be dropped otherwise)
-        for st_ptr in &mut self.subtables {
-            let ptr = st_ptr.get_mut();
-            let st = core::mem::replace(ptr, ptr::null_mut());
+        for st_ptr in &self.subtables {
+            let st = st_ptr.swap(ptr::null_mut(), Ordering::Acquire);
             if st != ptr::null_mut() { drop(unsafe{ Box::from_raw(st) }) };
         }
     }
-}
\ No newline at end of file
+}
+
+/* Several tasks create, look up, upgrade, downgrade and drop handles in random order, yielding between every step so that their operations interleave.
+    (as tasks are only switched when they yield, this can't catch races inside a single operation - kernel/modeltests checks those under loom - but it does exercise every transition of rc_a/rc_b against the others) */
+#[cfg(not(loom))]
+pub(crate) mod stress_test {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use crate::sync::YMutex;
+    use crate::multitasking::scheduler::spin_yield;
+    
+    const WORKERS: usize = 4;
+    const STEPS: usize = 400;
+    
+    /// The number of A and B sections that currently exist (to check they're dropped exactly when they should be)
+    static LIVE_A: AtomicUsize = AtomicUsize::new(0);
+    static LIVE_B: AtomicUsize = AtomicUsize::new(0);
+    
+    #[derive(Default)]
+    struct TestT {}
+    struct TestA { id: DescriptorID }
+    impl Drop for TestA { fn drop(&mut self){ LIVE_A.fetch_sub(1, Ordering::SeqCst); } }
+    struct TestB { id: DescriptorID }
+    impl Drop for TestB { fn drop(&mut self){ LIVE_B.fetch_sub(1, Ordering::SeqCst); } }
+    
+    // (tiny tables, so that it has to grow several levels deep, and small IDs, so that the split between slot and generation is exercised)
+    type TestTable = DescriptorTable<TestT,TestA,TestB,2,2>;
+    type TestHandleA = DescriptorHandleA<'static,TestT,TestA,TestB>;
+    type TestHandleB = DescriptorHandleB<'static,TestT,TestA,TestB>;
+    lazy_static::lazy_static! {
+        static ref TABLE: TestTable = DescriptorTable::with_id_layout(DescriptorIdLayout::new(16, 16));
+        /// IDs that any worker may try to look up
+        static ref SHARED_IDS: YMutex<Vec<DescriptorID>> = YMutex::new(Vec::new());
+    }
+    
+    fn create() -> TestHandleB {
+        let init = TABLE.create_new_descriptor().expect("Test table is full!");
+        let id = init.id();
+        LIVE_A.fetch_add(1, Ordering::SeqCst); LIVE_B.fetch_add(1, Ordering::SeqCst);
+        init.commit(TestA { id }, TestB { id })
+    }
+    
+    crate::multitasking::util::def_task_fn! {
+        task fn worker(seed: u64) -> Result<(),String> {
+            let mut rng = seed | 1;
+            let mut next = move |n: usize|{ rng ^= rng << 13; rng ^= rng >> 7; rng ^= rng << 17; (rng % n as u64) as usize };
+            let mut a_handles: Vec<TestHandleA> = Vec::new();
+            let mut b_handles: Vec<TestHandleB> = Vec::new();
+            for _ in 0..STEPS {
+                match next(7) {
+                    // Create a descriptor, sharing its ID with the others
+                    0 | 1 => { let b = create(); SHARED_IDS.lock().push(b.get_id()); b_handles.push(b); },
+                    // Look up someone else's descriptor (it may have been freed already, which is fine, but it must never be the wrong one)
+                    2 => {
+                        let id = { let ids = SHARED_IDS.lock(); if ids.is_empty() { continue; } ids[next(ids.len())] };
+                        match TABLE.acquire_b(id) {
+                            Ok(b) => {
+                                if b.get_a().id != id || b.get_b().id != id { return Err(format!("Looked up {} but got {}/{}", id, b.get_a().id, b.get_b().id)); }
+                                b_handles.push(b);
+                            },
+                            Err(_) => if let Ok(a) = TABLE.acquire_a(id) {
+                                if a.get_a().id != id { return Err(format!("Looked up {} but got {}", id, a.get_a().id)); }
+                                a_handles.push(a);
+                            },
+                        }
+                    },
+                    // Downgrade or upgrade a handle
+                    3 if !b_handles.is_empty() => { let b = b_handles.swap_remove(next(b_handles.len())); a_handles.push(b.downgrade()); },
+                    4 if !a_handles.is_empty() => {
+                        let a = a_handles.swap_remove(next(a_handles.len()));
+                        let id = a.get_id();
+                        match a.upgrade() {
+                            Ok(b) => { if b.get_b().id != id { return Err(format!("Upgraded {} but got {}", id, b.get_b().id)); } b_handles.push(b); },
+                            Err(_) => {},  // (all B-handles were already dropped)
+                        }
+                    },
+                    // Once the last B-handle is gone, the B section must be dropped, and no more B-handles may be created (even though A-handles remain)
+                    5 => {
+                        let b = create();
+                        let id = b.get_id();
+                        let a = b.downgrade();
+                        if TABLE.acquire_b(id).is_ok() { return Err(format!("Acquired a B-handle to {} after its last one was dropped", id)); }
+                        if a.clone_a_ref().upgrade().is_ok() { return Err(format!("Upgraded {} after its last B-handle was dropped", id)); }
+                        spin_yield();
+                        drop(TABLE.acquire_a(id).map_err(|_|format!("Could not look up {} while holding an A-handle", id))?);
+                        // Once it's been freed, its ID is stale (even if the slot has been re-used in the meantime)
+                        drop(a);
+                        spin_yield();
+                        match TABLE.acquire_a(id) {
+                            Err(DescriptorAcquireError::Stale) => {},
+                            Ok(_) => return Err(format!("Looked up {} after it was freed", id)),
+                            Err(e) => return Err(format!("Looking up {} after it was freed gave {:?} rather than Stale", id, e)),
+                        }
+                    },
+                    // Drop a handle
+                    _ => {
+                        if next(2) == 0 && !a_handles.is_empty() { a_handles.swap_remove(next(a_handles.len())); }
+                        else if !b_handles.is_empty() { b_handles.swap_remove(next(b_handles.len())); }
+                    },
+                }
+                spin_yield();
+            }
+            Ok(())
+        }
+    }
+    
+    pub fn run() -> crate::debugshell::CommandResult {
+        let workers: Vec<_> = (0..WORKERS).map(|i|worker::spawn(0x2545_F491_4F6C_DD1D ^ (i as u64 * 0x9E37_79B9))).collect();
+        for worker in workers.iter() {
+            worker.join().map_err(|e|format!("Worker failed: {:?}", e))?.clone()?;
+        }
+        SHARED_IDS.lock().clear();
+        // Every handle has been dropped, so everything should have been cleaned up
+        let (live_a, live_b) = (LIVE_A.load(Ordering::SeqCst), LIVE_B.load(Ordering::SeqCst));
+        if live_a != 0 || live_b != 0 { return Err(format!("{} A and {} B sections were not dropped", live_a, live_b)); }
+        let remaining = TABLE.iter().count();
+        if remaining != 0 { return Err(format!("{} descriptors are still live", remaining)); }
+        let subtables = TABLE.subtable_count();
+        let freed = TABLE.reclaim_empty_subtables();
+        if freed != subtables || TABLE.subtable_count() != 0 { return Err(format!("Only {} of {} empty sub-tables were reclaimed", freed, subtables)); }
+        Ok(())
+    }
+}
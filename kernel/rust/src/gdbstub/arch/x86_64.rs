@@ -0,0 +1,153 @@
+//! x86_64 support for the GDB stub: trap entry points, register layout, and single-stepping
+
+use x86_64::registers::control::{Cr0,Cr0Flags};
+use x86_64::VirtAddr;
+
+/// The registers GDB expects for amd64, in 'g' packet order (rax..r15, rip, eflags, then the segment registers).
+/// The x87/SSE registers are left out, which GDB treats as unavailable.
+pub const REGISTER_COUNT: usize = 24;
+const REG_RSP: usize = 7;
+const REG_RIP: usize = 16;
+const REG_EFLAGS: usize = 17;
+const RFLAGS_TF: u64 = 1<<8;
+
+pub const BREAKPOINT_INSTRUCTION: u8 = 0xCC;
+const VECTOR_DEBUG: u64 = 1;
+const VECTOR_BREAKPOINT: u64 = 3;
+
+/// The size of the given register, in bytes
+pub fn register_size(reg: usize) -> usize {
+    if reg < REG_EFLAGS { 8 } else { 4 }
+}
+
+/// The state saved by the trap entry points (see below). The general-purpose registers are in the same order as GDB's.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    rax: u64, rbx: u64, rcx: u64, rdx: u64, rsi: u64, rdi: u64, rbp: u64,
+    r8: u64, r9: u64, r10: u64, r11: u64, r12: u64, r13: u64, r14: u64, r15: u64,
+    vector: u64,
+    _error_code: u64,
+    // Pushed by the CPU
+    rip: u64, cs: u64, rflags: u64, rsp: u64, ss: u64,
+}
+impl TrapFrame {
+    fn gprs(&self) -> &[u64; 15] {
+        // Safety: TrapFrame is repr(C) and starts with 15 u64s
+        unsafe { &*(self as *const Self as *const [u64; 15]) }
+    }
+    fn gprs_mut(&mut self) -> &mut [u64; 15] {
+        unsafe { &mut *(self as *mut Self as *mut [u64; 15]) }
+    }
+    /// Map GDB's register number to our index in gprs() (GDB puts rsp between rbp and r8)
+    fn gpr_index(reg: usize) -> Option<usize> {
+        match reg { 0..=6 => Some(reg), 8..=15 => Some(reg-1), _ => None }
+    }
+
+    pub fn get_register(&self, reg: usize) -> Option<u64> {
+        if let Some(i) = Self::gpr_index(reg) { return Some(self.gprs()[i]); }
+        match reg {
+            REG_RSP => Some(self.rsp),
+            REG_RIP => Some(self.rip),
+            REG_EFLAGS => Some(self.rflags),
+            18 => Some(self.cs),
+            19 => Some(self.ss),
+            // ds/es/fs/gs aren't saved (they're unused in long mode anyway)
+            _ => None,
+        }
+    }
+    /* Set a register. Returns false if it can't be changed. (rsp, cs and ss can't be changed, as they are used by iretq to return to the right place) */
+    pub fn set_register(&mut self, reg: usize, value: u64) -> bool {
+        if let Some(i) = Self::gpr_index(reg) { self.gprs_mut()[i] = value; return true; }
+        match reg {
+            REG_RIP => { self.rip = value; true },
+            REG_EFLAGS => { self.rflags = value; true },
+            _ => false,
+        }
+    }
+
+    pub fn pc(&self) -> usize { self.rip as usize }
+    pub fn set_pc(&mut self, pc: usize) { self.rip = pc as u64; }
+    pub fn is_breakpoint(&self) -> bool { self.vector == VECTOR_BREAKPOINT }
+    pub fn is_debug(&self) -> bool { self.vector == VECTOR_DEBUG }
+    /// The address of the int3 instruction that caused this trap (as rip points after it)
+    pub fn breakpoint_addr(&self) -> usize { self.pc().wrapping_sub(1) }
+    pub fn set_single_step(&mut self, enabled: bool) {
+        if enabled { self.rflags |= RFLAGS_TF; } else { self.rflags &= !RFLAGS_TF; }
+    }
+}
+
+/* Read a register of a suspended task, from the frame left on its stack by _cs_push.
+    Only the callee-saved registers (plus rdi/rsi, rsp and rip) are saved there - anything else returns None. */
+pub fn suspended_task_register(saved_rsp: usize, reg: usize, read: impl Fn(usize) -> Option<u64>) -> Option<u64> {
+    // Layout (from saved_rsp upwards): _cs_push's RBP, RSI, RDI, R15, R14, R13, R12, RBX, caller's RBP, return address
+    let slot = |i: usize| read(saved_rsp + i*8);
+    match reg {
+        1 => slot(7),   // rbx
+        4 => slot(1),   // rsi
+        5 => slot(2),   // rdi
+        6 => slot(8),   // rbp
+        REG_RSP => Some((saved_rsp + 10*8) as u64),  // (after _cs_push returns)
+        12 => slot(6), 13 => slot(5), 14 => slot(4), 15 => slot(3),  // r12-r15
+        REG_RIP => slot(9),
+        _ => None,
+    }
+}
+
+/* Returns true if the given address is mapped in the current page table. */
+pub fn is_mapped(addr: usize) -> bool {
+    if VirtAddr::try_new(addr as u64).is_err() { return false; }
+    let steps = crate::memory::paging::walk_active_page_table(addr);
+    steps.last().is_some_and(|step|step.is_present() && step.is_leaf())
+}
+/* Write to memory, even if it is mapped read-only (e.g. kernel code, when inserting breakpoints).
+    Safety: addr must be mapped, and writing to it must not break anything (beyond what the person debugging intended) */
+pub unsafe fn write_byte_forced(addr: usize, value: u8) {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    core::ptr::write_volatile(addr as *mut u8, value);
+    Cr0::write(cr0);
+}
+
+/* Trigger a breakpoint trap, entering the stub (if it's enabled) */
+#[inline(always)]
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+// Entry points for the breakpoint (#BP) and debug (#DB) exceptions
+// These save all general-purpose registers into a TrapFrame so that GDB can see (and modify) them, which an x86-interrupt fn can't do
+core::arch::global_asm!(
+    ".global _gdbstub_debug_entry",
+    "_gdbstub_debug_entry:",
+    "    push 0",  // (no error code)
+    "    push 1",
+    "    jmp 2f",
+    ".global _gdbstub_breakpoint_entry",
+    "_gdbstub_breakpoint_entry:",
+    "    push 0",
+    "    push 3",
+    "2:",
+    "    push r15", "    push r14", "    push r13", "    push r12", "    push r11", "    push r10", "    push r9", "    push r8",
+    "    push rbp", "    push rdi", "    push rsi", "    push rdx", "    push rcx", "    push rbx", "    push rax",
+    // (the stack is 16-byte aligned here: the CPU aligns it before pushing its 40-byte frame, and we've pushed another 136 bytes)
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {handler}",
+    "    pop rax", "    pop rbx", "    pop rcx", "    pop rdx", "    pop rsi", "    pop rdi", "    pop rbp",
+    "    pop r8", "    pop r9", "    pop r10", "    pop r11", "    pop r12", "    pop r13", "    pop r14", "    pop r15",
+    "    add rsp, 16",
+    "    iretq",
+    handler = sym trap_handler,
+);
+extern "sysv64" {
+    fn _gdbstub_debug_entry();
+    fn _gdbstub_breakpoint_entry();
+}
+extern "sysv64" fn trap_handler(frame: &mut TrapFrame) {
+    super::super::handle_trap(frame);
+}
+
+/* The addresses of the #DB and #BP entry points, for the IDT */
+pub fn debug_entry_addr() -> VirtAddr { VirtAddr::new(_gdbstub_debug_entry as *const () as u64) }
+pub fn breakpoint_entry_addr() -> VirtAddr { VirtAddr::new(_gdbstub_breakpoint_entry as *const () as u64) }
@@ -0,0 +1,495 @@
+//! An in-kernel GDB stub, speaking the GDB Remote Serial Protocol on a dedicated serial port (enabled by the dbg_gdbstub feature)
+//! Supports software breakpoints, single-stepping, reading/writing registers and memory, and interrupting the kernel with Ctrl-C.
+//! Each scheduler Task is presented to GDB as a thread (with thread ID task_id+1). Suspended tasks only have the registers saved by _cs_push available,
+//!  and tasks that are running on other CPUs have none. Code running outside of any task (e.g. the scheduler) appears as a thread of its own.
+//! Limitations: other CPUs keep running while the stub is stopped, and breakpoints inside the kernel heap or the stub's serial port will hang it.
+//! Connect with `gdb --symbols=<kernel.bin> -ex "target remote localhost:4322"` (see the Makefile).
+
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+
+use crate::coredrivers::serial_uart::{ComPort,SerialPort,SerialError,get_port,LineConfig};
+use crate::cpu::interrupts::{register_irq_handler,defer_until_after_irq};
+use crate::logging::klog;
+use crate::multitasking::scheduler::try_snapshot_tasks_into;
+use crate::multitasking::{get_cpu_num,TaskSnapshot,TaskState,TaskPriority};
+use crate::sync::kspin::KMutex;
+
+crate::arch_specific_module!(pub mod arch);
+use arch::{TrapFrame,REGISTER_COUNT,register_size};
+pub use arch::breakpoint;
+
+/// The maximum size of a packet (in either direction), as advertised to GDB
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 64;
+/// The most tasks that are shown to GDB as threads (any others are left out)
+const MAX_THREADS: usize = 256;
+/// The thread ID given to code on CPU n that is not running a task (e.g. the scheduler) is CPU_CONTEXT_TID_BASE+n
+const CPU_CONTEXT_TID_BASE: usize = 0x1_0000_0000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const CTRL_C: u8 = 0x03;
+
+/// The addresses of the breakpoints currently inserted (0 = unused slot)
+/// These are kept outside of the stub's lock so that CPUs which hit a breakpoint while the stub is busy can still recognise it
+static BREAKPOINTS: [AtomicUsize; MAX_BREAKPOINTS] = [const { AtomicUsize::new(0) }; MAX_BREAKPOINTS];
+/// Set by the serial IRQ handler when GDB asks us to stop (Ctrl-C)
+static INTERRUPT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by the serial IRQ handler if it consumed the '$' at the start of a packet (e.g. when GDB first connects)
+static PACKET_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// A fixed-size buffer for packets (the stub avoids allocating where it can, as it may be stopped with the heap locked)
+struct PacketBuffer<const N: usize = PACKET_SIZE> {
+    data: [u8; N],
+    len: usize,
+}
+impl<const N: usize> PacketBuffer<N> {
+    const fn new() -> Self { Self { data: [0; N], len: 0 } }
+    fn as_bytes(&self) -> &[u8] { &self.data[..self.len] }
+    fn clear(&mut self) { self.len = 0; }
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N { return false; }
+        self.data[self.len] = byte; self.len += 1;
+        true
+    }
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(hex_digit(byte >> 4)); self.push(hex_digit(byte & 0xF));
+    }
+    /// Push the lowest `size` bytes of a value, in target (little-endian) byte order
+    fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in value.to_le_bytes().iter().take(size) { self.push_hex_byte(*byte); }
+    }
+}
+impl<const N: usize> fmt::Write for PacketBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() > N { return Err(fmt::Error); }
+        for byte in s.bytes() { self.push(byte); }
+        Ok(())
+    }
+}
+
+fn hex_digit(nibble: u8) -> u8 {
+    b"0123456789abcdef"[(nibble & 0xF) as usize]
+}
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 { return None; }
+    s.iter().try_fold(0usize, |acc, d|Some((acc << 4) | hex_value(*d)? as usize))
+}
+/// Parse a little-endian hex value (as sent by GDB for register contents)
+fn parse_hex_le(s: &[u8]) -> Option<u64> {
+    if s.len() % 2 != 0 || s.len() > 16 { return None; }
+    let mut value = 0u64;
+    for (i, pair) in s.chunks(2).enumerate() {
+        value |= (((hex_value(pair[0])? << 4) | hex_value(pair[1])?) as u64) << (i*8);
+    }
+    Some(value)
+}
+/// Split "a,b" (or "a:b" etc.) at the first occurrence of sep
+fn split_at_byte(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|b|*b == sep)?;
+    Some((&s[..i], &s[i+1..]))
+}
+
+/// A thread ID, as sent by GDB in H/T/qThreadExtraInfo packets
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum ThreadSelector { All, Any, Thread(usize) }
+fn parse_thread_id(s: &[u8]) -> Option<ThreadSelector> {
+    match s {
+        b"-1" => Some(ThreadSelector::All),
+        b"0" => Some(ThreadSelector::Any),
+        _ => parse_hex(s).map(ThreadSelector::Thread),
+    }
+}
+fn task_tid(task_id: usize) -> usize { task_id + 1 }
+
+/// Information about the threads at the moment the kernel was stopped
+struct StopContext {
+    /// The thread that hit the breakpoint (or was interrupted)
+    current_tid: usize,
+    /// The thread selected for register access with 'Hg' (None = the current thread)
+    selected_tid: Option<usize>,
+}
+
+struct GdbStub {
+    port: Option<&'static SerialPort>,
+    /// The original bytes replaced by each breakpoint in BREAKPOINTS
+    breakpoint_originals: [u8; MAX_BREAKPOINTS],
+    output: PacketBuffer,
+}
+/// A fixed-size buffer for the task snapshot taken when the kernel stops (filled in by try_snapshot_tasks_into, as we can't allocate)
+struct TaskBuffer([TaskSnapshot; MAX_THREADS]);
+const NO_TASK: TaskSnapshot = TaskSnapshot { task_id: 0, cpu: 0, state: TaskState::Running, priority: TaskPriority::Normal, saved_rsp: None };
+// SAFETY: The saved stack pointers are only used as addresses (and read through read_memory, which checks they're mapped)
+unsafe impl Send for TaskBuffer {}
+/// The stub, plus the buffers that packets are received into (kept separate so that a packet can be parsed while the reply is written) and that tasks are snapshotted into
+struct StubState {
+    stub: GdbStub,
+    input: PacketBuffer,
+    tasks: TaskBuffer,
+}
+static STUB: KMutex<StubState> = KMutex::new(StubState {
+    stub: GdbStub {
+        port: None,
+        breakpoint_originals: [0; MAX_BREAKPOINTS],
+        output: PacketBuffer::new(),
+    },
+    input: PacketBuffer::new(),
+    tasks: TaskBuffer([NO_TASK; MAX_THREADS]),
+});
+
+/// What to do once the current command has been handled
+enum Resume { No, Continue, Step }
+
+impl GdbStub {
+    fn port(&self) -> &'static SerialPort {
+        self.port.expect("GDB stub used before being initialised!")
+    }
+    fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.port().poll_read_byte() { return byte; }
+            core::hint::spin_loop();
+        }
+    }
+
+    /* Read the next valid packet into the given buffer, acknowledging it. */
+    fn receive_packet(&self, input: &mut PacketBuffer) {
+        loop {
+            if !PACKET_STARTED.swap(false, Ordering::Relaxed) {
+                // Skip anything before the start of the packet (acks, stray Ctrl-Cs, ...)
+                while self.read_byte() != b'$' {}
+            }
+            input.clear();
+            let mut checksum: u8 = 0;
+            let mut overflowed = false;
+            loop {
+                let byte = self.read_byte();
+                if byte == b'#' { break; }
+                checksum = checksum.wrapping_add(byte);
+                overflowed |= !input.push(byte);
+            }
+            let expected = hex_value(self.read_byte()).zip(hex_value(self.read_byte())).map(|(hi,lo)|(hi<<4)|lo);
+            if expected == Some(checksum) && !overflowed {
+                self.port().write_bytes(b"+");
+                self.port().flush();
+                return;
+            }
+            self.port().write_bytes(b"-");
+            self.port().flush();
+        }
+    }
+    /* Send the contents of self.output as a packet, resending it until GDB acknowledges it. */
+    fn send_packet(&mut self) {
+        let checksum = self.output.as_bytes().iter().fold(0u8, |acc,b|acc.wrapping_add(*b));
+        loop {
+            let port = self.port();
+            port.write_bytes(b"$");
+            port.write_bytes(self.output.as_bytes());
+            port.write_bytes(&[b'#', hex_digit(checksum >> 4), hex_digit(checksum & 0xF)]);
+            port.flush();
+            match self.read_byte() {
+                b'+' => return,
+                b'$' => { PACKET_STARTED.store(true, Ordering::Relaxed); return; },  // (GDB has moved on without acknowledging)
+                _ => {},
+            }
+        }
+    }
+    fn reply(&mut self, s: &str) {
+        self.output.clear();
+        let _ = self.output.write_str(s);
+    }
+    fn reply_error(&mut self, code: u8) {
+        self.output.clear();
+        let _ = write!(self.output, "E{:02x}", code);
+    }
+
+    fn find_breakpoint(addr: usize) -> Option<usize> {
+        BREAKPOINTS.iter().position(|bp|bp.load(Ordering::Relaxed) == addr)
+    }
+    fn insert_breakpoint(&mut self, addr: usize) -> bool {
+        if addr == 0 || !arch::is_mapped(addr) { return false; }
+        if Self::find_breakpoint(addr).is_some() { return true; }
+        let Some(slot) = Self::find_breakpoint(0) else { return false; };
+        self.breakpoint_originals[slot] = unsafe { core::ptr::read_volatile(addr as *const u8) };
+        unsafe { arch::write_byte_forced(addr, arch::BREAKPOINT_INSTRUCTION); }
+        BREAKPOINTS[slot].store(addr, Ordering::Release);
+        true
+    }
+    fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        if addr == 0 { return false; }
+        let Some(slot) = Self::find_breakpoint(addr) else { return false; };
+        unsafe { arch::write_byte_forced(addr, self.breakpoint_originals[slot]); }
+        BREAKPOINTS[slot].store(0, Ordering::Release);
+        true
+    }
+    fn remove_all_breakpoints(&mut self) {
+        for slot in 0..MAX_BREAKPOINTS {
+            let addr = BREAKPOINTS[slot].load(Ordering::Relaxed);
+            if addr != 0 { self.remove_breakpoint(addr); }
+        }
+    }
+
+    /* Read a byte of memory, as GDB should see it (i.e. with our breakpoints hidden). Returns None if it isn't mapped. */
+    fn read_memory(&self, addr: usize) -> Option<u8> {
+        if !arch::is_mapped(addr) { return None; }
+        if let Some(slot) = Self::find_breakpoint(addr) { return Some(self.breakpoint_originals[slot]); }
+        Some(unsafe { core::ptr::read_volatile(addr as *const u8) })
+    }
+    fn write_memory(&mut self, addr: usize, value: u8) -> bool {
+        if !arch::is_mapped(addr) { return false; }
+        // If there's a breakpoint here, keep it in place (GDB will restore the byte it wrote when it removes the breakpoint)
+        if let Some(slot) = Self::find_breakpoint(addr) { self.breakpoint_originals[slot] = value; return true; }
+        unsafe { arch::write_byte_forced(addr, value); }
+        true
+    }
+    fn read_u64(&self, addr: usize) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() { *byte = self.read_memory(addr + i)?; }
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /* Read a register of the given thread. Returns None if it isn't available. */
+    fn read_register(&self, frame: &TrapFrame, ctx: &StopContext, tasks: Option<&[TaskSnapshot]>, tid: usize, reg: usize) -> Option<u64> {
+        if tid == ctx.current_tid { return frame.get_register(reg); }
+        let task = tasks?.iter().find(|t|task_tid(t.task_id) == tid)?;
+        let saved_rsp = task.saved_rsp? as usize;
+        arch::suspended_task_register(saved_rsp, reg, |addr|self.read_u64(addr))
+    }
+
+    fn write_stop_reply(&mut self, signal: u8, ctx: &StopContext) {
+        self.output.clear();
+        let _ = write!(self.output, "T{:02x}thread:{:x};", signal, ctx.current_tid);
+    }
+    fn write_thread_list(&mut self, ctx: &StopContext, tasks: Option<&[TaskSnapshot]>) {
+        self.output.clear();
+        let _ = write!(self.output, "m{:x}", ctx.current_tid);
+        for task in tasks.unwrap_or(&[]) {
+            let tid = task_tid(task.task_id);
+            if tid != ctx.current_tid { let _ = write!(self.output, ",{:x}", tid); }
+        }
+    }
+    fn write_thread_extra_info(&mut self, ctx: &StopContext, tasks: Option<&[TaskSnapshot]>, tid: usize) -> bool {
+        let mut info = PacketBuffer::<128>::new();
+        let task = tasks.and_then(|t|t.iter().find(|t|task_tid(t.task_id) == tid));
+        let _ = match task {
+            Some(task) => match task.state {
+                TaskState::Running => write!(info, "Task {} on CPU {}: running", task.task_id, task.cpu),
+                TaskState::Ready => write!(info, "Task {} on CPU {}: ready", task.task_id, task.cpu),
+                TaskState::Sleeping { wake_at } => write!(info, "Task {} on CPU {}: sleeping until t={}", task.task_id, task.cpu, wake_at),
+            },
+            None if tid >= CPU_CONTEXT_TID_BASE => write!(info, "CPU {} (not in a task)", tid - CPU_CONTEXT_TID_BASE),
+            None if tid == ctx.current_tid => write!(info, "Task {} (current)", tid - 1),
+            None => return false,
+        };
+        self.output.clear();
+        for byte in info.as_bytes() { self.output.push_hex_byte(*byte); }
+        true
+    }
+    fn thread_exists(ctx: &StopContext, tasks: Option<&[TaskSnapshot]>, tid: usize) -> bool {
+        tid == ctx.current_tid || tasks.is_some_and(|t|t.iter().any(|t|task_tid(t.task_id) == tid))
+    }
+
+    /* Handle a packet, leaving the reply in self.output. */
+    fn handle_packet(&mut self, packet: &[u8], frame: &mut TrapFrame, ctx: &mut StopContext, tasks: Option<&[TaskSnapshot]>) -> Resume {
+        self.output.clear();
+        let Some((&command, args)) = packet.split_first() else { return Resume::No; };
+        match command {
+            b'?' => self.write_stop_reply(SIGTRAP, ctx),
+            b'g' => {
+                let tid = ctx.selected_tid.unwrap_or(ctx.current_tid);
+                for reg in 0..REGISTER_COUNT {
+                    match self.read_register(frame, ctx, tasks, tid, reg) {
+                        Some(value) => self.output.push_hex_le(value, register_size(reg)),
+                        None => for _ in 0..register_size(reg) { self.output.push(b'x'); self.output.push(b'x'); },
+                    }
+                }
+            },
+            b'G' => {
+                // Only the current thread's registers can be changed (suspended tasks would need their stack rewriting)
+                if ctx.selected_tid.is_some_and(|tid|tid != ctx.current_tid) { self.reply_error(1); return Resume::No; }
+                let mut rest = args;
+                for reg in 0..REGISTER_COUNT {
+                    let size = register_size(reg)*2;
+                    if rest.len() < size { break; }
+                    let (value, next) = rest.split_at(size);
+                    rest = next;
+                    if let Some(value) = parse_hex_le(value) {
+                        // (registers that can't be changed are silently left alone, as GDB always sends all of them)
+                        if frame.get_register(reg) != Some(value) { frame.set_register(reg, value); }
+                    }
+                }
+                self.reply("OK");
+            },
+            b'p' => {
+                let tid = ctx.selected_tid.unwrap_or(ctx.current_tid);
+                let Some(reg) = parse_hex(args) else { self.reply_error(1); return Resume::No; };
+                if reg >= REGISTER_COUNT { self.reply_error(1); return Resume::No; }
+                match self.read_register(frame, ctx, tasks, tid, reg) {
+                    Some(value) => self.output.push_hex_le(value, register_size(reg)),
+                    None => for _ in 0..register_size(reg) { self.output.push(b'x'); self.output.push(b'x'); },
+                }
+            },
+            b'P' => {
+                let parsed = split_at_byte(args, b'=').and_then(|(reg,value)|Some((parse_hex(reg)?, parse_hex_le(value)?)));
+                let Some((reg, value)) = parsed else { self.reply_error(1); return Resume::No; };
+                if ctx.selected_tid.is_some_and(|tid|tid != ctx.current_tid) || !frame.set_register(reg, value) { self.reply_error(1); return Resume::No; }
+                self.reply("OK");
+            },
+            b'm' => {
+                let parsed = split_at_byte(args, b',').and_then(|(addr,len)|Some((parse_hex(addr)?, parse_hex(len)?)));
+                let Some((addr, len)) = parsed else { self.reply_error(1); return Resume::No; };
+                for i in 0..len.min(PACKET_SIZE/2) {
+                    match self.read_memory(addr.wrapping_add(i)) {
+                        Some(byte) => self.output.push_hex_byte(byte),
+                        // Partial reads are allowed, but an error must be returned if nothing could be read
+                        None => { if i == 0 { self.reply_error(0x0e); } break; },
+                    }
+                }
+            },
+            b'M' => {
+                let parsed = split_at_byte(args, b',').and_then(|(addr,rest)|{
+                    let (len, data) = split_at_byte(rest, b':')?;
+                    Some((parse_hex(addr)?, parse_hex(len)?, data))
+                });
+                let Some((addr, len, data)) = parsed else { self.reply_error(1); return Resume::No; };
+                if data.len() != len*2 { self.reply_error(1); return Resume::No; }
+                for (i, pair) in data.chunks(2).enumerate() {
+                    let Some(byte) = hex_value(pair[0]).zip(hex_value(pair[1])).map(|(hi,lo)|(hi<<4)|lo) else { self.reply_error(1); return Resume::No; };
+                    if !self.write_memory(addr.wrapping_add(i), byte) { self.reply_error(0x0e); return Resume::No; }
+                }
+                self.reply("OK");
+            },
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let Some(addr) = parse_hex(args) else { self.reply_error(1); return Resume::No; };
+                    frame.set_pc(addr);
+                }
+                return if command == b's' { Resume::Step } else { Resume::Continue };
+            },
+            b'Z' | b'z' => {
+                // Only software breakpoints (type 0) are supported
+                let Some(rest) = args.strip_prefix(b"0,") else { return Resume::No; };
+                let Some(addr) = split_at_byte(rest, b',').and_then(|(addr,_kind)|parse_hex(addr)) else { self.reply_error(1); return Resume::No; };
+                let ok = if command == b'Z' { self.insert_breakpoint(addr) } else { self.remove_breakpoint(addr) };
+                if ok { self.reply("OK") } else { self.reply_error(0x0e) }
+            },
+            b'H' => {
+                let Some((&op, tid)) = args.split_first() else { self.reply_error(1); return Resume::No; };
+                match (op, parse_thread_id(tid)) {
+                    (b'g', Some(ThreadSelector::Thread(tid))) if Self::thread_exists(ctx, tasks, tid) => ctx.selected_tid = Some(tid),
+                    (b'g', Some(ThreadSelector::All | ThreadSelector::Any)) => ctx.selected_tid = None,
+                    // Only the current thread can be resumed/stepped, but GDB expects this to succeed
+                    (b'c', Some(_)) => {},
+                    _ => { self.reply_error(1); return Resume::No; },
+                }
+                self.reply("OK");
+            },
+            b'T' => match parse_hex(args) {
+                Some(tid) if Self::thread_exists(ctx, tasks, tid) => self.reply("OK"),
+                _ => self.reply_error(1),
+            },
+            b'q' => {
+                if args == b"fThreadInfo" { self.write_thread_list(ctx, tasks); }
+                else if args == b"sThreadInfo" { self.reply("l"); }
+                else if args == b"C" { let _ = write!(self.output, "QC{:x}", ctx.current_tid); }
+                else if args == b"Attached" { self.reply("1"); }
+                else if args.starts_with(b"Supported") { let _ = write!(self.output, "PacketSize={:x}", PACKET_SIZE); }
+                else if let Some(tid) = args.strip_prefix(b"ThreadExtraInfo,") {
+                    let ok = parse_hex(tid).is_some_and(|tid|self.write_thread_extra_info(ctx, tasks, tid));
+                    if !ok { self.reply_error(1); }
+                }
+                // (anything else is unsupported, and gets an empty reply)
+            },
+            b'D' => {
+                self.remove_all_breakpoints();
+                self.reply("OK");
+                self.send_packet();
+                return Resume::Continue;
+            },
+            b'k' => {
+                // We can't exactly kill the kernel, so treat this like a detach (with no reply, as GDB doesn't expect one)
+                self.remove_all_breakpoints();
+                return Resume::Continue;
+            },
+            _ => {},  // unsupported (including vCont), so send an empty reply
+        }
+        Resume::No
+    }
+
+    /* Talk to GDB until it tells us to resume. */
+    fn run(&mut self, input: &mut PacketBuffer, task_buffer: &mut TaskBuffer, frame: &mut TrapFrame, signal: u8) {
+        let cpu = get_cpu_num();
+        // (this might fail if the scheduler was locked when we stopped, in which case only the current thread is visible)
+        let tasks = try_snapshot_tasks_into(&mut task_buffer.0).map(|len|&task_buffer.0[..len]);
+        let current_tid = tasks
+            .and_then(|t|t.iter().find(|t|t.cpu == cpu && t.state == TaskState::Running))
+            .map_or(CPU_CONTEXT_TID_BASE + cpu, |t|task_tid(t.task_id));
+        let mut ctx = StopContext { current_tid, selected_tid: None };
+
+        self.write_stop_reply(signal, &ctx);
+        self.send_packet();
+        loop {
+            self.receive_packet(input);
+            match self.handle_packet(input.as_bytes(), frame, &mut ctx, tasks) {
+                Resume::No => self.send_packet(),
+                Resume::Continue => return,
+                Resume::Step => { frame.set_single_step(true); return; },
+            }
+        }
+    }
+}
+
+/* Called by the arch-specific trap entry points on a breakpoint (#BP) or debug (#DB) exception. */
+fn handle_trap(frame: &mut TrapFrame) {
+    let hit_breakpoint = frame.is_breakpoint() && GdbStub::find_breakpoint(frame.breakpoint_addr()).is_some();
+    // Rewind past our int3, so that the original instruction is executed once the breakpoint is removed
+    if hit_breakpoint { frame.set_pc(frame.breakpoint_addr()); }
+    frame.set_single_step(false);
+
+    let Some(mut state) = STUB.try_lock() else {
+        // Another CPU is talking to GDB. If we hit a breakpoint, we'll hit it again (and try again) once we return.
+        // Otherwise (e.g. a single-step left over after a detach), just carry on
+        return;
+    };
+    let StubState { stub, input, tasks } = &mut *state;
+    if stub.port.is_none() {
+        if frame.is_breakpoint() { klog!(Warning, DEBUG_GDBSTUB, "Breakpoint hit at {:#x} but the GDB stub isn't running.", frame.pc()); }
+        return;
+    }
+    let signal = if INTERRUPT_REQUESTED.swap(false, Ordering::Relaxed) { SIGINT } else { SIGTRAP };
+    stub.run(input, tasks, frame, signal);
+}
+
+/* Start the GDB stub on the given serial port, initialising the port (with the default line settings) if needed.
+    Once started, GDB can attach at any time, and the kernel will stop when a breakpoint is hit (including any call to breakpoint()). */
+pub fn init(port: ComPort) -> Result<(),SerialError> {
+    let serial = get_port(port);
+    if !serial.is_initialised() { serial.init(LineConfig::default())?; }
+    serial.enable_interrupts();
+    STUB.lock().stub.port = Some(serial);
+    // Stop as soon as GDB sends us anything while the kernel is running (either a Ctrl-C or, if it has just connected, its first packet)
+    // (this runs after the port's own IRQ handler, so anything received will already be in its queue)
+    register_irq_handler(port.irq(), move ||{
+        let mut wants_stop = false;
+        while let Some(byte) = serial.try_read_byte() {
+            match byte {
+                CTRL_C => { INTERRUPT_REQUESTED.store(true, Ordering::Relaxed); wants_stop = true; },
+                b'$' => { PACKET_STARTED.store(true, Ordering::Relaxed); wants_stop = true; break; },
+                _ => {},
+            }
+        }
+        // (stopping is deferred until the IRQ has been dispatched, so that the handler list isn't left locked while we talk to GDB)
+        if wants_stop { defer_until_after_irq(breakpoint); }
+    });
+    klog!(Info, DEBUG_GDBSTUB, "GDB stub listening on {:?}.", port);
+    Ok(())
+}
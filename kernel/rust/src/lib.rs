@@ -28,6 +28,9 @@ use logging::{klog, emergency_kernel_log};
 pub mod panic;
 
 pub mod descriptors;
+pub mod debugshell;
+#[cfg(feature = "dbg_gdbstub")]
+pub mod gdbstub;
 
 // arch-specific code lives in "x::arch" for some modules
 macro_rules! arch_specific_module {
@@ -51,6 +54,11 @@ pub extern "sysv64" fn _kstart() -> ! {
     unsafe { memory::kernel_heap::init_kheap(); }
     // Initialise Fixed CPU Locals
     multitasking::fixedcpulocal::init_fixed_cpu_locals();
+    // Start checking the order locks are taken in (this needs to know which CPU we're on)
+    #[cfg(feature="dbg_lockdep")]
+    sync::lockdep::enable();
+    #[cfg(feature="dbg_spin_timeout")]
+    sync::spintimeout::enable();
     // LATE BOOTSTRAP - The bare minimum is ready for rust code to execute
     klog!(Info, BOOT, "COOKIE version 0.0.2");
     klog!(Info, BOOT, "\"Now with less asbestos!\"");
@@ -69,20 +77,60 @@ pub extern "sysv64" fn _kstart() -> ! {
     //klog!(Info, BOOT, "Initialising virtual memory mappings...");
     let pagetable = memory::alloc_util::new_user_paging_context();
     unsafe{pagetable.activate()};
+    // (and use it for tasks that aren't part of a process)
+    pagetable.set_kernel_context();
     // Initialise kernel heap rescue
     unsafe { memory::kernel_heap::init_kheap_2(); }
+    // Enable interrupts
+    cpu::init_bsp_2();
+    // Enable the local APIC, so that other CPUs can wake this one up when they give it work
+    if let Err(e) = coredrivers::system_apic::init_local_apic() {
+        klog!(Warning, COREDRIVERS_XAPIC, "Unable to enable local APIC: {:?}. Idle CPUs will only notice new tasks on their next interrupt.", e);
+    }
+    coredrivers::serial_uart::SERIAL1.enable_interrupts();
+    // Start the scheduler's clock
+    coredrivers::timer_pit::init();
+    // Start the reaper task (which drops tasks once they've terminated)
+    multitasking::scheduler::spawn_reaper_task();
+    // Start the RCU task (which frees old copies of RCU-protected data, such as the logging pipeline)
+    sync::rcu::spawn_rcu_task();
+    // Initialise the screen (the bootloader's framebuffer if we have one, otherwise VGA text mode), and log to it (only Info and above, as there isn't much room)
+    {
+        use logging::{LogDestination,LogLevel};
+        use coredrivers::display_ansi::ScreenLogFormatter;
+        if coredrivers::display_framebuffer::init_framebuffer_console() {
+            use coredrivers::display_framebuffer::FramebufferConsoleWriter;
+            logging::update_logging_pipeline(|p|{ p.add_destination(LogDestination::new("framebuffer", Box::new(FramebufferConsoleWriter)).with_formatter(Box::new(ScreenLogFormatter())).with_min_level(LogLevel::Info)); });
+        } else if coredrivers::display_vga::init_vga_console() {
+            use coredrivers::display_vga::VGAConsoleWriter;
+            logging::update_logging_pipeline(|p|{ p.add_destination(LogDestination::new("vga", Box::new(VGAConsoleWriter)).with_formatter(Box::new(ScreenLogFormatter())).with_min_level(LogLevel::Info)); });
+        }
+    }
+    // Initialise the keyboard
+    if let Err(e) = coredrivers::keyboard_ps2::KEYBOARD.init() {
+        klog!(Warning, COREDRIVERS_PS2, "Unable to initialise PS/2 keyboard: {:?}", e);
+    }
     
     klog!(Info, ROOT, "Spawning test tasks...");
     let test = equals_fourty_two::spawn(42);
     let test2 = equals_fourty_two::spawn(69);
-    assert!(test.1.get().unwrap());
-    assert!(!test2.1.get().unwrap());
+    assert!(test.join().unwrap());
+    assert!(!test2.join().unwrap());
 
     for i in 0..3 {
         test_task_2::spawn();
         spin_yield()
     }
 
+    // Start the debug shell (on COM2, as COM1 is used for logging)
+    if let Err(e) = debugshell::spawn_shell(coredrivers::serial_uart::ComPort::COM2) {
+        klog!(Info, BOOT, "Debug shell not started: {:?}", e);
+    }
+    #[cfg(feature = "dbg_gdbstub")]
+    if let Err(e) = gdbstub::init(coredrivers::serial_uart::ComPort::COM3) {
+        klog!(Warning, BOOT, "GDB stub not started: {:?}", e);
+    }
+
     // TODO
     //let x = multitasking::interruptions::disable_interruptions();
     klog!(Info, BOOT, "Further boot process not yet implemented.");
@@ -107,4 +155,18 @@ multitasking::util::def_task_fn! {
         }
         klog!(Info, ROOT, "Test DONE");
     }
-}
\ No newline at end of file
+}
+
+// Debug shell tests for the above (see debugshell::tests)
+pub(crate) mod boot_test {
+    use super::*;
+    pub fn equals_fourty_two() -> debugshell::CommandResult {
+        let yes = equals_fourty_two::spawn(42);
+        let no = equals_fourty_two::spawn(69);
+        if yes.join() == Ok(&true) && no.join() == Ok(&false) { Ok(()) } else { Err("Wrong result".into()) }
+    }
+    pub fn test_task_2() -> debugshell::CommandResult {
+        let handles: alloc::vec::Vec<_> = (0..3).map(|_|test_task_2::spawn()).collect();
+        if handles.iter().all(|h|h.join().is_ok()) { Ok(()) } else { Err("Task was cancelled".into()) }
+    }
+}
@@ -0,0 +1,345 @@
+//! Structured binary logging (enabled by the "klog_binary" feature)
+//! Instead of formatting each message into text on the heap, klog! call sites are interned, and each message is sent over serial as a compact binary record:
+//!     the call site's id, the ExecutionContext it was logged in, and the raw values of its arguments.
+//! The first time a call site is used, a definition record (containing its format string, level, component and source location) is sent ahead of it.
+//! Arguments that can't be sent raw (i.e. anything other than integers, bools, chars, floats and strings) are formatted on the stack and sent as text instead.
+//!
+//! Messages are still recorded in the kernel message buffer (see kmsg), and passed through the text logging pipeline for destinations that need text (e.g. the screen).
+//! The pipeline has no serial destination in this mode, so serial only receives the binary stream.
+//!
+//! The serial output can be turned back into readable text with tools/klogdecode.py. Anything that isn't a valid frame (e.g. emergency_kernel_log output) is passed through as-is.
+//!
+//! Frame layout (all multi-byte integers are little-endian, "varint" is unsigned LEB128):
+//!     0x1E 'K' <frame type: u8> <payload length: u16> <payload> <checksum: u8 (wrapping sum of the payload)>
+//! Frame types:
+//!     0 StreamStart   - version: u8 (sent once, before anything else. The decoder forgets all definitions when it sees one)
+//!     1 Definition    - id: varint, level: u8, flags: u8 (bit 0: verbatim, i.e. the template is not a format string), component: str, file: str, line: varint, column: varint, template: str
+//!     2 Record        - id: varint, cpu: varint, task: varint (0 = scheduler, otherwise task id + 1), task name: str (only present if task != 0), ticks: varint, arg count: u8, args...
+//! Strings are encoded as a varint length followed by that many bytes of UTF-8.
+//! Args are encoded as a tag byte followed by the value (see [ArgTag]).
+
+use core::cell::Cell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool,AtomicU32,Ordering};
+use super::LogLevel;
+use crate::multitasking::ExecutionContext;
+use crate::sync::kspin::KMutex;
+
+pub const FRAME_MAGIC: [u8; 2] = [0x1E, b'K'];
+pub const STREAM_VERSION: u8 = 2;
+/// The maximum size of a frame's payload. Anything that doesn't fit is truncated.
+const MAX_PAYLOAD: usize = 480;
+/// The maximum number of arguments that can be captured as text for a single message
+const MAX_CAPTURED_ARGS: usize = 16;
+/// The amount of stack space used to capture arguments as text
+const CAPTURE_BUFFER_SIZE: usize = 256;
+
+#[repr(u8)]
+enum FrameType {
+    StreamStart = 0,
+    Definition = 1,
+    Record = 2,
+}
+#[repr(u8)]
+enum ArgTag {
+    /// varint
+    Unsigned = 0,
+    /// zigzag-encoded varint
+    Signed = 1,
+    /// u8 (0 or 1)
+    Bool = 2,
+    /// varint (unicode scalar value)
+    Char = 3,
+    /// str
+    Str = 4,
+    /// f64
+    Float = 5,
+    /// str - the argument has already been formatted according to its format spec
+    Text = 6,
+    /// (no value) - the argument could not be captured (e.g. it didn't fit)
+    Missing = 7,
+}
+
+/// A klog! call site. One of these is created (as a static) for every call site, and is assigned an id the first time it is used.
+pub struct LogSite {
+    id: AtomicU32,
+    level: LogLevel,
+    component: &'static str,
+    template: &'static str,
+    verbatim: bool,
+    file: &'static str,
+    line: u32,
+    column: u32,
+}
+impl LogSite {
+    pub const fn new(level: LogLevel, component: &'static str, template: &'static str, verbatim: bool, file: &'static str, line: u32, column: u32) -> Self {
+        Self { id: AtomicU32::new(0), level, component, template, verbatim, file, line, column }
+    }
+}
+
+// RAW ARGUMENTS
+/// An argument value that can be sent as-is, and formatted by the decoder
+pub enum RawArg<'a> {
+    Unsigned(u64),
+    Signed(i64),
+    Bool(bool),
+    Char(char),
+    Str(&'a str),
+    Float(f64),
+}
+pub trait AsRawArg {
+    fn as_raw_arg(&self) -> RawArg<'_>;
+}
+macro_rules! impl_raw_arg {
+    ($variant:ident as $ct:ty: $($t:ty),+) => {
+        $(impl AsRawArg for $t {
+            #[inline(always)]
+            fn as_raw_arg(&self) -> RawArg<'_> { RawArg::$variant(*self as $ct) }
+        })+
+    }
+}
+impl_raw_arg!(Unsigned as u64: u8, u16, u32, u64, usize);
+impl_raw_arg!(Signed as i64: i8, i16, i32, i64, isize);
+impl_raw_arg!(Float as f64: f32, f64);
+impl AsRawArg for bool { fn as_raw_arg(&self) -> RawArg<'_> { RawArg::Bool(*self) } }
+impl AsRawArg for char { fn as_raw_arg(&self) -> RawArg<'_> { RawArg::Char(*self) } }
+impl AsRawArg for str { fn as_raw_arg(&self) -> RawArg<'_> { RawArg::Str(self) } }
+impl AsRawArg for alloc::string::String { fn as_raw_arg(&self) -> RawArg<'_> { RawArg::Str(self.as_str()) } }
+impl<T: AsRawArg + ?Sized> AsRawArg for &T { fn as_raw_arg(&self) -> RawArg<'_> { (**self).as_raw_arg() } }
+
+/* Probe(x).raw() returns Some(..) if x can be sent raw, and None otherwise.
+    This uses autoref-based specialisation: (&Probe(x)).raw() resolves to ProbeRaw if T: AsRawArg, as it doesn't require an extra autoref, and falls back to ProbeFallback otherwise.
+    (both traits must be in scope for this to work) */
+pub struct Probe<'a,T: ?Sized>(pub &'a T);
+pub trait ProbeRaw { fn raw(&self) -> Option<RawArg<'_>>; }
+impl<T: AsRawArg + ?Sized> ProbeRaw for Probe<'_,T> {
+    #[inline(always)]
+    fn raw(&self) -> Option<RawArg<'_>> { Some(self.0.as_raw_arg()) }
+}
+pub trait ProbeFallback { fn raw(&self) -> Option<RawArg<'_>>; }
+impl<T: ?Sized> ProbeFallback for &Probe<'_,T> {
+    #[inline(always)]
+    fn raw(&self) -> Option<RawArg<'_>> { None }
+}
+
+// CAPTURED (TEXT) ARGUMENTS
+/* Wraps an argument so that, when the message is formatted, we can tell which bytes of the output belong to which argument.
+    This means arguments are formatted according to their actual format spec (e.g. {:?} or {:#x}), without having to parse the template ourselves.
+    Each formatting trait is only implemented if the inner type implements it, so the template's requirements carry over unchanged. */
+pub struct Captured<'a,T: ?Sized> {
+    inner: &'a T,
+    index: usize,
+    current: &'a Cell<usize>,
+}
+impl<'a,T: ?Sized> Captured<'a,T> {
+    #[inline(always)]
+    pub fn new(inner: &'a T, index: usize, current: &'a Cell<usize>) -> Self {
+        Self { inner, index, current }
+    }
+}
+macro_rules! impl_captured_fmt {
+    ($($tr:ident),+) => {
+        $(impl<T: fmt::$tr + ?Sized> fmt::$tr for Captured<'_,T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.current.set(self.index+1);
+                let r = fmt::$tr::fmt(self.inner, f);
+                self.current.set(0);
+                r
+            }
+        })+
+    }
+}
+impl_captured_fmt!(Display, Debug, LowerHex, UpperHex, Octal, Binary, LowerExp, UpperExp, Pointer);
+
+/// Collects the formatted text of each (captured) argument. The template's own text is discarded, as the decoder already has it.
+struct CaptureSink<'a> {
+    current: &'a Cell<usize>,
+    buf: [u8; CAPTURE_BUFFER_SIZE],
+    len: usize,
+    /// (start, length, complete) for each argument
+    segments: [Option<(usize,usize,bool)>; MAX_CAPTURED_ARGS],
+    active: Option<usize>,
+}
+impl<'a> CaptureSink<'a> {
+    fn new(current: &'a Cell<usize>) -> Self {
+        Self { current, buf: [0; CAPTURE_BUFFER_SIZE], len: 0, segments: [None; MAX_CAPTURED_ARGS], active: None }
+    }
+    /// Get the captured text for the given argument. Returns None if it was not captured (or did not fit in the buffer)
+    fn get(&self, index: usize) -> Option<&str> {
+        let (start, len, complete) = (*self.segments.get(index)?)?;
+        if !complete { return None; }
+        // Safety: only ever filled in from &strs, and never truncated
+        Some(unsafe { core::str::from_utf8_unchecked(&self.buf[start..start+len]) })
+    }
+}
+impl fmt::Write for CaptureSink<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let index = match self.current.get() {
+            0 => { self.active = None; return Ok(()) },  // part of the template
+            n => n-1,
+        };
+        let Some(segment) = self.segments.get_mut(index) else { return Ok(()) };
+        match segment {
+            None => { *segment = Some((self.len, 0, true)); self.active = Some(index); },
+            Some(_) if self.active == Some(index) => {},
+            Some(_) => return Ok(()),  // already captured (the argument is used more than once in the template)
+        }
+        let (start, len, complete) = segment.as_mut().unwrap();
+        if !*complete { return Ok(()); }
+        if self.len + s.len() > self.buf.len() { *complete = false; return Ok(()); }
+        self.buf[self.len..self.len+s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len(); *len += s.len();
+        Ok(())
+    }
+}
+
+// ENCODING
+struct FrameBuilder {
+    buf: [u8; 5+MAX_PAYLOAD+1],
+    len: usize,
+}
+impl FrameBuilder {
+    fn new() -> Self {
+        Self { buf: [0; 5+MAX_PAYLOAD+1], len: 5 }
+    }
+    fn remaining(&self) -> usize {
+        5+MAX_PAYLOAD - self.len
+    }
+    fn u8(&mut self, x: u8) -> bool {
+        if self.remaining() < 1 { return false; }
+        self.buf[self.len] = x; self.len += 1;
+        true
+    }
+    fn bytes(&mut self, x: &[u8]) -> bool {
+        if self.remaining() < x.len() { return false; }
+        self.buf[self.len..self.len+x.len()].copy_from_slice(x); self.len += x.len();
+        true
+    }
+    fn varint(&mut self, mut x: u64) -> bool {
+        loop {
+            let byte = (x & 0x7F) as u8; x >>= 7;
+            if x == 0 { return self.u8(byte); }
+            if !self.u8(byte | 0x80) { return false; }
+        }
+    }
+    fn str(&mut self, s: &str) -> bool {
+        // Truncate strings that don't fit (on a char boundary), rather than dropping them entirely
+        let mut n = core::cmp::min(s.len(), self.remaining().saturating_sub(2));
+        while !s.is_char_boundary(n) { n -= 1; }
+        self.varint(n as u64) && self.bytes(&s.as_bytes()[..n])
+    }
+    fn arg(&mut self, raw: Option<&RawArg<'_>>, text: Option<&str>) -> bool {
+        match (raw, text) {
+            (Some(RawArg::Unsigned(x)), _) => self.u8(ArgTag::Unsigned as u8) && self.varint(*x),
+            (Some(RawArg::Signed(x)), _) => self.u8(ArgTag::Signed as u8) && self.varint(((*x << 1) ^ (*x >> 63)) as u64),
+            (Some(RawArg::Bool(x)), _) => self.u8(ArgTag::Bool as u8) && self.u8(*x as u8),
+            (Some(RawArg::Char(x)), _) => self.u8(ArgTag::Char as u8) && self.varint(*x as u64),
+            (Some(RawArg::Str(x)), _) => self.u8(ArgTag::Str as u8) && self.str(x),
+            (Some(RawArg::Float(x)), _) => self.u8(ArgTag::Float as u8) && self.bytes(&x.to_le_bytes()),
+            (None, Some(text)) => self.u8(ArgTag::Text as u8) && self.str(text),
+            (None, None) => self.u8(ArgTag::Missing as u8),
+        }
+    }
+    /// Fill in the header and checksum, returning the finished frame
+    fn finish(&mut self, frame_type: FrameType) -> &[u8] {
+        let payload_len = self.len - 5;
+        let checksum = self.buf[5..self.len].iter().fold(0u8, |a,b|a.wrapping_add(*b));
+        self.buf[0..2].copy_from_slice(&FRAME_MAGIC);
+        self.buf[2] = frame_type as u8;
+        self.buf[3..5].copy_from_slice(&(payload_len as u16).to_le_bytes());
+        self.buf[self.len] = checksum;
+        &self.buf[..self.len+1]
+    }
+}
+
+static STREAM_STARTED: AtomicBool = AtomicBool::new(false);
+static NEXT_SITE_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Held while sending frames, so that records from different CPUs aren't interleaved
+static SEND_LOCK: KMutex<()> = KMutex::new(());
+fn write_frame(frame: &[u8]) {
+    crate::coredrivers::serial_uart::SERIAL1.write_bytes(frame);
+}
+
+/* Emit a binary log record for the given call site. Called by klog! when the klog_binary feature is enabled.
+    `args` must be format_args!(template, Captured::new(arg0,0,current), Captured::new(arg1,1,current), ...) (or format_args!("{}", msg) for verbatim sites) */
+pub fn _kernel_log_binary(site: &'static LogSite, raw: &[Option<RawArg<'_>>], current: &Cell<usize>, args: fmt::Arguments<'_>){
+    // Always record it in the kernel message buffer (which doesn't allocate either)
+    super::kmsg::record(site.level, site.component, &args, site.file, site.line, site.column);
+    // Capture any arguments that can't be sent raw
+    let mut capture = CaptureSink::new(current);
+    if raw.iter().any(|a|a.is_none()) {
+        let _ = fmt::write(&mut capture, args);
+    }
+    let context = ExecutionContext::current();
+
+    // Build the record (the site id is filled in later, as it may not have been assigned yet)
+    let mut record = FrameBuilder::new();
+    let _: Option<()> = try {
+        record.varint(context.cpu_id as u64).then_some(())?;
+        record.varint(context.task_id.map_or(0, |t|t as u64+1)).then_some(())?;
+        if context.task_id.is_some() { record.str(context.task_name.unwrap_or("")).then_some(())?; }
+        record.varint(context.scheduler_clock_ticks as u64).then_some(())?;
+        record.u8(raw.len() as u8).then_some(())?;
+        for (i,arg) in raw.iter().enumerate() {
+            record.arg(arg.as_ref(), capture.get(i)).then_some(())?;
+        }
+    };
+
+    // Destinations that need text (e.g. the screen) still get it formatted as usual
+    super::_log_to_pipeline(site.level, site.component, &args, site.file, site.line, site.column);
+
+    // Send it (everything is done while holding the send lock, so that definitions are always sent before the records that use them)
+    let _send_lock = SEND_LOCK.lock();
+    if !STREAM_STARTED.swap(true, Ordering::Relaxed) {
+        let mut frame = FrameBuilder::new();
+        frame.u8(STREAM_VERSION);
+        write_frame(frame.finish(FrameType::StreamStart));
+    }
+    let mut id = site.id.load(Ordering::Relaxed);
+    if id == 0 {
+        id = NEXT_SITE_ID.fetch_add(1, Ordering::Relaxed);
+        site.id.store(id, Ordering::Relaxed);
+
+        let mut frame = FrameBuilder::new();
+        let _ = frame.varint(id as u64) && frame.u8(site.level as u8) && frame.u8(site.verbatim as u8)
+             && frame.str(site.component) && frame.str(site.file) && frame.varint(site.line as u64) && frame.varint(site.column as u64)
+             && frame.str(site.template);
+        write_frame(frame.finish(FrameType::Definition));
+    }
+    let mut header = FrameBuilder::new();
+    header.varint(id as u64);
+    // Prepend the id to the record's payload
+    let id_len = header.len - 5;
+    let payload_len = core::cmp::min(record.len - 5, MAX_PAYLOAD - id_len);
+    header.bytes(&record.buf[5..5+payload_len]);
+    write_frame(header.finish(FrameType::Record));
+}
+
+/* Binds each argument to a (hygienic) local exactly once, so that it can be both encoded and formatted without being evaluated twice.
+    Each expansion's `arg` is a distinct binding, as it is created by a different macro expansion. */
+macro_rules! _klog_bind {
+    ([$($bound:ident),*] [$x:expr $(, $rest:expr)*] $($params:tt)*) => {
+        match &$x { arg => $crate::logging::binary::_klog_bind!([$($bound,)* arg] [$($rest),*] $($params)*) }
+    };
+    ([$($bound:ident),*] [] $level:ident, $component:ident, $template:literal) => {{
+        #[allow(unused_imports)]
+        use $crate::logging::binary::{ProbeRaw as _, ProbeFallback as _};
+        static SITE: $crate::logging::binary::LogSite = $crate::logging::binary::LogSite::new($level, stringify!($component), $template, false, file!(), line!(), column!());
+        let current = core::cell::Cell::new(0usize);
+        let mut _index = 0usize;
+        $crate::logging::binary::_kernel_log_binary(&SITE, &[$( (&$crate::logging::binary::Probe($bound)).raw() ),*], &current,
+            core::format_args!($template, $( $crate::logging::binary::Captured::new($bound, { _index += 1; _index-1 }, &current) ),*));
+    }};
+}
+pub(crate) use _klog_bind;
+
+/* A message with no arguments, sent verbatim (the decoder will not interpret it as a format string) */
+macro_rules! _klog_verbatim {
+    ($level:ident, $component:ident, $msg:literal) => {{
+        static SITE: $crate::logging::binary::LogSite = $crate::logging::binary::LogSite::new($level, stringify!($component), $msg, true, file!(), line!(), column!());
+        let current = core::cell::Cell::new(0usize);
+        $crate::logging::binary::_kernel_log_binary(&SITE, &[], &current, core::format_args!("{}", $msg));
+    }};
+}
+pub(crate) use _klog_verbatim;
@@ -0,0 +1,272 @@
+//! The kernel message buffer ("kmsg", a la dmesg)
+//! A fixed-size, lock-free ring buffer that every log message is recorded into, regardless of which destinations are attached.
+//! This means messages from early boot (or from before a destination was attached) can still be read back later,
+//!     e.g. by a debug shell, in a crash dump, or through some future /dev/kmsg-like interface.
+//!
+//! Writing never allocates and never blocks, so it is safe to call from anywhere (allocators, the scheduler, interrupt handlers, etc.)
+//! Each slot is protected by a sequence counter (a la seqlock): writers claim a sequence number, mark the slot as "being written", fill it in, and then publish it.
+//! Readers copy the slot out and then check that the sequence counter did not change while they were reading it.
+
+use core::cell::SyncUnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64,Ordering};
+use core::fmt;
+use super::LogLevel;
+use crate::multitasking::ExecutionContext;
+
+/// The number of records kept in the buffer. Once it is full, the oldest records are overwritten.
+pub const KMSG_CAPACITY: usize = 256;
+/// The maximum length of a recorded message, in bytes. Longer messages are truncated.
+pub const KMSG_MESSAGE_LENGTH: usize = 192;
+
+#[derive(Clone,Copy)]
+struct KmsgRecordData {
+    level: LogLevel,
+    component: &'static str,
+    context: ExecutionContext,
+    file: &'static str,
+    line: u32,
+    column: u32,
+
+    msg_len: usize,
+    msg_truncated: bool,
+    msg: [u8; KMSG_MESSAGE_LENGTH],
+}
+impl KmsgRecordData {
+    const EMPTY: Self = Self {
+        level: LogLevel::Debug, component: "", file: "", line: 0, column: 0,
+        context: ExecutionContext { cpu_id: 0, task_id: None, task_name: None, scheduler_clock_ticks: 0 },
+        msg_len: 0, msg_truncated: false, msg: [0; KMSG_MESSAGE_LENGTH],
+    };
+}
+
+struct KmsgSlot {
+    /// The state of the slot. For record number n, this is 2n+1 while it is being written, and 2n+2 once it has been published.
+    /// 0 means the slot has never been written to.
+    state: AtomicU64,
+    data: SyncUnsafeCell<KmsgRecordData>,
+}
+impl KmsgSlot {
+    const EMPTY: Self = Self { state: AtomicU64::new(0), data: SyncUnsafeCell::new(KmsgRecordData::EMPTY) };
+}
+
+struct KmsgBuffer {
+    slots: [KmsgSlot; KMSG_CAPACITY],
+    /// The sequence number that will be given to the next record
+    next_seq: AtomicU64,
+    /// Records before this sequence number have been cleared
+    first_seq: AtomicU64,
+}
+static KMSG: KmsgBuffer = KmsgBuffer {
+    slots: [KmsgSlot::EMPTY; KMSG_CAPACITY],
+    next_seq: AtomicU64::new(0),
+    first_seq: AtomicU64::new(0),
+};
+
+#[inline(always)]
+fn slot_for(seq: u64) -> &'static KmsgSlot {
+    &KMSG.slots[(seq % (KMSG_CAPACITY as u64)) as usize]
+}
+
+/// A fmt::Write implementation that writes into a fixed-size buffer, silently truncating anything that doesn't fit (on a char boundary)
+struct TruncatingWriter<'a> { buf: &'a mut [u8], len: usize, truncated: bool }
+impl fmt::Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = self.buf.len() - self.len;
+        let mut n = core::cmp::min(remaining, s.len());
+        while !s.is_char_boundary(n) { n -= 1; }
+        if n < s.len() { self.truncated = true; }
+        self.buf[self.len..self.len+n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/* Record a message into the kernel message buffer. Called by _kernel_log for every message.
+    If the slot the message would go in is still being written to by someone else (which only happens if the buffer has wrapped all the way around in the meantime), the message is dropped. */
+pub(super) fn record(level: LogLevel, component: &'static str, msg: &(impl fmt::Display + ?Sized), file: &'static str, line: u32, column: u32){
+    let context = ExecutionContext::current();
+    let seq = KMSG.next_seq.fetch_add(1, Ordering::AcqRel);
+    let slot = slot_for(seq);
+
+    // Claim the slot
+    let writing = 2*seq+1; let published = 2*seq+2;
+    let mut current = slot.state.load(Ordering::Acquire);
+    loop {
+        // Slot is busy, or a newer record has already taken it
+        if current&1 == 1 || current >= published { return; }
+        match slot.state.compare_exchange_weak(current, writing, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(x) => current = x,
+        }
+    }
+
+    // Fill it in
+    // Safety: We have exclusive write access to the slot, as we are the only one who successfully set it to 'writing'
+    //         Readers may read it concurrently, but will discard what they read as the state will have changed.
+    let data = unsafe { &mut *slot.data.get() };
+    data.level = level; data.component = component; data.context = context;
+    data.file = file; data.line = line; data.column = column;
+    let mut writer = TruncatingWriter { buf: &mut data.msg, len: 0, truncated: false };
+    let _ = fmt::Write::write_fmt(&mut writer, format_args!("{}", msg));
+    data.msg_len = writer.len; data.msg_truncated = writer.truncated;
+
+    // And publish it
+    slot.state.store(published, Ordering::Release);
+}
+
+/// A record read out of the kernel message buffer
+#[derive(Clone)]
+pub struct KmsgEntry {
+    pub seq: u64,
+    pub level: LogLevel,
+    pub component: &'static str,
+    /// The context the message was logged in (also serves as the timestamp)
+    pub context: ExecutionContext,
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+
+    msg_len: usize,
+    msg_truncated: bool,
+    msg: [u8; KMSG_MESSAGE_LENGTH],
+}
+impl KmsgEntry {
+    pub fn message(&self) -> &str {
+        // Safety: the message was copied from a &str, and only ever truncated on a char boundary
+        unsafe { core::str::from_utf8_unchecked(&self.msg[..self.msg_len]) }
+    }
+    /// Returns true if the message was truncated to fit into the buffer
+    pub fn is_truncated(&self) -> bool {
+        self.msg_truncated
+    }
+}
+impl fmt::Display for KmsgEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {} - {}", self.level.name(), self.context, self.component, self.message())?;
+        if self.is_truncated() { write!(f, "...")?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum KmsgReadError {
+    /// The record has been overwritten (or cleared) and is no longer available
+    Lost,
+    /// The record has not been written yet
+    NotYetWritten,
+}
+
+/* Read the record with the given sequence number. */
+pub fn read(seq: u64) -> Result<KmsgEntry,KmsgReadError> {
+    if seq < KMSG.first_seq.load(Ordering::Acquire) { return Err(KmsgReadError::Lost); }
+    let slot = slot_for(seq);
+    let published = 2*seq+2;
+
+    let before = slot.state.load(Ordering::Acquire);
+    if before < published { return Err(KmsgReadError::NotYetWritten); }
+    else if before > published { return Err(KmsgReadError::Lost); }
+    // The data may be written to concurrently, in which case what we copy may be torn (e.g. half of a &str, or an invalid LogLevel).
+    // So it's copied as a MaybeUninit (which doesn't have to be valid), and only treated as a KmsgRecordData once we know the state didn't change while we were copying it.
+    // Safety: The pointer is valid for reads (the slot is static), and MaybeUninit places no requirements on what was read
+    let data = unsafe { core::ptr::read_volatile(slot.data.get().cast::<MaybeUninit<KmsgRecordData>>()) };
+    core::sync::atomic::fence(Ordering::Acquire);
+    let after = slot.state.load(Ordering::Relaxed);
+    if after != before { return Err(KmsgReadError::Lost); }
+    // Safety: The state was published both before and after the copy, so nobody was writing to the slot in the meantime, and it held a complete record
+    let data = unsafe { data.assume_init() };
+
+    Ok(KmsgEntry {
+        seq, level: data.level, component: data.component, context: data.context,
+        file: data.file, line: data.line, column: data.column,
+        msg_len: data.msg_len, msg_truncated: data.msg_truncated, msg: data.msg,
+    })
+}
+
+/// The sequence number of the oldest record that may still be available
+pub fn first_seq() -> u64 {
+    let next = KMSG.next_seq.load(Ordering::Acquire);
+    core::cmp::max(KMSG.first_seq.load(Ordering::Acquire), next.saturating_sub(KMSG_CAPACITY as u64))
+}
+/// The sequence number that the next record will be given
+pub fn next_seq() -> u64 {
+    KMSG.next_seq.load(Ordering::Acquire)
+}
+
+/* Clear the buffer. Records logged before this point will no longer be returned. */
+pub fn clear(){
+    let next = KMSG.next_seq.load(Ordering::Acquire);
+    KMSG.first_seq.fetch_max(next, Ordering::AcqRel);
+}
+
+/// A cursor over the kernel message buffer. Remembers its position, so that it can be polled repeatedly for new messages (like reading from /dev/kmsg).
+pub struct KmsgReader {
+    next: u64,
+}
+impl KmsgReader {
+    /// Create a reader starting at the oldest available record
+    pub fn new() -> Self {
+        Self { next: first_seq() }
+    }
+    /// Create a reader that only returns records logged after this point
+    pub fn new_at_end() -> Self {
+        Self { next: next_seq() }
+    }
+    /// Create a reader starting at the given sequence number (if older records than that have already been lost, the first read reports them as lost)
+    pub fn starting_at(seq: u64) -> Self {
+        Self { next: seq }
+    }
+
+    /* Read the next record, if one is available.
+        Returns Err(n) if n records were lost (overwritten or cleared) since the last read. The next call will continue from the oldest available record. */
+    pub fn read_next(&mut self) -> Option<Result<KmsgEntry,u64>> {
+        match read(self.next) {
+            Ok(entry) => { self.next += 1; Some(Ok(entry)) },
+            Err(KmsgReadError::NotYetWritten) => {
+                // Either there are no new records, or the record is currently being written
+                // If a newer record has already been claimed, then we can't wait for this one (and so it counts as lost)
+                if self.next+1 >= next_seq() { return None; }
+                self.next += 1; Some(Err(1))
+            },
+            Err(KmsgReadError::Lost) => {
+                let first = first_seq();
+                if first > self.next {
+                    let lost = first - self.next;
+                    self.next = first;
+                    return Some(Err(lost));
+                }
+                // It was overwritten between us reading first_seq and the record itself. Skip it
+                self.next += 1; Some(Err(1))
+            },
+        }
+    }
+}
+/// Iterates over all available records, skipping any that are lost along the way
+impl Iterator for KmsgReader {
+    type Item = KmsgEntry;
+    fn next(&mut self) -> Option<KmsgEntry> {
+        loop {
+            match self.read_next()? {
+                Ok(entry) => return Some(entry),
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// Iterate over all records currently in the buffer, oldest first
+pub fn iter() -> KmsgReader {
+    KmsgReader::new()
+}
+
+/* Write the contents of the buffer to the given writer, oldest first. Does not allocate on the heap, so is suitable for crash dumps. */
+pub fn dump(writer: &mut impl fmt::Write) -> fmt::Result {
+    let mut reader = KmsgReader::new();
+    while let Some(result) = reader.read_next() {
+        match result {
+            Ok(entry) => write!(writer, "{}\r\n", entry)?,
+            Err(lost) => write!(writer, "({} messages lost)\r\n", lost)?,
+        }
+    }
+    Ok(())
+}
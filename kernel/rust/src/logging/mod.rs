@@ -0,0 +1,394 @@
+use alloc::format;
+use core::{file,line};
+use lazy_static::lazy_static;
+
+pub mod kmsg;
+#[cfg(feature="klog_binary")]
+pub mod binary;
+
+#[derive(Debug,Clone,Copy,PartialOrd,Ord,PartialEq,Eq)]
+#[repr(u8)]
+pub enum LogLevel {
+    // Debug: very specific, low-level information.
+    Debug = 0,
+    // Info: General information, status messages, etc.
+    Info = 1,
+    // Warning: Abnormal conditions that may require attention
+    Warning = 2,
+    // Severe: Abnormal conditions that may directly impair the operation of the system
+    Severe = 3,
+    // Critical: Abnormal conditions that will cause issues - generally require things to shut down soon
+    Critical = 4,
+    // Fatal: Things can no longer continue in this state. Usually followed by a kernel panic
+    Fatal = 5,
+}
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        use LogLevel::*;
+        match self {
+            Debug    => "DBG",
+            Info     => "INFO",
+            Warning  => "WARN",
+            Severe   => "SEVERE",
+            Critical => "CRITICAL",
+            Fatal    => "FATAL ERROR",
+        }
+    }
+    /// Parse a level from its name (case-insensitive). Accepts both the full names (e.g. "warning") and the short names used in log output (e.g. "WARN")
+    pub fn from_name(name: &str) -> Option<Self> {
+        use LogLevel::*;
+        [Debug, Info, Warning, Severe, Critical, Fatal].into_iter().find(|level|{
+            name.eq_ignore_ascii_case(level.name()) || name.eq_ignore_ascii_case(&format!("{:?}", level))
+        })
+    }
+}
+
+// LOG FORMATTING
+use alloc::{boxed::Box,vec::Vec,sync::Arc};
+use crate::sync::kspin::KMutex;
+use crate::sync::rcu::{RcuCell,rcu_read_lock};
+pub trait LogFormatter: Send + Sync {
+    fn format_log_message(&self, level: LogLevel, component: &str, msg: &str, file: &str, line: u32, column: u32) -> alloc::string::String;
+}
+pub struct DefaultLogFormatter();
+impl LogFormatter for DefaultLogFormatter {
+    fn format_log_message(&self, level: LogLevel, component: &str, msg: &str, file: &str, line: u32, column: u32) -> alloc::string::String {
+        let context = crate::multitasking::ExecutionContext::current();
+        format!("[{}] {}: {} - {} ({}:{}:{})", level.name(), context, component, msg, file, line, column)
+    }
+}
+/// Same as the default formatter, but with ANSI colour codes for the level and context (for serial terminals)
+pub struct AnsiLogFormatter();
+impl AnsiLogFormatter {
+    fn level_colour(level: LogLevel) -> &'static str {
+        use LogLevel::*;
+        match level {
+            Debug    => "\x1b[2;37m",   // dim grey
+            Info     => "\x1b[0;32m",   // green
+            Warning  => "\x1b[0;33m",   // yellow
+            Severe   => "\x1b[0;31m",   // red
+            Critical => "\x1b[1;31m",   // bold red
+            Fatal    => "\x1b[1;37;41m",  // bold white on red
+        }
+    }
+}
+impl LogFormatter for AnsiLogFormatter {
+    fn format_log_message(&self, level: LogLevel, component: &str, msg: &str, file: &str, line: u32, column: u32) -> alloc::string::String {
+        let context = crate::multitasking::ExecutionContext::current();
+        format!("{}[{}]\x1b[0m \x1b[2m{}:\x1b[0m \x1b[1m{}\x1b[0m - {} \x1b[2m({}:{}:{})\x1b[0m", Self::level_colour(level), level.name(), context, component, msg, file, line, column)
+    }
+}
+/// A short format with no context or source location, for places where space is limited (e.g. the screen)
+pub struct TerseLogFormatter();
+impl LogFormatter for TerseLogFormatter {
+    fn format_log_message(&self, level: LogLevel, component: &str, msg: &str, file: &str, line: u32, column: u32) -> alloc::string::String {
+        format!("[{}] {}: {}", level.name(), component, msg)
+    }
+}
+/// The names of the formatters that can be chosen with formatter_from_name (e.g. by the debug shell's logformat command)
+pub const FORMATTER_NAMES: [&str; 3] = ["default", "ansi", "terse"];
+/// Create one of the built-in formatters by name (case-insensitive, see FORMATTER_NAMES)
+pub fn formatter_from_name(name: &str) -> Option<Box<dyn LogFormatter>> {
+    if name.eq_ignore_ascii_case("default") { Some(Box::new(DefaultLogFormatter())) }
+    else if name.eq_ignore_ascii_case("ansi") { Some(Box::new(AnsiLogFormatter())) }
+    else if name.eq_ignore_ascii_case("terse") { Some(Box::new(TerseLogFormatter())) }
+    else { None }
+}
+
+// LOG DESTINATIONS
+pub struct GuardFmtWriter<T: core::fmt::Write, G: core::ops::DerefMut<Target=T>>(G,core::marker::PhantomData<T>);
+impl<T: core::fmt::Write, G: core::ops::DerefMut<Target=T>> GuardFmtWriter<T,G> {
+    pub fn new(guard: G) -> Self {
+        Self(guard, core::marker::PhantomData)
+    }
+    pub fn into_guard(self) -> G {
+        self.0
+    }
+}
+impl<T: core::fmt::Write, G: core::ops::DerefMut<Target=T>> core::fmt::Write for GuardFmtWriter<T,G> {
+    fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
+        self.0.write_str(s)
+    }
+}
+impl<T: core::fmt::Write, G: core::ops::DerefMut<Target=T>> core::ops::Deref for GuardFmtWriter<T,G> {
+    type Target = G;
+    fn deref(&self) -> &G {
+        &self.0
+    }
+}
+impl<T: core::fmt::Write, G: core::ops::DerefMut<Target=T>> core::ops::DerefMut for GuardFmtWriter<T,G> {
+    fn deref_mut(&mut self) -> &mut G {
+        &mut self.0
+    }
+}
+
+// FORMATTER/DESTINATION SELECTION
+/// Decides which contexts' messages are sent to a destination.
+/// Contexts are matched by name, including any child contexts (e.g. "MEMORY_PAGING" also matches "MEMORY_PAGING_TLB" - see the contexts module).
+#[derive(Clone)]
+pub enum ContextFilter {
+    /// Accept messages from all contexts
+    All,
+    /// Only accept messages from the given contexts (and their children)
+    Only(Vec<&'static str>),
+    /// Accept messages from all contexts except the given contexts (and their children)
+    Except(Vec<&'static str>),
+}
+impl ContextFilter {
+    fn matches_context(context: &str, component: &str) -> bool {
+        component.strip_prefix(context).is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+    }
+    pub fn accepts(&self, component: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(contexts) => contexts.iter().any(|c|Self::matches_context(c, component)),
+            Self::Except(contexts) => !contexts.iter().any(|c|Self::matches_context(c, component)),
+        }
+    }
+}
+
+/// A destination for log messages, along with its own formatter and filters.
+/// Note: The compile-time filters in the contexts module are applied first, so a destination can only be more restrictive than them, not less.
+/// Cloning a destination is cheap, as the clones share the same writer and formatter (see LoggingPipeline).
+#[derive(Clone)]
+pub struct LogDestination {
+    name: &'static str,
+    /// IMPORTANT: All destinations MUST be writable without interruptions available (i.e. they must not yield to the scheduler)
+    /// kernel_log is called in all sorts of places, including the allocators, scheduler, and interrupt handlers!
+    /// Use KMutexes or lock-free write mechanisms ONLY
+    /// (your best options are to either push to a kmutex-locked queue, or permanently hold a mutex guard and use that inside a [GuardFmtWriter])
+    /// (the writer is locked while each message is formatted and written, which also keeps the formatter from yielding)
+    writer: Arc<KMutex<Box<dyn core::fmt::Write + Send>>>,
+    formatter: Arc<dyn LogFormatter>,
+    min_level: LogLevel,
+    filter: ContextFilter,
+}
+impl LogDestination {
+    /// Create a new destination, which accepts all messages and uses the [DefaultLogFormatter]
+    pub fn new(name: &'static str, writer: Box<dyn core::fmt::Write + Send>) -> Self {
+        Self {
+            name, writer: Arc::new(KMutex::new(writer)),
+            formatter: Arc::new(DefaultLogFormatter()),
+            min_level: LogLevel::Debug,
+            filter: ContextFilter::All,
+        }
+    }
+    pub fn with_formatter(mut self, formatter: Box<dyn LogFormatter>) -> Self {
+        self.formatter = Arc::from(formatter); self
+    }
+    pub fn with_min_level(mut self, min_level: LogLevel) -> Self {
+        self.min_level = min_level; self
+    }
+    pub fn with_filter(mut self, filter: ContextFilter) -> Self {
+        self.filter = filter; self
+    }
+    
+    pub fn name(&self) -> &'static str { self.name }
+    pub fn min_level(&self) -> LogLevel { self.min_level }
+    pub fn filter(&self) -> &ContextFilter { &self.filter }
+    pub fn set_formatter(&mut self, formatter: Box<dyn LogFormatter>) { self.formatter = Arc::from(formatter); }
+    pub fn set_min_level(&mut self, min_level: LogLevel) { self.min_level = min_level; }
+    pub fn set_filter(&mut self, filter: ContextFilter) { self.filter = filter; }
+    
+    /// Returns true if a message with the given level and component should be sent to this destination
+    pub fn accepts(&self, level: LogLevel, component: &str) -> bool {
+        level >= self.min_level && self.filter.accepts(component)
+    }
+}
+
+/// The destinations log messages are sent to.
+/// It's read-copy-update: loggers read the current pipeline without taking any locks, and update_logging_pipeline publishes a modified copy.
+#[derive(Clone)]
+pub struct LoggingPipeline {
+    destinations: Vec<LogDestination>,
+}
+impl LoggingPipeline {
+    /// Add a destination. If there is already a destination with the same name, it is replaced (and the old one is returned)
+    pub fn add_destination(&mut self, destination: LogDestination) -> Option<LogDestination> {
+        let old = self.remove_destination(destination.name);
+        self.destinations.push(destination);
+        old
+    }
+    pub fn remove_destination(&mut self, name: &str) -> Option<LogDestination> {
+        let index = self.destinations.iter().position(|d|d.name == name)?;
+        Some(self.destinations.remove(index))
+    }
+    pub fn get_destination_mut(&mut self, name: &str) -> Option<&mut LogDestination> {
+        self.destinations.iter_mut().find(|d|d.name == name)
+    }
+    pub fn destinations(&self) -> impl Iterator<Item=&LogDestination> {
+        self.destinations.iter()
+    }
+    pub fn destinations_mut(&mut self) -> impl Iterator<Item=&mut LogDestination> {
+        self.destinations.iter_mut()
+    }
+}
+impl core::default::Default for LoggingPipeline {
+    fn default() -> Self {
+        // (unless binary logging is enabled, in which case serial1 is used for binary log records instead)
+        #[cfg_attr(feature="klog_binary", allow(unused_mut))]
+        let mut destinations = Vec::new();
+        #[cfg(not(feature="klog_binary"))]
+        destinations.push(LogDestination::new("serial1", Box::new(crate::coredrivers::serial_uart::SERIAL1.writer())));
+        Self {
+            destinations: destinations,
+        }
+    }
+}
+
+lazy_static! {
+    static ref PIPELINE: RcuCell<LoggingPipeline> = RcuCell::default();
+}
+
+pub fn _kernel_log(level: LogLevel, component: &'static str, msg: &(impl core::fmt::Display + ?Sized), file: &'static str, line: u32, column: u32){
+    // Always record it in the kernel message buffer, regardless of what destinations are attached
+    kmsg::record(level, component, msg, file, line, column);
+    _log_to_pipeline(level, component, msg, file, line, column);
+}
+/* Send a message to every destination in the logging pipeline that accepts it (but not to kmsg - see _kernel_log) */
+pub(crate) fn _log_to_pipeline(level: LogLevel, component: &'static str, msg: &(impl core::fmt::Display + ?Sized), file: &'static str, line: u32, column: u32){
+    // don't bother formatting it if there's nowhere for it to go
+    let count = {
+        let guard = rcu_read_lock();
+        let Some(context) = PIPELINE.read(&guard) else { return };
+        context.destinations.iter().filter(|d|d.accepts(level, component)).count()
+    };
+    if count == 0 { return; }
+    // (formatting allocates, which may yield, so it can't be done inside a read-side critical section.
+    //  Instead, the destinations are cloned out of it (which is cheap, as they're reference-counted) into space allocated beforehand, and written to afterwards)
+    let msg = format!("{}",msg);
+    let mut destinations = Vec::with_capacity(count);
+    {
+        let guard = rcu_read_lock();
+        let Some(context) = PIPELINE.read(&guard) else { return };
+        // (if destinations were added in the meantime, they miss this message rather than allocating here)
+        destinations.extend(context.destinations.iter().filter(|d|d.accepts(level, component)).take(count).cloned());
+    }
+    for dest in destinations {
+        // (interruptions are disabled while the writer is locked, so nothing in here can yield)
+        let mut writer = dest.writer.lock();
+        let formatted = dest.formatter.format_log_message(level, component, &msg, file, line, column);
+        let _=write!(writer,"{}\r\n",formatted);
+    }
+}
+/* Look at the current logging pipeline (or None if there isn't one yet), without copying it.
+    The reader runs inside a read-side critical section, so it must not allocate or yield (copy out what you need, and format it afterwards). */
+pub fn read_logging_pipeline<R>(reader: impl FnOnce(&LoggingPipeline)->R) -> Option<R> {
+    let guard = rcu_read_lock();
+    PIPELINE.read(&guard).map(reader)
+}
+/* Modify the logging pipeline, e.g. to add/remove destinations or change their filters/formatters:
+    update_logging_pipeline(|p|{ p.get_destination_mut("serial1").map(|d|d.set_formatter(Box::new(AnsiLogFormatter()))); });
+    The updater is given a copy of the current pipeline, which replaces it once the updater returns. */
+pub fn update_logging_pipeline(updater: impl FnOnce(&mut LoggingPipeline)){
+    PIPELINE.update(|context|{
+        let mut context = context.cloned().unwrap_or_default();
+        updater(&mut context);
+        context
+    });
+}
+
+macro_rules! klog {
+    (@filter $level: ident, $component:ident, $body:block) => {
+        {
+            use $crate::logging::LogLevel::*;
+            use $crate::logging::contexts::*;
+            if const { ($level as u8) >= ($component as u8) } $body
+        }
+    };
+    
+    ($level: ident, $component:ident, $template:literal, $($x:expr),*) => {
+        $crate::logging::klog!(@filter $level, $component, {
+            #[cfg(not(feature="klog_binary"))]
+            $crate::logging::_kernel_log($level, stringify!($component), &core::format_args!($template, $($x),*), file!(), line!(), column!());
+            #[cfg(feature="klog_binary")]
+            $crate::logging::binary::_klog_bind!([] [$($x),*] $level, $component, $template);
+        })
+    };
+    
+    ($level: ident, $component:ident, $msg: literal) => {
+        $crate::logging::klog!(@filter $level, $component, {
+            #[cfg(not(feature="klog_binary"))]
+            $crate::logging::_kernel_log($level, stringify!($component), $msg, file!(), line!(), column!());
+            #[cfg(feature="klog_binary")]
+            $crate::logging::binary::_klog_verbatim!($level, $component, $msg);
+        })
+    };
+    ($level: ident, $component:ident, $msg: expr) => {
+        $crate::logging::klog!(@filter $level, $component, {
+            #[cfg(not(feature="klog_binary"))]
+            $crate::logging::_kernel_log($level, stringify!($component), $msg, file!(), line!(), column!());
+            #[cfg(feature="klog_binary")]
+            $crate::logging::binary::_klog_bind!([] [$msg] $level, $component, "{}");
+        })
+    };
+}
+pub(crate) use klog;
+
+// For use in emergency situations, such as a kernel panic.
+// uses no heap allocation and forcibly bypasses locks
+// generally if you're using this function, shit is fucked and the program should be due to abort any second now
+macro_rules! emergency_kernel_log {
+    ($($msg:tt)*) => {
+        unsafe{$crate::multitasking::interruptions::_without_interruptions_noalloc(||{
+            use $crate::coredrivers::serial_uart::{EmergencySerialWriter,ComPort};
+            use core::fmt::Write;
+            let mut serial = EmergencySerialWriter::new(ComPort::COM1);
+            let _ = write!(serial, $($msg)*);
+        })}
+    }
+}
+pub(crate) use emergency_kernel_log;
+
+// Logging contexts allow filtered log levels to be configured per-context
+pub mod contexts {
+    use super::LogLevel; use LogLevel::*;
+    macro_rules! def_context {
+        ($id: ident, $parent: ident, $filter_level: ident) => {
+            pub const $id: LogLevel = $filter_level;
+        };
+        ($id: ident, $parent: ident) => {
+            pub const $id: LogLevel = $parent;
+        };
+    }
+    
+    pub const DEFAULT_MIN_LOG_LEVEL: super::LogLevel = Info;
+    pub const ROOT: LogLevel = DEFAULT_MIN_LOG_LEVEL;
+    
+    // Configure contexts in here! :)
+    def_context!(BOOT, ROOT);  // boot-time top-level progress messages
+    def_context!(MEMORY, ROOT);
+      def_context!(MEMORY_PAGING, MEMORY);
+        def_context!(MEMORY_PAGING_CONTEXT, MEMORY_PAGING);
+        def_context!(MEMORY_PAGING_GLOBALPAGES, MEMORY_PAGING);
+        def_context!(MEMORY_PAGING_ALLOCATOR, MEMORY_PAGING);
+          def_context!(MEMORY_PAGING_ALLOCATOR_MLFF, MEMORY_PAGING_ALLOCATOR);
+        def_context!(MEMORY_PAGING_MAPPINGS, MEMORY_PAGING);
+        def_context!(MEMORY_PAGING_TLB, MEMORY_PAGING);
+          def_context!(MEMORY_PAGING_TLB_APIC, MEMORY_PAGING_TLB);
+          def_context!(MEMORY_PAGING_TLB_RECUR, MEMORY_PAGING_TLB, Info);
+      def_context!(MEMORY_KHEAP, MEMORY, Debug);
+      def_context!(MEMORY_PHYSICAL, MEMORY);
+        def_context!(MEMORY_PHYSICAL_BUDDIES, MEMORY_PHYSICAL, Warning);
+        def_context!(MEMORY_PHYSICAL_RAMMAP, MEMORY_PHYSICAL);
+        def_context!(MEMORY_PHYSICAL_ALLOCATOR, MEMORY_PHYSICAL, Warning);
+      def_context!(MEMORY_ALLOCUTIL, MEMORY);
+      def_context!(MEMORY_UNIFIED, MEMORY);
+        def_context!(MEMORY_UNIFIED_EXPANSION, MEMORY_UNIFIED);
+        def_context!(MEMORY_UNIFIED_PAGEMAPPING, MEMORY_UNIFIED);
+    def_context!(FEATURE_FLAGS, ROOT, Debug);
+    def_context!(SCHEDULER, ROOT);
+    def_context!(PROCESSES, ROOT);
+    def_context!(CPU_MANAGEMENT, ROOT);
+      def_context!(CPU_MANAGEMENT_SMP, CPU_MANAGEMENT);
+      def_context!(CPU_MANAGEMENT_INTERRUPTS, CPU_MANAGEMENT);
+    def_context!(COREDRIVERS, ROOT);
+      def_context!(COREDRIVERS_XAPIC, COREDRIVERS);
+      def_context!(COREDRIVERS_SERIAL, COREDRIVERS);
+      def_context!(COREDRIVERS_VGA, COREDRIVERS);
+      def_context!(COREDRIVERS_FRAMEBUFFER, COREDRIVERS);
+      def_context!(COREDRIVERS_PS2, COREDRIVERS);
+      def_context!(COREDRIVERS_PIT, COREDRIVERS);
+    def_context!(DEBUG_SHELL, ROOT);
+    def_context!(DEBUG_GDBSTUB, ROOT);
+}
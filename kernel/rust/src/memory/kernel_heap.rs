@@ -84,7 +84,7 @@ unsafe fn on_oom(heap: &LockedHeap<32>, layout: &Layout) {
            _reinit_rescue::spawn();
 
            // Also, allocate more memory if possible, so we don't have to rescue so often
-           _expand_further_post_rescue::spawn();
+           _expand_further_post_rescue::spawn_with_priority(crate::multitasking::TaskPriority::Background);
        }
    }
 }
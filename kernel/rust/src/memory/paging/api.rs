@@ -8,7 +8,7 @@ use crate::multitasking::cpulocal::CpuLocal;
 use super::*;
 
 type BaseTLPageAllocator = arch::TopLevelPageAllocator;
-use arch::{set_active_page_table,inval_tlb_pg};
+use arch::{set_active_page_table,set_active_page_table_quietly,inval_tlb_pg};
 
 // Page-Alignable Numbers
 /// The alignment (in bytes) for pages. In other words, the minimum possible amount of memory worth caring about for system-wide memory management.
@@ -396,6 +396,11 @@ bitflags::bitflags! {
         // On pages: influences the memory type of the memory mapped via the page
         // On sub-tables: influences the memory type used for reading the sub-table
         const CACHE_WRITE_THROUGH = 1<<3;
+        // Affects the "memory type" of the selected area - enables "write combining", meaning that writes may be buffered and merged together before being written out (and reads are uncached)
+        // Intended for framebuffers. If the CPU doesn't support it, write-through caching is used instead.
+        // On pages: influences the memory type of the memory mapped via the page
+        // On sub-tables: No effect.
+        const CACHE_WRITE_COMBINING = 1<<4;
     }
 }
 
@@ -468,6 +473,11 @@ impl<PFA: PageFrameAllocator> LockedPageAllocator<PFA> {
         // Return
         lock
     }
+    /* As _begin_active, but without locking the allocator, for the scheduler (which mustn't wait for it).
+    A writer that locked the allocator before we incremented active_count won't have planned on flushing the TLB, so writers check active_count again after each write (see LockedPageAllocatorWriteGuard::should_flush_tlb). */
+    pub(super) fn _begin_active_unlocked(&self) {
+        self.0.active_count.fetch_add(1,Ordering::SeqCst);
+    }
     pub(super) unsafe fn _end_active(&self,
                                      // called if the active ID was cleared
                                      active_id_destructor: impl FnOnce(u8),
@@ -511,7 +521,7 @@ impl<PFA: PageFrameAllocator> LockedPageAllocator<PFA> {
     }
 }
 
-pub struct PagingContext(LockedPageAllocator<BaseTLPageAllocator>);
+pub struct PagingContext(LockedPageAllocator<BaseTLPageAllocator>, /* physical address of the page table (which never moves) */ usize);
 impl PagingContext {
     pub fn new() -> Self {
         klog!(Debug, MEMORY_PAGING_CONTEXT, "Creating new paging context.");
@@ -531,10 +541,12 @@ impl PagingContext {
         }
         // Return
         let dopts = LPAWGOptions::new_default();
-        Self(LockedPageAllocator::new(allocator, LPAMetadata { offset: 0, default_options: dopts }))
+        let allocator = LockedPageAllocator::new(allocator, LPAMetadata { offset: 0, default_options: dopts });
+        let table_addr = allocator.get_phys_addr();
+        Self(allocator, table_addr)
     }
     pub fn clone_ref(x: &Self) -> Self {
-        Self(LockedPageAllocator::clone_ref(&x.0))
+        Self(LockedPageAllocator::clone_ref(&x.0), x.1)
     }
     
     /* Activate this page table. Once active, this page table will be used to map virtual addresses to physical ones.
@@ -552,7 +564,7 @@ impl PagingContext {
         
         // activate table
         let table_addr = ptaddr_virt_to_phys(allocator.get_page_table_ptr() as usize);
-        klog!(Info, MEMORY_PAGING_CONTEXT, "Switching active context to 0x{:x}", table_addr);
+        klog!(Debug, MEMORY_PAGING_CONTEXT, "Switching active context to 0x{:x}", table_addr);
 
         let ni = disable_interruptions();
         // Set active
@@ -571,6 +583,33 @@ impl PagingContext {
             old_table.0._end_active(|_id|{});  // not implemented yet
         }}
     }
+    /* Activate this page table from the scheduler, if it isn't already active on this CPU (see activate).
+        Unlike activate, this doesn't lock the allocator or log anything. The page table that was active before is returned instead of dropped,
+        as ours may be the last reference to it (e.g. if its process has exited), and it mustn't be freed from the scheduler.
+        SAFETY: As activate. Interruptions must be disabled. */
+    pub unsafe fn activate_from_scheduler(&self) -> Option<Self> {
+        let mut active = _ACTIVE_PAGE_TABLE.lock();
+        if active.as_ref().is_some_and(|active|Arc::ptr_eq(&active.0.0, &self.0.0)) { return None; }
+        self.0._begin_active_unlocked();
+        set_active_page_table_quietly(self.1);
+        let old_table = active.replace(Self::clone_ref(self))?;
+        // SAFETY: (see activate)
+        unsafe { old_table.0._end_active(|_id|{}); }
+        Some(old_table)
+    }
+    /// True if this is the page table currently active on this CPU (see activate)
+    pub fn is_active(&self) -> bool {
+        _ACTIVE_PAGE_TABLE.lock().as_ref().is_some_and(|active|Arc::ptr_eq(&active.0.0, &self.0.0))
+    }
+    
+    /* Make this the address space of tasks that aren't part of a process (see kernel_context). Called once, during boot. */
+    pub fn set_kernel_context(&self) {
+        _KERNEL_PAGE_TABLE.call_once(||Self::clone_ref(self));
+    }
+    /// The address space of tasks that aren't part of a process, or None if it hasn't been set yet (see set_kernel_context)
+    pub fn kernel_context() -> Option<&'static Self> {
+        _KERNEL_PAGE_TABLE.get()
+    }
 }
 impl core::ops::Deref for PagingContext {
     type Target = LockedPageAllocator<BaseTLPageAllocator>;
@@ -768,7 +807,7 @@ impl<PFA: PageFrameAllocator, GuardT> LockedPageAllocatorWriteGuard<PFA, GuardT>
         allocation.assert_pt_tag(self);
         klog!(Debug, MEMORY_PAGING_CONTEXT, "Deallocating {}", self._fmt_pa(allocation));
         self.get_page_table().deallocate(&allocation.allocation);
-        if self.options.auto_flush_tlb { self.invalidate_tlb(allocation) };
+        if self.should_flush_tlb() { self.invalidate_tlb(allocation) };
     }
     
     // Managing allocations
@@ -784,7 +823,7 @@ impl<PFA: PageFrameAllocator, GuardT> LockedPageAllocatorWriteGuard<PFA, GuardT>
         unsafe {
             Self::_set_addr_inner(self.get_page_table(), allocation.into(), 0, base_addr, flags);
         }
-        if self.options.auto_flush_tlb { self.invalidate_tlb(allocation) };
+        if self.should_flush_tlb() { self.invalidate_tlb(allocation) };
     }
     ppa_define_foreach!(unsafe: _set_addr_inner, allocator: &mut PFA, allocation: &PPA, ptable: &mut IPT, index: usize, offset: usize, base_addr: usize, flags: PageFlags, {
         ptable.set_huge_addr(index, base_addr+offset, flags);
@@ -799,12 +838,19 @@ impl<PFA: PageFrameAllocator, GuardT> LockedPageAllocatorWriteGuard<PFA, GuardT>
         unsafe {
             Self::_set_missing_inner(self.get_page_table(), allocation.into(), 0, data);
         }
-        if self.options.auto_flush_tlb { self.invalidate_tlb(allocation) };
+        if self.should_flush_tlb() { self.invalidate_tlb(allocation) };
     }
     ppa_define_foreach!(unsafe: _set_missing_inner, allocator: &mut PFA, allocation: &PPA, ptable: &mut IPT, index: usize, offset: usize, data: usize, {
         ptable.set_absent(index, data);
     }, {});
     
+    /* True if writes must be followed by a TLB flush: if the page table was active when we locked it, or has been activated (by the scheduler, which doesn't wait for the lock) since */
+    fn should_flush_tlb(&self) -> bool {
+        if self.options.auto_flush_tlb { return true; }
+        // (the fence orders the write before the check, so either the scheduler sees the write when it loads the table, or we see that it has)
+        core::sync::atomic::fence(Ordering::SeqCst);
+        self.allocator.0.active_count.load(Ordering::SeqCst) > 0
+    }
     /* Invalidate the TLB entries for the given allocation (on the current CPU).
         Note: No check is performed to ensure that the allocation is correct nor that this page table is active, as the only consequence (provided all other code handling Page Tables / TLB is correct) is a performance hit from the unnecessary INVLPG operations + the resulting cache misses.
         Note: Using this method is unnecessary yourself. Usually it is provided by write_when_active or similar. */
@@ -851,6 +897,8 @@ impl<T> core::ops::DerefMut for ForcedUpgradeGuard<'_, T>{
 use crate::sync::kspin::KMutex;
 use crate::multitasking::{disable_interruptions, get_cpu_num};
 static _ACTIVE_PAGE_TABLE: CpuLocal<KMutex<Option<PagingContext>>,false> = CpuLocal::new();
+// the page table used by tasks that aren't part of a process
+static _KERNEL_PAGE_TABLE: spin::Once<PagingContext> = spin::Once::new();
 
 // = ALLOCATIONS =
 // Note: Allocations must be allocated/deallocated manually
@@ -9,6 +9,18 @@ use paging_root::impl_firstfit::MLFFAllocator;
 use crate::logging::klog;
 
 const PF_PINNED: PageTableFlags = PageTableFlags::BIT_9;
+// The PAT bit is bit 7 in page table (level 1) entries, but bit 12 (the lowest bit of the address) in huge page entries, as bit 7 is used for the huge page flag there
+const PF_PAT_4K: PageTableFlags = PageTableFlags::HUGE_PAGE;
+const PAT_BIT_HUGE: u64 = 1<<12;
+
+/// The value written to the IA32_PAT MSR (see cpu::featureflags). Entries 0-3 (selected using PCD/PWT) are left at their power-on defaults,
+///  and entry 4 (PAT=1, PCD=0, PWT=0) is changed from write-back to write-combining (0x01)
+pub const PAT_MSR_VALUE: u64 = 0x0007_0401_0007_0406;
+/// Set once the PAT has been programmed. Until then (or if the CPU doesn't support it), CACHE_WRITE_COMBINING falls back to write-through
+pub static WRITE_COMBINING_AVAILABLE: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+fn write_combining_available() -> bool {
+    WRITE_COMBINING_AVAILABLE.load(core::sync::atomic::Ordering::Relaxed)
+}
 
 #[repr(transparent)]
 pub struct X64PageTable<const LEVEL: usize>(PageTable);
@@ -33,6 +45,11 @@ impl<const LEVEL: usize> X64PageTable<LEVEL> {
             if add.contains(MF::PINNED             ) { previous |= PF_PINNED                     };
             if add.contains(MF::CACHE_DISABLE      ) { previous |= PageTableFlags::NO_CACHE      };
             if add.contains(MF::CACHE_WRITE_THROUGH) { previous |= PageTableFlags::WRITE_THROUGH };
+            if add.contains(MF::CACHE_WRITE_COMBINING) {
+                // (huge pages have their PAT bit set by set_huge_addr instead, and sub-tables don't have one at all)
+                if !write_combining_available() { previous |= PageTableFlags::WRITE_THROUGH }
+                else if LEVEL == 1 { previous |= PF_PAT_4K }
+            };
             if cfg!(feature="page_global_bit") && add.contains(MF::GLOBAL) { previous |=  PageTableFlags::GLOBAL };
         }
         previous
@@ -52,6 +69,7 @@ impl<const LEVEL: usize> X64PageTable<LEVEL> {
                 if  flags.contains(PF_PINNED)                     { mf |= MF::PINNED             }
                 if  flags.contains(PageTableFlags::NO_CACHE)      { mf |= MF::CACHE_DISABLE      }
                 if  flags.contains(PageTableFlags::WRITE_THROUGH) { mf |= MF::CACHE_WRITE_THROUGH}
+                if  LEVEL == 1 && flags.contains(PF_PAT_4K)       { mf |= MF::CACHE_WRITE_COMBINING}
                 mf
             },
         )
@@ -79,9 +97,13 @@ impl<const LEVEL: usize> IPageTableImpl for X64PageTable<LEVEL> {
     fn get_entry(&self, idx: usize) -> Result<(usize, PageFlags),usize> {
         let flags = self.0[idx].flags();
         if flags.contains(PageTableFlags::PRESENT) {
-            let addr: usize = self.0[idx].addr().as_u64().try_into().unwrap();
-            let flags = Self::_deser_flags(flags);
-            Ok((addr, flags))
+            let mut addr: u64 = self.0[idx].addr().as_u64();
+            let mut flags_out = Self::_deser_flags(flags);
+            if LEVEL > 1 && flags.contains(PageTableFlags::HUGE_PAGE) && addr & PAT_BIT_HUGE != 0 {
+                addr &= !PAT_BIT_HUGE;
+                flags_out |= MappingSpecificPageFlags::CACHE_WRITE_COMBINING;
+            }
+            Ok((addr.try_into().unwrap(), flags_out))
         } else {
             let data = unsafe { *((&self.0[idx] as *const PageTableEntry) as *const u64) } >> 1;
             Err(data.try_into().unwrap())
@@ -100,13 +122,15 @@ impl<const LEVEL: usize> IPageTableImpl for X64PageTable<LEVEL> {
         self.0[idx].set_flags(flags);
     }
     
-    fn set_huge_addr(&mut self, idx: usize, physaddr: usize, flags: PageFlags){
+    fn set_huge_addr(&mut self, idx: usize, physaddr: usize, flags_in: PageFlags){
         let flags = Self::_calc_flags::<true>(Self::_default_flags() | match LEVEL { // Set present + huge flag
             1 => PageTableFlags::PRESENT,  // (huge page flag is used for PAT on level 1 page tables)
             _ => PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE,
-        }, &flags);
+        }, &flags_in);
         klog!(Debug, MEMORY_PAGING_MAPPINGS, "Mapping entry {:x}[{}] to {:x} (flags={:?})", self._logging_physaddr(), idx, physaddr, flags);
-        self.0[idx].set_addr(PhysAddr::new(physaddr as u64), flags);  // set addr
+        let mut physaddr = physaddr as u64;
+        if LEVEL > 1 && write_combining_available() && flags.contains(PageTableFlags::HUGE_PAGE) && flags_in.mflags.contains(MappingSpecificPageFlags::CACHE_WRITE_COMBINING) { physaddr |= PAT_BIT_HUGE; }
+        self.0[idx].set_addr(PhysAddr::new(physaddr), flags);  // set addr
     }
     fn set_absent(&mut self, idx: usize, data: usize){
         let data = data.checked_shl(1).expect("Data value is out-of-bounds!") &!1;  // clear the "present" flag (TODO: reserve bit 2 for "is swapped out" / "is guard")
@@ -157,6 +181,66 @@ pub fn ptaddr_virt_to_phys(vaddr: usize) -> usize {
     vaddr-paging_root::global_pages::KERNEL_PTABLE_VADDR // note: this will break if the area where the page table lives is not offset-mapped (or if the address has been cropped to hold all 0s for non-canonical bits)
 }
 
+/* Convert the physical address of a page table back into a virtual address (the inverse of ptaddr_virt_to_phys) */
+pub fn ptaddr_phys_to_virt(paddr: usize) -> usize {
+    paddr+paging_root::global_pages::KERNEL_PTABLE_VADDR
+}
+
+/// One entry visited during a page table walk (see walk_active_page_table)
+#[derive(Debug,Clone,Copy)]
+pub struct PageWalkStep {
+    /// 4 = PML4, 1 = Page Table
+    pub level: usize,
+    pub table_phys_addr: usize,
+    pub index: usize,
+    pub entry: u64,
+}
+impl PageWalkStep {
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags::from_bits_truncate(self.entry)
+    }
+    pub fn is_present(&self) -> bool {
+        self.flags().contains(PageTableFlags::PRESENT)
+    }
+    /// Returns true if this entry maps a page directly, rather than pointing to another table
+    pub fn is_leaf(&self) -> bool {
+        self.level == 1 || self.flags().contains(PageTableFlags::HUGE_PAGE)
+    }
+    pub fn addr(&self) -> usize {
+        let addr = (self.entry & 0x000f_ffff_ffff_f000) as usize;
+        // (huge pages keep their PAT bit in the lowest bit of the address)
+        if self.is_leaf() { addr & !(self.page_size()-1) } else { addr }
+    }
+    /// The size of the memory covered by this entry
+    pub fn page_size(&self) -> usize {
+        1 << (12 + 9*(self.level-1))
+    }
+}
+impl core::fmt::Display for PageWalkStep {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "L{} {:#x}[{:3}] = {:#018x}", self.level, self.table_phys_addr, self.index, self.entry)?;
+        if self.is_present() { write!(f, " -> {:#x} {:?}", self.addr(), self.flags()) }
+        else { write!(f, " (not present)") }
+    }
+}
+/* Walk the active page table on this CPU for the given virtual address, the same way the MMU would.
+    Returns each entry visited, starting from the top level. The last entry is either a leaf (the mapping itself) or not present. */
+pub fn walk_active_page_table(vaddr: usize) -> alloc::vec::Vec<PageWalkStep> {
+    use x86_64::registers::control::Cr3;
+    let mut steps = alloc::vec::Vec::new();
+    let mut table_phys_addr = Cr3::read().0.start_address().as_u64() as usize;
+    for level in (1..=4).rev() {
+        let index = (vaddr >> (12 + 9*(level-1))) & 0x1FF;
+        // Safety: page tables are always stored in the offset-mapped region of KERNEL_PTABLE (see ptaddr_virt_to_phys)
+        let entry = unsafe { core::ptr::read_volatile((ptaddr_phys_to_virt(table_phys_addr) as *const u64).add(index)) };
+        let step = PageWalkStep { level, table_phys_addr, index, entry };
+        steps.push(step);
+        if !step.is_present() || step.is_leaf() { break; }
+        table_phys_addr = step.addr();
+    }
+    steps
+}
+
 /* Ensure a virtual address is canonical */
 #[inline(always)]
 pub const fn canonical_addr(vaddr: usize) -> usize {
@@ -172,6 +256,15 @@ pub unsafe fn set_active_page_table(phys_addr: usize){
     klog!(Debug, MEMORY_PAGING_MAPPINGS, "Switching active page table from 0x{:x} to 0x{:x}. (cr3flags={:?})", oldaddr.start_address(), phys_addr, cr3flags);
     Cr3::write(PhysFrame::from_start_address(PhysAddr::new(phys_addr.try_into().unwrap())).expect("Page Table Address Not Aligned!"), cr3flags)
 }
+/* As set_active_page_table, but without logging (for the scheduler, see PagingContext::activate_from_scheduler) */
+pub unsafe fn set_active_page_table_quietly(phys_addr: usize){
+    use x86_64::addr::PhysAddr;
+    use x86_64::structures::paging::frame::PhysFrame;
+    use x86_64::registers::control::Cr3;
+    
+    let (_, cr3flags) = Cr3::read();
+    Cr3::write(PhysFrame::from_start_address(PhysAddr::new(phys_addr as u64)).expect("Page Table Address Not Aligned!"), cr3flags)
+}
 
 // allocation, voffset - define the vmem addresses to invalidate TLB mappings for
 // include_global - If true, include global pages as well
@@ -15,6 +15,7 @@ impl<T: IPageTableImpl> IPageTable for T {}
 
 crate::arch_specific_module!(pub mod arch);
 pub use arch::{canonical_addr,crop_addr,ptaddr_virt_to_phys,MIN_PAGE_SIZE};
+pub use arch::{walk_active_page_table,PageWalkStep};
 
 mod allocators;
 use allocators::firstfit as impl_firstfit;
@@ -155,7 +155,8 @@ impl VirtualAllocation {
                 ) -> Self {
         let size = allocation.size();
         
-        let apt_initialiser = ABSENT_PAGES_TABLE.create_new_descriptor();
+        // (with 32 bits of slot numbers, this can only fail if there are billions of allocations)
+        let apt_initialiser = ABSENT_PAGES_TABLE.create_new_descriptor().expect("Absent pages table is full!");
         apt_initialiser.slot_t().pt_phys_addr.store(allocation.pt_phys_addr(), core::sync::atomic::Ordering::Relaxed);
         apt_initialiser.slot_t().virt_addr.store(allocation.start().get(), core::sync::atomic::Ordering::Relaxed);
         let apth = apt_initialiser.commit(meta_a, AbsentPagesItemB{});
@@ -619,7 +620,7 @@ impl Drop for UnifiedVirtGuard {
     }
 }
 
-use crate::descriptors::{DescriptorTable, DescriptorHandleA, DescriptorHandleB, DescriptorID};
+use crate::descriptors::{DescriptorTable, DescriptorIdLayout, DescriptorHandleA, DescriptorHandleB, DescriptorID};
 use crate::memory::alloc_util::AnyAllocatedStack;
 
 #[derive(Default)]
@@ -657,10 +658,11 @@ type AbsentPagesTab = DescriptorTable<AbsentPagesItemT,AbsentPagesItemA,AbsentPa
 type AbsentPagesHandleA = DescriptorHandleA<'static,AbsentPagesItemT,AbsentPagesItemA,AbsentPagesItemB>;
 type AbsentPagesHandleB = DescriptorHandleB<'static,AbsentPagesItemT,AbsentPagesItemA,AbsentPagesItemB>;
 lazy_static::lazy_static! {
-    static ref ABSENT_PAGES_TABLE: AbsentPagesTab = DescriptorTable::new();
+    // (IDs are stored in absent page table entries, which only have room for 63 bits - see set_absent)
+    static ref ABSENT_PAGES_TABLE: AbsentPagesTab = DescriptorTable::with_id_layout(DescriptorIdLayout::new(32, 31));
 
     pub static ref ABSENT_PAGES_ID_NULL_GUARD: DescriptorID = {
-        let initializer = ABSENT_PAGES_TABLE.create_new_descriptor();
+        let initializer = ABSENT_PAGES_TABLE.create_new_descriptor().unwrap();
         let handle = initializer.commit(
             AbsentPagesItemA::StaticGuardPage(GuardPageType::NullPointer),
             AbsentPagesItemB{}
@@ -0,0 +1,34 @@
+//! Halting the CPU while there's nothing to run
+
+use core::sync::atomic::{AtomicBool,Ordering};
+use x86_64::instructions::interrupts;
+
+/// Set during init_msr if the CPU supports MONITOR/MWAIT. Otherwise, HLT is used (which only wakes up on interrupts).
+pub static MWAIT_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/* Read the timestamp counter (used for idle time accounting) */
+#[inline(always)]
+pub fn read_timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/* Halt until an interrupt occurs or (if MWAIT is available) wake_flag is written to by another CPU. Returns immediately if wake_flag is already set.
+    Interrupts must be disabled when this is called, so that anything which would wake us up can't happen between checking for work and halting. They are enabled while halted (so that the interrupt which woke us can be handled), and disabled again before returning. */
+pub fn halt_until_woken(wake_flag: &AtomicBool) {
+    debug_assert!(!interrupts::are_enabled());
+    if MWAIT_AVAILABLE.load(Ordering::Relaxed) {
+        unsafe {
+            // Arm the monitor before checking the flag, so that a write in between still wakes us
+            core::arch::asm!("monitor", in("rax") wake_flag as *const AtomicBool, in("ecx") 0, in("edx") 0, options(nostack, preserves_flags));
+            // (SeqCst, as the waker checks whether we've halted after setting it - see scheduler::wake_cpu)
+            if wake_flag.load(Ordering::SeqCst) { return; }
+            // (sti only takes effect after the next instruction, so an interrupt can't slip in before mwait starts)
+            core::arch::asm!("sti", "mwait", "cli", in("eax") 0, in("ecx") 0, options(nostack));
+        }
+    } else {
+        if wake_flag.load(Ordering::SeqCst) { return; }
+        // (likewise, an interrupt can't slip in between sti and hlt)
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+}
@@ -1,3 +1,4 @@
 pub mod fixedcpulocal;
 pub mod enable_interrupts;
-pub mod context_switch;
\ No newline at end of file
+pub mod context_switch;
+pub mod idle;
\ No newline at end of file
@@ -5,60 +5,56 @@ use alloc::boxed::Box;
 use core::default::Default;
 use core::ptr::NonNull;
 use super::get_cpu_num;
-type RwLock<T> = crate::sync::kspin::KRwLock<T>;
+use crate::sync::rcu::{RcuCell,rcu_read_lock};
+
+/// The pointers to each CPU's value, indexed by CPU ID.
+/// (type-erased, so that old copies can be freed by the RCU task whatever T is. Dropping a copy doesn't drop the values it points to.)
+#[derive(Clone)]
+struct Slots(Vec<Option<NonNull<u8>>>);
+// SAFETY: Slots only holds the pointers, and never accesses (or drops) what they point to
+unsafe impl Send for Slots {}
+unsafe impl Sync for Slots {}
 
 /// T - the type of the value
 /// 'a - the lifetime of the value
 /// SHARED - If true, other CPUs may use get_for to acquire a reference to the value for another CPU
 /// (items must still be Sync as multiple threads may run on the same CPU)
-pub struct CpuLocal<T: Default + ?Sized, const SHARED: bool>(RwLock<Vec<Option<NonNull<T>>>>);
+/// The pointer table is read with RCU, so getting a value takes no locks. It's only copied and replaced the first time each CPU gets its value (and the old copies are freed along with the RCU task's next batch).
+pub struct CpuLocal<T: Default + ?Sized, const SHARED: bool>(RcuCell<Slots>, core::marker::PhantomData<T>);
 impl<T: Default + ?Sized,const SHARED: bool> CpuLocal<T,SHARED> {
     pub const fn new() -> Self {
-        Self(RwLock::new(Vec::new()))
+        Self(RcuCell::empty(), core::marker::PhantomData)
     }
     
-    #[inline]
-    fn _initialise_empty(&self, up_to_id_inclusive: usize) {
-        let mut pointers = self.0.write();
-        while pointers.len() <= up_to_id_inclusive {
-            // Push a null pointer to our vec
-            pointers.push(None);
-        }
+    /* Create the value for the given CPU (unless someone else got there first), and return a pointer to it */
+    #[cold]
+    fn _create(&self, id: usize) -> NonNull<u8> {
+        let mut created = None;
+        // (the old table is retired quietly, as call_rcu itself uses CpuLocals)
+        self.0.update_quietly(|slots|{
+            let mut slots = slots.cloned().unwrap_or(Slots(Vec::new()));
+            if slots.0.len() <= id { slots.0.resize(id+1, None); }
+            if slots.0[id].is_none() {
+                // Create a new item in a box, and store the raw pointer
+                slots.0[id] = NonNull::new(Box::into_raw(Box::new(T::default())).cast::<u8>());
+            }
+            created = slots.0[id];
+            slots
+        });
+        created.unwrap()
     }
     
     fn _get_for_inner(&self, id: usize) -> &T {
-        let rg = self.0.read();
-        let item = if rg.len() <= id {
-            // Add null pointers if needed
-            drop(rg);
-            self._initialise_empty(id);
-            self.0.read()[id]
-        } else {
-            let item = rg[id];
-            drop(rg);
-            item
+        let item = {
+            let guard = rcu_read_lock();
+            self.0.read(&guard).and_then(|slots|slots.0.get(id).copied().flatten())
         };
-
+        // CpuLocals have generally been used in statics, where their values live for the remainder of the program
+        // Therefore, by allocating on the heap and storing a shared reference, we only need to stay in the read-side critical section long enough to obtain a reference
+        // Rather than for the duration we spend referencing the value
         let item: *const T = match item {
-            Some(ptr) => ptr.as_ptr(),
-            None => {
-                let mut wg = self.0.write();
-                // Create a new item in a box
-                let item_box = Box::new(T::default());
-                // Convert to raw pointer
-                let item_ref = Box::into_raw(item_box);
-                // Store in vec
-                wg[id] = NonNull::new(item_ref);
-                // Release write lock
-                drop(wg);
-                // And return
-                item_ref
-
-                // CpuLocals have generally been used in statics, where their values live for the remainder of the program
-                // Therefore, by allocating on the heap and storing a shared reference, we only need to keep a read guard long enough to obtain a reference
-                // Rather than having to keep it for the duration we spend referencing the value
-                // (thus reducing contention)
-            },
+            Some(ptr) => ptr.cast::<T>().as_ptr(),
+            None => self._create(id).cast::<T>().as_ptr(),
         };
 
         // SAFETY: The pointer is guaranteed to live as long as we do
@@ -71,6 +67,10 @@ impl<T: Default + ?Sized,const SHARED: bool> CpuLocal<T,SHARED> {
     pub fn get_current(x: &Self) -> &T  {
         x._get_for_inner(get_cpu_num())
     }
+    /// One more than the highest CPU ID that has been given a slot so far (by get_current or get_for)
+    pub fn slot_count(x: &Self) -> usize {
+        x.0.read(&rcu_read_lock()).map_or(0, |slots|slots.0.len())
+    }
 }
 impl<T: Default + ?Sized> CpuLocal<T,true> {
     #[inline(always)]
@@ -88,8 +88,8 @@ impl<T: Default + ?Sized,const SHARED: bool> core::ops::Deref for CpuLocal<T,SHA
 impl<T: Default + ?Sized,const SHARED: bool> Drop for CpuLocal<T,SHARED> {
     fn drop(&mut self) {
         // Drop all contained values
-        let inner_mut = self.0.get_mut();
-        for ptr in inner_mut.iter_mut() {
+        let Some(inner_mut) = self.0.get_mut() else { return };
+        for ptr in inner_mut.0.iter_mut() {
             match ptr.take() {
                 Some(ptr) => unsafe {
                     // Drop the pointed-to value
@@ -98,7 +98,7 @@ impl<T: Default + ?Sized,const SHARED: bool> Drop for CpuLocal<T,SHARED> {
                     //         As this is running in a Drop impl, and our getter methods ensure that
                     //          their references are bound by our lifetime, we can be sure that we are the only
                     //          reference to the contained value, so it may safely be dropped.
-                    let contained = Box::from_raw(ptr.as_ptr());
+                    let contained = Box::from_raw(ptr.cast::<T>().as_ptr());
                     drop(contained)  // drop the box, de-allocating the contained value
                 },
                 None => {},  // already empty
@@ -2,17 +2,22 @@ use super::scheduler;
 use super::get_cpu_num;
 
 // A snapshot of the execution context. Mostly useful for annotating log messages and the like.
+#[derive(Debug,Clone,Copy)]
 pub struct ExecutionContext {
     pub cpu_id: usize,
     pub task_id: Option<usize>,
+    /// The name of the task (see TaskControl::name)
+    pub task_name: Option<&'static str>,
     pub scheduler_clock_ticks: usize,
 }
 impl ExecutionContext {
     #[inline]
     pub fn current() -> Self {
+        let task = scheduler::get_executing_task_info();
         Self {
             cpu_id: get_cpu_num(),
-            task_id: scheduler::get_executing_task_id(),
+            task_id: task.map(|(id,_)|id),
+            task_name: task.map(|(_,name)|name),
             scheduler_clock_ticks: scheduler::get_scheduler_ticks(),
         }
     }
@@ -25,6 +30,7 @@ impl fmt::Display for ExecutionContext {
         
         if let Some(task) = self.task_id {
             write!(f, " TASK {}", task)?;
+            if let Some(name) = self.task_name { write!(f, " ({})", name)?; }
         } else {
             write!(f, " SCHED")?;
         }
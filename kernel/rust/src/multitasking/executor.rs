@@ -0,0 +1,212 @@
+//! An executor for kernel futures, so that one task can juggle many operations at once (waiting on promises, queues, waiting lists, timers, etc.) without each one needing a task (and stack) of its own.
+//! Futures are spawned onto an Executor, and polled by whichever task(s) call Executor::run. Their wakers may be used from anywhere, including interrupt handlers.
+//! Things to await: WaitingList::wait_until_try_async, Promise::get_async, WQueue::get_async, sleep_async, and the lock_async/read_async/write_async extensions on locks.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc,Weak};
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use core::task::{Context,Poll,Waker};
+use crate::sync::kspin::KMutex;
+use crate::sync::{YMutex,WaitingList,Promise};
+use super::scheduler::{spin_yield,preempt_point};
+use super::util::def_task_fn;
+
+/// A future spawned onto an executor. Its waker is the AsyncTask itself.
+struct AsyncTask {
+    /// None once it has finished
+    future: YMutex<Option<Pin<Box<dyn Future<Output=()>+Send>>>>,
+    executor: Weak<ExecutorInner>,
+    /// Set while it's in the executor's ready queue (so that waking it several times only queues it once)
+    queued: AtomicBool,
+}
+impl Wake for AsyncTask {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) { return; }
+        // (if the executor has been dropped, there's nobody left to poll it)
+        if let Some(executor) = self.executor.upgrade() { executor.push(Arc::clone(self)); }
+    }
+}
+
+struct ExecutorInner {
+    /// Futures that have been woken and are waiting to be polled.
+    /// (this is only ever locked briefly, with interruptions disabled, so futures can be woken from interrupt handlers)
+    ready: KMutex<VecDeque<Arc<AsyncTask>>>,
+    /// Notified whenever a future is queued, or the last one finishes
+    waiters: WaitingList,
+    /// The number of futures that haven't finished yet
+    pending: AtomicUsize,
+}
+impl ExecutorInner {
+    fn push(&self, task: Arc<AsyncTask>) {
+        self.ready.lock().push_back(task);
+        self.waiters.notify_one();
+    }
+}
+
+/// A queue of futures, run by one or more kernel tasks (see run).
+/// Cloning an Executor gives another handle to the same queue.
+/// Keep a handle until every future has finished (e.g. by running it): futures left over when the last one is dropped are only dropped when they're next woken, which may be somewhere they can't clean up after themselves (e.g. an interrupt handler).
+#[derive(Clone)]
+pub struct Executor(Arc<ExecutorInner>);
+impl Executor {
+    pub fn new() -> Self {
+        Self(Arc::new(ExecutorInner { ready: KMutex::new(VecDeque::new()), waiters: WaitingList::new(), pending: AtomicUsize::new(0) }))
+    }
+
+    /* Spawn a future onto the executor, returning a promise for its result. It'll be polled by whichever task is running the executor. */
+    pub fn spawn<F>(&self, future: F) -> Promise<F::Output> where F: Future+Send+'static, F::Output: Send+Sync+'static {
+        let (fulfiller, promise) = Promise::new();
+        let future = async move { let _ = fulfiller.complete(future.await); };
+        let task = Arc::new(AsyncTask { future: YMutex::new(Some(Box::pin(future))), executor: Arc::downgrade(&self.0), queued: AtomicBool::new(true) });
+        self.0.pending.fetch_add(1, Ordering::AcqRel);
+        self.0.push(task);
+        promise
+    }
+    /// The number of futures that haven't finished yet
+    pub fn pending(&self) -> usize {
+        self.0.pending.load(Ordering::Acquire)
+    }
+
+    /* Poll futures as they're woken, until every future spawned on the executor has finished (sleeping while none of them are ready).
+        Several tasks may run the same executor at once, in which case each future is only polled by one of them at a time. */
+    pub fn run(&self) {
+        loop {
+            let next = self.0.waiters.wait_until_try(||{
+                if let Some(task) = self.0.ready.lock().pop_front() { return Some(Some(task)); }
+                if self.pending() == 0 { return Some(None); }
+                None
+            });
+            let Some(task) = next else { return };
+            self.poll_task(task);
+        }
+    }
+    fn poll_task(&self, task: Arc<AsyncTask>) {
+        // (cleared first, so that it can be woken again while it's being polled)
+        task.queued.store(false, Ordering::Release);
+        let waker = Waker::from(Arc::clone(&task));
+        let mut future = task.future.lock();
+        let Some(inner) = future.as_mut() else { return };  // (woken after it had already finished)
+        match inner.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(()) => {
+                *future = None;
+                drop(future);
+                // (the other tasks running the executor need to know when the last one finishes, so that they stop too)
+                if self.0.pending.fetch_sub(1, Ordering::AcqRel) == 1 { self.0.waiters.notify_all(); }
+                preempt_point();
+            },
+            Poll::Pending => {
+                drop(future);
+                // A future that woke itself is waiting for something that can't notify it (e.g. a spinlock), so give other tasks a chance to run before we try it again
+                if task.queued.load(Ordering::Acquire) { spin_yield(); }
+                else { preempt_point(); }
+            },
+        }
+    }
+
+    /* Spawn a kernel task which runs the executor (see run), returning its handle */
+    pub fn spawn_runner(&self) -> super::TaskHandle<()> {
+        executor_runner::spawn(self.clone())
+    }
+}
+impl core::default::Default for Executor {
+    fn default() -> Self { Self::new() }
+}
+
+def_task_fn! {
+    task fn executor_runner(executor: Executor) {
+        executor.run();
+    }
+}
+
+/// Wakes the task blocked in block_on
+struct BlockOnWaker {
+    woken: AtomicBool,
+    waiters: WaitingList,
+}
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
+/* Run a single future on the current task, blocking until it finishes, and return its result.
+    Unlike Executor::spawn, the future doesn't need to be Send or 'static. */
+pub fn block_on<F:Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let waiter = Arc::new(BlockOnWaker { woken: AtomicBool::new(false), waiters: WaitingList::new() });
+    let waker = Waker::from(Arc::clone(&waiter));
+    loop {
+        waiter.woken.store(false, Ordering::Release);
+        if let Poll::Ready(value) = future.as_mut().poll(&mut Context::from_waker(&waker)) { return value; }
+        // (see Executor::poll_task)
+        if waiter.woken.load(Ordering::Acquire) { spin_yield(); }
+        waiter.waiters.wait_until(||waiter.woken.load(Ordering::Acquire));
+    }
+}
+
+pub(crate) mod executor_test {
+    use super::*;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use core::time::Duration;
+    use crate::sync::{WQueue,MutexAsyncExt};
+    use crate::sync::promise::PromiseFulfiller;
+    use crate::multitasking::scheduler::{sleep,sleep_async,get_scheduler_ticks,duration_to_ticks};
+    
+    static QUEUE: WQueue<usize> = WQueue::new();
+    static LOCK: YMutex<usize> = YMutex::new(0);
+    const SLEEP: Duration = Duration::from_millis(50);
+    
+    // Holds the lock for a while, then fills the queue and the promise from another task
+    def_task_fn! {
+        task fn producer(fulfiller: PromiseFulfiller<usize>) {
+            let guard = LOCK.lock();
+            sleep(Duration::from_millis(20));
+            drop(guard);
+            for i in 0..4 { QUEUE.push(i); }
+            let _ = fulfiller.complete(42);
+        }
+    }
+    
+    pub fn run() -> crate::debugshell::CommandResult {
+        *LOCK.lock() = 0;
+        let executor = Executor::new();
+        let (fulfiller, promise) = Promise::new();
+        let from_queue: Vec<_> = (0..4).map(|_|executor.spawn(async { QUEUE.get_async().await })).collect();
+        let from_promise = { let promise = promise.clone(); executor.spawn(async move { *promise.get_async().await.unwrap() }) };
+        let slept = executor.spawn(async { let start = get_scheduler_ticks(); sleep_async(SLEEP).await; get_scheduler_ticks() - start });
+        let locked = executor.spawn(async { let mut value = LOCK.lock_async().await; *value += 1; *value });
+        let producer = producer::spawn(fulfiller);
+        
+        executor.run();
+        producer.join().map_err(|e|format!("Producer failed: {:?}", e))?;
+        
+        let mut got: Vec<usize> = from_queue.iter().map(|p|*p.try_get().unwrap()).collect();
+        got.sort_unstable();
+        if got != [0,1,2,3] { return Err(format!("Got {:?} from the queue", got)); }
+        if *from_promise.try_get().unwrap() != 42 { return Err(format!("Got {} from the promise", from_promise.try_get().unwrap())); }
+        let ticks = *slept.try_get().unwrap();
+        if ticks < duration_to_ticks(SLEEP) { return Err(format!("Slept for only {} ticks", ticks)); }
+        if *locked.try_get().unwrap() != 1 { return Err(format!("Lock held {} (expected 1)", locked.try_get().unwrap())); }
+        // And block_on, for futures that aren't Send/'static
+        let from_block_on = block_on(async { *promise.get_async().await.unwrap() });
+        if from_block_on != 42 { return Err(format!("Got {} from block_on", from_block_on)); }
+        // A promise whose fulfiller is dropped must wake whoever is waiting on it with an error (rather than leaving them waiting forever)
+        let (fulfiller, promise) = Promise::<usize>::new();
+        let abandoned = executor.spawn(async move { promise.get_async().await.is_err() });
+        executor.spawn(async move { sleep_async(Duration::from_millis(10)).await; drop(fulfiller); });
+        executor.run();
+        if abandoned.try_get() != Ok(&true) { return Err(format!("Waiting on an abandoned promise gave {:?}", abandoned.try_get())); }
+        Ok(())
+    }
+}
@@ -68,6 +68,10 @@ def_cl_struct! {
         use crate::multitasking::interruptions::CURRENT_NOINTERRUPTIONS_STATE as CURRENT_NOINTERRUPTIONS_STATE,
         use crate::multitasking::interruptions::SCHEDULER_YIELD_DISABLED as SCHEDULER_YIELD_DISABLED,
         use crate::multitasking::scheduler::_IS_EXECUTING_TASK as _IS_EXECUTING_TASK,
+        use crate::multitasking::scheduler::_EXECUTING_TASK_ID as _EXECUTING_TASK_ID,
+        use crate::sync::rcu::RCU_READ_NESTING as RCU_READ_NESTING,
+        use crate::sync::rcu::RCU_IDLE as RCU_IDLE,
+        use crate::cpu::interrupts::DEFERRED_AFTER_IRQ as DEFERRED_AFTER_IRQ,
     }
 }
 // pub struct FixedCpuLocals {
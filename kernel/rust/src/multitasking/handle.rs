@@ -0,0 +1,343 @@
+//! Task handles: a way to keep track of a task after it has been spawned (query its status, join it, cancel or kill it),
+//! and the global task registry, which holds every live task regardless of which CPU (or waiting list) it's on.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8,AtomicUsize,AtomicU64,AtomicBool,Ordering};
+use core::time::Duration;
+use crate::sync::kspin::KMutex;
+use crate::sync::{WaitingList,Promise};
+use super::interruptions::disable_interruptions;
+use super::arch::idle::read_timestamp;
+
+/// What a task is currently doing
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[repr(u8)]
+pub enum TaskStatus {
+    /// In a run queue, waiting for its turn
+    Runnable = 0,
+    /// Currently running on a CPU
+    Running = 1,
+    /// Sleeping for a set amount of time (see scheduler::sleep)
+    Sleeping = 2,
+    /// Blocked on a waiting list (e.g. a lock, queue, or promise)
+    Waiting = 3,
+    /// Finished (or killed). It will never run again.
+    Terminated = 4,
+}
+impl TaskStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Runnable, 1 => Self::Running, 2 => Self::Sleeping, 3 => Self::Waiting,
+            _ => Self::Terminated,
+        }
+    }
+}
+
+/// The part of a task that outlives it, shared between the Task itself, the registry, and any TaskHandles
+pub struct TaskControl {
+    task_id: usize,
+    /// A name for humans (e.g. in logs). Tasks spawned with def_task_fn! are named after their function.
+    name: &'static str,
+    /// The scheduler tick (on the CPU that created it) when the task was created
+    created_at: usize,
+    /// The task that created this one, if any (None for tasks created by the scheduler itself)
+    parent: Option<usize>,
+    status: AtomicU8,
+    /// The CPU the task was last run on (usize::MAX if it hasn't run yet)
+    last_cpu: AtomicUsize,
+    cancel_requested: AtomicBool,
+    kill_requested: AtomicBool,
+    /// Set if the task was terminated by kill() rather than finishing by itself
+    killed: AtomicBool,
+    /// Tasks waiting for this one to terminate
+    exited: WaitingList,
+    
+    /// Timestamp counter cycles spent running, not including the current run (see set_running_on)
+    run_cycles: AtomicU64,
+    /// The timestamp when the task was last resumed
+    resumed_at: AtomicU64,
+    /// The number of times the task has been switched out
+    context_switches: AtomicUsize,
+    /// The locks the task is holding
+    #[cfg(feature="dbg_lockdep")]
+    held_locks: crate::sync::lockdep::TaskLocks,
+}
+impl TaskControl {
+    pub(super) fn new(task_id: usize, name: &'static str) -> Self {
+        Self {
+            task_id, name,
+            created_at: super::scheduler::get_scheduler_ticks(),
+            parent: super::scheduler::get_executing_task_id(),
+            status: AtomicU8::new(TaskStatus::Runnable as u8),
+            last_cpu: AtomicUsize::new(usize::MAX),
+            cancel_requested: AtomicBool::new(false),
+            kill_requested: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
+            exited: WaitingList::new(),
+            run_cycles: AtomicU64::new(0),
+            resumed_at: AtomicU64::new(0),
+            context_switches: AtomicUsize::new(0),
+            #[cfg(feature="dbg_lockdep")]
+            held_locks: crate::sync::lockdep::TaskLocks::new(),
+        }
+    }
+    #[cfg(feature="dbg_lockdep")]
+    pub(super) fn held_locks(&self) -> &crate::sync::lockdep::TaskLocks { &self.held_locks }
+
+    pub fn task_id(&self) -> usize { self.task_id }
+    pub fn name(&self) -> &'static str { self.name }
+    pub fn created_at(&self) -> usize { self.created_at }
+    pub fn parent(&self) -> Option<usize> { self.parent }
+    /// Timestamp counter cycles the task has spent running so far
+    pub fn run_cycles(&self) -> u64 {
+        let mut cycles = self.run_cycles.load(Ordering::Relaxed);
+        if self.status() == TaskStatus::Running { cycles += read_timestamp().saturating_sub(self.resumed_at.load(Ordering::Relaxed)); }
+        cycles
+    }
+    /// The number of times the task has given up the CPU (by yielding, sleeping, waiting, etc.)
+    pub fn context_switches(&self) -> usize { self.context_switches.load(Ordering::Relaxed) }
+    pub fn status(&self) -> TaskStatus {
+        TaskStatus::from_u8(self.status.load(Ordering::Acquire))
+    }
+    /// The CPU the task was last run on, or None if it hasn't been run yet
+    pub fn last_cpu(&self) -> Option<usize> {
+        match self.last_cpu.load(Ordering::Relaxed) { usize::MAX => None, cpu => Some(cpu) }
+    }
+    pub fn is_cancel_requested(&self) -> bool { self.cancel_requested.load(Ordering::Acquire) }
+    pub fn is_kill_requested(&self) -> bool { self.kill_requested.load(Ordering::Acquire) }
+    pub fn was_killed(&self) -> bool { self.killed.load(Ordering::Acquire) }
+
+    /// Called by the scheduler whenever the task changes state
+    pub(super) fn set_status(&self, status: TaskStatus) {
+        self.status.store(status as u8, Ordering::Release);
+    }
+    /// Called by the scheduler when the task is resumed
+    pub(super) fn set_running_on(&self, cpu: usize) {
+        self.last_cpu.store(cpu, Ordering::Relaxed);
+        self.resumed_at.store(read_timestamp(), Ordering::Relaxed);
+        self.set_status(TaskStatus::Running);
+    }
+    /// Called by the scheduler when the task is switched out (before its new status is set)
+    pub(super) fn record_switch_out(&self) {
+        let ran_for = read_timestamp().saturating_sub(self.resumed_at.load(Ordering::Relaxed));
+        self.run_cycles.fetch_add(ran_for, Ordering::Relaxed);
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+    }
+    /* Called by the scheduler once the task has been discarded: removes it from the registry and wakes anyone joining it.
+        This may be called from the scheduler, so it must be called with interruptions disabled (so that the waiting list doesn't try to yield). */
+    pub(super) fn mark_terminated(&self, killed: bool) {
+        debug_assert!(super::interruptions::is_sched_yield_disabled());
+        self.killed.store(killed, Ordering::Release);
+        self.set_status(TaskStatus::Terminated);
+        TASK_REGISTRY.lock().remove(&self.task_id);
+        self.exited.notify_all();
+    }
+
+    /// Ask the task to stop. It's up to the task to check for this (see is_cancel_requested) and finish early.
+    pub fn request_cancel(&self) {
+        self.cancel_requested.store(true, Ordering::Release);
+    }
+    /* Forcibly terminate the task. As with terminate_current_task, its stack is not unwound, so anything it owns on the stack is leaked (including locks!) - prefer request_cancel where possible.
+        The task is killed the next time it passes through the scheduler (i.e. immediately if it's in a run queue, or once it wakes up if it's sleeping or waiting). Killing the current task terminates it straight away. */
+    pub fn kill(&self) {
+        if self.status() == TaskStatus::Terminated { return; }
+        self.kill_requested.store(true, Ordering::Release);
+        if super::scheduler::get_executing_task_id() == Some(self.task_id) { super::scheduler::terminate_current_task(); }
+    }
+
+    /// Block until the task has terminated
+    pub fn wait_for_exit(&self) {
+        self.exited.wait_until(||self.status() == TaskStatus::Terminated);
+    }
+    /// Block until the task has terminated or the timeout expires. Returns false if it timed out.
+    pub fn wait_for_exit_timeout(&self, timeout: Duration) -> bool {
+        self.exited.wait_until_timeout(||self.status() == TaskStatus::Terminated, timeout)
+    }
+}
+
+/// Why join() was unable to return the task's result
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum JoinError {
+    /// The task was killed before it could finish
+    Killed,
+    /// The task terminated without producing a result
+    NoResult,
+    /// The timeout expired before the task terminated (see join_timeout)
+    TimedOut,
+}
+
+/// A handle to a spawned task, which can be used to query its status, wait for its result, or stop it.
+/// Dropping the handle does not affect the task.
+pub struct TaskHandle<T> {
+    control: Arc<TaskControl>,
+    result: Promise<T>,
+}
+impl<T> TaskHandle<T> {
+    pub(super) fn new(control: Arc<TaskControl>, result: Promise<T>) -> Self {
+        Self { control, result }
+    }
+
+    pub fn task_id(&self) -> usize { self.control.task_id() }
+    pub fn status(&self) -> TaskStatus { self.control.status() }
+    pub fn is_finished(&self) -> bool { self.status() == TaskStatus::Terminated }
+    /// The promise for the task's return value
+    pub fn promise(&self) -> &Promise<T> { &self.result }
+    pub fn control(&self) -> &Arc<TaskControl> { &self.control }
+
+    /// See TaskControl::request_cancel
+    pub fn request_cancel(&self) { self.control.request_cancel() }
+    /// See TaskControl::kill
+    pub fn kill(&self) { self.control.kill() }
+
+    /// Block until the task has terminated, and return its result
+    pub fn join(&self) -> Result<&T,JoinError> {
+        self.control.wait_for_exit();
+        self.finished_result()
+    }
+    /// Same as join(), but gives up after the given timeout
+    pub fn join_timeout(&self, timeout: Duration) -> Result<&T,JoinError> {
+        if !self.control.wait_for_exit_timeout(timeout) { return Err(JoinError::TimedOut); }
+        self.finished_result()
+    }
+    fn finished_result(&self) -> Result<&T,JoinError> {
+        // (a task that was killed after producing its result still counts as finished)
+        self.result.try_get().map_err(|_|if self.control.was_killed() { JoinError::Killed } else { JoinError::NoResult })
+    }
+}
+impl<T> core::clone::Clone for TaskHandle<T> {
+    fn clone(&self) -> Self {
+        Self { control: Arc::clone(&self.control), result: self.result.clone() }
+    }
+}
+
+// == REGISTRY ==
+/// Every task that hasn't terminated yet, by ID
+static TASK_REGISTRY: KMutex<BTreeMap<usize,Arc<TaskControl>>> = KMutex::new(BTreeMap::new());
+
+pub(super) fn register_task(control: Arc<TaskControl>) {
+    TASK_REGISTRY.lock().insert(control.task_id(), control);
+}
+/* Look up a live task by its ID */
+pub fn lookup_task(task_id: usize) -> Option<Arc<TaskControl>> {
+    TASK_REGISTRY.lock().get(&task_id).cloned()
+}
+/* Every live task, on any CPU, in order of ID. Unlike snapshot_tasks, this includes tasks that are blocked on waiting lists. */
+pub fn live_tasks() -> Vec<Arc<TaskControl>> {
+    TASK_REGISTRY.lock().values().cloned().collect()
+}
+/* Same as live_tasks, but returns None instead of waiting if the registry is currently locked (e.g. by the code that was interrupted). */
+pub fn try_live_tasks() -> Option<Vec<Arc<TaskControl>>> {
+    Some(TASK_REGISTRY.try_lock()?.values().cloned().collect())
+}
+
+/* Returns true if the current task has been asked to stop (see TaskControl::request_cancel). Long-running tasks should check this regularly. */
+pub fn is_cancel_requested() -> bool {
+    super::scheduler::with_current_task_control(|control|control.is_cancel_requested()).unwrap_or(false)
+}
+
+/* Mark a discarded task as terminated, once the scheduler's locks have been released (see TaskControl::mark_terminated). It counts as killed if a kill was requested. */
+pub(super) fn finish_terminated(control: &TaskControl) {
+    let _ni = disable_interruptions();
+    control.mark_terminated(control.is_kill_requested());
+}
+
+pub(crate) mod handle_test {
+    use super::*;
+    use alloc::format;
+    use crate::multitasking::scheduler::{sleep,spin_yield,terminate_current_task,get_executing_task_id};
+    use crate::multitasking::util::def_task_fn;
+
+    def_task_fn! {
+        task fn adder(a: usize, b: usize) -> usize {
+            sleep(Duration::from_millis(10));
+            a + b
+        }
+    }
+    def_task_fn! {
+        task fn canceller() -> usize {
+            let mut naps = 0;
+            while !is_cancel_requested() { sleep(Duration::from_millis(5)); naps += 1; }
+            naps
+        }
+    }
+    def_task_fn! {
+        task fn spinner() {
+            loop { spin_yield(); }
+        }
+    }
+    def_task_fn! {
+        task fn long_sleeper() -> usize {
+            sleep(Duration::from_millis(100));
+            1
+        }
+    }
+    def_task_fn! {
+        task fn quitter() -> usize {
+            // (always true, but keeps the return below from being unreachable)
+            if get_executing_task_id().is_some() { terminate_current_task(); }
+            0
+        }
+    }
+
+    fn is_registered(task_id: usize) -> bool {
+        lookup_task(task_id).is_some() && live_tasks().iter().any(|t|t.task_id() == task_id)
+    }
+
+    /* Check that joining returns the task's result (to every clone of the handle), and that the task is only in the registry until it terminates */
+    fn check_join() -> crate::debugshell::CommandResult {
+        let task = adder::spawn(40, 2);
+        let clone = task.clone();
+        if !is_registered(task.task_id()) { return Err(format!("A running task wasn't in the registry")); }
+        if task.control().parent() != get_executing_task_id() { return Err(format!("Task's parent was {:?}, expected {:?}", task.control().parent(), get_executing_task_id())); }
+        match task.join() { Ok(&42) => {}, other => return Err(format!("join returned {:?}, expected Ok(42)", other)) }
+        match clone.join() { Ok(&42) => {}, other => return Err(format!("Joining a clone of the handle returned {:?}, expected Ok(42)", other)) }
+        if !task.is_finished() || task.control().was_killed() { return Err(format!("Joined task has status {:?} (killed: {})", task.status(), task.control().was_killed())); }
+        if is_registered(task.task_id()) { return Err(format!("A terminated task was still in the registry")); }
+        Ok(())
+    }
+    /* Check that a task that's asked to stop does so (and join_timeout gives up until it has) */
+    fn check_cancel() -> crate::debugshell::CommandResult {
+        let task = canceller::spawn();
+        match task.join_timeout(Duration::from_millis(20)) { Err(JoinError::TimedOut) => {}, other => return Err(format!("join_timeout returned {:?} before the task was cancelled, expected Err(TimedOut)", other)) }
+        task.request_cancel();
+        if !task.control().is_cancel_requested() { return Err(format!("request_cancel wasn't recorded")); }
+        let naps = task.join().map_err(|e|format!("Cancelled task failed: {:?}", e))?;
+        if *naps == 0 { return Err(format!("Cancelled task stopped before it was asked to")); }
+        Ok(())
+    }
+    /* Check that killing a task stops it (straight away if it's runnable, or once it wakes up if it's sleeping), and that join reports it */
+    fn check_kill() -> crate::debugshell::CommandResult {
+        let task = spinner::spawn();
+        sleep(Duration::from_millis(5));
+        task.kill();
+        match task.join() { Err(JoinError::Killed) => {}, other => return Err(format!("Joining a killed spinning task returned {:?}, expected Err(Killed)", other)) }
+        if !task.control().was_killed() || is_registered(task.task_id()) { return Err(format!("Killed task wasn't recorded as killed and removed from the registry")); }
+
+        let task = long_sleeper::spawn();
+        sleep(Duration::from_millis(10));
+        task.kill();
+        if task.is_finished() { return Err(format!("A sleeping task was terminated before it woke up")); }
+        match task.join() { Err(JoinError::Killed) => {}, other => return Err(format!("Joining a killed sleeping task returned {:?}, expected Err(Killed)", other)) }
+
+        // Killing a task that has already finished does nothing
+        let task = adder::spawn(1, 2);
+        let _ = task.join();
+        task.kill();
+        match task.join() { Ok(&3) => {}, other => return Err(format!("Killing a finished task changed its result to {:?}", other)) }
+        if task.control().was_killed() { return Err(format!("A task killed after it finished was recorded as killed")); }
+        Ok(())
+    }
+    /* Check that a task that terminates without producing a result is reported as such */
+    fn check_no_result() -> crate::debugshell::CommandResult {
+        match quitter::spawn().join() { Err(JoinError::NoResult) => Ok(()), other => Err(format!("Joining a task that terminated itself returned {:?}, expected Err(NoResult)", other)) }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        check_join()?;
+        check_cancel()?;
+        check_kill()?;
+        check_no_result()
+    }
+}
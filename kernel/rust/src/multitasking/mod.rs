@@ -10,10 +10,21 @@ pub use interruptions::disable_interruptions;
 
 pub mod scheduler;
 pub use scheduler::{is_executing_task,SchedulerCommand};
-pub use scheduler::{yield_to_scheduler,terminate_current_task,spin_yield};
+pub use scheduler::{yield_to_scheduler,terminate_current_task,spin_yield,sleep,sleep_async};
+pub use scheduler::{snapshot_tasks,TaskSnapshot,TaskState};
+pub use scheduler::{idle_stats,IdleStats};
+pub mod runqueue;
+pub mod timerwheel;
+pub use runqueue::TaskPriority;
 pub mod task;
-pub use task::{Task,TaskType};
+pub use task::{Task,TaskType,CpuSet};
+pub mod handle;
+pub use handle::{TaskHandle,TaskStatus,JoinError,is_cancel_requested};
+pub mod process;
+pub use process::{Process,ProcessRef,ProcessID,ExitStatus,exit_current_process};
 pub mod util;
+pub mod executor;
+pub use executor::{Executor,block_on};
 
 pub mod econtext;
 pub use econtext::ExecutionContext;
\ No newline at end of file
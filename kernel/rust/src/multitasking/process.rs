@@ -0,0 +1,322 @@
+//! Processes: a group of tasks sharing an address space and a set of handles.
+//! Processes live in the global process table (a DescriptorTable). Holding a Process (a B-handle) keeps it alive, and every task in the process holds one.
+//! Once the last Process handle is dropped (usually because its last task has terminated), its address space and handles are freed,
+//!     but it remains in the table as a "zombie" so that its exit status can be read through a ProcessRef (an A-handle), until every ProcessRef has been dropped too (it's been "reaped").
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicBool,Ordering};
+use crate::descriptors::{DescriptorTable,DescriptorHandleA,DescriptorHandleB,DescriptorID,DescriptorTableFull};
+use crate::logging::klog;
+use crate::memory::paging::PagingContext;
+use crate::sync::{YMutex,WaitingList};
+use crate::sync::promise::POnceLock;
+use super::handle::{TaskControl,TaskHandle,TaskStatus};
+use super::util::TaskEntryPoint;
+
+pub type ProcessID = DescriptorID;
+
+/// How a process ended
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ExitStatus {
+    /// It called exit() with the given code (or its last task terminated, which counts as exiting with 0)
+    Exited(i32),
+    /// It was killed
+    Killed,
+}
+
+/// Shared between the A and B sections, so that the B section can record how the process ended when it's dropped
+struct ExitState {
+    status: POnceLock<ExitStatus>,
+    /// Set once the B section has been dropped
+    dead: AtomicBool,
+    waiters: WaitingList,
+}
+
+#[derive(Default)]
+struct ProcessT {}
+/// Everything that can be inspected after the process has died
+struct ProcessA {
+    name: String,
+    parent: Option<ProcessID>,
+    exit: Arc<ExitState>,
+}
+/// Everything the process owns while it's alive
+struct ProcessB {
+    paging: PagingContext,
+    tasks: YMutex<Vec<Arc<TaskControl>>>,
+    handles: HandleTable,
+    /// Child processes that haven't been reaped yet
+    children: YMutex<Vec<ProcessRef>>,
+    exit: Arc<ExitState>,
+}
+impl core::ops::Drop for ProcessB {
+    fn drop(&mut self) {
+        // (processes that never called exit() exit with 0 once they run out of tasks)
+        let _ = self.exit.status.set(ExitStatus::Exited(0));
+        self.exit.dead.store(true, Ordering::Release);
+        self.exit.waiters.notify_all();
+    }
+}
+
+type ProcessTable = DescriptorTable<ProcessT,ProcessA,ProcessB,16,8>;
+type ProcessHandleA = DescriptorHandleA<'static,ProcessT,ProcessA,ProcessB>;
+type ProcessHandleB = DescriptorHandleB<'static,ProcessT,ProcessA,ProcessB>;
+lazy_static::lazy_static! {
+    static ref PROCESS_TABLE: ProcessTable = DescriptorTable::new();
+}
+
+/// A handle to a live process. While any exist, the process (and its address space) stays alive.
+pub struct Process(ProcessHandleB);
+impl Process {
+    /* Create a new, empty process with a fresh address space. It's a child of the current task's process (if any).
+        Fails if there's no room for it in the process table. */
+    pub fn create(name: String) -> Result<Process,DescriptorTableFull> {
+        let initialiser = PROCESS_TABLE.create_new_descriptor()?;
+        let parent = current_process();
+        let exit = Arc::new(ExitState { status: POnceLock::new(), dead: AtomicBool::new(false), waiters: WaitingList::new() });
+        let a = ProcessA { name, parent: parent.as_ref().map(|p|p.id()), exit: Arc::clone(&exit) };
+        let b = ProcessB {
+            paging: crate::memory::alloc_util::new_user_paging_context(),
+            tasks: YMutex::new(Vec::new()),
+            handles: HandleTable::new(),
+            children: YMutex::new(Vec::new()),
+            exit,
+        };
+        let process = Process(initialiser.commit(a, b));
+        klog!(Info, PROCESSES, "Created process {} ({}), parent={:?}.", process.id(), process.name(), process.parent());
+        if let Some(parent) = parent { parent.0.get_b().children.lock().push(process.downgrade()); }
+        Ok(process)
+    }
+
+    pub fn id(&self) -> ProcessID { self.0.get_id() }
+    pub fn name(&self) -> &str { &self.0.get_a().name }
+    pub fn parent(&self) -> Option<ProcessID> { self.0.get_a().parent }
+    /// The process's address space
+    pub fn paging_context(&self) -> &PagingContext { &self.0.get_b().paging }
+    /// The kernel objects the process has open
+    pub fn handles(&self) -> &HandleTable { &self.0.get_b().handles }
+    /// A reference that can be used to inspect the process after it has died
+    pub fn downgrade(&self) -> ProcessRef { ProcessRef(self.0.clone_a_ref()) }
+
+    /* Spawn a new kernel task as part of this process. (tasks spawned by a task in a process are automatically part of it, so this is only needed for the first one) */
+    pub fn spawn_task(&self, name: &'static str, entry: TaskEntryPoint) -> TaskHandle<()> {
+        super::util::spawn_kernel_task_in(Some(self.clone()), name, entry)
+    }
+    pub(super) fn add_task(&self, task: &Arc<TaskControl>) {
+        let mut tasks = self.0.get_b().tasks.lock();
+        tasks.retain(|t|t.status() != TaskStatus::Terminated);
+        tasks.push(Arc::clone(task));
+    }
+    /// The process's tasks that haven't terminated yet
+    pub fn tasks(&self) -> Vec<Arc<TaskControl>> {
+        self.0.get_b().tasks.lock().iter().filter(|t|t.status() != TaskStatus::Terminated).cloned().collect()
+    }
+
+    /* Exit the process with the given code, killing all of its tasks (including the current one, if it's part of this process, in which case this doesn't return).
+        Tasks exiting their own process should use exit_current_process instead: the stack isn't unwound, so the handle this is called on would never be dropped (and the process would never die). */
+    pub fn exit(&self, code: i32) {
+        if self._end(ExitStatus::Exited(code)) { super::scheduler::terminate_current_task(); }
+    }
+    /* Same as exit(), but the process's status is Killed */
+    pub fn kill(&self) {
+        if self._end(ExitStatus::Killed) { super::scheduler::terminate_current_task(); }
+    }
+    /* Record the exit status, and kill all of the process's tasks except the current one. Returns true if the current task is part of the process (in which case the caller must terminate it). */
+    fn _end(&self, status: ExitStatus) -> bool {
+        if self.0.get_a().exit.status.set(status).is_ok() {
+            klog!(Info, PROCESSES, "Process {} ({}) is exiting: {:?}", self.id(), self.name(), status);
+        }
+        let current = super::scheduler::get_executing_task_id();
+        let mut current_is_ours = false;
+        for task in self.tasks() {
+            if Some(task.task_id()) == current { current_is_ours = true; }
+            else { task.kill(); }
+        }
+        current_is_ours
+    }
+
+    /* Reap any children that have died, returning their IDs and exit statuses. */
+    pub fn reap_children(&self) -> Vec<(ProcessID,ExitStatus)> {
+        let mut children = self.0.get_b().children.lock();
+        let mut reaped = Vec::new();
+        children.retain(|child|match child.exit_status_if_dead() {
+            Some(status) => { reaped.push((child.id(), status)); false },
+            None => true,
+        });
+        reaped
+    }
+}
+impl core::clone::Clone for Process {
+    fn clone(&self) -> Self { Self(self.0.clone_b_ref()) }
+}
+
+/// A reference to a process that may have died. Dead processes stay in the process table (as zombies) until every ProcessRef has been dropped.
+pub struct ProcessRef(ProcessHandleA);
+impl ProcessRef {
+    pub fn id(&self) -> ProcessID { self.0.get_id() }
+    pub fn name(&self) -> &str { &self.0.get_a().name }
+    pub fn parent(&self) -> Option<ProcessID> { self.0.get_a().parent }
+    /// Get a handle to the process, if it's still alive
+    pub fn upgrade(&self) -> Option<Process> {
+        self.0.clone_a_ref().upgrade().ok().map(Process)
+    }
+    pub fn is_alive(&self) -> bool { !self.0.get_a().exit.dead.load(Ordering::Acquire) }
+    /// How the process ended, or None if it's still alive
+    pub fn exit_status_if_dead(&self) -> Option<ExitStatus> {
+        if self.is_alive() { return None; }
+        self.0.get_a().exit.status.get().copied()
+    }
+    /* Block until the process has died, and return how it ended */
+    pub fn wait(&self) -> ExitStatus {
+        self.0.get_a().exit.waiters.wait_until_try(||self.exit_status_if_dead())
+    }
+}
+impl core::clone::Clone for ProcessRef {
+    fn clone(&self) -> Self { Self(self.0.clone_a_ref()) }
+}
+
+/* Look up a process by its ID. This works for zombies as well as live processes. */
+pub fn lookup_process(id: ProcessID) -> Option<ProcessRef> {
+    PROCESS_TABLE.acquire_a(id).ok().map(ProcessRef)
+}
+/* Every process in the table (including zombies that haven't been reaped yet), in no particular order */
+pub fn all_processes() -> Vec<ProcessRef> {
+    PROCESS_TABLE.iter().map(ProcessRef).collect()
+}
+/* Exit the current task's process with the given code (see Process::exit). Panics if the current task isn't part of a process. */
+pub fn exit_current_process(code: i32) -> ! {
+    let process = current_process().expect("exit_current_process() called by a task that isn't part of a process!");
+    process._end(ExitStatus::Exited(code));
+    // (the current task's own handle is dropped once it has terminated)
+    drop(process);
+    super::scheduler::terminate_current_task();
+}
+/* The process the current task is part of, if any */
+pub fn current_process() -> Option<Process> {
+    super::scheduler::with_current_task(|task|task.process().cloned()).flatten()
+}
+
+// == HANDLES ==
+pub type Handle = usize;
+/// A process's open kernel objects, each identified by a small integer (a la file descriptors)
+pub struct HandleTable {
+    handles: YMutex<Vec<Option<Arc<dyn Any+Send+Sync>>>>,
+}
+impl HandleTable {
+    pub const fn new() -> Self {
+        Self { handles: YMutex::new(Vec::new()) }
+    }
+    /* Add an object to the table, returning its handle. Handles of removed objects are re-used. */
+    pub fn insert(&self, object: Arc<dyn Any+Send+Sync>) -> Handle {
+        let mut handles = self.handles.lock();
+        match handles.iter().position(|h|h.is_none()) {
+            Some(handle) => { handles[handle] = Some(object); handle },
+            None => { handles.push(Some(object)); handles.len()-1 },
+        }
+    }
+    /* Get the object with the given handle, if it exists and is a T */
+    pub fn get<T: Any+Send+Sync>(&self, handle: Handle) -> Option<Arc<T>> {
+        let object = Arc::clone(self.handles.lock().get(handle)?.as_ref()?);
+        object.downcast::<T>().ok()
+    }
+    /* Remove an object from the table, returning it (or None if the handle wasn't in use) */
+    pub fn remove(&self, handle: Handle) -> Option<Arc<dyn Any+Send+Sync>> {
+        self.handles.lock().get_mut(handle)?.take()
+    }
+    /// The number of open handles
+    pub fn len(&self) -> usize {
+        self.handles.lock().iter().filter(|h|h.is_some()).count()
+    }
+}
+
+pub(crate) mod process_test {
+    use super::*;
+    use alloc::format;
+    use core::time::Duration;
+    use crate::multitasking::sleep;
+
+    /// Failures reported by the tasks below (which can't return anything, as they're started with Process::spawn_task)
+    static FAILURES: YMutex<Vec<String>> = YMutex::new(Vec::new());
+    fn fail(message: String) { FAILURES.lock().push(message); }
+
+    extern "sysv64" fn exiter() -> ! {
+        let process = current_process().unwrap();
+        if !process.paging_context().is_active() { fail(format!("Process {}'s address space wasn't active while its task was running", process.id())); }
+        drop(process);
+        exit_current_process(7);
+    }
+    extern "sysv64" fn sleeper() -> ! {
+        loop { sleep(Duration::from_millis(10)); }
+    }
+    // Creates a child that exits, then checks it stays a zombie until it's reaped
+    extern "sysv64" fn parent() -> ! {
+        let me = current_process().unwrap();
+        let child = Process::create(String::from("process_test_child")).unwrap();
+        if child.parent() != Some(me.id()) { fail(format!("Child's parent is {:?} (expected {})", child.parent(), me.id())); }
+        let child_ref = child.downgrade();
+        child.spawn_task("process_test_child", exiter);
+        drop(child);
+        child_ref.wait();
+        let id = child_ref.id();
+        if lookup_process(id).is_none() { fail(format!("Child vanished from the process table before it was reaped")); }
+        let reaped = me.reap_children();
+        if reaped != [(id, ExitStatus::Exited(7))] { fail(format!("Reaped {:?} (expected [({}, Exited(7))])", reaped, id)); }
+        if !me.reap_children().is_empty() { fail(format!("Child was reaped twice")); }
+        drop(child_ref);
+        if lookup_process(id).is_some() { fail(format!("Child is still in the process table after its last reference was dropped")); }
+        drop(me);
+        exit_current_process(0);
+    }
+
+    /* Wait for a process to die, giving up after a few seconds (so that a process that never dies fails the test instead of hanging the shell) */
+    fn wait_for(process: &ProcessRef) -> Result<ExitStatus,String> {
+        for _ in 0..500 {
+            if let Some(status) = process.exit_status_if_dead() { return Ok(status); }
+            sleep(Duration::from_millis(10));
+        }
+        Err(format!("Process {} ({}) didn't die", process.id(), process.name()))
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        FAILURES.lock().clear();
+        // Exiting: the status is recorded, the process becomes a zombie, and it's removed from the table once it has been reaped
+        let process = Process::create(String::from("process_test_exit")).map_err(|e|format!("{:?}", e))?;
+        let exited = process.downgrade();
+        process.spawn_task("process_test_exit", exiter);
+        drop(process);
+        if wait_for(&exited)? != ExitStatus::Exited(7) { return Err(format!("Exit status was {:?} (expected Exited(7))", exited.exit_status_if_dead())); }
+        if exited.wait() != ExitStatus::Exited(7) { return Err(format!("wait() disagrees with exit_status_if_dead()")); }
+        if exited.is_alive() || exited.upgrade().is_some() { return Err(format!("Zombie process can still be upgraded")); }
+        let id = exited.id();
+        let lookup = lookup_process(id).ok_or(format!("Zombie process {} is missing from the process table", id))?;
+        if lookup.exit_status_if_dead() != Some(ExitStatus::Exited(7)) { return Err(format!("Zombie's exit status was {:?} through lookup_process", lookup.exit_status_if_dead())); }
+        drop((exited, lookup));
+        if lookup_process(id).is_some() { return Err(format!("Process {} is still in the table after being reaped", id)); }
+        // (and we aren't part of a process, so we must have been switched back to the kernel's address space, rather than left in the dead one's)
+        if PagingContext::kernel_context().is_some_and(|kernel|!kernel.is_active()) { return Err(format!("The kernel's address space wasn't active while a kernel task was running")); }
+
+        // Killing: every task is killed, even ones that are asleep
+        let process = Process::create(String::from("process_test_kill")).map_err(|e|format!("{:?}", e))?;
+        let killed = process.downgrade();
+        let tasks = [process.spawn_task("process_test_sleeper", sleeper), process.spawn_task("process_test_sleeper", sleeper)];
+        sleep(Duration::from_millis(20));
+        process.kill();
+        drop(process);
+        if wait_for(&killed)? != ExitStatus::Killed { return Err(format!("Exit status was {:?} (expected Killed)", killed.exit_status_if_dead())); }
+        if tasks.iter().any(|task|task.join_timeout(Duration::from_secs(1)) != Err(crate::multitasking::JoinError::Killed)) { return Err(format!("Killed process's tasks weren't killed")); }
+
+        // Reaping children (from a task in the parent, as children are recorded in the process that created them)
+        let process = Process::create(String::from("process_test_parent")).map_err(|e|format!("{:?}", e))?;
+        let parent_ref = process.downgrade();
+        process.spawn_task("process_test_parent", parent);
+        drop(process);
+        if wait_for(&parent_ref)? != ExitStatus::Exited(0) { return Err(format!("Parent's exit status was {:?}", parent_ref.exit_status_if_dead())); }
+
+        let failures = core::mem::take(&mut *FAILURES.lock());
+        if !failures.is_empty() { return Err(failures.join("; ")); }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Run queues: each CPU's scheduler keeps its ready tasks in a RunQueue, which decides which one runs next.
+//! The policy is chosen at build time (see DefaultRunQueue): a multilevel feedback queue by default, or plain round-robin with the `sched_fifo` feature.
+
+use alloc::collections::VecDeque;
+use super::task::Task;
+
+/// How important a task is. Higher priorities are always run first (under the MLFQ policy), so keep High for things that need to respond quickly and don't hog the CPU (drivers, interactive tasks).
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum TaskPriority {
+    /// Housekeeping that can wait until nothing else needs doing (e.g. growing the kernel heap in advance)
+    Background,
+    Normal,
+    /// Drivers and interactive tasks
+    High,
+}
+impl TaskPriority {
+    /// The MLFQ level that tasks of this priority start at (and drop back to after a boost). 0 is the highest.
+    const fn base_level(self) -> usize {
+        match self { Self::High => 0, Self::Normal => 1, Self::Background => MLFQ_LEVELS-1 }
+    }
+}
+impl core::default::Default for TaskPriority {
+    fn default() -> Self { Self::Normal }
+}
+
+/// Why a task is being put into the run queue
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EnqueueReason {
+    /// It has just been created
+    New,
+    /// It yielded while still having work to do (e.g. spin_yield)
+    Yielded,
+    /// It was blocked (on a waiting list, or sleeping) and has been woken up
+    Woken,
+}
+
+/// A scheduling policy
+pub trait RunQueue: Default + Send {
+    /// Shown in logs and the debug shell
+    const NAME: &'static str;
+
+    /// Add a task which is ready to run
+    fn push(&mut self, task: Task, reason: EnqueueReason);
+    /// Take the task that should run next
+    fn pop(&mut self) -> Option<Task>;
+    /// The number of tasks in the queue
+    fn len(&self) -> usize;
+    /// Every task in the queue (in no particular order)
+    fn iter(&self) -> impl Iterator<Item=&Task>;
+    /// Returns true if a task in the queue should be run instead of the given (currently running) one, as soon as it reaches a preemption point
+    fn should_preempt(&self, current: &Task) -> bool;
+    /// Take a task that's allowed to run on the given CPU, so that it can be moved there (for load balancing). This should be the task that will miss being here the least (i.e. the one that would run last).
+    fn steal(&mut self, cpu: usize) -> Option<Task>;
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature="sched_fifo")] {
+        pub type DefaultRunQueue = FifoRunQueue;
+    } else {
+        pub type DefaultRunQueue = MlfqRunQueue;
+    }
+}
+
+/// Round-robin: tasks run in the order they became ready, ignoring their priority
+#[derive(Default)]
+pub struct FifoRunQueue {
+    queue: VecDeque<Task>,
+}
+impl RunQueue for FifoRunQueue {
+    const NAME: &'static str = "fifo";
+
+    fn push(&mut self, task: Task, _reason: EnqueueReason) { self.queue.push_back(task); }
+    fn pop(&mut self) -> Option<Task> { self.queue.pop_front() }
+    fn len(&self) -> usize { self.queue.len() }
+    fn iter(&self) -> impl Iterator<Item=&Task> { self.queue.iter() }
+    fn should_preempt(&self, _current: &Task) -> bool { false }
+    fn steal(&mut self, cpu: usize) -> Option<Task> {
+        let index = self.queue.iter().rposition(|task|task.affinity.contains(cpu))?;
+        self.queue.remove(index)
+    }
+}
+
+/// The number of levels in the MLFQ
+pub const MLFQ_LEVELS: usize = 4;
+/// How many times a task may yield without blocking before it's moved down a level (the lowest level has no limit)
+pub const MLFQ_ALLOTMENT: [usize; MLFQ_LEVELS] = [2, 4, 8, usize::MAX];
+/// Every this many scheduling decisions, every task is moved up to the top level for one turn (so that demoted and background tasks don't starve behind busy higher-priority ones)
+pub const MLFQ_BOOST_INTERVAL: usize = 64;
+
+/// Multilevel feedback queue: a round-robin queue per level, with higher levels always run first.
+/// Tasks start at a level based on their priority. Tasks that keep yielding without blocking (i.e. CPU-bound ones) are gradually moved down, while those that block keep their level.
+#[derive(Default)]
+pub struct MlfqRunQueue {
+    levels: [VecDeque<Task>; MLFQ_LEVELS],
+    /// Scheduling decisions since the last boost
+    since_boost: usize,
+}
+impl MlfqRunQueue {
+    fn boost(&mut self) {
+        // (they drop back to their base level the next time they're queued, so each gets one turn at the top)
+        for level in 1..MLFQ_LEVELS {
+            while let Some(mut task) = self.levels[level].pop_front() {
+                task.sched_level = 0; task.sched_used = 0;
+                self.levels[0].push_back(task);
+            }
+        }
+        self.since_boost = 0;
+    }
+}
+impl RunQueue for MlfqRunQueue {
+    const NAME: &'static str = "mlfq";
+
+    fn push(&mut self, mut task: Task, reason: EnqueueReason) {
+        let base = task.priority.base_level();
+        match reason {
+            EnqueueReason::New => { task.sched_level = base; task.sched_used = 0; },
+            EnqueueReason::Yielded => {
+                task.sched_used += 1;
+                if task.sched_used >= MLFQ_ALLOTMENT[task.sched_level] && task.sched_level < MLFQ_LEVELS-1 {
+                    task.sched_level += 1; task.sched_used = 0;
+                }
+            },
+            // (blocking doesn't use up the task's allotment, so interactive tasks stay where they are)
+            EnqueueReason::Woken => {},
+        }
+        // (the priority may have changed since the task was last queued, or it may have been boosted above its base level)
+        if task.sched_level < base { task.sched_level = base; task.sched_used = 0; }
+        self.levels[task.sched_level].push_back(task);
+    }
+    fn pop(&mut self) -> Option<Task> {
+        self.since_boost += 1;
+        if self.since_boost >= MLFQ_BOOST_INTERVAL { self.boost(); }
+        self.levels.iter_mut().find_map(|level|level.pop_front())
+    }
+    fn len(&self) -> usize { self.levels.iter().map(|level|level.len()).sum() }
+    fn iter(&self) -> impl Iterator<Item=&Task> { self.levels.iter().flat_map(|level|level.iter()) }
+    fn should_preempt(&self, current: &Task) -> bool {
+        self.levels[..current.sched_level].iter().any(|level|!level.is_empty())
+    }
+    fn steal(&mut self, cpu: usize) -> Option<Task> {
+        self.levels.iter_mut().rev().find_map(|level|{
+            let index = level.iter().rposition(|task|task.affinity.contains(cpu))?;
+            level.remove(index)
+        })
+    }
+}
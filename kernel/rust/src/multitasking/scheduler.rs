@@ -1,7 +1,12 @@
 
 use super::arch::{context_switch as cswitch_impl};
-use super::task::{Task,TaskType};
-use alloc::collections::VecDeque;
+use super::arch::idle::{halt_until_woken,read_timestamp,MWAIT_AVAILABLE};
+use super::task::{Task,TaskType,CpuSet};
+use super::handle::{TaskControl,TaskStatus,finish_terminated};
+use super::runqueue::{RunQueue,DefaultRunQueue,EnqueueReason,TaskPriority};
+use super::timerwheel::TimerWheel;
+use alloc::sync::Arc;
+use core::time::Duration;
 use crate::logging::klog;
 use super::cpulocal::CpuLocal;
 use super::fixedcpulocal::{get_fixed_cpu_locals,fixed_cpu_local};
@@ -9,53 +14,109 @@ use super::interruptions::{disable_interruptions,NoInterruptionsGuard};
 
 //use crate::sync::kspin::{KMutexRaw,KRwLockRaw};
 use crate::sync::kspin::{KMutex,KMutexGuard};
-use core::sync::atomic::{AtomicUsize,AtomicBool,Ordering};
-use crate::sync::{yspin::YMutexGuard,waitlist::WaitingListEntry};
+use core::sync::atomic::{AtomicUsize,AtomicU64,AtomicBool,Ordering};
+use crate::sync::waitlist::{WaitingListGuard,WaitingListEntry,TimedWaiter,AsyncWaiter};
+use crate::sync::rcu;
+use crate::memory::paging::PagingContext;
+use super::util::def_task_fn;
 
 // Currently active task & run queue
 struct SchedulerState {
-    run_queue: VecDeque<Task>, // TODO replace with a better run queue system
+    run_queue: DefaultRunQueue,
     
-    /// Some internal IO tasks are currently put here when they need to sleep for a while
-    /// This will eventually be superseded by a proper "sleep" system
-    sleeping: VecDeque<(Task,usize)>,
-    
-    // We can't drop tasks in scheduler code because the memory allocators use Y/WLocks
-    deferred_drop: alloc::vec::Vec<Task>,
+    /// Things to do at a particular tick (see _scheduler_tick)
+    timers: TimerWheel<TimerAction>,
 }
 impl core::default::Default for SchedulerState {
     fn default() -> Self {
         Self {
-            run_queue: VecDeque::new(),
-            sleeping: VecDeque::new(),
-            deferred_drop: alloc::vec::Vec::new(),
+            run_queue: DefaultRunQueue::default(),
+            timers: TimerWheel::new(),
         }
     }
 }
+enum TimerAction {
+    /// Wake a sleeping task
+    Wake(Task),
+    /// Wake a task waiting on a waiting list, if it hasn't been notified already
+    WaitTimeout(Arc<TimedWaiter>),
+}
 // current_task is stored separately to the rest of the state as it is commonly accessed by logging methods,
 // and usually isn't held for very long. If it was part of _SCHEDULER_STATE, then logging during with_scheduler_state! would cause a deadlock
-static _CURRENT_TASK: CpuLocal<KMutex<Option<Task>>,false> = CpuLocal::new();
+static _CURRENT_TASK: CpuLocal<KMutex<Option<Task>>,true> = CpuLocal::new();
 
 static _SCHEDULER_STATE: CpuLocal<KMutex<SchedulerState>,true> = CpuLocal::new();
-static _SCHEDULER_TICKS: CpuLocal<AtomicUsize,false> = CpuLocal::new();
+/// Sleeping futures' timers (see sleep_async). They're kept apart from the scheduler's, as futures' wakers may queue tasks (which needs the scheduler lock),
+///  so they're woken with only these locked (see advance_timers)
+static _ASYNC_TIMERS: CpuLocal<KMutex<TimerWheel<Arc<AsyncWaiter>>>,true> = CpuLocal::new();
+/// The scheduler's clock, shared by every CPU (see _scheduler_tick)
+static SCHEDULER_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+// We can't drop tasks in scheduler code because the memory allocators use Y/WLocks, so terminated tasks are dropped by the reaper task instead
+// (dropping a task can also drop the last handle to its process, which frees its address space and wakes anyone waiting for it to exit, so this can't wait until something else happens to run)
+static DEFERRED_DROP: KMutex<alloc::vec::Vec<Deferred>> = KMutex::new(alloc::vec::Vec::new());
+static DEFERRED_DROP_WAITERS: crate::sync::WaitingList = crate::sync::WaitingList::new();
+
+/// Things the scheduler has finished with, but can't drop itself (see DEFERRED_DROP)
+enum Deferred {
+    Task(Task),
+    /// An address space we've switched away from, which may belong to a process that has since exited (see switch_address_space)
+    PagingContext(PagingContext),
+}
+
+#[derive(Default)]
+struct IdleState {
+    /// Set whenever a task is pushed to this CPU's run queue, so that it stops halting (see halt_until_woken)
+    wake_pending: AtomicBool,
+    /// Set while the CPU is (about to be) halted, so that wake_cpu knows to interrupt it
+    halted: AtomicBool,
+    /// Timestamp counter cycles spent halted
+    idle_cycles: AtomicU64,
+    /// The number of times the CPU has halted
+    halts: AtomicUsize,
+    /// The timestamp when the scheduler was initialised on this CPU
+    start_timestamp: AtomicU64,
+}
+static _IDLE_STATE: CpuLocal<IdleState,true> = CpuLocal::new();
+
+/// How busy each CPU is, for load balancing. This is only a hint (it's updated without holding the scheduler's lock), so don't rely on it being exact.
+#[derive(Default)]
+struct CpuLoad {
+    /// Set once the CPU's scheduler has been initialised
+    online: AtomicBool,
+    /// The number of tasks in the CPU's run queue
+    queued: AtomicUsize,
+    /// Set while the CPU has nothing to run
+    idle: AtomicBool,
+    /// Set by the scheduler's clock every BALANCE_INTERVAL_TICKS. The balancing itself is done by the next call to schedule, as it takes other CPUs' scheduler locks (which we don't want to spin on in an interrupt handler).
+    balance_due: AtomicBool,
+}
+static _CPU_LOAD: CpuLocal<CpuLoad,true> = CpuLocal::new();
+/// How often (in scheduler ticks) each CPU checks whether it should hand some of its tasks to less busy CPUs
+const BALANCE_INTERVAL_TICKS: usize = 10;
 
 // _IS_EXECUTING_TASK is a lock-free heuristic for checking if a task is not currently executing, even if the scheduler is not initialised yet on this CPU or if the scheduler is deadlocked
 // It is false when scheduler/bootstrap code is executing, and is true starting right before resume_context is called.
 // It is only intended as a heuristic. If you intend to interact with tasks properly, use a standard lock acquire and match statement.
 // static _IS_EXECUTING_TASK: CpuLocal<AtomicBool,KRwLockRaw> = CpuLocal::new();
 fixed_cpu_local!(fixedcpulocal static _IS_EXECUTING_TASK: AtomicBool = AtomicBool::new(false));
+// Likewise, _EXECUTING_TASK_ID is a lock-free copy of the current task's ID (usize::MAX if there isn't one), for code that can't lock _CURRENT_TASK (such as the locks themselves)
+fixed_cpu_local!(fixedcpulocal static _EXECUTING_TASK_ID: AtomicUsize = AtomicUsize::new(usize::MAX));
 
 pub type StackPointer = cswitch_impl::StackPointer;
 
 #[inline]
-#[cfg_attr(feature="dbg_scheduler_yield_errinfo", track_caller)]
+#[cfg_attr(any(feature="dbg_scheduler_yield_errinfo", feature="dbg_lockdep"), track_caller)]
 pub fn yield_to_scheduler(command: SchedulerCommand) {
     // This wrapper fn is included to allow for adding debug assertions and such
     
+    #[cfg(feature="dbg_lockdep")]
+    crate::sync::lockdep::check_yield();
     cfg_if::cfg_if! {
         if #[cfg(any(debug_assertions, feature="dbg_scheduler_yield_errinfo"))] {
             assert!(!super::interruptions::is_sched_yield_disabled(), "yield_to_scheduler() called when interruptions were disabled!\n(no_interruptions state stack={:?})", super::interruptions::fmt_current_state_stack());
             assert!(_CURRENT_TASK.lock().is_some(), "yield_to_scheduler() called when no task was currently active!");
+            assert!(!crate::sync::rcu::in_rcu_read_section(), "yield_to_scheduler() called inside an RCU read-side critical section!");
         } else {}
     }
     
@@ -65,16 +126,16 @@ pub fn yield_to_scheduler(command: SchedulerCommand) {
 /* Terminate the current task. This is akin to calling yield_to_scheduler(Terminate), but returns the "!" type to hint that it cannot resume afterwards.
     This currently does not unwind the stack, so any objects you store in the stack will not be dropped. However, this may change in the future without warning (so be cautious but don't depend on it).
     Anything held in the task object itself (e.g. stack allocations, handles to the relevant process/thread, etc.) will be dropped as normal
-        at a non-deterministic time in the near future (usually when another terminate call occurs in the scheduler, or sooner if i add a cleanup task that periodically cleans up terminated tasks). */
+        shortly afterwards, by the reaper task (see spawn_reaper_task). */
 #[inline]
-#[cfg_attr(feature="dbg_scheduler_yield_errinfo", track_caller)]
+#[cfg_attr(any(feature="dbg_scheduler_yield_errinfo", feature="dbg_lockdep"), track_caller)]
 pub fn terminate_current_task() -> ! {
     yield_to_scheduler(SchedulerCommand::Terminate);
     unreachable!();  // the scheduler will drop the task when yield is called with the Terminate command
 }
 /* Shorthand for yielding as part of a spinloop. */
 #[inline]
-#[cfg_attr(feature="dbg_scheduler_yield_errinfo", track_caller)]
+#[cfg_attr(any(feature="dbg_scheduler_yield_errinfo", feature="dbg_lockdep"), track_caller)]
 pub fn spin_yield(){
     yield_to_scheduler(SchedulerCommand::PushBack);
 }
@@ -87,11 +148,13 @@ pub enum SchedulerCommand<'a> {
     /// Discard the current task - it has terminated. (this does not perform unwinding).
     /// It is preferred to use terminate_current_task or similar instead of yield_to_scheduler(Terminate) where possible.
     Terminate,
-    /// Sleep for the requested number of PIT ticks
+    /// Sleep for the requested number of scheduler ticks (see sleep, which takes a Duration instead)
     SleepNTicks(usize),
     /// Push a waiting list entry to the given waiting list, then unlock the mutex by dropping the guard
     /// (the Option<> is used internally, and must always be passed as Some(). Passing a None may (will) cause a kernel panic.
-    PushToWaitingList(core::cell::Cell<Option<YMutexGuard<'a,VecDeque<WaitingListEntry>>>>),
+    PushToWaitingList(core::cell::Cell<Option<WaitingListGuard<'a>>>),
+    /// Same as PushToWaitingList, but the task is woken anyway after the given number of scheduler ticks (in which case the TimedWaiter's timed_out() returns true)
+    PushToWaitingListTimed(core::cell::Cell<Option<WaitingListGuard<'a>>>, usize, Arc<TimedWaiter>),
 }
 
 /* Do not call this function yourself! (unless you know what you're doing). Use yield_to_scheduler instead!
@@ -100,7 +163,21 @@ pub enum SchedulerCommand<'a> {
 pub(super) fn schedule(command: SchedulerCommand, rsp: StackPointer) -> ! {
     if super::interruptions::is_sched_yield_disabled() { panic!("schedule() called when interruptions were disabled?"); }
     _IS_EXECUTING_TASK.store(false, Ordering::Release);
+    _EXECUTING_TASK_ID.store(usize::MAX, Ordering::Relaxed);
+    rcu::rcu_quiescent_state();
     let mut current_task = _CURRENT_TASK.lock().take().expect("schedule() called but no task currently active?");
+    // (lockdep counts the locks we take as the outgoing task's until its waiting list has been unlocked, so it has to be kept alive until then)
+    #[cfg(feature="dbg_lockdep")]
+    let lockdep_control = Arc::clone(&current_task.control);
+    let cpu = super::get_cpu_num();
+    // Waiting lists must be unlocked after the state is unlocked, as unlocking them may push tasks to our run queue (see WaitingListGuard)
+    let mut waitlist_guard = None;
+    // Tasks that aren't allowed to run here any more are moved once the state is unlocked (as only one scheduler may be locked at a time)
+    let mut migrating = None;
+    // Likewise, anyone joining a terminated task is woken once the state is unlocked
+    let mut terminated = None;
+    let killed = current_task.control.is_kill_requested();
+    current_task.control.record_switch_out();
     {
         let mut state = _SCHEDULER_STATE.lock();
         // Update current task
@@ -114,55 +191,150 @@ pub(super) fn schedule(command: SchedulerCommand, rsp: StackPointer) -> ! {
             SchedulerCommand::Terminate => {
                 // Terminate the task
                 klog!(Debug, SCHEDULER, "Terminating task: {}", current_task.task_id);
-                state.deferred_drop.clear();  // (drop previous stacks)
-                state.deferred_drop.push(current_task)
+                terminated = Some(discard_task(current_task));
+            }
+            
+            _ if killed => {
+                // Killed (see TaskControl::kill). Any waiting list we were given still needs unlocking.
+                klog!(Debug, SCHEDULER, "Killing task: {}", current_task.task_id);
+                if let SchedulerCommand::PushToWaitingList(list) | SchedulerCommand::PushToWaitingListTimed(list, ..) = &command { waitlist_guard = list.replace(None); }
+                terminated = Some(discard_task(current_task));
             }
             
             SchedulerCommand::SleepNTicks(ticks) => {
                 // Sleep
-                let wake_at = get_scheduler_ticks() + ticks;
+                let wake_at = state.timers.now() + ticks;
                 klog!(Debug, SCHEDULER, "Task {} sleeping for {} ticks. (until t={})", current_task.task_id, ticks, wake_at);
-                state.sleeping.push_back((current_task, wake_at));
+                current_task.control.set_status(TaskStatus::Sleeping);
+                state.timers.insert(wake_at, TimerAction::Wake(current_task));
             }
             
             SchedulerCommand::PushToWaitingList(list) => {
                 // Construct and push a waiting list entry
                 let mut list = list.replace(None).expect("Schedule was passed PushToWaitingList(None)!");
                 klog!(Debug, SCHEDULER, "Task {} waiting on list.");
-                list.push_back(WaitingListEntry { cpu: super::get_cpu_num(), task: current_task });
+                current_task.control.set_status(TaskStatus::Waiting);
+                list.push_back(WaitingListEntry::Task { cpu, task: current_task });
+                waitlist_guard = Some(list);
+            }
+            
+            SchedulerCommand::PushToWaitingListTimed(list, ticks, waiter) => {
+                let mut list = list.replace(None).expect("Schedule was passed PushToWaitingListTimed(None,..)!");
+                let wake_at = state.timers.now() + ticks;
+                klog!(Debug, SCHEDULER, "Task {} waiting on list (until t={}).", current_task.task_id, wake_at);
+                current_task.control.set_status(TaskStatus::Waiting);
+                waiter.park(current_task, cpu);
+                list.push_back(WaitingListEntry::Timed(Arc::clone(waiter)));
+                state.timers.insert(wake_at, TimerAction::WaitTimeout(Arc::clone(waiter)));
+                waitlist_guard = Some(list);
+            }
+            
+            _ if !current_task.affinity.contains(cpu) => {
+                klog!(Debug, SCHEDULER, "Task {} is no longer allowed on CPU {}. Migrating.", current_task.task_id, cpu);
+                migrating = Some(current_task);
             }
             
             _ => {
                 // Push back onto run queue
                 klog!(Debug, SCHEDULER, "Suspending task: {}", current_task.task_id);
-                state.run_queue.push_back(current_task);
+                current_task.control.set_status(TaskStatus::Runnable);
+                state.run_queue.push(current_task, EnqueueReason::Yielded);
+                record_load(cpu, &state);
             }
         }
     };  // <-- lock is released here
+    drop(waitlist_guard);
+    #[cfg(feature="dbg_lockdep")]
+    { crate::sync::lockdep::switch_out(); drop(lockdep_control); }
+    if let Some(task) = migrating { push_task_to(select_cpu(task.affinity, cpu), task); }
+    if let Some(control) = terminated { finish_discarded(&control); }
     
+    let idle = &*_IDLE_STATE;
+    let load = &*_CPU_LOAD;
+    if load.balance_due.swap(false, Ordering::Relaxed) { balance_load(cpu); }
     // Pick the next task off of the run queue
     loop {
+        // (interruptions stay disabled from checking the run queue until we either resume a task or halt, so a wakeup can't be missed in between)
+        let ni = disable_interruptions();
+        idle.wake_pending.store(false, Ordering::Release);
         let next_task = {
             let mut state = _SCHEDULER_STATE.lock();
-            (match &command {
-                _ => {
-                    // Take next task
-                    state.run_queue.pop_front()
-                }
-            }).map(|task|(task, state))
+            let task = state.run_queue.pop();
+            record_load(cpu, &state);
+            task
         };
+        // If we've run out of work, take some from a busier CPU
+        let next_task = next_task.or_else(||steal_task(cpu));
         
-        if let Some((next_task, state_guard)) = next_task {
+        if let Some(next_task) = next_task {
+            if next_task.control.is_kill_requested() {
+                klog!(Debug, SCHEDULER, "Killing task: {}", next_task.task_id);
+                let control = discard_task(next_task);
+                finish_discarded(&control);
+                continue;
+            }
             klog!(Debug, SCHEDULER, "Resuming task: {}", next_task.task_id);
-            let ni = disable_interruptions(); drop(state_guard);  // (unlock state but keep interruptions disabled)
+            load.idle.store(false, Ordering::Relaxed);
+            switch_address_space(&next_task);
             resume_context(next_task, ni)
         } else {
-            // No tasks to do
-            // spin for now
-            core::hint::spin_loop();
+            // No tasks to do - halt until an interrupt (or another CPU) gives us something
+            load.idle.store(true, Ordering::Relaxed);
+            let halted_at = read_timestamp();
+            rcu::rcu_enter_idle();
+            // (SeqCst, see wake_cpu)
+            idle.halted.store(true, Ordering::SeqCst);
+            halt_until_woken(&idle.wake_pending);
+            idle.halted.store(false, Ordering::Relaxed);
+            rcu::rcu_exit_idle();
+            idle.idle_cycles.fetch_add(read_timestamp().wrapping_sub(halted_at), Ordering::Relaxed);
+            idle.halts.fetch_add(1, Ordering::Relaxed);
+            drop(ni);
+        }
+    }
+}
+
+/* Hand a task that will never run again over to the reaper task, returning its control block (which must then be passed to finish_discarded once the state is unlocked) */
+fn discard_task(task: Task) -> Arc<TaskControl> {
+    let control = Arc::clone(&task.control);
+    DEFERRED_DROP.lock().push(Deferred::Task(task));
+    control
+}
+/* Wake anyone joining a discarded task, and the reaper task (so that it drops it) */
+fn finish_discarded(control: &TaskControl) {
+    finish_terminated(control);
+    DEFERRED_DROP_WAITERS.notify_one();
+}
+
+def_task_fn! {
+    task fn reaper_task() {
+        loop {
+            let tasks = DEFERRED_DROP_WAITERS.wait_until_try(||{
+                let mut tasks = DEFERRED_DROP.lock();
+                (!tasks.is_empty()).then(||core::mem::take(&mut *tasks))
+            });
+            // (frees their stacks, and drops their handles to their processes and address spaces)
+            drop(tasks);
         }
     }
 }
+/* Switch to the address space of the task we're about to resume: its process's, or the kernel's if it isn't part of one (so that a process's address space doesn't stay in use once it's gone).
+    Whichever one was active before is handed to the reaper task, as ours may be the last reference to it. Interruptions must be disabled. */
+fn switch_address_space(task: &Task) {
+    let paging = match task.process() {
+        Some(process) => process.paging_context(),
+        None => match PagingContext::kernel_context() { Some(paging) => paging, None => return },
+    };
+    // SAFETY: The kernel (including this stack) is mapped at the same place in every address space, and interruptions are disabled
+    if let Some(old) = unsafe { paging.activate_from_scheduler() } {
+        DEFERRED_DROP.lock().push(Deferred::PagingContext(old));
+        DEFERRED_DROP_WAITERS.notify_one();
+    }
+}
+/* Spawn the task that drops terminated tasks. Until it's been spawned, they're only queued. */
+pub fn spawn_reaper_task() {
+    reaper_task::spawn();
+}
 
 /* Resume the requested task, discarding the current one (if any). */
 #[inline]
@@ -178,13 +350,15 @@ pub fn resume_context(task: Task, state_guard: NoInterruptionsGuard) -> !{
 #[inline]
 pub(super) fn __resume_callback(args: (Task,NoInterruptionsGuard)){
     let (task, ni) = args;
+    task.control.set_running_on(super::get_cpu_num());
     
     // set active task
+    #[cfg(feature="dbg_lockdep")]
+    crate::sync::lockdep::switch_in(task.control.held_locks());
+    _EXECUTING_TASK_ID.store(task.task_id, Ordering::Relaxed);
     *_CURRENT_TASK.lock() = Some(task);
     _IS_EXECUTING_TASK.store(true, Ordering::Release);
     
-    // TODO: Switch paging context if necessary?
-    
     // Done! We are now "in" the task, with CURRENT_TASK set and the stack switched, so we can now enable interruptions without issue
     drop(ni);
 }
@@ -194,20 +368,24 @@ pub(super) fn __resume_callback(args: (Task,NoInterruptionsGuard)){
     Once this has been called, it is ok to call yield_to_scheduler.
     (calling this again will discard a large amount of the scheduler's state for the current CPU, so uh, don't)*/
 pub fn init_scheduler(stack: Option<alloc::boxed::Box<dyn crate::memory::alloc_util::AnyAllocatedStack>>){
+    // (our timers are advanced by whichever CPU drives the clock once we're online, so they must start from the current tick rather than from 0)
+    *_ASYNC_TIMERS.lock() = TimerWheel::starting_at(get_scheduler_ticks());
     let boot_task = {
         let mut state = _SCHEDULER_STATE.lock();
-        // initialise run queue?
-        state.run_queue.reserve(8);
-        
         // Initialise task
         // Note: resuming the task is undefined (however that is the same for all "currently active tasks" - as they must be paused first)
-        let task = unsafe { Task::new_with_rsp(TaskType::KernelTask, core::ptr::null_mut(), stack) };
+        let task = unsafe { Task::new_with_rsp(TaskType::KernelTask, "bootstrap", core::ptr::null_mut(), stack) };
         let task_id = task.task_id;
+        task.control.set_running_on(super::get_cpu_num());
         
         // All gucci :)
         // log message
-        klog!(Info, SCHEDULER, "Initialised scheduler on CPU {}. Bootstrapper task has become task {}.", super::get_cpu_num(), task_id);
+        klog!(Info, SCHEDULER, "Initialised scheduler on CPU {} (policy: {}). Bootstrapper task has become task {}.", super::get_cpu_num(), DefaultRunQueue::NAME, task_id);
         
+        _IDLE_STATE.start_timestamp.store(read_timestamp(), Ordering::Relaxed);
+        state.timers = TimerWheel::starting_at(get_scheduler_ticks());
+        _CPU_LOAD.online.store(true, Ordering::Release);
+        rcu::rcu_cpu_online();
         // Signal that scheduler is online
         BSP_SCHEDULER_READY.store(true,core::sync::atomic::Ordering::Release);
         // Return the task
@@ -218,43 +396,243 @@ pub fn init_scheduler(stack: Option<alloc::boxed::Box<dyn crate::memory::alloc_u
     // We should not be holding any locks once we initialise the current task to a non-None value,
     // as otherwise any unexpected event (or held lock) would attempt to yield to the scheduler
     // (which cannot be done if the scheduler lock is held, causing what I think is a stack overflow)
+    #[cfg(feature="dbg_lockdep")]
+    crate::sync::lockdep::switch_in(boot_task.control.held_locks());
+    _EXECUTING_TASK_ID.store(boot_task.task_id, Ordering::Relaxed);
     *_CURRENT_TASK.lock() = Some(boot_task);
 }
 /// If true, then the scheduler has been initialised on the bootstrap processor
 static BSP_SCHEDULER_READY: AtomicBool = AtomicBool::new(false);
 
-/* Push a new task to the current scheduler's run queue. */
+/* Push a new task to a scheduler's run queue. The current CPU is used unless the task isn't allowed there, or it's busy while another CPU is idle. */
 pub fn push_task(task: Task){
-    klog!(Debug, SCHEDULER, "Pushing new task: {}", task.task_id);
-    _SCHEDULER_STATE.lock().run_queue.push_back(task);
+    let cpu = select_cpu(task.affinity, super::get_cpu_num());
+    push_task_to(cpu, task);
 }
-/* Push a new task to another scheduler's run queue. */
+/* Push a new task to the given scheduler's run queue. */
 pub fn push_task_to(cpu: usize, task: Task){
     klog!(Debug, SCHEDULER, "Pushing new task to CPU {}: {}", cpu, task.task_id);
-    CpuLocal::get_for(&_SCHEDULER_STATE, cpu).lock().run_queue.push_back(task);
+    debug_assert!(task.affinity.contains(cpu), "Task {} pushed to CPU {}, which it isn't allowed to run on!", task.task_id, cpu);
+    _enqueue(cpu, task, EnqueueReason::New);
+}
+/* Push a task that was blocked (e.g. on a waiting list) back to a run queue. The CPU it last ran on is preferred, as its caches are most likely to still be warm. */
+pub fn wake_task(last_cpu: usize, task: Task){
+    let cpu = select_cpu(task.affinity, last_cpu);
+    klog!(Debug, SCHEDULER, "Waking task on CPU {}: {}", cpu, task.task_id);
+    _enqueue(cpu, task, EnqueueReason::Woken);
+}
+fn _enqueue(cpu: usize, task: Task, reason: EnqueueReason){
+    task.control.set_status(TaskStatus::Runnable);
+    let mut state = CpuLocal::get_for(&_SCHEDULER_STATE, cpu).lock();
+    state.run_queue.push(task, reason);
+    record_load(cpu, &state);
+    drop(state);
+    wake_cpu(cpu);
+}
+/* Wake the given CPU if it's idle, so that it checks its run queue again.
+    If MWAIT is available, setting wake_pending is enough (it's the line being monitored). Otherwise, a halted CPU only wakes on an interrupt, so it's sent a wakeup IPI.
+    (if its local APIC isn't enabled, the IPI can't be sent, and it won't notice until its next interrupt) */
+fn wake_cpu(cpu: usize){
+    let idle = CpuLocal::get_for(&_IDLE_STATE, cpu);
+    // Both of these are SeqCst, as are the idle loop's store to halted and halt_until_woken's load of wake_pending: either it sees wake_pending and doesn't halt, or we see that it's halted and interrupt it
+    idle.wake_pending.store(true, Ordering::SeqCst);
+    if cpu == super::get_cpu_num() || MWAIT_AVAILABLE.load(Ordering::Relaxed) { return; }
+    if idle.halted.load(Ordering::SeqCst) { crate::coredrivers::system_apic::send_wakeup(cpu); }
+}
+
+/* Update the load hint for the given CPU, from its (locked) state */
+fn record_load(cpu: usize, state: &SchedulerState){
+    CpuLocal::get_for(&_CPU_LOAD, cpu).queued.store(state.run_queue.len(), Ordering::Relaxed);
+}
+/* The CPUs that have initialised their scheduler */
+fn online_cpus() -> impl Iterator<Item=usize> {
+    (0..CpuLocal::slot_count(&_CPU_LOAD)).filter(|cpu|CpuLocal::get_for(&_CPU_LOAD, *cpu).online.load(Ordering::Acquire))
+}
+/* Choose which CPU a task that's ready to run should be queued on.
+    The preferred CPU is used if it's allowed and not busy. Otherwise, an idle CPU is used if possible, or failing that, the least busy one. */
+fn select_cpu(affinity: CpuSet, preferred: usize) -> usize {
+    let load_of = |cpu: usize|{
+        let load = CpuLocal::get_for(&_CPU_LOAD, cpu);
+        (!load.idle.load(Ordering::Relaxed), load.queued.load(Ordering::Relaxed))
+    };
+    let allowed = |cpu: &usize|affinity.contains(*cpu);
+    if allowed(&preferred) && load_of(preferred) == (false, 0) { return preferred; }
+    // (ties go to the preferred CPU)
+    let best = online_cpus().filter(allowed).min_by_key(|cpu|(load_of(*cpu), *cpu != preferred));
+    match best {
+        Some(cpu) => cpu,
+        None if allowed(&preferred) => preferred,
+        None => {
+            // None of the allowed CPUs have started yet, so the task will have to wait for one of them
+            let cpu = affinity.iter().next().expect("Task has an empty affinity mask!");
+            klog!(Warning, SCHEDULER, "No CPU the task is allowed on (affinity={:?}) is online. Queueing it on CPU {} anyway.", affinity, cpu);
+            cpu
+        },
+    }
+}
+/* Take a task from the busiest other CPU that has one we're allowed to run (for when we'd otherwise be idle).
+    This doesn't allocate, as it's called with interruptions disabled. */
+fn steal_task(cpu: usize) -> Option<Task> {
+    let queued = |victim: usize|CpuLocal::get_for(&_CPU_LOAD, victim).queued.load(Ordering::Relaxed);
+    let busiest = online_cpus().filter(|victim|*victim != cpu).max_by_key(|victim|queued(*victim))?;
+    // Try the busiest first, then the rest in order (the loads are only hints, so sorting them all wouldn't gain much)
+    let others = online_cpus().filter(|victim|*victim != cpu && *victim != busiest);
+    for victim in core::iter::once(busiest).chain(others).filter(|victim|queued(*victim) > 0) {
+        let mut state = CpuLocal::get_for(&_SCHEDULER_STATE, victim).lock();
+        let task = state.run_queue.steal(cpu);
+        record_load(victim, &state);
+        drop(state);
+        if let Some(task) = task {
+            klog!(Debug, SCHEDULER, "CPU {} stole task {} from CPU {}.", cpu, task.task_id, victim);
+            return Some(task);
+        }
+    }
+    None
+}
+/* Hand tasks from our run queue to any CPU that has at least two fewer queued (so that tasks spawned on one CPU spread out over all of them).
+    Tasks are moved one at a time (so that we never hold two CPUs' scheduler locks at once, and don't need to allocate). */
+fn balance_load(cpu: usize){
+    for target in online_cpus().filter(|target|*target != cpu) {
+        let task = {
+            let mut state = _SCHEDULER_STATE.lock();
+            let target_load = CpuLocal::get_for(&_CPU_LOAD, target).queued.load(Ordering::Relaxed);
+            let task = if target_load + 1 < state.run_queue.len() { state.run_queue.steal(target) } else { None };
+            record_load(cpu, &state);
+            task
+        };
+        if let Some(task) = task {
+            klog!(Debug, SCHEDULER, "Moving task {} from CPU {} to CPU {} to balance load.", task.task_id, cpu, target);
+            _enqueue(target, task, EnqueueReason::Woken);
+        }
+    }
+}
+
+/* Set the CPUs the current task may run on. If the current CPU isn't one of them, the task is moved immediately (so this must not be called while interruptions are disabled). */
+pub fn set_current_task_affinity(affinity: CpuSet){
+    assert!(!affinity.is_empty(), "Tasks must be allowed to run on at least one CPU!");
+    {
+        let mut current = _CURRENT_TASK.lock();
+        let Some(task) = current.as_mut() else { return };
+        task.set_affinity(affinity);
+    }
+    if !affinity.contains(super::get_cpu_num()) { yield_to_scheduler(SchedulerCommand::PushBack); }
+}
+
+/* Set the priority of the current task. Returns the previous priority (or None if no task is running). */
+pub fn set_current_task_priority(priority: TaskPriority) -> Option<TaskPriority> {
+    let mut current = _CURRENT_TASK.lock();
+    let task = current.as_mut()?;
+    let old = task.priority();
+    task.set_priority(priority);
+    Some(old)
+}
+/* A preemption point: yields to the scheduler if a task that should run before the current one is waiting (see RunQueue::should_preempt).
+    Long-running tasks (especially Background ones) should call this regularly, so that higher-priority work isn't held up behind them. */
+#[cfg_attr(feature="dbg_scheduler_yield_errinfo", track_caller)]
+pub fn preempt_point(){
+    // (we're allowed to yield here, so we can't be in an RCU read-side critical section)
+    rcu::rcu_quiescent_state();
+    let should_yield = {
+        let current = _CURRENT_TASK.lock();
+        let Some(task) = current.as_ref() else { return };
+        _SCHEDULER_STATE.lock().run_queue.should_preempt(task)
+    };
+    if should_yield { yield_to_scheduler(SchedulerCommand::PushBack); }
 }
 
-/* Advances the scheduler's clock by 1 tick. Called by the PIT. */
+/* Advances the scheduler's clock by 1 tick. Called by the PIT.
+    Only one CPU receives the PIT's interrupts, so it advances every online CPU's timers (waking any that have tasks to run) rather than just its own. */
 pub fn _scheduler_tick(){
+    let current_ticks = SCHEDULER_TICKS.fetch_add(1, Ordering::SeqCst)+1;
+    // (one at a time, as only one scheduler may be locked at a time)
+    for cpu in online_cpus() { advance_timers(cpu, current_ticks); }
+}
+/* Process the given CPU's timers up to the given tick */
+fn advance_timers(cpu: usize, current_ticks: usize){
+    let mut woken = false;
     {
-        let mut state = _SCHEDULER_STATE.lock();
-        let current_ticks = _SCHEDULER_TICKS.fetch_add(1, Ordering::SeqCst)+1;
+        let mut state = CpuLocal::get_for(&_SCHEDULER_STATE, cpu).lock();
         
-        // wake tasks sleeping on clock ticks
-        // We go in reverse order to ensure that the indexes still line up 
-        for i in (0..state.sleeping.len()).rev() {
-            let (_, wake_at) = state.sleeping[i];
-            if wake_at <= current_ticks {
-                // Take item
-                // Since this is either the back, or we've already processed the back, we can safely perform a swap_remove_back
-                let (task, _) = state.sleeping.swap_remove_back(i).unwrap();
-                klog!(Debug, SCHEDULER, "Waking task: {}", task.task_id);
-                // Push to run queue
-                state.run_queue.push_back(task);
-            }
+        // wake tasks whose sleep (or wait) has finished
+        let SchedulerState { timers, run_queue, .. } = &mut *state;
+        timers.advance(current_ticks, |_, action|{
+            let task = match action {
+                TimerAction::Wake(task) => task,
+                TimerAction::WaitTimeout(waiter) => match waiter.take_timed_out() {
+                    Some((task, _)) => task,
+                    None => return,  // (already notified)
+                },
+            };
+            klog!(Debug, SCHEDULER, "Waking task: {}", task.task_id);
+            task.control.set_status(TaskStatus::Runnable);
+            run_queue.push(task, EnqueueReason::Woken);
+            woken = true;
+        });
+        record_load(cpu, &state);
+    }
+    if woken { wake_cpu(cpu); }
+    // (now that the scheduler is unlocked, wake futures whose sleep has finished)
+    CpuLocal::get_for(&_ASYNC_TIMERS, cpu).lock().advance(current_ticks, |_, waiter|{ waiter.wake(); });
+    // (balanced by the CPU's next call to schedule, rather than here in the interrupt handler)
+    if current_ticks % BALANCE_INTERVAL_TICKS == 0 { CpuLocal::get_for(&_CPU_LOAD, cpu).balance_due.store(true, Ordering::Relaxed); }
+}
+
+/* Convert a duration to a number of scheduler ticks, rounding up (so that sleeps and timeouts are never shorter than requested) */
+pub fn duration_to_ticks(duration: Duration) -> usize {
+    let tick_rate = crate::coredrivers::timer_pit::TICK_RATE_HZ as u128;
+    duration.as_nanos().saturating_mul(tick_rate).div_ceil(1_000_000_000).try_into().unwrap_or(usize::MAX)
+}
+/* Suspend the current task for (at least) the given duration. A zero duration just yields. */
+#[cfg_attr(feature="dbg_scheduler_yield_errinfo", track_caller)]
+pub fn sleep(duration: Duration){
+    match duration_to_ticks(duration) {
+        0 => yield_to_scheduler(SchedulerCommand::PushBack),
+        ticks => yield_to_scheduler(SchedulerCommand::SleepNTicks(ticks)),
+    }
+}
+
+/* The asynchronous version of sleep: a future that completes once (at least) the given duration has passed. A zero duration just yields to the executor once. */
+pub fn sleep_async(duration: Duration) -> Sleep {
+    Sleep { ticks: duration_to_ticks(duration), waiter: None }
+}
+/// Returned by sleep_async
+pub struct Sleep {
+    ticks: usize,
+    /// Our timer, once we've started waiting
+    waiter: Option<Arc<AsyncWaiter>>,
+}
+impl core::future::Future for Sleep {
+    type Output = ();
+    fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> core::task::Poll<()> {
+        use core::task::Poll;
+        match &self.waiter {
+            Some(waiter) if waiter.is_notified() => Poll::Ready(()),
+            Some(waiter) => { waiter.update_waker(cx.waker()); Poll::Pending },
+            None if self.ticks == 0 => {
+                // Yield once
+                self.waiter = Some(Arc::new(AsyncWaiter::new(cx.waker().clone())));
+                self.waiter.as_ref().unwrap().wake();
+                Poll::Pending
+            },
+            None => {
+                let waiter = Arc::new(AsyncWaiter::new(cx.waker().clone()));
+                // (the timer is on the current CPU, so the sleep is timed by its ticks even if the future is later polled elsewhere)
+                let mut timers = _ASYNC_TIMERS.lock();
+                let wake_at = timers.now() + self.ticks;
+                timers.insert(wake_at, Arc::clone(&waiter));
+                drop(timers);
+                self.waiter = Some(waiter);
+                Poll::Pending
+            },
         }
     }
 }
+impl core::ops::Drop for Sleep {
+    fn drop(&mut self){
+        // (the timer stays in the wheel until it expires, but does nothing)
+        if let Some(waiter) = &self.waiter { waiter.cancel(); }
+    }
+}
 
 /* Returns true if the scheduler is currently executing a task. Returns false otherwise (i.e. it's instead executing bootstrap or scheduler code). */
 #[inline(always)]
@@ -266,10 +644,114 @@ pub fn is_executing_task() -> bool {
 pub fn get_executing_task_id() -> Option<usize> {
     _CURRENT_TASK.lock().as_ref().map(|t|t.task_id)
 }
-/* Get the current tick count on the current CPU's scheduler.
-This may differ between CPUs, and is not a good way of keeping time, but is lowlevel and does not rely on the RTC or anything complicated like that. 
+/* Same as get_executing_task_id, but without taking any locks. Like _IS_EXECUTING_TASK, this is only a heuristic (e.g. interrupt handlers see the task they interrupted). */
+#[inline(always)]
+pub fn get_executing_task_id_lockless() -> Option<usize> {
+    Some(_EXECUTING_TASK_ID.load(Ordering::Relaxed)).filter(|&id|id != usize::MAX)
+}
+/* Get the ID and name of the current task, or None if the scheduler is running right now instead of a specific task. */
+#[inline(always)]
+pub fn get_executing_task_info() -> Option<(usize,&'static str)> {
+    _CURRENT_TASK.lock().as_ref().map(|t|(t.task_id, t.name()))
+}
+/* Call the given closure with the current task, or return None if no task is running.
+    The current task is locked while the closure runs, so keep it short (and don't log from it). */
+pub(super) fn with_current_task<R>(f: impl FnOnce(&Task)->R) -> Option<R> {
+    _CURRENT_TASK.lock().as_ref().map(f)
+}
+/* Call the given closure with the current task's control block (see TaskControl), or return None if no task is running */
+pub fn with_current_task_control<R>(f: impl FnOnce(&TaskControl)->R) -> Option<R> {
+    _CURRENT_TASK.lock().as_ref().map(|t|f(&t.control))
+}
+/* Get the current tick count of the scheduler's clock (which is shared by every CPU).
+This is not a good way of keeping time, but is lowlevel and does not rely on the RTC or anything complicated like that. 
 Will probably be deprecated once support for actual time is added. */
 #[inline(always)]
 pub fn get_scheduler_ticks() -> usize {
-    _SCHEDULER_TICKS.load(Ordering::Relaxed)
-}
\ No newline at end of file
+    SCHEDULER_TICKS.load(Ordering::Relaxed)
+}
+
+/// What a task is currently doing, as far as its scheduler knows
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TaskState {
+    Running,
+    /// In the run queue, waiting for its turn
+    Ready,
+    /// Sleeping until the scheduler's clock reaches wake_at
+    Sleeping { wake_at: usize },
+}
+#[derive(Debug,Clone,Copy)]
+pub struct TaskSnapshot {
+    pub task_id: usize,
+    pub cpu: usize,
+    pub state: TaskState,
+    pub priority: TaskPriority,
+    /// The stack pointer saved when the task was suspended (see _cs_push). None if the task is running.
+    pub saved_rsp: Option<StackPointer>,
+}
+/* Take a snapshot of every task currently owned by a scheduler (i.e. running, in a run queue, or sleeping), on all CPUs.
+    Tasks that are waiting on a WaitingList are held by the list rather than a scheduler, so they aren't included. */
+pub fn snapshot_tasks() -> alloc::vec::Vec<TaskSnapshot> {
+    let mut tasks = alloc::vec::Vec::new();
+    _snapshot_tasks(true, |task|tasks.push(task)).unwrap();
+    tasks
+}
+/* Same as snapshot_tasks, but fills in the given buffer instead of allocating (returning how much of it was used - any tasks that don't fit are left out),
+    and returns None instead of waiting if any scheduler is currently locked (e.g. by the code that was interrupted). */
+pub fn try_snapshot_tasks_into(buffer: &mut [TaskSnapshot]) -> Option<usize> {
+    let mut len = 0;
+    _snapshot_tasks(false, |task|{
+        if let Some(slot) = buffer.get_mut(len) { *slot = task; len += 1; }
+    })?;
+    Some(len)
+}
+fn _snapshot_tasks(blocking: bool, mut push: impl FnMut(TaskSnapshot)) -> Option<()> {
+    for cpu in 0..CpuLocal::slot_count(&_SCHEDULER_STATE) {
+        let current = CpuLocal::get_for(&_CURRENT_TASK, cpu);
+        let current = if blocking { current.lock() } else { current.try_lock()? };
+        if let Some(task) = current.as_ref() {
+            push(TaskSnapshot { task_id: task.task_id, cpu, state: TaskState::Running, priority: task.priority(), saved_rsp: None });
+        }
+        drop(current);
+        let state = CpuLocal::get_for(&_SCHEDULER_STATE, cpu);
+        let state = if blocking { state.lock() } else { state.try_lock()? };
+        for task in state.run_queue.iter() {
+            push(TaskSnapshot { task_id: task.task_id, cpu, state: TaskState::Ready, priority: task.priority(), saved_rsp: Some(task.get_rsp()) });
+        }
+        for (wake_at,action) in state.timers.iter() {
+            if let TimerAction::Wake(task) = action {
+                push(TaskSnapshot { task_id: task.task_id, cpu, state: TaskState::Sleeping { wake_at }, priority: task.priority(), saved_rsp: Some(task.get_rsp()) });
+            }
+        }
+    }
+    Some(())
+}
+
+/// Idle time accounting for a CPU (see idle_stats)
+#[derive(Debug,Clone,Copy)]
+pub struct IdleStats {
+    pub cpu: usize,
+    /// Timestamp counter cycles spent halted, and since the scheduler was initialised on the CPU
+    pub idle_cycles: u64,
+    pub total_cycles: u64,
+    /// The number of times the CPU has halted
+    pub halts: usize,
+}
+impl IdleStats {
+    /// The proportion of time the CPU has spent running tasks (or the scheduler) rather than halted, in tenths of a percent
+    pub fn busy_permille(&self) -> u64 {
+        if self.total_cycles == 0 { return 0; }
+        let busy = self.total_cycles.saturating_sub(self.idle_cycles) as u128;
+        (busy * 1000 / self.total_cycles as u128) as u64
+    }
+}
+/* Get the idle time statistics of every CPU that has initialised its scheduler. */
+pub fn idle_stats() -> alloc::vec::Vec<IdleStats> {
+    let now = read_timestamp();
+    (0..CpuLocal::slot_count(&_IDLE_STATE)).filter_map(|cpu|{
+        let idle = CpuLocal::get_for(&_IDLE_STATE, cpu);
+        let start = idle.start_timestamp.load(Ordering::Relaxed);
+        if start == 0 { return None; }
+        Some(IdleStats { cpu, idle_cycles: idle.idle_cycles.load(Ordering::Relaxed), total_cycles: now.wrapping_sub(start), halts: idle.halts.load(Ordering::Relaxed) })
+    }).collect()
+}
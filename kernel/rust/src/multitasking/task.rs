@@ -1,10 +1,42 @@
 use super::scheduler::StackPointer;
+use super::runqueue::TaskPriority;
+use super::handle::TaskControl;
+use super::process::Process;
 
 use crate::memory::alloc_util::AnyAllocatedStack;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 
 static NEXT_ID: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
 
+/// A set of CPUs (by CPU number, up to 64), e.g. the CPUs a task is allowed to run on
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct CpuSet(u64);
+impl CpuSet {
+    pub const ALL: Self = Self(u64::MAX);
+    pub const EMPTY: Self = Self(0);
+
+    pub const fn single(cpu: usize) -> Self {
+        Self::EMPTY.with(cpu)
+    }
+    pub const fn with(self, cpu: usize) -> Self {
+        if cpu >= 64 { self } else { Self(self.0 | (1<<cpu)) }
+    }
+    pub const fn without(self, cpu: usize) -> Self {
+        if cpu >= 64 { self } else { Self(self.0 & !(1<<cpu)) }
+    }
+    pub const fn contains(&self, cpu: usize) -> bool {
+        cpu < 64 && self.0 & (1<<cpu) != 0
+    }
+    pub const fn is_empty(&self) -> bool { self.0 == 0 }
+    pub fn iter(self) -> impl Iterator<Item=usize> {
+        (0..64).filter(move |cpu|self.contains(*cpu))
+    }
+}
+impl core::default::Default for CpuSet {
+    fn default() -> Self { Self::ALL }
+}
+
 /// The type of task (i.e. where it came from / what it's for)
 #[derive(Debug)]
 pub enum TaskType {
@@ -14,41 +46,75 @@ pub enum TaskType {
 pub struct Task {
     pub(super) task_id: usize,
     pub(super) task_type: TaskType,
+    pub(super) priority: TaskPriority,
+    /// The CPUs this task may be run on
+    pub(super) affinity: CpuSet,
+    /// Used by the run queue (see MlfqRunQueue): the task's current level, and how much of its allotment at that level has been used
+    pub(super) sched_level: usize,
+    pub(super) sched_used: usize,
+    /// Shared with the task registry and any TaskHandles
+    pub(super) control: Arc<TaskControl>,
+    /// The process the task is part of (None for kernel tasks that aren't part of one). This keeps the process alive until the task is dropped.
+    pub(super) process: Option<Process>,
     
     pub(super) rsp: usize,
     pub(super) stack_allocation: Option<Box<dyn AnyAllocatedStack>>,
 }
 impl Task {
-    pub unsafe fn new_with_rsp(task_type: TaskType, rsp: StackPointer, stack_allocation: Option<Box<dyn AnyAllocatedStack>>) -> Self {
+    pub unsafe fn new_with_rsp(task_type: TaskType, name: &'static str, rsp: StackPointer, stack_allocation: Option<Box<dyn AnyAllocatedStack>>) -> Self {
+        let task_id = NEXT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        let control = Arc::new(TaskControl::new(task_id, name));
+        super::handle::register_task(Arc::clone(&control));
         Self {
-            task_id: NEXT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed),
+            task_id,
             task_type,
+            priority: TaskPriority::default(),
+            affinity: CpuSet::ALL,
+            sched_level: 0, sched_used: 0,
+            control,
+            process: None,
             rsp: rsp as usize,
             stack_allocation: stack_allocation,
         }
     }
     /// Create a new task using the given stack and entry point. This calls _cs_new to initialise the stack with the necessary function pointer, and then returns a suitable task.
-    pub fn new_kernel_task(entry_point: extern "sysv64" fn() -> !, stack: Box<dyn AnyAllocatedStack>) -> Task {
+    pub fn new_kernel_task(name: &'static str, entry_point: extern "sysv64" fn() -> !, stack: Box<dyn AnyAllocatedStack>) -> Task {
         // Initialise stack and get RSP
         // SAFETY: This MUST have exclusive access to the given stack, which is enforced (hopefully) by ownership rules
         // (stack must grow downwards. TODO: Kill myself if I'm ever porting this to an architecture where the stack grows upwards)
         unsafe {
             let rsp = super::arch::context_switch::_cs_new(entry_point, stack.bottom_vaddr() as *const u8);
-            Self::new_with_rsp(TaskType::KernelTask, rsp, Some(stack))
+            Self::new_with_rsp(TaskType::KernelTask, name, rsp, Some(stack))
         }
     }
-    pub fn new_kernel_task_v<T:Sized>(entry_point: extern "sysv64" fn(*mut T) -> !, stack: Box<dyn AnyAllocatedStack>, arg1: *mut T) -> Task {
+    pub fn new_kernel_task_v<T:Sized>(name: &'static str, entry_point: extern "sysv64" fn(*mut T) -> !, stack: Box<dyn AnyAllocatedStack>, arg1: *mut T) -> Task {
         unsafe {
             // SAFETY: It's fine to cast *mut T to *mut u8 as we've already checked that the arg1 pointer and the argument in the fn(...) are the same type
             let rsp = super::arch::context_switch::_cs_newv(core::mem::transmute(entry_point), stack.bottom_vaddr() as *const u8, arg1 as *mut u8);
-            Self::new_with_rsp(TaskType::KernelTask, rsp, Some(stack))
+            Self::new_with_rsp(TaskType::KernelTask, name, rsp, Some(stack))
         }
     }
     
     pub fn task_id(&self) -> usize { self.task_id }
+    pub fn name(&self) -> &'static str { self.control.name() }
     pub fn task_type(&self) -> &TaskType {
         &self.task_type
     }
+    pub fn control(&self) -> &Arc<TaskControl> { &self.control }
+    pub fn process(&self) -> Option<&Process> { self.process.as_ref() }
+    pub(super) fn set_process(&mut self, process: Process){
+        self.process = Some(process)
+    }
+    pub fn priority(&self) -> TaskPriority { self.priority }
+    pub fn set_priority(&mut self, priority: TaskPriority){
+        self.priority = priority
+    }
+    pub fn affinity(&self) -> CpuSet { self.affinity }
+    /* Restrict the task to the given CPUs. This doesn't move it if it's already queued on another CPU - that happens the next time it yields. */
+    pub fn set_affinity(&mut self, affinity: CpuSet){
+        assert!(!affinity.is_empty(), "Task {} must be allowed to run on at least one CPU!", self.task_id);
+        self.affinity = affinity
+    }
     
     #[inline]
     pub(super) fn set_rsp(&mut self, rsp: StackPointer){
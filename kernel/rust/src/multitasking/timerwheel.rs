@@ -0,0 +1,164 @@
+//! A hierarchical timer wheel, for keeping track of things that need doing at a particular scheduler tick (e.g. waking sleeping tasks).
+//! Inserting is O(1), and advancing by one tick is O(1) plus the number of timers that expire (or are moved down a level) on that tick, no matter how many timers are pending.
+
+use alloc::vec::Vec;
+
+const WHEEL_BITS: usize = 6;
+const WHEEL_SIZE: usize = 1<<WHEEL_BITS;
+const WHEEL_MASK: usize = WHEEL_SIZE-1;
+/// Level N holds timers due between 64^N and 64^(N+1) ticks away. Anything further than 64^4 ticks away (~46 hours at 100Hz) is kept in the overflow list until it gets closer.
+const WHEEL_LEVELS: usize = 4;
+
+pub struct TimerWheel<T> {
+    /// The last tick that has been processed
+    now: usize,
+    levels: [[Vec<(usize,T)>; WHEEL_SIZE]; WHEEL_LEVELS],
+    overflow: Vec<(usize,T)>,
+    len: usize,
+}
+impl<T> TimerWheel<T> {
+    pub const fn new() -> Self {
+        Self::starting_at(0)
+    }
+    /* A wheel whose clock starts at the given tick (i.e. which will next process the tick after it) */
+    pub const fn starting_at(now: usize) -> Self {
+        Self {
+            now,
+            levels: [const { [const { Vec::new() }; WHEEL_SIZE] }; WHEEL_LEVELS],
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+    /// The last tick that has been processed (see advance)
+    pub fn now(&self) -> usize { self.now }
+    /// The number of pending timers
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /* Add a timer that expires at the given tick. Deadlines that have already passed expire on the next tick. */
+    pub fn insert(&mut self, deadline: usize, item: T) {
+        self.len += 1;
+        self.place(deadline.max(self.now+1), item);
+    }
+    fn place(&mut self, deadline: usize, item: T) {
+        let delta = deadline.saturating_sub(self.now);
+        for level in 0..WHEEL_LEVELS {
+            if delta < 1<<(WHEEL_BITS*(level+1)) {
+                let slot = (deadline >> (WHEEL_BITS*level)) & WHEEL_MASK;
+                self.levels[level][slot].push((deadline, item));
+                return;
+            }
+        }
+        self.overflow.push((deadline, item));
+    }
+
+    /* Process every tick up to and including `now`, calling `expired` with each timer that expires (in order of deadline). */
+    pub fn advance(&mut self, now: usize, mut expired: impl FnMut(usize, T)) {
+        while self.now < now {
+            self.now += 1;
+            let tick = self.now;
+            // Once a level has gone all the way around, move the next slot of the level above down (highest first, so things can move down more than one level at once)
+            let mut cascade_levels = 0;
+            while cascade_levels < WHEEL_LEVELS && (tick >> (WHEEL_BITS*cascade_levels)) & WHEEL_MASK == 0 { cascade_levels += 1; }
+            if cascade_levels == WHEEL_LEVELS {
+                for (deadline, item) in core::mem::take(&mut self.overflow) { self.place(deadline, item); }
+            }
+            for level in (1..cascade_levels.min(WHEEL_LEVELS-1)+1).rev() {
+                let slot = (tick >> (WHEEL_BITS*level)) & WHEEL_MASK;
+                for (deadline, item) in core::mem::take(&mut self.levels[level][slot]) { self.place(deadline, item); }
+            }
+
+            let due = core::mem::take(&mut self.levels[0][tick & WHEEL_MASK]);
+            self.len -= due.len();
+            for (deadline, item) in due { expired(deadline, item); }
+        }
+    }
+
+    /// Every pending timer (in no particular order), with its deadline
+    pub fn iter(&self) -> impl Iterator<Item=(usize,&T)> {
+        self.levels.iter().flat_map(|level|level.iter()).flat_map(|slot|slot.iter())
+            .chain(self.overflow.iter()).map(|(deadline, item)|(*deadline, item))
+    }
+}
+impl<T> core::default::Default for TimerWheel<T> {
+    fn default() -> Self { Self::new() }
+}
+
+// (the wheel itself is tested on the host, see kernel/modeltests/tests/timerwheel.rs)
+#[cfg(not(loom))]
+pub(crate) mod timerwheel_test {
+    use super::*;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+    use core::time::Duration;
+    use crate::sync::WaitingList;
+    use crate::sync::kspin::KMutex;
+    use crate::multitasking::scheduler::{sleep,get_scheduler_ticks,duration_to_ticks};
+    use crate::multitasking::util::def_task_fn;
+
+    static ORDER: KMutex<Vec<u64>> = KMutex::new(Vec::new());
+    def_task_fn! {
+        task fn sleeper(millis: u64) {
+            sleep(Duration::from_millis(millis));
+            ORDER.lock().push(millis);
+        }
+    }
+    /* Check that sleeping tasks are woken in order of their deadlines (not the order they went to sleep in) */
+    fn check_sleep_order() -> crate::debugshell::CommandResult {
+        ORDER.lock().clear();
+        let sleeps = [120, 40, 80];
+        let tasks: Vec<_> = sleeps.iter().map(|millis|sleeper::spawn(*millis)).collect();
+        for task in tasks.iter() { task.join().map_err(|e|format!("Sleeper failed: {:?}", e))?; }
+        let order = core::mem::take(&mut *ORDER.lock());
+        if order != [40, 80, 120] { return Err(format!("Sleepers woke in order {:?}, expected [40, 80, 120]", order)); }
+        Ok(())
+    }
+
+    static FIRST: WaitingList = WaitingList::new();
+    static SECOND: WaitingList = WaitingList::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+    static WAITING_ON_SECOND: AtomicBool = AtomicBool::new(false);
+    static WOKEN_AT: AtomicUsize = AtomicUsize::new(0);
+    const TIMEOUT: Duration = Duration::from_millis(100);
+    def_task_fn! {
+        task fn timed_waiter() -> bool {
+            // Notified well before the timeout, which cancels it...
+            let notified = FIRST.wait_until_timeout(||READY.load(Ordering::Acquire), TIMEOUT);
+            // ...so when its timer goes off, it mustn't wake us from this (untimed) wait
+            SECOND.wait_releasing(||WAITING_ON_SECOND.store(true, Ordering::Release));
+            WOKEN_AT.store(get_scheduler_ticks(), Ordering::Release);
+            notified
+        }
+    }
+    /* Check that a timed wait that's notified in time doesn't time out (and that its timer, which still expires later, is ignored), and that one that isn't does time out, on time */
+    fn check_timed_wait() -> crate::debugshell::CommandResult {
+        READY.store(false, Ordering::Release); WAITING_ON_SECOND.store(false, Ordering::Release);
+        let task = timed_waiter::spawn();
+        sleep(Duration::from_millis(20));
+        READY.store(true, Ordering::Release);
+        FIRST.notify_all();
+        while !WAITING_ON_SECOND.load(Ordering::Acquire) { sleep(Duration::from_millis(1)); }
+        // Wait until well after the first wait's timeout would have expired, then wake the task
+        sleep(TIMEOUT * 2);
+        let notify_tick = get_scheduler_ticks();
+        SECOND.notify_all();
+        let notified = *task.join().map_err(|e|format!("Waiter failed: {:?}", e))?;
+        if !notified { return Err(format!("A wait that was notified in time reported a timeout")); }
+        let woken_at = WOKEN_AT.load(Ordering::Acquire);
+        if woken_at < notify_tick { return Err(format!("A cancelled timeout woke its task from a later wait (at tick {}, but it was only notified at tick {})", woken_at, notify_tick)); }
+
+        // An un-notified wait gives up, and not before its timeout
+        let start = get_scheduler_ticks();
+        let notified = FIRST.wait_until_timeout(||false, Duration::from_millis(30));
+        let waited = get_scheduler_ticks() - start;
+        if notified { return Err(format!("A wait whose predicate was never true reported being notified")); }
+        if waited < duration_to_ticks(Duration::from_millis(30)) { return Err(format!("A 30ms timeout expired after only {} ticks", waited)); }
+        Ok(())
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        check_sleep_order()?;
+        check_timed_wait()
+    }
+}
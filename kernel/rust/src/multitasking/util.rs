@@ -10,21 +10,38 @@ pub fn allocate_kernel_task_stack() -> Option<impl AnyAllocatedStack> {
 }
 
 /// Create and start a new kernel task on the current CPU, with the default stack size and settings
-/// Returns the task ID.
-pub fn spawn_kernel_task(entry: TaskEntryPoint) -> usize {
+/// The task is part of the same process as the current task (if any).
+/// Returns a handle to the task (as the entry point never returns, joining it only waits for it to terminate).
+pub fn spawn_kernel_task(name: &'static str, entry: TaskEntryPoint) -> TaskHandle<()> {
+    spawn_kernel_task_in(current_process(), name, entry)
+}
+/// Same as spawn_kernel_task, but the task is part of the given process instead
+pub fn spawn_kernel_task_in(process: Option<Process>, name: &'static str, entry: TaskEntryPoint) -> TaskHandle<()> {
     let kstack = allocate_kernel_task_stack().unwrap();
-    let task = super::Task::new_kernel_task(entry, alloc::boxed::Box::new(kstack));
-    let task_id = task.task_id();
-    super::scheduler::push_task(task);
-    task_id
+    let task = super::Task::new_kernel_task(name, entry, alloc::boxed::Box::new(kstack));
+    let (result, promise) = Promise::new();
+    let _ = result.complete(());
+    _start_task(task, process, promise)
 }
 
-pub fn spawn_kernel_task_v<T:Sized>(entry: TaskEntryPointV<T>, arg: *mut T) -> usize {
+/// Returns a handle to the task, whose result is fulfilled using the given promise (which should be completed by the task itself, e.g. through arg)
+pub fn spawn_kernel_task_v<T:Sized,R>(name: &'static str, entry: TaskEntryPointV<T>, arg: *mut T, result: Promise<R>) -> TaskHandle<R> {
+    spawn_kernel_task_v_with_priority(name, entry, arg, result, TaskPriority::default())
+}
+pub fn spawn_kernel_task_v_with_priority<T:Sized,R>(name: &'static str, entry: TaskEntryPointV<T>, arg: *mut T, result: Promise<R>, priority: TaskPriority) -> TaskHandle<R> {
     let kstack = allocate_kernel_task_stack().unwrap();
-    let task = super::Task::new_kernel_task_v(entry, alloc::boxed::Box::new(kstack), arg);
-    let task_id = task.task_id();
+    let mut task = super::Task::new_kernel_task_v(name, entry, alloc::boxed::Box::new(kstack), arg);
+    task.set_priority(priority);
+    _start_task(task, current_process(), result)
+}
+fn _start_task<R>(mut task: super::Task, process: Option<Process>, result: Promise<R>) -> TaskHandle<R> {
+    let handle = TaskHandle::new(alloc::sync::Arc::clone(task.control()), result);
+    if let Some(process) = process {
+        process.add_task(task.control());
+        task.set_process(process);
+    }
     super::scheduler::push_task(task);
-    task_id
+    handle
 }
 
 macro_rules! def_task_fn {
@@ -50,12 +67,15 @@ macro_rules! def_task_fn {
                 }
                 terminate_current_task();
             }
-            /// Spawn the task as a new Kernel Task, returning the task ID and a promise for the return value (or a Promise<()> which is fulfilled upon task completion, if no return value is given)
-            pub fn spawn($($arg : $argty,)*) -> (usize, Promise<def_task_fn!(@return_type, $($rt)?)>) {
+            /// Spawn the task as a new Kernel Task, returning a handle that can be joined to get the return value (or () once the task completes, if no return value is given)
+            pub fn spawn($($arg : $argty,)*) -> $crate::multitasking::TaskHandle<def_task_fn!(@return_type, $($rt)?)> {
+                spawn_with_priority($crate::multitasking::TaskPriority::Normal, $($arg,)*)
+            }
+            /// Same as spawn(), but with the given priority instead of Normal
+            pub fn spawn_with_priority(__priority: $crate::multitasking::TaskPriority, $($arg : $argty,)*) -> $crate::multitasking::TaskHandle<def_task_fn!(@return_type, $($rt)?)> {
                 let (__out_tx, __out_rx) = Promise::<def_task_fn!(@return_type, $($rt)?)>::new();
                 let args = Box::new(Args{$($arg,)* __out: __out_tx});
-                let task_id = $crate::multitasking::util::spawn_kernel_task_v(entry, Box::into_raw(args));
-                (task_id, __out_rx)
+                $crate::multitasking::util::spawn_kernel_task_v_with_priority(stringify!($name), entry, Box::into_raw(args), __out_rx, __priority)
             }
         }
     };
@@ -65,6 +85,10 @@ use crate::memory::alloc_util::AnyAllocatedStack;
 use crate::memory::paging::{pageFlags, PageAlignedValue, PageAllocationSizeT, KALLOCATION_KERNEL_STACK};
 use crate::memory::paging::global_pages::KERNEL_PTABLE;
 use crate::memory::unified;
+use super::TaskPriority;
+use super::handle::TaskHandle;
+use super::process::{Process,current_process};
+use crate::sync::promise::Promise;
 
 def_task_fn! {
     pub task fn call_task_dyn(closure:Box<dyn FnOnce()>){
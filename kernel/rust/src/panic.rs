@@ -50,12 +50,11 @@ fn panic(_info: &PanicInfo) -> ! {
         let context = multitasking::ExecutionContext::current();
         emergency_kernel_log!("Execution Context: {}\r\n", context);
         
-        // // Forcefully acquire a reference to the current writer, bypassing the lock (which may have been locked at the time of the panic and will not unlock as we don't have stack unwinding)
-        // // Requires MMIO to be mapped - med risk
-        // let mut writer = unsafe{let wm=&*VGA_WRITER;wm.force_unlock();wm.lock()};
-        // writer.set_colour(display_vga::VGAColour::new(display_vga::BaseColour::LightGray,display_vga::BaseColour::Red,true,false));
-        // // Write message and location to screen
-        // let _ = write!(writer, "\n\nKERNEL PANICKED (@{}): {}", context, _info);
+        // Paint the panic on screen, bypassing the console's lock (which may have been locked at the time of the panic and will not unlock as we don't have stack unwinding)
+        // Requires MMIO to be mapped - med risk (each console does nothing if it was never mapped)
+        macro_rules! panic_message { () => { format_args!("KERNEL PANIC on CPU {} (unrecoverable)\n\nExecution Context: {}\n\n{}\n\nThe system has been halted.", cpu_num, context, _info) } }
+        unsafe { crate::coredrivers::display_framebuffer::show_panic_screen(panic_message!()); }
+        unsafe { crate::coredrivers::display_vga::show_panic_screen(panic_message!()); }
         
         // Attempt to perform backtrace
         // TODO
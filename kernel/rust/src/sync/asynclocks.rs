@@ -0,0 +1,111 @@
+//! Acquiring locks from futures (see multitasking::executor).
+//! WMutex and WRwLock futures queue up on the lock's waiting list with a ticket, just like a task would, and are woken when it's their turn (see wlock).
+//! Spinlocks have nobody to tell them when they've been unlocked, so their futures simply try the lock each time they're polled. They wake themselves straight away a few times
+//!     (the executor treats a future that wakes itself like a task calling spin_yield, so whoever holds the lock gets a chance to release it), and then fall back to trying once per scheduler tick, so that a long-held lock doesn't keep the executor spinning.
+//! As with any spinlock, don't hold the guard across an .await unless you have to.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context,Poll};
+use core::time::Duration;
+use lock_api::{RawMutex,RawRwLock,Mutex,MutexGuard,RwLock,RwLockReadGuard,RwLockWriteGuard};
+use super::baselocks::{BaseMutexRaw,BaseRwLockRaw,MutexStrategy,RwLockStrategy};
+use crate::multitasking::scheduler::{sleep_async,Sleep};
+
+/// Raw mutexes that futures can wait for (see MutexAsyncExt)
+pub trait RawMutexAsync: RawMutex {
+    /// A future which locks the mutex. If it's dropped before completing, the mutex is left as it was.
+    fn lock_async(&self) -> impl Future<Output=()> + Send + '_;
+}
+/// Raw readers-writer locks that futures can wait for (see RwLockAsyncExt)
+pub trait RawRwLockAsync: RawRwLock {
+    /// Futures which lock the lock for reading/writing. If they're dropped before completing, the lock is left as it was.
+    fn lock_shared_async(&self) -> impl Future<Output=()> + Send + '_;
+    fn lock_exclusive_async(&self) -> impl Future<Output=()> + Send + '_;
+}
+
+pub trait MutexAsyncExt<R:RawMutex,T:?Sized> {
+    /// The asynchronous version of lock()
+    fn lock_async<'a>(&'a self) -> impl Future<Output=MutexGuard<'a,R,T>> where R: 'a, T: 'a;
+}
+impl<R:RawMutexAsync,T:?Sized> MutexAsyncExt<R,T> for Mutex<R,T> {
+    fn lock_async<'a>(&'a self) -> impl Future<Output=MutexGuard<'a,R,T>> where R: 'a, T: 'a {
+        async move {
+            // SAFETY: We only lock it through the raw lock, and make a guard once we have
+            unsafe { self.raw() }.lock_async().await;
+            unsafe { self.make_guard_unchecked() }
+        }
+    }
+}
+
+pub trait RwLockAsyncExt<R:RawRwLock,T:?Sized> {
+    /// The asynchronous version of read()
+    fn read_async<'a>(&'a self) -> impl Future<Output=RwLockReadGuard<'a,R,T>> where R: 'a, T: 'a;
+    /// The asynchronous version of write()
+    fn write_async<'a>(&'a self) -> impl Future<Output=RwLockWriteGuard<'a,R,T>> where R: 'a, T: 'a;
+}
+impl<R:RawRwLockAsync,T:?Sized> RwLockAsyncExt<R,T> for RwLock<R,T> {
+    fn read_async<'a>(&'a self) -> impl Future<Output=RwLockReadGuard<'a,R,T>> where R: 'a, T: 'a {
+        async move {
+            // SAFETY: (see MutexAsyncExt)
+            unsafe { self.raw() }.lock_shared_async().await;
+            unsafe { self.make_read_guard_unchecked() }
+        }
+    }
+    fn write_async<'a>(&'a self) -> impl Future<Output=RwLockWriteGuard<'a,R,T>> where R: 'a, T: 'a {
+        async move {
+            unsafe { self.raw() }.lock_exclusive_async().await;
+            unsafe { self.make_write_guard_unchecked() }
+        }
+    }
+}
+
+impl<S:MutexStrategy+Sync> RawMutexAsync for BaseMutexRaw<S> {
+    fn lock_async(&self) -> impl Future<Output=()> + Send + '_ {
+        RetryUntil::new(||self.try_lock())
+    }
+}
+impl<S:RwLockStrategy+Sync> RawRwLockAsync for BaseRwLockRaw<S> {
+    fn lock_shared_async(&self) -> impl Future<Output=()> + Send + '_ {
+        RetryUntil::new(||self.try_lock_shared())
+    }
+    fn lock_exclusive_async(&self) -> impl Future<Output=()> + Send + '_ {
+        RetryUntil::new(||self.try_lock_exclusive())
+    }
+}
+
+/// How many times RetryUntil tries again straight away, before it starts waiting a tick between attempts
+const IMMEDIATE_RETRIES: usize = 8;
+/// A future which calls the closure each time it's polled, until it returns true
+struct RetryUntil<F> {
+    attempt: F,
+    failures: usize,
+    /// Set while we're waiting before the next attempt
+    backoff: Option<Sleep>,
+}
+impl<F:Fn()->bool+Unpin> RetryUntil<F> {
+    fn new(attempt: F) -> Self {
+        Self { attempt, failures: 0, backoff: None }
+    }
+}
+impl<F:Fn()->bool+Unpin> Future for RetryUntil<F> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if let Some(backoff) = this.backoff.as_mut() {
+            if Pin::new(backoff).poll(cx).is_pending() { return Poll::Pending; }
+            this.backoff = None;
+        }
+        if (this.attempt)() { return Poll::Ready(()); }
+        this.failures += 1;
+        if this.failures < IMMEDIATE_RETRIES {
+            cx.waker().wake_by_ref();
+        } else {
+            // (rounded up to a tick)
+            let mut backoff = sleep_async(Duration::from_nanos(1));
+            let _ = Pin::new(&mut backoff).poll(cx);
+            this.backoff = Some(backoff);
+        }
+        Poll::Pending
+    }
+}
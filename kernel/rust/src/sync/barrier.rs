@@ -0,0 +1,104 @@
+//! Reusable barriers, for a fixed number of tasks to wait for each other, built on a WaitingList.
+//! Once the last task arrives, everyone is released and the barrier resets for the next round (each round is a "generation").
+//! These may only be used in task context.
+
+use core::time::Duration;
+use super::WaitingList;
+use super::kspin::KMutex;
+
+struct BarrierState {
+    /// The number of tasks waiting in the current generation
+    arrived: usize,
+    generation: usize,
+}
+
+pub struct Barrier {
+    parties: usize,
+    state: KMutex<BarrierState>,
+    waiters: WaitingList,
+}
+impl Barrier {
+    /* Create a barrier that releases tasks once the given number of them are waiting */
+    pub const fn new(parties: usize) -> Self {
+        Self { parties, state: KMutex::new(BarrierState { arrived: 0, generation: 0 }), waiters: WaitingList::new() }
+    }
+    /// The number of tasks needed to release the barrier
+    pub fn parties(&self) -> usize { self.parties }
+
+    /* Arrive at the barrier, and wait until everyone else has. Returns true for exactly one of the tasks in each generation (the last to arrive), e.g. to elect one to do some cleanup. */
+    pub fn wait(&self) -> bool {
+        let generation = core::cell::Cell::new(None);
+        let leader = self.waiters.wait_until_try(||self._arrive_or_check(&generation));
+        if leader { self.waiters.notify_all(); }
+        leader
+    }
+    /* Same as wait, but gives up after the given timeout, returning None. Tasks that give up no longer count as having arrived. */
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<bool> {
+        let generation = core::cell::Cell::new(None);
+        match self.waiters.wait_until_try_timeout(||self._arrive_or_check(&generation), timeout) {
+            Some(true) => { self.waiters.notify_all(); Some(true) },
+            Some(false) => Some(false),
+            None => {
+                let mut state = self.state.lock();
+                // (if the barrier was released just as we timed out, we count as having got through)
+                if Some(state.generation) != generation.get() { return Some(false); }
+                state.arrived -= 1;
+                None
+            },
+        }
+    }
+    /* The first time this is called (with the list locked), arrive at the barrier, releasing it if we're the last. After that, check whether it's been released. */
+    fn _arrive_or_check(&self, generation: &core::cell::Cell<Option<usize>>) -> Option<bool> {
+        let mut state = self.state.lock();
+        if let Some(g) = generation.get() { return (state.generation != g).then_some(false); }
+        state.arrived += 1;
+        if state.arrived >= self.parties {
+            state.arrived = 0;
+            state.generation = state.generation.wrapping_add(1);
+            return Some(true);
+        }
+        generation.set(Some(state.generation));
+        None
+    }
+}
+
+pub(crate) mod barrier_test {
+    use super::*;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use core::sync::atomic::{AtomicUsize,Ordering};
+    use crate::multitasking::util::def_task_fn;
+
+    const PARTIES: usize = 4;
+    const ROUNDS: usize = 5;
+    static BARRIER: Barrier = Barrier::new(PARTIES);
+    static PROGRESS: [AtomicUsize; PARTIES] = [const { AtomicUsize::new(0) }; PARTIES];
+    static LEADERS: AtomicUsize = AtomicUsize::new(0);
+
+    // Each round, a task records its progress and waits. Once released, nobody may be behind the round it's in.
+    def_task_fn! {
+        task fn party(i: usize) -> bool {
+            for round in 0..ROUNDS {
+                PROGRESS[i].store(round+1, Ordering::SeqCst);
+                if BARRIER.wait() { LEADERS.fetch_add(1, Ordering::SeqCst); }
+                if PROGRESS.iter().any(|p|p.load(Ordering::SeqCst) < round+1) { return false; }
+            }
+            true
+        }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        LEADERS.store(0, Ordering::SeqCst);
+        for p in PROGRESS.iter() { p.store(0, Ordering::SeqCst); }
+        let tasks: Vec<_> = (0..PARTIES).map(|i|party::spawn(i)).collect();
+        for task in tasks.iter() {
+            if !*task.join().map_err(|e|format!("Task failed: {:?}", e))? { return Err(format!("A task was released before everyone arrived")); }
+        }
+        let leaders = LEADERS.load(Ordering::SeqCst);
+        if leaders != ROUNDS { return Err(format!("{} leaders were elected over {} rounds", leaders, ROUNDS)); }
+        // On our own, we can't release the barrier, so this must time out (and leave the barrier as it was)
+        if BARRIER.wait_timeout(Duration::from_millis(20)).is_some() { return Err(format!("wait_timeout returned without everyone arriving")); }
+        if BARRIER.state.lock().arrived != 0 { return Err(format!("Timed out task still counted as arrived")); }
+        Ok(())
+    }
+}
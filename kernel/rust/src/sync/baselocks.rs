@@ -1,16 +1,22 @@
 
 use lock_api::{RawMutex,RawRwLock,GuardSend};
 use core::sync::atomic::*;
+#[cfg(feature="dbg_lockdep")]
+use super::lockdep;
+#[cfg(feature="dbg_spin_timeout")]
+use super::spintimeout::{LockHolder,SpinTimer,DiagnosableLock};
 
 pub trait MutexStrategy {
     const INIT: Self;
+    /// True if tasks waiting for the lock yield to the scheduler (e.g. YMutex), rather than spinning (e.g. KMutex). Only locks that yield may be held across a yield.
+    const YIELDS: bool;
     /// Called after the mutex has unlocked
     fn on_unlock(&self);
     /// Called when an attempt to lock the mutex is made, but fails
     fn lock_relax(&self);
 }
 
-pub struct BaseMutexRaw<S:MutexStrategy>(AtomicBool,S);
+pub struct BaseMutexRaw<S:MutexStrategy>(AtomicBool,S, #[cfg(feature="dbg_spin_timeout")] LockHolder);
 impl<S:MutexStrategy> BaseMutexRaw<S> {
     pub fn is_locked(&self) -> bool {
         self.0.load(Ordering::Relaxed)
@@ -18,23 +24,56 @@ impl<S:MutexStrategy> BaseMutexRaw<S> {
 }
 unsafe impl<S:MutexStrategy> RawMutex for BaseMutexRaw<S> {
     type GuardMarker = GuardSend;
-    const INIT: Self = Self(AtomicBool::new(false),S::INIT);
+    const INIT: Self = Self(AtomicBool::new(false),S::INIT, #[cfg(feature="dbg_spin_timeout")] LockHolder::new());
     
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn try_lock(&self) -> bool {
         let ok = self.0.compare_exchange(false,true, Ordering::Acquire, Ordering::Relaxed).is_ok();
+        #[cfg(feature="dbg_lockdep")]
+        if ok { lockdep::acquired(self, false, S::YIELDS); }
+        #[cfg(feature="dbg_spin_timeout")]
+        if ok { self.2.set(); }
         ok
     }
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn lock(&self) {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::check_order(self, false);
+        #[cfg(feature="dbg_spin_timeout")]
+        let mut timer = SpinTimer::new(S::YIELDS);
         while self.0.compare_exchange_weak(false,true, Ordering::Acquire, Ordering::Relaxed).is_err() {
-            while self.0.load(Ordering::Relaxed) { self.1.lock_relax() }
+            while self.0.load(Ordering::Relaxed) {
+                self.1.lock_relax();
+                #[cfg(feature="dbg_spin_timeout")]
+                timer.check(self);
+            }
         }
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::acquired(self, false, S::YIELDS);
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.set();
     }
     
     unsafe fn unlock(&self) {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::released(self);
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.clear();
         self.0.store(false, Ordering::Release);
         self.1.on_unlock();
     }
 }
+#[cfg(feature="dbg_spin_timeout")]
+impl<S:MutexStrategy> DiagnosableLock for BaseMutexRaw<S> {
+    fn holder(&self) -> &LockHolder { &self.2 }
+    fn readers(&self) -> usize { 0 }
+}
+#[cfg(feature="dbg_lockdep")]
+impl<S:MutexStrategy> core::ops::Drop for BaseMutexRaw<S> {
+    fn drop(&mut self) {
+        lockdep::forget(self)
+    }
+}
 pub type BaseMutex<T,S> = lock_api::Mutex<BaseMutexRaw<S>,T>;
 pub type BaseMutexGuard<'a,T,S> = lock_api::MutexGuard<'a,BaseMutexRaw<S>,T>;
 pub type MappedBaseMutexGuard<'a,T,S> = lock_api::MappedMutexGuard<'a,BaseMutexRaw<S>,T>;
@@ -43,6 +82,8 @@ pub type ArcBaseMutexGuard<T,S> = lock_api::ArcMutexGuard<BaseMutexRaw<S>,T>;
 
 pub trait RwLockStrategy {
     const INIT: Self;
+    /// (see MutexStrategy::YIELDS)
+    const YIELDS: bool;
     
     /// Called after the rwlock has unlocked from reading
     /// (this and the other _unlock methods that take a reader count may be called spuriously - whenever the reader count is decremented e.g. if reading failed)
@@ -73,7 +114,7 @@ pub trait RwLockStrategy {
 const WRITER: usize = 1<<56;
 const UPGRADER: usize = 1<<48;
 const EXCLUSIVE_THRESHOLD: usize = 1<<40;
-pub struct BaseRwLockRaw<S:RwLockStrategy>(AtomicUsize,S);
+pub struct BaseRwLockRaw<S:RwLockStrategy>(AtomicUsize,S, #[cfg(feature="dbg_spin_timeout")] LockHolder);
 impl<S:RwLockStrategy> BaseRwLockRaw<S> {
     /* If locked exclusively, returns Err(num_readers). Otherwise returns Ok(num_readers).
     Upgradeable locks (despite being readers) do not count towards this total. */
@@ -86,79 +127,172 @@ impl<S:RwLockStrategy> BaseRwLockRaw<S> {
     pub fn is_locked_exclusively(&self) -> bool {
         self.0.load(Ordering::Relaxed) >= EXCLUSIVE_THRESHOLD
     }
+    
+    // (the try_lock implementations are separate from the RawRwLock ones, so that lockdep only sees locks that are actually taken)
+    fn _try_lock_shared(&self) -> bool {
+        let value = self.0.fetch_add(1, Ordering::Acquire);
+        if value>=EXCLUSIVE_THRESHOLD {
+            let x = self.0.fetch_sub(1, Ordering::Release);
+            self.1.on_read_unlock((x%EXCLUSIVE_THRESHOLD)-1);
+            return false;
+        }
+        return true;
+    }
+    fn _try_lock_upgradable(&self) -> bool {
+        let value = self.0.fetch_add(UPGRADER, Ordering::Acquire);
+        if value>=EXCLUSIVE_THRESHOLD {  // (existing writer or upgrader)
+            let x = self.0.fetch_sub(UPGRADER, Ordering::Release);
+            return false;
+        }
+        return true;
+    }
 }
 unsafe impl<S:RwLockStrategy> RawRwLock for BaseRwLockRaw<S> {
     type GuardMarker = GuardSend;
-    const INIT: Self = Self(AtomicUsize::new(0),S::INIT);
+    const INIT: Self = Self(AtomicUsize::new(0),S::INIT, #[cfg(feature="dbg_spin_timeout")] LockHolder::new());
 
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn lock_shared(&self) {
-        while !self.try_lock_shared() {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::check_order(self, true);
+        #[cfg(feature="dbg_spin_timeout")]
+        let mut timer = SpinTimer::new(S::YIELDS);
+        while !self._try_lock_shared() {
             // Failed
-            while self.0.load(Ordering::Relaxed)>=EXCLUSIVE_THRESHOLD { self.1.read_relax(); }
+            while self.0.load(Ordering::Relaxed)>=EXCLUSIVE_THRESHOLD {
+                self.1.read_relax();
+                #[cfg(feature="dbg_spin_timeout")]
+                timer.check(self);
+            }
         }
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::acquired(self, true, S::YIELDS);
     }
 
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn try_lock_shared(&self) -> bool {
-        let value = self.0.fetch_add(1, Ordering::Acquire);
-        if value>=EXCLUSIVE_THRESHOLD {
-            let x = self.0.fetch_sub(1, Ordering::Release);
-            self.1.on_read_unlock((x%EXCLUSIVE_THRESHOLD)-1);
-            return false;
-        }
-        return true;
+        let ok = self._try_lock_shared();
+        #[cfg(feature="dbg_lockdep")]
+        if ok { lockdep::acquired(self, true, S::YIELDS); }
+        ok
     }
 
     unsafe fn unlock_shared(&self) {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::released(self);
         let x = self.0.fetch_sub(1, Ordering::Release);
         self.1.on_read_unlock((x%EXCLUSIVE_THRESHOLD)-1)
     }
 
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn lock_exclusive(&self) {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::check_order(self, false);
+        #[cfg(feature="dbg_spin_timeout")]
+        let mut timer = SpinTimer::new(S::YIELDS);
         while self.0.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
-            while self.0.load(Ordering::Relaxed)!=0 { self.1.write_relax(); }
+            while self.0.load(Ordering::Relaxed)!=0 {
+                self.1.write_relax();
+                #[cfg(feature="dbg_spin_timeout")]
+                timer.check(self);
+            }
         }
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::acquired(self, false, S::YIELDS);
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.set();
     }
 
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn try_lock_exclusive(&self) -> bool {
-        self.0.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        let ok = self.0.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok();
+        #[cfg(feature="dbg_lockdep")]
+        if ok { lockdep::acquired(self, false, S::YIELDS); }
+        #[cfg(feature="dbg_spin_timeout")]
+        if ok { self.2.set(); }
+        ok
     }
 
     unsafe fn unlock_exclusive(&self) {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::released(self);
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.clear();
         self.0.fetch_sub(WRITER, Ordering::Release);
         self.1.on_write_unlock();
     }
 }
+#[cfg(feature="dbg_spin_timeout")]
+impl<S:RwLockStrategy> DiagnosableLock for BaseRwLockRaw<S> {
+    fn holder(&self) -> &LockHolder { &self.2 }
+    fn readers(&self) -> usize { self.reader_count().unwrap_or_else(|n|n) }
+}
+#[cfg(feature="dbg_lockdep")]
+impl<S:RwLockStrategy> core::ops::Drop for BaseRwLockRaw<S> {
+    fn drop(&mut self) {
+        lockdep::forget(self)
+    }
+}
 unsafe impl<S:RwLockStrategy> lock_api::RawRwLockDowngrade for BaseRwLockRaw<S> {
     unsafe fn downgrade(&self) {
+        // (readers aren't recorded as holders)
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.clear();
         // Subtracting (WRITER-1) means that when x=WRITER, x will now equal 1 (a single shared lock), or so on
         self.0.fetch_sub(WRITER-1, Ordering::Release);
         self.1.on_downgrade_w2r();
     }
 }
 unsafe impl<S:RwLockStrategy> lock_api::RawRwLockUpgrade for BaseRwLockRaw<S> {
+    // (lockdep counts upgradeable locks as shared, as they don't exclude readers)
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn lock_upgradable(&self) {
-        while !self.try_lock_upgradable() {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::check_order(self, true);
+        #[cfg(feature="dbg_spin_timeout")]
+        let mut timer = SpinTimer::new(S::YIELDS);
+        while !self._try_lock_upgradable() {
             // Failed
-            while self.0.load(Ordering::Relaxed)>=EXCLUSIVE_THRESHOLD { self.1.upgradeable_relax(); }
+            while self.0.load(Ordering::Relaxed)>=EXCLUSIVE_THRESHOLD {
+                self.1.upgradeable_relax();
+                #[cfg(feature="dbg_spin_timeout")]
+                timer.check(self);
+            }
         }
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::acquired(self, true, S::YIELDS);
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.set();
     }
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     fn try_lock_upgradable(&self) -> bool {
-        let value = self.0.fetch_add(UPGRADER, Ordering::Acquire);
-        if value>=EXCLUSIVE_THRESHOLD {  // (existing writer or upgrader)
-            let x = self.0.fetch_sub(UPGRADER, Ordering::Release);
-            return false;
-        }
-        return true;
+        let ok = self._try_lock_upgradable();
+        #[cfg(feature="dbg_lockdep")]
+        if ok { lockdep::acquired(self, true, S::YIELDS); }
+        #[cfg(feature="dbg_spin_timeout")]
+        if ok { self.2.set(); }
+        ok
     }
     
     unsafe fn unlock_upgradable(&self) {
+        #[cfg(feature="dbg_lockdep")]
+        lockdep::released(self);
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.clear();
         let x = self.0.fetch_sub(UPGRADER,Ordering::Release);
         self.1.on_upgradeable_release();
     }
+    #[cfg_attr(feature="dbg_spin_timeout", track_caller)]
     unsafe fn upgrade(&self) {
+        #[cfg(feature="dbg_spin_timeout")]
+        let mut timer = SpinTimer::new(S::YIELDS);
         while !self.try_upgrade() {
             // Failed
-            while self.0.load(Ordering::Relaxed)!=UPGRADER { self.1.upgrade_u2w_relax(); }
+            while self.0.load(Ordering::Relaxed)!=UPGRADER {
+                self.1.upgrade_u2w_relax();
+                #[cfg(feature="dbg_spin_timeout")]
+                timer.check(self);
+            }
         }
     }
     unsafe fn try_upgrade(&self) -> bool {
@@ -167,6 +301,8 @@ unsafe impl<S:RwLockStrategy> lock_api::RawRwLockUpgrade for BaseRwLockRaw<S> {
 }
 unsafe impl<S:RwLockStrategy> lock_api::RawRwLockUpgradeDowngrade for BaseRwLockRaw<S> {
     unsafe fn downgrade_upgradable(&self) {
+        #[cfg(feature="dbg_spin_timeout")]
+        self.2.clear();
         self.0.fetch_sub(UPGRADER-1,Ordering::Release);
         self.1.on_downgrade_u2r();
     }
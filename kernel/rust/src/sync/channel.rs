@@ -0,0 +1,358 @@
+//! Multi-producer multi-consumer channels, which may be bounded (senders wait for space, giving backpressure) or unbounded (like WQueue).
+//! A channel is disconnected once every Sender (or every Receiver) has been dropped: receivers can still take whatever is left, but after that recv() fails instead of waiting forever, and send() fails straight away once nobody is left to receive.
+//! try_send/try_recv may be used from anywhere (including interrupt handlers), but the blocking versions may only be used in task context.
+//! select() waits on several channel ends at once.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use super::WaitingList;
+use super::kspin::KMutex;
+use crate::multitasking::{block_on,sleep_async};
+
+/// Returned by send() if every receiver has been dropped. Contains the value that couldn't be sent.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SendError<T>(pub T);
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity
+    Full(T),
+    /// Every receiver has been dropped
+    Disconnected(T),
+}
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SendTimeoutError<T> {
+    Timeout(T),
+    Disconnected(T),
+}
+/// Returned by recv() if the channel is empty and every sender has been dropped
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct RecvError;
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+struct Channel<T> {
+    /// (only ever locked briefly, with interruptions disabled, so that try_send/try_recv work in interrupt handlers)
+    queue: KMutex<VecDeque<T>>,
+    /// None if unbounded
+    capacity: Option<usize>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    /// Receivers waiting for an item (or for the senders to disconnect)
+    recv_waiters: WaitingList,
+    /// Senders waiting for space (or for the receivers to disconnect)
+    send_waiters: WaitingList,
+}
+impl<T> Channel<T> {
+    /* Push a value without waking any receivers (see _notify_sent).
+        The blocking versions call this from inside the senders' waiting list's predicate, where waking a receiver would lock the receivers' list while the senders' list is locked (and a receiver may be doing the opposite). So they wake them after the wait has returned instead. */
+    fn _push(&self, value: T) -> Result<(),TrySendError<T>> {
+        let mut queue = self.queue.lock();
+        // (receivers are checked with the queue locked, and the last one disconnects with it locked, so a value is never queued after the last receiver has gone)
+        if self.receivers.load(Ordering::Acquire) == 0 { return Err(TrySendError::Disconnected(value)); }
+        if self.capacity.is_some_and(|c|queue.len() >= c) { return Err(TrySendError::Full(value)); }
+        queue.push_back(value);
+        Ok(())
+    }
+    /* Pop a value without waking any senders (see _push and _notify_received) */
+    fn _pop(&self) -> Result<T,TryRecvError> {
+        let item = self.queue.lock().pop_front();
+        match item {
+            Some(item) => Ok(item),
+            // (senders are checked after the queue, so that anything sent before the last sender was dropped is still received)
+            None if self.senders.load(Ordering::Acquire) == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+    /* Wake a receiver after an item has been pushed. Must not be called with either waiting list locked. */
+    fn _notify_sent(&self) {
+        self.recv_waiters.notify_one();
+    }
+    /* Wake a sender after an item has been popped (if it may have been waiting for space). Must not be called with either waiting list locked. */
+    fn _notify_received(&self) {
+        if self.capacity.is_some() { self.send_waiters.notify_one(); }
+    }
+
+    fn _try_send(&self, value: T) -> Result<(),TrySendError<T>> {
+        self._push(value)?;
+        self._notify_sent();
+        Ok(())
+    }
+    fn _try_recv(&self) -> Result<T,TryRecvError> {
+        let item = self._pop()?;
+        self._notify_received();
+        Ok(item)
+    }
+    /// Try to push, taking the value out of the cell. Returns None (with the value left in the cell) if the channel is full. (for use as a wait_until_try predicate, followed by _sent)
+    fn _push_from(&self, value: &Cell<Option<T>>) -> Option<Result<(),SendError<T>>> {
+        match self._push(value.take().unwrap()) {
+            Ok(()) => Some(Ok(())),
+            Err(TrySendError::Disconnected(value)) => Some(Err(SendError(value))),
+            Err(TrySendError::Full(v)) => { value.set(Some(v)); None },
+        }
+    }
+    /// Try to pop. Returns None if the channel is empty. (for use as a wait_until_try predicate, followed by _received)
+    fn _pop_ready(&self) -> Option<Result<T,TryRecvError>> {
+        match self._pop() {
+            Err(TryRecvError::Empty) => None,
+            result => Some(result),
+        }
+    }
+    /* Called once a wait using _push_from/_pop_ready has returned (and the list is unlocked), to wake whoever we made room/an item for */
+    fn _sent<E>(&self, result: Result<(),E>) -> Result<(),E> {
+        if result.is_ok() { self._notify_sent(); }
+        result
+    }
+    fn _received(&self, result: Result<T,TryRecvError>) -> Result<T,TryRecvError> {
+        if result.is_ok() { self._notify_received(); }
+        result
+    }
+    fn _recv_ready(&self) -> bool {
+        !self.queue.lock().is_empty() || self.senders.load(Ordering::Acquire) == 0
+    }
+    fn _send_ready(&self) -> bool {
+        self.receivers.load(Ordering::Acquire) == 0 || self.capacity.map_or(true, |c|self.queue.lock().len() < c)
+    }
+}
+
+/* Create a channel which holds at most the given number of items (which must be at least 1). Senders wait while it's full. */
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "Bounded channels must have room for at least one item!");
+    _new_channel(Some(capacity))
+}
+/* Create a channel with no limit on how many items it holds. Sending never waits. */
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    _new_channel(None)
+}
+fn _new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        queue: KMutex::new(VecDeque::new()), capacity,
+        senders: AtomicUsize::new(1), receivers: AtomicUsize::new(1),
+        recv_waiters: WaitingList::new(), send_waiters: WaitingList::new(),
+    });
+    (Sender(Arc::clone(&channel)), Receiver(channel))
+}
+
+/// The sending end of a channel. Clone it to get more senders.
+pub struct Sender<T>(Arc<Channel<T>>);
+impl<T> Sender<T> {
+    /* Send a value, waiting while the channel is full. Fails if every receiver has been dropped. */
+    pub fn send(&self, value: T) -> Result<(),SendError<T>> {
+        let value = Cell::new(Some(value));
+        let result = self.0.send_waiters.wait_until_try(||self.0._push_from(&value));
+        self.0._sent(result)
+    }
+    /* Same as send, but gives up after the given timeout */
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(),SendTimeoutError<T>> {
+        let value = Cell::new(Some(value));
+        match self.0.send_waiters.wait_until_try_timeout(||self.0._push_from(&value), timeout) {
+            Some(Ok(())) => { self.0._notify_sent(); Ok(()) },
+            Some(Err(SendError(value))) => Err(SendTimeoutError::Disconnected(value)),
+            None => Err(SendTimeoutError::Timeout(value.take().unwrap())),
+        }
+    }
+    /* Send a value if there's room, without waiting */
+    pub fn try_send(&self, value: T) -> Result<(),TrySendError<T>> {
+        self.0._try_send(value)
+    }
+
+    /// The number of items in the channel
+    pub fn len(&self) -> usize { self.0.queue.lock().len() }
+    /// The most items the channel can hold (None if unbounded)
+    pub fn capacity(&self) -> Option<usize> { self.0.capacity }
+    /// True if every receiver has been dropped
+    pub fn is_disconnected(&self) -> bool { self.0.receivers.load(Ordering::Acquire) == 0 }
+}
+impl<T> core::clone::Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::AcqRel);
+        Self(Arc::clone(&self.0))
+    }
+}
+impl<T> core::ops::Drop for Sender<T> {
+    fn drop(&mut self) {
+        // (receivers check the count with the list locked, so they either see it or get woken)
+        if self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 { self.0.recv_waiters.notify_all(); }
+    }
+}
+
+/// The receiving end of a channel. Clone it to get more receivers (each item is only received by one of them).
+pub struct Receiver<T>(Arc<Channel<T>>);
+impl<T> Receiver<T> {
+    /* Receive a value, waiting until one is available. Fails once the channel is empty and every sender has been dropped. */
+    pub fn recv(&self) -> Result<T,RecvError> {
+        let result = self.0.recv_waiters.wait_until_try(||self.0._pop_ready());
+        self.0._received(result).map_err(|_|RecvError)
+    }
+    /* Same as recv, but gives up after the given timeout */
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T,RecvTimeoutError> {
+        match self.0.recv_waiters.wait_until_try_timeout(||self.0._pop_ready(), timeout) {
+            Some(result) => self.0._received(result).map_err(|_|RecvTimeoutError::Disconnected),
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+    /// The asynchronous version of recv(), which waits without blocking the task (see multitasking::executor)
+    pub async fn recv_async(&self) -> Result<T,RecvError> {
+        let result = self.0.recv_waiters.wait_until_try_async(||self.0._pop_ready()).await;
+        self.0._received(result).map_err(|_|RecvError)
+    }
+    /* Receive a value if one is available, without waiting */
+    pub fn try_recv(&self) -> Result<T,TryRecvError> {
+        self.0._try_recv()
+    }
+
+    /// The number of items in the channel
+    pub fn len(&self) -> usize { self.0.queue.lock().len() }
+    /// The most items the channel can hold (None if unbounded)
+    pub fn capacity(&self) -> Option<usize> { self.0.capacity }
+    /// True if every sender has been dropped (though there may still be items left to receive)
+    pub fn is_disconnected(&self) -> bool { self.0.senders.load(Ordering::Acquire) == 0 }
+}
+impl<T> core::clone::Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.0.receivers.fetch_add(1, Ordering::AcqRel);
+        Self(Arc::clone(&self.0))
+    }
+}
+impl<T> core::ops::Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // (see _push)
+        let last = { let _queue = self.0.queue.lock(); self.0.receivers.fetch_sub(1, Ordering::AcqRel) == 1 };
+        if last { self.0.send_waiters.notify_all(); }
+    }
+}
+
+// == SELECT ==
+/// A channel end that select() can wait on
+pub trait Selectable {
+    /// True if the operation wouldn't wait (there's an item to receive/room to send, or the other side has disconnected)
+    fn is_ready(&self) -> bool;
+    /// A future that completes once is_ready() is true
+    fn ready_async(&self) -> Pin<Box<dyn Future<Output=()> + '_>>;
+}
+impl<T> Selectable for Receiver<T> {
+    fn is_ready(&self) -> bool { self.0._recv_ready() }
+    fn ready_async(&self) -> Pin<Box<dyn Future<Output=()> + '_>> {
+        Box::pin(self.0.recv_waiters.wait_until_async(||self.0._recv_ready()))
+    }
+}
+impl<T> Selectable for Sender<T> {
+    fn is_ready(&self) -> bool { self.0._send_ready() }
+    fn ready_async(&self) -> Pin<Box<dyn Future<Output=()> + '_>> {
+        Box::pin(self.0.send_waiters.wait_until_async(||self.0._send_ready()))
+    }
+}
+
+/* Wait until one of the given channel ends is ready (see Selectable), and return its index. If several are, the first is chosen.
+    Another task may get to the channel first (in which case try_recv/try_send will say so), so call select() again if the operation fails. */
+pub fn select(ends: &[&dyn Selectable]) -> usize {
+    _select(ends, None).unwrap()
+}
+/* Same as select, but gives up after the given timeout, returning None */
+pub fn select_timeout(ends: &[&dyn Selectable], timeout: Duration) -> Option<usize> {
+    _select(ends, Some(timeout))
+}
+fn _select(ends: &[&dyn Selectable], timeout: Option<Duration>) -> Option<usize> {
+    // (no need to queue up on every channel if one is ready already)
+    if let Some(index) = ends.iter().position(|end|end.is_ready()) { return Some(index); }
+    // Otherwise, wait on all of them at once. Whichever waits aren't used are dropped, handing on any notification they received.
+    let mut waits: Vec<_> = ends.iter().map(|end|end.ready_async()).collect();
+    let mut sleep = timeout.map(sleep_async);
+    block_on(core::future::poll_fn(|cx|{
+        for (index, wait) in waits.iter_mut().enumerate() {
+            if wait.as_mut().poll(cx).is_ready() { return Poll::Ready(Some(index)); }
+        }
+        if let Some(sleep) = sleep.as_mut() {
+            if Pin::new(sleep).poll(cx).is_ready() { return Poll::Ready(None); }
+        }
+        Poll::Pending
+    }))
+}
+
+pub(crate) mod channel_test {
+    use super::*;
+    use alloc::format;
+    use crate::multitasking::util::def_task_fn;
+
+    const COUNT: usize = 16;
+    const CAPACITY: usize = 2;
+
+    // Sends COUNT values (tagged with which producer sent them) and then drops its sender
+    def_task_fn! {
+        task fn producer(tx: Sender<(usize,usize)>, tag: usize) -> bool {
+            (0..COUNT).all(|i|tx.send((tag,i)).is_ok() && tx.capacity().map_or(true, |c|tx.len() <= c))
+        }
+    }
+
+    // Receives COUNT values with blocking recv, checking they arrive in order
+    def_task_fn! {
+        task fn consumer(rx: Receiver<(usize,usize)>) -> bool {
+            (0..COUNT).all(|i|rx.recv() == Ok((0,i)))
+        }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        // A sender blocked on a full channel and a receiver blocked on an empty one, each waking the other (on a channel small enough that they take turns waiting)
+        let (tx, rx) = bounded(1);
+        let receiving = consumer::spawn(rx);
+        let sending = producer::spawn(tx, 0);
+        if !*sending.join_timeout(Duration::from_secs(5)).map_err(|e|format!("Blocking sender failed or got stuck: {:?}", e))? { return Err(format!("A blocking send failed")); }
+        if !*receiving.join_timeout(Duration::from_secs(5)).map_err(|e|format!("Blocking receiver failed or got stuck: {:?}", e))? { return Err(format!("A blocking recv got the wrong value")); }
+
+        let (btx, brx) = bounded(CAPACITY);
+        let (utx, urx) = unbounded();
+        // Backpressure and timeouts
+        for i in 0..CAPACITY { btx.try_send((9,i)).map_err(|e|format!("try_send failed on an empty channel: {:?}", e))?; }
+        if !matches!(btx.try_send((9,9)), Err(TrySendError::Full(_))) { return Err(format!("try_send didn't fail on a full channel")); }
+        if !matches!(btx.send_timeout((9,9), Duration::from_millis(20)), Err(SendTimeoutError::Timeout(_))) { return Err(format!("send_timeout didn't time out on a full channel")); }
+        for _ in 0..CAPACITY { brx.recv().map_err(|_|format!("recv failed on a non-empty channel"))?; }
+        if urx.recv_timeout(Duration::from_millis(20)) != Err(RecvTimeoutError::Timeout) { return Err(format!("recv_timeout didn't time out on an empty channel")); }
+        if select_timeout(&[&brx, &urx], Duration::from_millis(20)).is_some() { return Err(format!("select_timeout returned with nothing ready")); }
+
+        // Receive everything from both channels through select, until both are disconnected
+        let btask = producer::spawn(btx, 0);
+        let utask = producer::spawn(utx, 1);
+        let mut next = [0; 2];
+        let mut open = [true; 2];
+        while open[0] || open[1] {
+            let mut ends: Vec<(usize,&dyn Selectable)> = Vec::new();
+            if open[0] { ends.push((0, &brx)); }
+            if open[1] { ends.push((1, &urx)); }
+            let selected = ends[select(&ends.iter().map(|e|e.1).collect::<Vec<_>>())].0;
+            let received = if selected == 0 { brx.try_recv() } else { urx.try_recv() };
+            match received {
+                Ok((tag,i)) => {
+                    if tag != selected || i != next[tag] { return Err(format!("Received {:?} from channel {} (expected {:?})", (tag,i), selected, (selected,next[selected]))); }
+                    next[tag] += 1;
+                },
+                Err(TryRecvError::Disconnected) => open[selected] = false,
+                Err(TryRecvError::Empty) => return Err(format!("Channel {} was selected but empty", selected)),
+            }
+        }
+        if next != [COUNT; 2] { return Err(format!("Received {:?} values (expected {})", next, COUNT)); }
+        for task in [btask, utask] {
+            if !*task.join().map_err(|e|format!("Producer failed: {:?}", e))? { return Err(format!("A send failed, or the bounded channel went over capacity")); }
+        }
+        if brx.recv() != Err(RecvError) { return Err(format!("recv succeeded on a disconnected channel")); }
+        // Sending fails once the receivers are gone
+        let (tx, rx) = bounded::<usize>(1);
+        drop(rx);
+        if tx.send(1) != Err(SendError(1)) { return Err(format!("send succeeded with no receivers")); }
+        Ok(())
+    }
+}
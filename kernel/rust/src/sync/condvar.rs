@@ -0,0 +1,112 @@
+//! Condition variables, for waiting until some state protected by a mutex changes.
+//! They work with any lock_api mutex that may be used in task context (YMutex, WMutex, etc.), and release the mutex atomically with suspending the task:
+//!     the condvar's waiting list is locked before the mutex is released, so a notification sent by whoever takes the mutex next can't be missed.
+//! As with any condvar, wakeups may be spurious (or the state may have changed again before we got the mutex back), so prefer wait_while over wait.
+
+use core::time::Duration;
+use lock_api::{RawMutex,MutexGuard};
+use super::WaitingList;
+use crate::multitasking::scheduler;
+
+pub struct Condvar {
+    waiters: WaitingList,
+}
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { waiters: WaitingList::new() }
+    }
+
+    /* Release the mutex and wait until notified, then take the mutex again. */
+    pub fn wait<R:RawMutex,T:?Sized>(&self, guard: &mut MutexGuard<'_,R,T>) {
+        let mutex = MutexGuard::mutex(guard);
+        // SAFETY: Same as MutexGuard::unlocked - the guard is borrowed until we've locked the mutex again, so nobody can use it while it isn't held
+        self.waiters.wait_releasing(|| unsafe { mutex.raw().unlock() });
+        unsafe { mutex.raw().lock() };
+    }
+    /* Same as wait, but gives up after the given timeout. Returns false if it timed out. (the mutex is taken again either way) */
+    pub fn wait_timeout<R:RawMutex,T:?Sized>(&self, guard: &mut MutexGuard<'_,R,T>, timeout: Duration) -> bool {
+        self.wait_deadline(guard, scheduler::get_scheduler_ticks() + scheduler::duration_to_ticks(timeout))
+    }
+    fn wait_deadline<R:RawMutex,T:?Sized>(&self, guard: &mut MutexGuard<'_,R,T>, deadline: usize) -> bool {
+        let mutex = MutexGuard::mutex(guard);
+        // SAFETY: See wait
+        let notified = self.waiters.wait_releasing_deadline(|| unsafe { mutex.raw().unlock() }, deadline);
+        unsafe { mutex.raw().lock() };
+        notified
+    }
+
+    /* Wait (see wait) for as long as the condition returns true. The condition is checked with the mutex held. */
+    pub fn wait_while<R:RawMutex,T:?Sized>(&self, guard: &mut MutexGuard<'_,R,T>, mut condition: impl FnMut(&mut T)->bool) {
+        while condition(&mut *guard) { self.wait(guard); }
+    }
+    /* Same as wait_while, but gives up after the given timeout. Returns false if it timed out (in which case the condition is still true). */
+    pub fn wait_while_timeout<R:RawMutex,T:?Sized>(&self, guard: &mut MutexGuard<'_,R,T>, timeout: Duration, mut condition: impl FnMut(&mut T)->bool) -> bool {
+        let deadline = scheduler::get_scheduler_ticks() + scheduler::duration_to_ticks(timeout);
+        while condition(&mut *guard) {
+            if scheduler::get_scheduler_ticks() >= deadline { return false; }
+            self.wait_deadline(guard, deadline);
+        }
+        true
+    }
+
+    /* Wake one task waiting on the condvar. Returns true if there was one. */
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+    /* Wake every task waiting on the condvar */
+    pub fn notify_all(&self) {
+        self.waiters.notify_all()
+    }
+}
+impl core::default::Default for Condvar {
+    fn default() -> Self { Self::new() }
+}
+
+pub(crate) mod condvar_test {
+    use super::*;
+    use alloc::format;
+    use crate::sync::{YMutex,WMutex};
+    use crate::multitasking::util::def_task_fn;
+
+    const ROUNDS: usize = 8;
+    static CONDVAR: Condvar = Condvar::new();
+    static YSTATE: YMutex<(usize,bool)> = YMutex::new((0,false));
+    static WSTATE: WMutex<(usize,bool)> = WMutex::new((0,false));
+
+    // Ping-pong with the test task: each side waits for the other to take its turn (the bool says whose turn it is)
+    def_task_fn! {
+        task fn ponger(use_wmutex: bool) {
+            for _ in 0..ROUNDS {
+                if use_wmutex { let mut s = WSTATE.lock(); CONDVAR.wait_while(&mut s, |s|!s.1); s.0 += 1; s.1 = false; }
+                else { let mut s = YSTATE.lock(); CONDVAR.wait_while(&mut s, |s|!s.1); s.0 += 1; s.1 = false; }
+                CONDVAR.notify_all();
+            }
+        }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        // YMutex
+        *YSTATE.lock() = (0,false);
+        let task = ponger::spawn(false);
+        for _ in 0..ROUNDS {
+            let mut s = YSTATE.lock(); s.1 = true; CONDVAR.notify_all();
+            if !CONDVAR.wait_while_timeout(&mut s, Duration::from_secs(1), |s|s.1) { return Err(format!("Timed out waiting for the other task (YMutex)")); }
+        }
+        task.join().map_err(|e|format!("Task failed: {:?}", e))?;
+        if YSTATE.lock().0 != ROUNDS { return Err(format!("Counted {} rounds with YMutex", YSTATE.lock().0)); }
+        // WMutex
+        *WSTATE.lock() = (0,false);
+        let task = ponger::spawn(true);
+        for _ in 0..ROUNDS {
+            let mut s = WSTATE.lock(); s.1 = true; CONDVAR.notify_all();
+            if !CONDVAR.wait_while_timeout(&mut s, Duration::from_secs(1), |s|s.1) { return Err(format!("Timed out waiting for the other task (WMutex)")); }
+        }
+        task.join().map_err(|e|format!("Task failed: {:?}", e))?;
+        if WSTATE.lock().0 != ROUNDS { return Err(format!("Counted {} rounds with WMutex", WSTATE.lock().0)); }
+        // Nobody notifies, so this must time out (and give the mutex back)
+        let mut s = YSTATE.lock();
+        if CONDVAR.wait_timeout(&mut s, Duration::from_millis(20)) { return Err(format!("wait_timeout returned without being notified")); }
+        if CONDVAR.wait_while_timeout(&mut s, Duration::from_millis(20), |_|true) { return Err(format!("wait_while_timeout didn't time out")); }
+        Ok(())
+    }
+}
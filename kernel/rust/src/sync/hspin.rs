@@ -2,30 +2,46 @@
 use super::kspin;
 use super::yspin;
 use crate::multitasking::is_executing_task;
+use crate::multitasking::interruptions::is_sched_yield_disabled;
 // (this whole implementation is a dirty hack implemented over KLocks)
 
 macro_rules! inherit_lock_fn {
     ($vis:vis fn $fname:ident(&self) -> try($rt:ident<'_,T>)) => {
+        #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
         $vis fn $fname(&self) -> Option<$rt<'_,T>> {
             self.0.$fname()
         }
     };
-    ($vis:vis fn $fname:ident(&self) -> block($rt:ident<'_,T>; using $tfname:ident; relax while self.$relcond:ident())) => {
+    ($vis:vis fn $fname:ident(&self) -> block($rt:ident<'_,T>; using $tfname:ident; relax while self.$relcond:ident(); shared=$shared:literal)) => {
+        #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
         $vis fn $fname(&self) -> $rt<'_,T> {
+            // (we only ever try_lock the underlying lock, so lockdep has to check the lock order here)
+            #[cfg(feature="dbg_lockdep")]
+            super::lockdep::check_order(self.raw(), $shared);
+            #[cfg(feature="dbg_spin_timeout")]
+            let mut timer = super::spintimeout::SpinTimer::new(can_yield());
             loop {
                 if let Some(guard) = self.$tfname() {
                     return guard;
                 }
                 // Relax
-                relax(||self.$relcond());
+                relax(||{
+                    #[cfg(feature="dbg_spin_timeout")]
+                    timer.check(self.raw());
+                    self.$relcond()
+                });
             }
         }
     };
 }
 
-fn relax(relcond:impl Fn()->bool){
+fn can_yield() -> bool {
+    // (interrupt handlers and the like may be "executing a task", but cannot yield)
+    is_executing_task() && !is_sched_yield_disabled()
+}
+fn relax(mut relcond:impl FnMut()->bool){
     use spin::RelaxStrategy;
-    let can_yield = is_executing_task();
+    let can_yield = can_yield();
     while relcond() {
         if can_yield {
             // Yield to scheduler
@@ -47,7 +63,7 @@ impl<T> HMutex<T> {
     }
     
     inherit_lock_fn!(pub fn try_lock(&self) -> try(HMutexGuard<'_,T>));
-    inherit_lock_fn!(pub fn lock(&self) -> block(HMutexGuard<'_,T>; using try_lock; relax while self.is_locked()));
+    inherit_lock_fn!(pub fn lock(&self) -> block(HMutexGuard<'_,T>; using try_lock; relax while self.is_locked(); shared=false));
     pub fn is_locked(&self) -> bool {
         self.raw().is_locked()
     }
@@ -82,16 +98,18 @@ impl<T> HRwLock<T> {
     }
     
     inherit_lock_fn!(pub fn try_read(&self) -> try(HRwLockReadGuard<'_,T>));
-    inherit_lock_fn!(pub fn read(&self) -> block(HRwLockReadGuard<'_,T>; using try_read; relax while self.is_locked_exclusively()));
+    inherit_lock_fn!(pub fn read(&self) -> block(HRwLockReadGuard<'_,T>; using try_read; relax while self.is_locked_exclusively(); shared=true));
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     pub fn try_write(&self) -> Option<HRwLockWriteGuard<'_,T>> {
         self.0.try_write().map(|wg|HRwLockWriteGuard(wg))
     }
-    inherit_lock_fn!(pub fn write(&self) -> block(HRwLockWriteGuard<'_,T>; using try_write; relax while self.is_locked_at_all()));
+    inherit_lock_fn!(pub fn write(&self) -> block(HRwLockWriteGuard<'_,T>; using try_write; relax while self.is_locked_at_all(); shared=false));
     
+    #[cfg_attr(any(feature="dbg_lockdep", feature="dbg_spin_timeout"), track_caller)]
     pub fn try_upgradable_read(&self) -> Option<HRwLockUpgradableGuard<'_,T>> {
         self.0.try_upgradable_read().map(|ug|HRwLockUpgradableGuard(ug))
     }
-    inherit_lock_fn!(pub fn upgradable_read(&self) -> block(HRwLockUpgradableGuard<'_,T>; using try_upgradable_read; relax while self.is_locked_exclusively()));
+    inherit_lock_fn!(pub fn upgradable_read(&self) -> block(HRwLockUpgradableGuard<'_,T>; using try_upgradable_read; relax while self.is_locked_exclusively(); shared=true));
 }
 pub type HRwLockReadGuard<'a,T> = kspin::KRwLockReadGuard<'a,T>;
 pub type MappedHRwLockReadGuard<'a,T> = kspin::MappedKRwLockReadGuard<'a,T>;
@@ -0,0 +1,402 @@
+//! Lock dependency validation ("lockdep"). Only compiled with the dbg_lockdep feature.
+//! Every lock built on baselocks (KMutex, HMutex, YMutex, LLMutex, their RwLocks, and so the page allocator locks) reports when it's taken and released.
+//! Each lock is its own "class" (identified by its address), and we remember each pair of classes that have been held together, in the order they were taken.
+//! If a lock is about to be taken in an order that closes a cycle (e.g. B while holding A, when A has been taken while holding B before), that's a potential deadlock,
+//!     so it's reported (with the call sites on both sides) before we start spinning on it. Taking a lock that's already held, and yielding while holding a lock whose waiters spin (e.g. a KMutex), are reported too.
+//!
+//! The locks held are tracked per task (as YMutexes may be held across a yield), or per CPU while no task is running (i.e. in the scheduler).
+//! Nothing in here may take a tracked lock or allocate (as the allocator's locks are tracked too), so everything lives in fixed-size tables behind plain spin::Mutexes,
+//!     and reports are written with emergency_kernel_log.
+//! Limitations: a lock that's moved (rather than dropped) leaves its old address in the graph, and guards handed to another task confuse the tracking.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool,AtomicPtr,AtomicUsize,Ordering};
+use crate::multitasking::arch::enable_interrupts::{clear_interrupts,restore_interrupts};
+use crate::multitasking::get_cpu_num;
+use crate::logging::emergency_kernel_log;
+
+type Site = &'static Location<'static>;
+
+const MAX_CPUS: usize = 64;
+/// The deepest nesting of locks we can keep track of (per task/CPU)
+const MAX_HELD: usize = 32;
+/// The number of lock orders we can remember (must be a power of two)
+const MAX_EDGES: usize = 2048;
+/// The number of locks we'll search through when looking for a cycle
+const MAX_SEARCH: usize = 64;
+/// The number of steps of a cycle that are printed in a report
+const MAX_REPORTED_PATH: usize = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+static WARNED_FULL: AtomicBool = AtomicBool::new(false);
+
+/* Start tracking locks. Must be called after the fixed CPU locals have been initialised, as we need to know which CPU we're running on. */
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+/// The number of problems reported so far
+pub fn report_count() -> usize {
+    REPORTS.load(Ordering::Relaxed)
+}
+
+// == HELD LOCKS ==
+#[derive(Clone,Copy)]
+struct HeldLock {
+    lock: usize,
+    shared: bool,
+    /// Set if tasks waiting for the lock spin rather than yielding (e.g. it's a KMutex, rather than a YMutex), in which case it mustn't be held across a yield
+    spinning: bool,
+    at: Site,
+}
+/// The locks held by a task or CPU, in the order they were taken
+struct HeldLocks {
+    locks: [Option<HeldLock>; MAX_HELD],
+    len: usize,
+}
+impl HeldLocks {
+    const fn new() -> Self {
+        Self { locks: [None; MAX_HELD], len: 0 }
+    }
+    fn iter(&self) -> impl Iterator<Item=&HeldLock> {
+        self.locks[..self.len].iter().flatten()
+    }
+    fn push(&mut self, held: HeldLock) {
+        if self.len < MAX_HELD { self.locks[self.len] = Some(held); self.len += 1; }
+        else { warn_full("held locks"); }
+    }
+    /* Remove the most recent entry for the given lock. Returns false if it isn't held. */
+    fn remove(&mut self, lock: usize) -> bool {
+        // (search from the top, as locks are usually released in the reverse order)
+        match (0..self.len).rev().find(|&i|self.locks[i].is_some_and(|h|h.lock == lock)) {
+            Some(i) => {
+                self.locks.copy_within(i+1..self.len, i);
+                self.len -= 1;
+                self.locks[self.len] = None;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// The locks held by a task. Stored in its TaskControl.
+pub struct TaskLocks(spin::Mutex<HeldLocks>);
+impl TaskLocks {
+    pub const fn new() -> Self {
+        Self(spin::Mutex::new(HeldLocks::new()))
+    }
+}
+
+struct CpuState {
+    /// Set while we're working on this CPU, so that any locks taken while we're at it (e.g. when reporting) aren't tracked
+    busy: AtomicBool,
+    /// The locks held by the task currently running on this CPU, or null if no task is running
+    task: AtomicPtr<TaskLocks>,
+    /// The locks held while no task is running
+    own: spin::Mutex<HeldLocks>,
+}
+impl CpuState {
+    const fn new() -> Self {
+        Self { busy: AtomicBool::new(false), task: AtomicPtr::new(core::ptr::null_mut()), own: spin::Mutex::new(HeldLocks::new()) }
+    }
+    /* Call the given closure with the locks held by whatever is running on this CPU */
+    fn with_held<R>(&self, f: impl FnOnce(&mut HeldLocks)->R) -> R {
+        let task = self.task.load(Ordering::Relaxed);
+        // SAFETY: The scheduler keeps the task's control alive until it's switched out (see switch_out)
+        if let Some(task) = unsafe { task.as_ref() } { f(&mut task.0.lock()) }
+        else { f(&mut self.own.lock()) }
+    }
+}
+// (these are plain statics indexed by CPU number, rather than CpuLocals, as CpuLocals take tracked locks)
+static CPUS: [CpuState; MAX_CPUS] = [const { CpuState::new() }; MAX_CPUS];
+
+/* Call the given closure with the state for the current CPU, with interrupts disabled.
+    Does nothing if lockdep isn't enabled yet, or if we're already busy on this CPU. */
+fn with_cpu(f: impl FnOnce(&CpuState)) {
+    if !ENABLED.load(Ordering::Acquire) { return; }
+    let interrupts = clear_interrupts();
+    if let Some(cpu) = CPUS.get(get_cpu_num()) {
+        if !cpu.busy.swap(true, Ordering::Acquire) {
+            f(cpu);
+            cpu.busy.store(false, Ordering::Release);
+        }
+    }
+    restore_interrupts(&interrupts);
+}
+
+/* Called by the scheduler when a task is switched in, so that locks are tracked against it. */
+pub fn switch_in(task: &TaskLocks) {
+    with_cpu(|cpu|cpu.task.store(task as *const TaskLocks as *mut TaskLocks, Ordering::Relaxed))
+}
+/* Called by the scheduler once it's done with the task that was switched out (it must keep the task alive until then).
+    Locks taken by the scheduler before this are tracked against the outgoing task, as the scheduler releases its waiting list on its behalf. */
+pub fn switch_out() {
+    with_cpu(|cpu|cpu.task.store(core::ptr::null_mut(), Ordering::Relaxed))
+}
+
+// == LOCK ORDERS ==
+/// "from was held while to was taken"
+#[derive(Clone,Copy)]
+struct Edge {
+    from: usize,
+    to: usize,
+    from_at: Site,
+    to_at: Site,
+}
+#[derive(Clone,Copy)]
+struct SearchNode {
+    lock: usize,
+    /// The index of the node we came from, and the edge we took from it
+    parent: usize,
+    via: Option<Edge>,
+}
+struct Path {
+    edges: [Option<Edge>; MAX_REPORTED_PATH],
+    /// The length of the whole path (which may be longer than the part we've kept)
+    len: usize,
+}
+
+enum Added { New, Exists, Full }
+
+/// Every lock order we've seen, as an open-addressing hash table (with linear probing)
+struct LockGraph {
+    edges: [Option<Edge>; MAX_EDGES],
+    count: usize,
+    /// A bloom filter of the locks appearing in any edge, so that dropping a lock that's never been nested with another doesn't have to search the whole table
+    seen: [u64; MAX_EDGES/64],
+    /// Scratch space for find_path (kept here rather than on the stack, as we may be called with very little stack to spare)
+    search: [SearchNode; MAX_SEARCH],
+}
+impl LockGraph {
+    const fn new() -> Self {
+        Self { edges: [None; MAX_EDGES], count: 0, seen: [0; MAX_EDGES/64], search: [SearchNode { lock: 0, parent: 0, via: None }; MAX_SEARCH] }
+    }
+
+    fn home(from: usize, to: usize) -> usize {
+        let hash = (from ^ to.rotate_left(29)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        hash >> (usize::BITS - MAX_EDGES.trailing_zeros())
+    }
+    fn seen_bit(lock: usize) -> (usize, u64) {
+        let bit = Self::home(lock, 0) % MAX_EDGES;
+        (bit / 64, 1 << (bit % 64))
+    }
+
+    fn add(&mut self, edge: Edge) -> Added {
+        let mut i = Self::home(edge.from, edge.to);
+        loop {
+            match self.edges[i] {
+                Some(e) if e.from == edge.from && e.to == edge.to => return Added::Exists,
+                Some(_) => i = (i+1) % MAX_EDGES,
+                None => break,
+            }
+        }
+        // (keep some slots free so that probing stays short)
+        if self.count >= MAX_EDGES*3/4 { return Added::Full; }
+        self.edges[i] = Some(edge);
+        self.count += 1;
+        for lock in [edge.from, edge.to] { let (word, bit) = Self::seen_bit(lock); self.seen[word] |= bit; }
+        Added::New
+    }
+    /* Remove the edge at the given index, shifting back any that probed past it so they can still be found */
+    fn remove_at(&mut self, mut hole: usize) {
+        self.edges[hole] = None;
+        self.count -= 1;
+        let mut i = hole;
+        loop {
+            i = (i+1) % MAX_EDGES;
+            let Some(e) = self.edges[i] else { break };
+            // It can fill the hole if its home isn't (cyclically) between the hole and where it is now
+            let home = Self::home(e.from, e.to);
+            if i.wrapping_sub(home) % MAX_EDGES >= i.wrapping_sub(hole) % MAX_EDGES {
+                self.edges[hole] = self.edges[i].take();
+                hole = i;
+            }
+        }
+    }
+    /* Forget every edge involving the given lock (e.g. because it's been dropped, and something else may take its address) */
+    fn forget(&mut self, lock: usize) {
+        let (word, bit) = Self::seen_bit(lock);
+        if self.seen[word] & bit == 0 { return; }
+        let mut i = 0;
+        while i < MAX_EDGES {
+            // (if we remove one, something else may be shifted into its place, so check the same index again)
+            match self.edges[i] {
+                Some(e) if e.from == lock || e.to == lock => self.remove_at(i),
+                _ => i += 1,
+            }
+        }
+    }
+
+    /* Search (breadth-first, so we find the shortest) for a chain of lock orders leading from start to goal */
+    fn find_path(&mut self, start: usize, goal: usize) -> Option<Path> {
+        self.search[0] = SearchNode { lock: start, parent: 0, via: None };
+        let mut len = 1;
+        let mut next = 0;
+        while next < len {
+            let node = self.search[next].lock;
+            for e in self.edges.iter().flatten().filter(|e|e.from == node) {
+                if e.to == goal {
+                    // Found it - walk back to the start to get the path
+                    let mut steps = 1;
+                    let mut n = next;
+                    while self.search[n].via.is_some() { steps += 1; n = self.search[n].parent; }
+                    let mut path = Path { edges: [None; MAX_REPORTED_PATH], len: steps };
+                    let (mut i, mut n) = (steps-1, next);
+                    if i < MAX_REPORTED_PATH { path.edges[i] = Some(*e); }
+                    while let Some(via) = self.search[n].via {
+                        i -= 1;
+                        if i < MAX_REPORTED_PATH { path.edges[i] = Some(via); }
+                        n = self.search[n].parent;
+                    }
+                    return Some(path);
+                }
+                if self.search[..len].iter().any(|s|s.lock == e.to) { continue; }
+                // (if there's too much to search, give up rather than report anything)
+                if len == MAX_SEARCH { return None; }
+                self.search[len] = SearchNode { lock: e.to, parent: next, via: Some(*e) };
+                len += 1;
+            }
+            next += 1;
+        }
+        None
+    }
+}
+static GRAPH: spin::Mutex<LockGraph> = spin::Mutex::new(LockGraph::new());
+
+// == REPORTING ==
+enum Report {
+    /// Taking a lock that's already held
+    Recursive { lock: usize, at: Site, held_at: Site },
+    /// Taking a lock in an order that closes a cycle: `path` leads from `lock` back to `held`
+    Inversion { lock: usize, at: Site, held: HeldLock, path: Path },
+    /// Yielding while holding a lock whose waiters spin
+    YieldWhileHolding { at: Site, held: HeldLock },
+}
+impl Report {
+    fn emit(&self) {
+        REPORTS.fetch_add(1, Ordering::Relaxed);
+        let cpu = get_cpu_num();
+        match self {
+            Report::Recursive { lock, at, held_at } => {
+                emergency_kernel_log!("\n[lockdep] Possible deadlock on CPU {}: lock {:#x} is being taken at {}, but is already held (taken at {})\n", cpu, lock, at, held_at);
+            },
+            Report::Inversion { lock, at, held, path } => {
+                emergency_kernel_log!("\n[lockdep] Possible deadlock on CPU {}: lock ordering inversion!\n  Taking lock {:#x} at {}\n  while holding lock {:#x} (taken at {}),\n  but they have been taken the other way round before:\n", cpu, lock, at, held.lock, held.at);
+                for e in path.edges.iter().flatten() {
+                    emergency_kernel_log!("    lock {:#x} was held (taken at {}) while taking lock {:#x} at {}\n", e.from, e.from_at, e.to, e.to_at);
+                }
+                if path.len > MAX_REPORTED_PATH { emergency_kernel_log!("    ... ({} more)\n", path.len - MAX_REPORTED_PATH); }
+            },
+            Report::YieldWhileHolding { at, held } => {
+                emergency_kernel_log!("\n[lockdep] Yielding at {} on CPU {} while holding lock {:#x}, which mustn't be held across a yield (e.g. a KMutex), taken at {}\n", at, cpu, held.lock, held.at);
+            },
+        }
+    }
+}
+fn warn_full(what: &str) {
+    if !WARNED_FULL.swap(true, Ordering::Relaxed) {
+        emergency_kernel_log!("\n[lockdep] Ran out of space for {}! Some lock orders will not be checked.\n", what);
+    }
+}
+
+// == HOOKS ==
+fn _check_order(held: &HeldLocks, lock: usize, shared: bool, at: Site) -> Option<Report> {
+    // (two shared locks don't exclude each other, anything else does)
+    if let Some(h) = held.iter().find(|h|h.lock == lock && !(shared && h.shared)) {
+        return Some(Report::Recursive { lock, at, held_at: h.at });
+    }
+    let mut graph = GRAPH.lock();
+    for h in held.iter().filter(|h|h.lock != lock) {
+        match graph.add(Edge { from: h.lock, to: lock, from_at: h.at, to_at: at }) {
+            Added::Exists => {},
+            // (only new orders can close a cycle)
+            Added::New => if let Some(path) = graph.find_path(lock, h.lock) {
+                return Some(Report::Inversion { lock, at, held: *h, path });
+            },
+            Added::Full => warn_full("lock orders"),
+        }
+    }
+    None
+}
+
+/* Called before blocking on a lock, to check that taking it (while holding whatever we're holding) can't deadlock. */
+#[track_caller]
+pub fn check_order<L>(lock: &L, shared: bool) {
+    let at = Location::caller();
+    let lock = lock as *const L as usize;
+    with_cpu(|cpu|{
+        let report = cpu.with_held(|held|_check_order(held, lock, shared, at));
+        if let Some(report) = report { report.emit(); }
+    })
+}
+/* Called once a lock has been taken (including by a successful try_lock). `yields` is true if tasks waiting for the lock yield rather than spin (see MutexStrategy::YIELDS). */
+#[track_caller]
+pub fn acquired<L>(lock: &L, shared: bool, yields: bool) {
+    let at = Location::caller();
+    let lock = lock as *const L as usize;
+    with_cpu(|cpu|cpu.with_held(|held|held.push(HeldLock { lock, shared, spinning: !yields, at })))
+}
+/* Called just before a lock is released */
+pub fn released<L>(lock: &L) {
+    let lock = lock as *const L as usize;
+    // (if it isn't there, it's been handed over from elsewhere or we ran out of space for it, and there's nothing useful we can do)
+    with_cpu(|cpu|{ cpu.with_held(|held|held.remove(lock)); })
+}
+/* Called when a lock is dropped, so that whatever's allocated at its address next doesn't inherit its lock orders */
+pub fn forget<L>(lock: &L) {
+    let lock = lock as *const L as usize;
+    with_cpu(|_|GRAPH.lock().forget(lock))
+}
+/* Called by yield_to_scheduler, to check we aren't holding any locks whose waiters spin */
+#[track_caller]
+pub fn check_yield() {
+    let at = Location::caller();
+    with_cpu(|cpu|{
+        let held = cpu.with_held(|held|held.iter().find(|h|h.spinning).copied());
+        if let Some(held) = held { Report::YieldWhileHolding { at, held }.emit(); }
+    })
+}
+
+pub(crate) mod lockdep_test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::format;
+    use crate::sync::kspin::KMutex;
+    use crate::sync::hspin::HMutex;
+    use crate::sync::yspin::YMutex;
+    use crate::sync::llspin::LLMutex;
+    use crate::multitasking::scheduler::spin_yield;
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        // (the locks are dropped after each round, so if forget() didn't work, the second round would find the orders already known and report nothing)
+        for round in 0..2 {
+            let a = Box::new(KMutex::new(()));
+            let b = Box::new(HMutex::new(()));
+            let before = report_count();
+            { let _a = a.lock(); let _b = b.lock(); }
+            if report_count() != before { return Err(format!("Reported a problem when taking the locks in one order (round {})", round)); }
+            { let _b = b.lock(); let _a = a.lock(); }
+            if report_count() != before+1 { return Err(format!("Taking the locks in the opposite order was not reported (round {})", round)); }
+        }
+
+        // Whether a lock may be held across a yield depends on the lock, not on what else was held when it was taken
+        let outer = Box::new(KMutex::new(()));
+        let yielding = Box::new(YMutex::new(()));
+        let before = report_count();
+        {
+            let outer = outer.lock();
+            let _yielding = yielding.lock();
+            drop(outer);
+            spin_yield();
+        }
+        if report_count() != before { return Err(format!("Yielding while holding a YMutex (taken inside a KMutex's critical section) was reported")); }
+        let spinning = Box::new(LLMutex::new(()));
+        {
+            let _spinning = spinning.lock();
+            spin_yield();
+        }
+        if report_count() != before+1 { return Err(format!("Yielding while holding a spinning lock was not reported")); }
+        Ok(())
+    }
+}
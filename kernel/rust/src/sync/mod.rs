@@ -1,6 +1,12 @@
 pub mod baselocks;
 pub mod spinlocks;
 pub mod nointerruptionslocks;
+/// lockdep - Lock ordering validation (dbg_lockdep only)
+#[cfg(feature="dbg_lockdep")]
+pub mod lockdep;
+/// spintimeout - Bounded spinning and lock holder reports (dbg_spin_timeout only)
+#[cfg(feature="dbg_spin_timeout")]
+pub mod spintimeout;
 
 // == MUTEXES AND RWLOCKS ==
 /// llspin - Low-level spin locks (used for implementing no_interruptions and cpulocals)
@@ -12,8 +18,12 @@ pub mod yspin;
 pub use yspin::*;
 // hspin - Hybrid Spin (yields to scheduler if possible, otherwise behaves like kspin. Always applies no_interruptions, even if used inside a task)
 pub mod hspin;
-// wlock - Waiting List Locks (ticket mutexes using WaitingLists)
-// TODO
+/// wlock - Waiting List Locks (ticket mutexes using WaitingLists)
+pub mod wlock;
+pub use wlock::{WMutex,WMutexGuard,WRwLock,WRwLockReadGuard,WRwLockWriteGuard,LockStatsExt};
+/// asynclocks - Acquiring locks from futures
+pub mod asynclocks;
+pub use asynclocks::{MutexAsyncExt,RwLockAsyncExt};
 
 // == OTHER USEFUL PRIMITIVES ==
 /// waitlist - "Waiting Lists" for tasks to queue up on
@@ -22,6 +32,21 @@ pub use waitlist::*;
 /// queue - A locked queue
 pub mod queue;
 pub use queue::*;
+/// channel - Bounded and unbounded MPMC channels, with select
+pub mod channel;
+pub use channel::{Sender,Receiver,Selectable};
 /// promise - Completable promises
 pub mod promise;
-pub use promise::*;
\ No newline at end of file
+pub use promise::*;
+/// rcu - Read-copy-update, for read-mostly data
+pub mod rcu;
+pub use rcu::{RcuCell,RcuReadGuard,rcu_read_lock,synchronize_rcu,call_rcu};
+/// condvar - Condition variables for use with YMutex/WMutex
+pub mod condvar;
+pub use condvar::Condvar;
+/// semaphore - Counting semaphores
+pub mod semaphore;
+pub use semaphore::{Semaphore,SemaphorePermit};
+/// barrier - Reusable barriers
+pub mod barrier;
+pub use barrier::Barrier;
\ No newline at end of file
@@ -44,7 +44,7 @@ impl<T,G> DerefMut for NoInterruptionsGuardWrapper<G> where G:DerefMut<Target=T>
 
 macro_rules! ni_wrap_lock {
     ($vis:vis fn $fname:ident(&self) -> wrap($baset:ident<'_,T,S>)) => {
-        #[cfg_attr(feature="dbg_track_nointerrupt_source", track_caller)]
+        #[cfg_attr(any(feature="dbg_track_nointerrupt_source", feature="dbg_lockdep"), track_caller)]
         $vis fn $fname(&self) -> NoInterruptionsGuardWrapper<$baset<'_,T,S>> {
             let ni = disable_interruptions(); // (we have to disable them here to prevent a rare race condition where one could happen between locking and disabling interruptions)
             // Acquire the lock
@@ -54,7 +54,7 @@ macro_rules! ni_wrap_lock {
         }
     };
     ($vis:vis fn $fname:ident(&self) -> wrap_Option($baset:ident<'_,T,S>)) => {
-        #[cfg_attr(feature="dbg_track_nointerrupt_source", track_caller)]
+        #[cfg_attr(any(feature="dbg_track_nointerrupt_source", feature="dbg_lockdep"), track_caller)]
         $vis fn $fname(&self) -> Option<NoInterruptionsGuardWrapper<$baset<'_,T,S>>> {
             let ni = disable_interruptions(); // this will be dropped if try_lock fails, or kept if try_lock succeeds
             self.0.$fname().map(|guard|NoInterruptionsGuardWrapper::new(guard,ni))
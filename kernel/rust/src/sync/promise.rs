@@ -91,6 +91,20 @@ impl<T> Promise<T> {
         let value = self.0.waiters.wait_until_try(||self.0.value.get());  // wait until the cell is filled
         value.as_ref().ok_or(())
     }
+    /// The asynchronous version of get(), which waits without blocking the task (see multitasking::executor)
+    pub async fn get_async(&self) -> Result<&T,()> {
+        let value = self.0.waiters.wait_until_try_async(||self.0.value.get()).await;
+        value.as_ref().ok_or(())
+    }
+    /// Get the result of the promise, blocking until fulfilled or until the timeout expires.
+    /// Returns Err(false) if it timed out, and Err(true) if the PromiseFulfiller was dropped without completing the promise (same as try_get)
+    pub fn get_timeout(&self, timeout: core::time::Duration) -> Result<&T,bool> {
+        match self.0.waiters.wait_until_try_timeout(||self.0.value.get(), timeout) {
+            Some(Some(value)) => Ok(value),
+            Some(None) => Err(true),
+            None => Err(false),
+        }
+    }
     /// Try to get the result of the promise. Returns Err(false) if the promise is not yet completed, and Err(true) if the promise has been cancelled.
     pub fn try_get(&self) -> Result<&T,bool> {
         match self.0.value.get() {
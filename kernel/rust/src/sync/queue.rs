@@ -1,13 +1,22 @@
 
-use super::{WaitingList,YMutex};
+use super::WaitingList;
+use super::kspin::KMutex;
 use alloc::collections::VecDeque;
 
 /// A synchronized queue.
+/// Items may be pushed from anywhere (including interrupt handlers), as the queue itself is only ever locked briefly, with interruptions disabled.
 pub struct WQueue<T> {
     waiters: WaitingList,
-    queue: YMutex<VecDeque<T>>,
+    queue: KMutex<VecDeque<T>>,
 }
 impl<T> WQueue<T> {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitingList::new(),
+            queue: KMutex::new(VecDeque::new()),
+        }
+    }
+    
     /// Get an item from the queue, blocking until one is available
     pub fn get(&self) -> T {
         loop{
@@ -18,6 +27,10 @@ impl<T> WQueue<T> {
             self.waiters.wait_until(||!self.queue.lock().is_empty());
         }
     }
+    /// The asynchronous version of get(), which waits without blocking the task (see multitasking::executor)
+    pub async fn get_async(&self) -> T {
+        self.waiters.wait_until_try_async(||self.queue.lock().pop_front()).await
+    }
     /// Try and get an item from the queue. Blocks until the queue is unlocked, returns Some() if one was there or None if the queue was empty.
     pub fn get_if_available(&self) -> Option<T> {
         self.queue.lock().pop_front()
@@ -26,6 +39,10 @@ impl<T> WQueue<T> {
     pub fn get_nonblocking(&self) -> Option<T> {
         self.queue.try_lock().map(|mut q|q.pop_front()).flatten()
     }
+    /// The number of items currently in the queue
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
     
     /// Push an item to the queue
     pub fn push(&self, item: T) {
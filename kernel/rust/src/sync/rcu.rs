@@ -0,0 +1,316 @@
+//! Read-copy-update, for read-mostly data that's read from hot paths (and from anywhere, including interrupt handlers and the scheduler).
+//! Readers take an RcuReadGuard (which costs nothing, not even an atomic), and may then follow RCU-protected pointers (see RcuCell) for as long as they hold it.
+//! Writers publish a new copy instead of modifying the data in place, and free the old one (with call_rcu or after synchronize_rcu) once every reader that might still be using it has finished.
+//!
+//! As tasks are only ever switched out when they yield, a read-side critical section is simply a stretch of code that doesn't yield to the scheduler (debug builds check this).
+//! So a CPU that has yielded (or reached a preempt_point) since an update is no longer using the old copy (a "quiescent state"),
+//!     and once every CPU has passed through one, the "grace period" is over. CPUs that are idle (halted with nothing to run, and not handling an interrupt) aren't waited for at all,
+//!     so a grace period doesn't depend on every CPU receiving timer ticks.
+//! Each CPU must eventually pass through one, so tasks that run for a long time without yielding should call preempt_point regularly (as they should anyway).
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool,AtomicPtr,AtomicUsize,Ordering};
+use super::WaitingList;
+use super::kspin::KMutex;
+use crate::multitasking::cpulocal::CpuLocal;
+use crate::multitasking::fixedcpulocal::fixed_cpu_local;
+use crate::multitasking::scheduler::{yield_to_scheduler,SchedulerCommand};
+use crate::multitasking::util::def_task_fn;
+
+// == READERS ==
+/// A read-side critical section. References obtained through it (see RcuCell::read) must not outlive it.
+/// It must not be held across anything that yields to the scheduler (e.g. YMutexes, waiting lists, or the memory allocators when interruptions are enabled), as that would let the grace period end while it's still in use.
+pub struct RcuReadGuard {
+    /// (not Send, as the critical section belongs to the current CPU)
+    _not_send: PhantomData<*const ()>,
+}
+/* Enter a read-side critical section. These may be nested. */
+#[inline(always)]
+pub fn rcu_read_lock() -> RcuReadGuard {
+    #[cfg(debug_assertions)]
+    RCU_READ_NESTING.store(RCU_READ_NESTING.load(Ordering::Relaxed)+1, Ordering::Relaxed);
+    RcuReadGuard { _not_send: PhantomData }
+}
+impl core::ops::Drop for RcuReadGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        RCU_READ_NESTING.store(RCU_READ_NESTING.load(Ordering::Relaxed)-1, Ordering::Relaxed);
+    }
+}
+// The number of read-side critical sections the current CPU is in (only tracked in debug builds, to catch ones that yield)
+// (these are per-CPU rather than per-task, but that's fine as critical sections can't be moved between CPUs without yielding)
+fixed_cpu_local!(fixedcpulocal static RCU_READ_NESTING: AtomicUsize = AtomicUsize::new(0));
+/* Returns true if the current CPU is in a read-side critical section. Only tracked in debug builds (otherwise this always returns false). */
+pub fn in_rcu_read_section() -> bool {
+    cfg!(debug_assertions) && RCU_READ_NESTING.load(Ordering::Relaxed) > 0
+}
+
+// == GRACE PERIODS ==
+#[derive(Default)]
+struct RcuCpuState {
+    /// Incremented each time the CPU passes through a quiescent state
+    quiescent: AtomicUsize,
+    /// Set while the CPU is halted with nothing to run, except while it's handling an interrupt (see rcu_irq_enter)
+    idle: AtomicBool,
+    /// Set once the CPU's scheduler has started (CPUs that haven't started aren't waited for)
+    online: AtomicBool,
+}
+static RCU_CPUS: CpuLocal<RcuCpuState,true> = CpuLocal::new();
+
+/* Called by the scheduler once it's running on the current CPU */
+pub(crate) fn rcu_cpu_online() {
+    RCU_CPUS.online.store(true, Ordering::Release);
+}
+/* Record that the current CPU isn't in a read-side critical section. Called by the scheduler whenever a task yields, and from preempt_point. */
+#[inline]
+pub(crate) fn rcu_quiescent_state() {
+    debug_assert!(!in_rcu_read_section(), "Task yielded inside an RCU read-side critical section!");
+    RCU_CPUS.quiescent.fetch_add(1, Ordering::SeqCst);
+}
+// Whether the current CPU is in the scheduler's idle loop (a copy of RcuCpuState::idle that interrupt handlers can check without touching RCU_CPUS, which may not have been created yet)
+fixed_cpu_local!(fixedcpulocal static RCU_IDLE: AtomicBool = AtomicBool::new(false));
+/* Called by the scheduler around halting. Interruptions must be disabled.
+    (SeqCst, so that synchronize_rcu either sees the CPU as idle, or sees its next quiescent state) */
+pub(crate) fn rcu_enter_idle() {
+    RCU_IDLE.store(true, Ordering::Relaxed);
+    RCU_CPUS.idle.store(true, Ordering::SeqCst);
+}
+pub(crate) fn rcu_exit_idle() {
+    RCU_CPUS.idle.store(false, Ordering::SeqCst);
+    RCU_IDLE.store(false, Ordering::Relaxed);
+}
+/* Called around IRQ handlers. Interrupt handlers may enter read-side critical sections (e.g. to log), so an idle CPU isn't idle while it's handling one.
+    Once the handler has finished, it can't be reading any more (as interrupt handlers don't nest), so it's a quiescent state. */
+#[inline]
+pub(crate) fn rcu_irq_enter() {
+    if RCU_IDLE.load(Ordering::Relaxed) { RCU_CPUS.idle.store(false, Ordering::SeqCst); }
+}
+#[inline]
+pub(crate) fn rcu_irq_exit() {
+    if RCU_IDLE.load(Ordering::Relaxed) {
+        RCU_CPUS.quiescent.fetch_add(1, Ordering::SeqCst);
+        RCU_CPUS.idle.store(true, Ordering::SeqCst);
+    }
+}
+
+/* Wait until every read-side critical section that was running when this was called has finished (i.e. until every other CPU has passed through a quiescent state, or been seen idle).
+    Must be called in task context, outside of any read-side critical section. */
+pub fn synchronize_rcu() {
+    // (the current CPU is in a quiescent state right now, as we're a task that's allowed to yield)
+    rcu_quiescent_state();
+    let cpu = crate::multitasking::get_cpu_num();
+    let waiting_for: Vec<(usize,usize)> = (0..CpuLocal::slot_count(&RCU_CPUS))
+        .filter(|c|*c != cpu && CpuLocal::get_for(&RCU_CPUS, *c).online.load(Ordering::Acquire))
+        .map(|c|(c, CpuLocal::get_for(&RCU_CPUS, c).quiescent.load(Ordering::SeqCst))).collect();
+    for (c, seen) in waiting_for {
+        let state = CpuLocal::get_for(&RCU_CPUS, c);
+        // (a CPU seen idle after we started can't be in a critical section that began before then - it may start a new one when it wakes, but that one will see the update)
+        while state.quiescent.load(Ordering::SeqCst) == seen && !state.idle.load(Ordering::SeqCst) {
+            yield_to_scheduler(SchedulerCommand::SleepNTicks(1));
+        }
+    }
+}
+
+// == CALLBACKS ==
+/// Callbacks waiting for a grace period, in the order they were queued
+static CALLBACKS: KMutex<Vec<Box<dyn FnOnce()+Send>>> = KMutex::new(Vec::new());
+static CALLBACK_WAITERS: WaitingList = WaitingList::new();
+/// Notified each time the RCU task finishes a batch of callbacks
+static BATCH_DONE: WaitingList = WaitingList::new();
+
+/* Call the given function once a grace period has passed (e.g. to free an old copy of something). This may be called from anywhere, including interrupt handlers.
+    Callbacks are run in task context by the RCU task (see spawn_rcu_task), in the order they were queued. */
+pub fn call_rcu(callback: impl FnOnce()+Send+'static) {
+    CALLBACKS.lock().push(Box::new(callback));
+    CALLBACK_WAITERS.notify_one();
+}
+
+/// Callbacks queued by call_rcu_quietly, as a lock-free stack (most recent first)
+static QUIET_CALLBACKS: AtomicPtr<QuietCallback> = AtomicPtr::new(core::ptr::null_mut());
+struct QuietCallback {
+    next: *mut QuietCallback,
+    callback: Box<dyn FnOnce()+Send>,
+}
+/* Like call_rcu, but without taking any locks or waking the RCU task: the callback is run along with its next batch instead (so it may wait a while, until someone else calls call_rcu).
+    This is for code that call_rcu itself relies on (waking a task uses CpuLocals), which would otherwise re-enter it. */
+fn call_rcu_quietly(callback: impl FnOnce()+Send+'static) {
+    let node = Box::into_raw(Box::new(QuietCallback { next: core::ptr::null_mut(), callback: Box::new(callback) }));
+    let mut head = QUIET_CALLBACKS.load(Ordering::Relaxed);
+    loop {
+        // SAFETY: The node isn't shared until the compare_exchange succeeds
+        unsafe { (*node).next = head; }
+        match QUIET_CALLBACKS.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(current) => head = current,
+        }
+    }
+}
+/* Take every callback queued by call_rcu_quietly so far, in the order they were queued */
+fn take_quiet_callbacks() -> Vec<Box<dyn FnOnce()+Send>> {
+    let mut node = QUIET_CALLBACKS.swap(core::ptr::null_mut(), Ordering::Acquire);
+    let mut callbacks = Vec::new();
+    while !node.is_null() {
+        // SAFETY: The nodes were created by Box::into_raw, and swapping the head out gave us sole ownership of them
+        let boxed = unsafe { Box::from_raw(node) };
+        node = boxed.next;
+        callbacks.push(boxed.callback);
+    }
+    callbacks.reverse();
+    callbacks
+}
+/* Wait until every callback queued by call_rcu before this was called has run. */
+pub fn rcu_barrier() {
+    let done = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&done);
+    call_rcu(move || flag.store(true, Ordering::Release));
+    BATCH_DONE.wait_until(||done.load(Ordering::Acquire));
+}
+
+def_task_fn! {
+    task fn rcu_task() {
+        loop {
+            let batch = CALLBACK_WAITERS.wait_until_try(||{
+                let mut callbacks = CALLBACKS.lock();
+                (!callbacks.is_empty()).then(||core::mem::take(&mut *callbacks))
+            });
+            // (anything queued quietly before the grace period starts is covered by it too)
+            let quiet = take_quiet_callbacks();
+            synchronize_rcu();
+            for callback in quiet { callback(); }
+            for callback in batch { callback(); }
+            BATCH_DONE.notify_all();
+        }
+    }
+}
+/* Spawn the task that runs call_rcu's callbacks. Until it's been spawned, they're only queued. */
+pub fn spawn_rcu_task() {
+    rcu_task::spawn();
+}
+
+// == RCU-PROTECTED POINTERS ==
+/// An RCU-protected pointer to a T (or to nothing).
+/// Readers get a reference to the current value for as long as their read-side critical section lasts. Writers replace it with a new value, and the old one is dropped once the grace period is over.
+/// Writers are serialised with a KMutex, so they may update it from anywhere (including interrupt handlers).
+pub struct RcuCell<T> {
+    ptr: AtomicPtr<T>,
+    writer: KMutex<()>,
+}
+impl<T> RcuCell<T> {
+    pub const fn empty() -> Self {
+        Self { ptr: AtomicPtr::new(core::ptr::null_mut()), writer: KMutex::new(()) }
+    }
+    pub fn new(value: T) -> Self {
+        Self { ptr: AtomicPtr::new(Box::into_raw(Box::new(value))), writer: KMutex::new(()) }
+    }
+
+    /* Get the current value (or None if it's empty) */
+    #[inline]
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> Option<&'a T> {
+        // SAFETY: Old values are only dropped after a grace period, which can't end while the guard is held
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+    /* Get the current value mutably. This doesn't need a grace period, as nobody else can be reading it. */
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { self.ptr.get_mut().as_mut() }
+    }
+}
+impl<T: Send+'static> RcuCell<T> {
+    /* Publish a new value. The old one is dropped once the grace period is over (see call_rcu). */
+    pub fn replace(&self, value: T) {
+        let writer = self.writer.lock();
+        let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        drop(writer);
+        Self::_retire(old);
+    }
+    /* Publish a new value, made from (a copy of) the current one. Updates are serialised, so none are lost. */
+    pub fn update(&self, updater: impl FnOnce(Option<&T>)->T) {
+        let writer = self.writer.lock();
+        // SAFETY: We hold the writer lock, so the current value can't be replaced (let alone dropped) while we're using it
+        let new = updater(unsafe { self.ptr.load(Ordering::Acquire).as_ref() });
+        let old = self.ptr.swap(Box::into_raw(Box::new(new)), Ordering::AcqRel);
+        drop(writer);
+        Self::_retire(old);
+    }
+    /* The same as update, but the old value is dropped with call_rcu_quietly, so this never takes call_rcu's locks or wakes the RCU task.
+        Used by CpuLocal, as call_rcu uses CpuLocals (and may do so while creating one). */
+    pub(crate) fn update_quietly(&self, updater: impl FnOnce(Option<&T>)->T) {
+        let writer = self.writer.lock();
+        // SAFETY: (see update)
+        let new = updater(unsafe { self.ptr.load(Ordering::Acquire).as_ref() });
+        let old = self.ptr.swap(Box::into_raw(Box::new(new)), Ordering::AcqRel);
+        drop(writer);
+        if old.is_null() { return; }
+        // SAFETY: (see _retire)
+        let old = unsafe { Box::from_raw(old) };
+        call_rcu_quietly(move || drop(old));
+    }
+    /* Drop an unpublished value once the grace period is over. (this is done after releasing the writer lock, as call_rcu may wake tasks, which uses CpuLocals, which are themselves RcuCells) */
+    fn _retire(old: *mut T) {
+        if old.is_null() { return; }
+        // SAFETY: The old pointer was created by Box::into_raw, and has now been unpublished
+        let old = unsafe { Box::from_raw(old) };
+        call_rcu(move || drop(old));
+    }
+}
+impl<T> core::ops::Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        let ptr = *self.ptr.get_mut();
+        // SAFETY: We have exclusive access, so nobody can still be reading it
+        if !ptr.is_null() { drop(unsafe { Box::from_raw(ptr) }); }
+    }
+}
+impl<T: Default> core::default::Default for RcuCell<T> {
+    fn default() -> Self { Self::new(T::default()) }
+}
+// SAFETY: References to the value are shared between CPUs (so T must be Sync), and it may be dropped by the RCU task (so T must be Send)
+unsafe impl<T: Send+Sync> Send for RcuCell<T> {}
+unsafe impl<T: Send+Sync> Sync for RcuCell<T> {}
+
+pub(crate) mod rcu_test {
+    use super::*;
+    use alloc::format;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Value(usize);
+    impl core::ops::Drop for Value {
+        fn drop(&mut self) { DROPPED.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        DROPPED.store(0, Ordering::SeqCst);
+        let cell = RcuCell::new(Value(1));
+        {
+            let guard = rcu_read_lock();
+            let old = cell.read(&guard).unwrap();
+            // Replacing it mid-read mustn't drop the value we're holding
+            cell.replace(Value(2));
+            if old.0 != 1 || DROPPED.load(Ordering::SeqCst) != 0 { return Err(format!("Old value was dropped while it was being read")); }
+            if cell.read(&guard).unwrap().0 != 2 { return Err(format!("New value wasn't published")); }
+        }
+        rcu_barrier();
+        if DROPPED.load(Ordering::SeqCst) != 1 { return Err(format!("Old value wasn't dropped after the grace period ({} dropped)", DROPPED.load(Ordering::SeqCst))); }
+        cell.update(|v|Value(v.unwrap().0 + 1));
+        synchronize_rcu();
+        if cell.read(&rcu_read_lock()).unwrap().0 != 3 { return Err(format!("update() lost the value")); }
+        drop(cell);
+        rcu_barrier();
+        if DROPPED.load(Ordering::SeqCst) != 3 { return Err(format!("{} values dropped (expected 3)", DROPPED.load(Ordering::SeqCst))); }
+        // A callback queued before a read-side critical section mustn't run until it's over (however long it lasts), as this CPU can't pass through a quiescent state in the meantime
+        let called = Arc::new(AtomicBool::new(false));
+        { let called = Arc::clone(&called); call_rcu(move ||called.store(true, Ordering::SeqCst)); }
+        {
+            let _guard = rcu_read_lock();
+            for _ in 0..1_000_000 {
+                if called.load(Ordering::SeqCst) { return Err(format!("call_rcu callback ran during a read-side critical section")); }
+                core::hint::spin_loop();
+            }
+        }
+        rcu_barrier();
+        if !called.load(Ordering::SeqCst) { return Err(format!("call_rcu callback didn't run")); }
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! Counting semaphores, built on a WaitingList.
+//! Acquiring may only be done in task context, but permits may be released from anywhere (including interrupt handlers, where the notification is deferred if the list is locked).
+//! Tasks are woken in the order they started waiting, but a task that arrives just as a permit is released may take it first.
+
+use core::sync::atomic::{AtomicUsize,Ordering};
+use core::time::Duration;
+use super::WaitingList;
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitingList,
+}
+impl Semaphore {
+    /* Create a semaphore with the given number of permits available */
+    pub const fn new(permits: usize) -> Self {
+        Self { permits: AtomicUsize::new(permits), waiters: WaitingList::new() }
+    }
+
+    /// The number of permits available right now
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    /* Take a permit if one is available, without waiting. Returns false if there were none. */
+    pub fn try_acquire(&self) -> bool {
+        self.permits.fetch_update(Ordering::AcqRel, Ordering::Acquire, |p|p.checked_sub(1)).is_ok()
+    }
+    /* Take a permit, waiting until one is available */
+    pub fn acquire(&self) {
+        self.waiters.wait_until(||self.try_acquire())
+    }
+    /* Same as acquire, but gives up after the given timeout. Returns false if it timed out. */
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.waiters.wait_until_timeout(||self.try_acquire(), timeout)
+    }
+    /* Take a permit (see acquire), returning a guard which releases it when dropped */
+    pub fn access(&self) -> SemaphorePermit<'_> {
+        self.acquire();
+        SemaphorePermit(self)
+    }
+
+    /* Release a permit, waking a task waiting for one (if any) */
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::AcqRel);
+        self.waiters.notify_one();
+    }
+    /* Release several permits at once */
+    pub fn release_n(&self, n: usize) {
+        self.permits.fetch_add(n, Ordering::AcqRel);
+        // (whoever doesn't get a permit goes back to waiting)
+        if n == 1 { self.waiters.notify_one(); }
+        else if n > 1 { self.waiters.notify_all(); }
+    }
+}
+
+/// A permit taken with Semaphore::access. It's released when dropped.
+pub struct SemaphorePermit<'a>(&'a Semaphore);
+impl core::ops::Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.0.release()
+    }
+}
+
+pub(crate) mod semaphore_test {
+    use super::*;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use crate::multitasking::scheduler::spin_yield;
+    use crate::multitasking::util::def_task_fn;
+
+    const PERMITS: usize = 2;
+    const WORKERS: usize = 6;
+    static SEMAPHORE: Semaphore = Semaphore::new(PERMITS);
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+
+    def_task_fn! {
+        task fn worker() {
+            let _permit = SEMAPHORE.access();
+            let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_INSIDE.fetch_max(inside, Ordering::SeqCst);
+            for _ in 0..4 { spin_yield(); }
+            INSIDE.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        MAX_INSIDE.store(0, Ordering::SeqCst);
+        let tasks: Vec<_> = (0..WORKERS).map(|_|worker::spawn()).collect();
+        for task in tasks.iter() { task.join().map_err(|e|format!("Worker failed: {:?}", e))?; }
+        let max = MAX_INSIDE.load(Ordering::SeqCst);
+        if max > PERMITS { return Err(format!("{} workers were let in at once (only {} permits)", max, PERMITS)); }
+        if SEMAPHORE.available() != PERMITS { return Err(format!("{} permits left afterwards (expected {})", SEMAPHORE.available(), PERMITS)); }
+        // With every permit taken, acquire_timeout must give up
+        for _ in 0..PERMITS { SEMAPHORE.acquire(); }
+        let timed_out = !SEMAPHORE.acquire_timeout(Duration::from_millis(20));
+        SEMAPHORE.release_n(PERMITS);
+        if !timed_out { return Err(format!("acquire_timeout succeeded with no permits available")); }
+        Ok(())
+    }
+}
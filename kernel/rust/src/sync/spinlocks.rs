@@ -1,16 +1,26 @@
 use spin::relax::RelaxStrategy;
 use super::baselocks::{MutexStrategy,RwLockStrategy};
 
-pub struct SpinLockStrategy<R:RelaxStrategy>(core::marker::PhantomData<R>);
-impl<R:RelaxStrategy> MutexStrategy for SpinLockStrategy<R> {
+/// A RelaxStrategy that says whether it yields to the scheduler
+pub trait LockRelaxStrategy: RelaxStrategy {
+    const YIELDS: bool;
+}
+impl LockRelaxStrategy for spin::relax::Spin {
+    const YIELDS: bool = false;
+}
+
+pub struct SpinLockStrategy<R:LockRelaxStrategy>(core::marker::PhantomData<R>);
+impl<R:LockRelaxStrategy> MutexStrategy for SpinLockStrategy<R> {
     const INIT: Self = Self(core::marker::PhantomData);
+    const YIELDS: bool = R::YIELDS;
     #[inline(always)]
     fn on_unlock(&self){}
     #[inline(always)]
     fn lock_relax(&self){R::relax()}
 }
-impl<R:RelaxStrategy> RwLockStrategy for SpinLockStrategy<R> {
+impl<R:LockRelaxStrategy> RwLockStrategy for SpinLockStrategy<R> {
     const INIT: Self = Self(core::marker::PhantomData);
+    const YIELDS: bool = R::YIELDS;
     
     #[inline(always)]
     fn on_read_unlock(&self, new_reader_count: usize){}
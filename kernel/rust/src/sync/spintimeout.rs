@@ -0,0 +1,187 @@
+//! Spinlock timeouts, for diagnosing hangs. Only compiled with the dbg_spin_timeout feature.
+//! Spinning on a lock built on baselocks (KMutex, LLMutex, YMutex, HMutex and their RwLocks) is bounded by a budget of timestamp counter cycles.
+//! When the budget runs out, we report the lock and whoever is holding it (their CPU, task, and where they took it).
+//!     Reports are written with emergency_kernel_log, as the stuck lock may well be one of the logging pipeline's.
+//! Then we either panic or keep waiting, depending on set_spin_timeout. If we keep waiting, the budget doubles before the next report, so a slow holder doesn't flood the serial port.
+//!     Locks whose waiters yield (YMutex, and so waiting lists) are only ever reported, as they may legitimately be held for a long time (e.g. across a sleep).
+//! Only exclusive holders are recorded (writers and upgraders, for RwLocks), as a lock may have any number of readers.
+
+use core::fmt;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool,AtomicU64,AtomicUsize,AtomicPtr,Ordering};
+use crate::multitasking::arch::idle::read_timestamp;
+use crate::multitasking::scheduler::get_executing_task_id_lockless;
+use crate::multitasking::get_cpu_num;
+use crate::logging::emergency_kernel_log;
+
+/// The default budget: around a second on most machines
+const DEFAULT_BUDGET_CYCLES: u64 = 1<<32;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SpinTimeoutAction {
+    /// Report the timeout, and keep waiting for the lock
+    Report,
+    /// Report the timeout, and panic (or keep waiting, if the lock's waiters yield rather than spin)
+    Panic,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BUDGET_CYCLES: AtomicU64 = AtomicU64::new(DEFAULT_BUDGET_CYCLES);
+static PANIC_ON_TIMEOUT: AtomicBool = AtomicBool::new(false);
+static TIMEOUTS: AtomicUsize = AtomicUsize::new(0);
+
+/* Start recording which CPU and task hold each lock. Must be called after the fixed CPU locals have been initialised (until then, only the locations are recorded). */
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+/* Set how many timestamp counter cycles may be spent spinning on a lock before it's reported, and what to do after reporting it */
+pub fn set_spin_timeout(budget_cycles: u64, action: SpinTimeoutAction) {
+    BUDGET_CYCLES.store(budget_cycles, Ordering::Relaxed);
+    PANIC_ON_TIMEOUT.store(action == SpinTimeoutAction::Panic, Ordering::Relaxed);
+}
+/// The current budget (in timestamp counter cycles) and action (see set_spin_timeout)
+pub fn spin_timeout() -> (u64, SpinTimeoutAction) {
+    let action = if PANIC_ON_TIMEOUT.load(Ordering::Relaxed) { SpinTimeoutAction::Panic } else { SpinTimeoutAction::Report };
+    (BUDGET_CYCLES.load(Ordering::Relaxed), action)
+}
+/// The number of timeouts reported so far
+pub fn timeout_count() -> usize {
+    TIMEOUTS.load(Ordering::Relaxed)
+}
+
+// == HOLDERS ==
+/// Whoever holds a lock exclusively, and where they took it. Stored in the lock itself.
+pub struct LockHolder {
+    cpu: AtomicUsize,
+    task: AtomicUsize,
+    at: AtomicPtr<Location<'static>>,
+}
+impl LockHolder {
+    pub const fn new() -> Self {
+        Self { cpu: AtomicUsize::new(usize::MAX), task: AtomicUsize::new(usize::MAX), at: AtomicPtr::new(core::ptr::null_mut()) }
+    }
+    /* Record the caller as the holder. Called once the lock has been taken. */
+    #[track_caller]
+    pub fn set(&self) {
+        let (cpu, task) = if ENABLED.load(Ordering::Relaxed) { (get_cpu_num(), get_executing_task_id_lockless().unwrap_or(usize::MAX)) } else { (usize::MAX, usize::MAX) };
+        self.cpu.store(cpu, Ordering::Relaxed);
+        self.task.store(task, Ordering::Relaxed);
+        self.at.store(Location::caller() as *const Location<'static> as *mut Location<'static>, Ordering::Release);
+    }
+    /* Forget the holder. Called just before the lock is released, so that we can't wipe out the next holder. */
+    pub fn clear(&self) {
+        self.at.store(core::ptr::null_mut(), Ordering::Release);
+    }
+
+    /// Where the lock was taken, or None if nobody holds it exclusively
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        // SAFETY: Only ever set from Location::caller(), which is 'static
+        unsafe { self.at.load(Ordering::Acquire).as_ref() }
+    }
+    /// The task holding the lock, if any (interrupt handlers count as the task they interrupted)
+    pub fn task(&self) -> Option<usize> {
+        self.location().and(Some(self.task.load(Ordering::Relaxed)).filter(|&t|t != usize::MAX))
+    }
+    /// The CPU holding the lock, if any (and if known)
+    pub fn cpu(&self) -> Option<usize> {
+        self.location().and(Some(self.cpu.load(Ordering::Relaxed)).filter(|&c|c != usize::MAX))
+    }
+}
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // (these are read separately, so may be torn if the lock changes hands while we're reporting)
+        let Some(at) = self.location() else { return write!(f, "no exclusive holder (it may have been released just now)") };
+        match self.cpu() { Some(cpu) => write!(f, "CPU {}", cpu)?, None => write!(f, "an unknown CPU")? };
+        match self.task() { Some(task) => write!(f, " (task {})", task)?, None => write!(f, " (no task)")? };
+        write!(f, ", taken at {}", at)
+    }
+}
+
+/// Locks whose holders can be reported
+pub trait DiagnosableLock {
+    fn holder(&self) -> &LockHolder;
+    /// The number of readers holding the lock (always 0 for mutexes)
+    fn readers(&self) -> usize;
+}
+
+// == TIMERS ==
+/// Tracks how long we've been spinning for a lock. The clock starts on the first call to check, so creating one costs (almost) nothing if the lock isn't contended.
+pub struct SpinTimer {
+    at: &'static Location<'static>,
+    started: u64,
+    budget: u64,
+    /// Set if we're yielding while we wait rather than spinning, in which case timeouts are only reported
+    yields: bool,
+}
+impl SpinTimer {
+    #[track_caller]
+    #[inline]
+    pub fn new(yields: bool) -> Self {
+        Self { at: Location::caller(), started: 0, budget: 0, yields }
+    }
+    /* Called each time we spin. Reports the lock once the budget has run out. */
+    #[inline]
+    pub fn check(&mut self, lock: &impl DiagnosableLock) {
+        let now = read_timestamp();
+        if self.budget == 0 { self.started = now; self.budget = BUDGET_CYCLES.load(Ordering::Relaxed).max(1); return; }
+        if now.wrapping_sub(self.started) >= self.budget { self.timed_out(lock, now); }
+    }
+    #[cold]
+    fn timed_out(&mut self, lock: &impl DiagnosableLock, now: u64) {
+        TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+        let waited = now.wrapping_sub(self.started);
+        let address = lock as *const _ as *const () as usize;
+        let readers = lock.readers();
+        if ENABLED.load(Ordering::Relaxed) {
+            let task = get_executing_task_id_lockless();
+            emergency_kernel_log!("\n[spin timeout] CPU {} (task {:?}) has spent {} cycles waiting for lock {:#x} at {}\n", get_cpu_num(), task, waited, address, self.at);
+        } else {
+            emergency_kernel_log!("\n[spin timeout] Spent {} cycles waiting for lock {:#x} at {}\n", waited, address, self.at);
+        }
+        emergency_kernel_log!("  It is held by {}", lock.holder());
+        if readers > 0 { emergency_kernel_log!(" and {} reader(s)", readers); }
+        emergency_kernel_log!("\n");
+        if PANIC_ON_TIMEOUT.load(Ordering::Relaxed) && !self.yields {
+            panic!("Timed out waiting for lock {:#x} at {} (held by {})", address, self.at, lock.holder());
+        }
+        // Keep waiting, but give it longer before complaining again
+        self.started = now;
+        self.budget = self.budget.saturating_mul(2);
+    }
+}
+
+pub(crate) mod spintimeout_test {
+    use super::*;
+    use alloc::format;
+    use core::time::Duration;
+    use crate::sync::YMutex;
+    use crate::multitasking::{sleep,spin_yield};
+    use crate::multitasking::util::def_task_fn;
+
+    static LOCK: YMutex<()> = YMutex::new(());
+
+    def_task_fn! {
+        task fn sleeper() {
+            let _guard = LOCK.lock();
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        let task = sleeper::spawn();
+        while !LOCK.is_locked() { spin_yield(); }
+        // SAFETY: We only look at the holder
+        let raw = unsafe { LOCK.raw() };
+        if raw.holder().task() != Some(task.task_id()) { return Err(format!("Lock holder recorded as task {:?} (expected {})", raw.holder().task(), task.task_id())); }
+        // Waiting for it (for about 50ms) must run out of a ~5ms budget, and must only be reported (even in Panic mode) as YMutex waiters yield
+        let (budget, action) = spin_timeout();
+        set_spin_timeout(1<<24, SpinTimeoutAction::Panic);
+        let before = timeout_count();
+        drop(LOCK.lock());
+        set_spin_timeout(budget, action);
+        task.join().map_err(|e|format!("Task failed: {:?}", e))?;
+        if timeout_count() == before { return Err(format!("No timeout was reported")); }
+        if raw.holder().location().is_some() { return Err(format!("Holder was not cleared on unlock")); }
+        Ok(())
+    }
+}
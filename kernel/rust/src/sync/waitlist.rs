@@ -1,30 +1,174 @@
 use super::YMutex;
+use super::kspin::KMutex;
 use alloc::collections::VecDeque;
-use crate::multitasking::scheduler;
+use alloc::sync::Arc;
+use core::time::Duration;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context,Poll,Waker};
+use crate::multitasking::{scheduler,Task};
+use crate::multitasking::interruptions::is_sched_yield_disabled;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
 // TODO: figure out why disable_interruptions was needed and how to add it if it was // use crate::multitasking::disable_interruptions;
 
-pub struct WaitingListEntry {
-    pub task: crate::multitasking::Task,
-    pub cpu: usize,
+pub enum WaitingListEntry {
+    /// A task waiting (with no timeout), and the CPU it was last run on
+    Task { task: Task, cpu: usize },
+    /// A task waiting with a timeout. The task may have already been taken by the timeout, in which case the entry is skipped.
+    Timed(Arc<TimedWaiter>),
+    /// A future waiting (see wait_until_try_async). The future may have already given up, in which case the entry is skipped.
+    Async(Arc<AsyncWaiter>),
+}
+
+/// A task waiting on a list with a timeout. It is referenced by both the waiting list and its CPU's timers, and whichever gets to it first (the notification or the timeout) takes the task and wakes it.
+pub struct TimedWaiter {
+    task: KMutex<Option<(Task,usize)>>,
+    timed_out: AtomicBool,
+}
+impl TimedWaiter {
+    pub fn new() -> Self {
+        Self { task: KMutex::new(None), timed_out: AtomicBool::new(false) }
+    }
+    /// Store the task (and the CPU it was last run on) until it's woken. Called by the scheduler.
+    pub(crate) fn park(&self, task: Task, cpu: usize) {
+        let old = self.task.lock().replace((task, cpu));
+        debug_assert!(old.is_none(), "TimedWaiter was used twice!");
+    }
+    /// Take the task, if it hasn't already been woken. Called by the scheduler when the timeout expires.
+    pub(crate) fn take_timed_out(&self) -> Option<(Task,usize)> {
+        let mut task = self.task.lock();
+        let taken = task.take()?;
+        self.timed_out.store(true, Ordering::Release);
+        Some(taken)
+    }
+    /// Take the task, if it hasn't already timed out
+    fn take_notified(&self) -> Option<(Task,usize)> {
+        self.task.lock().take()
+    }
+    /// Returns true if the task was woken by its timeout rather than a notification
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Acquire)
+    }
+}
+
+/// A future waiting to be woken (by a waiting list, or a timer). Whoever takes the waker first (the notification, or the future giving up) wins.
+pub struct AsyncWaiter {
+    waker: KMutex<Option<Waker>>,
+    notified: AtomicBool,
+}
+impl AsyncWaiter {
+    pub fn new(waker: Waker) -> Self {
+        Self { waker: KMutex::new(Some(waker)), notified: AtomicBool::new(false) }
+    }
+    /// Wake the future, if it's still waiting. Returns false if it had already been woken or given up.
+    /// This may be called from anywhere (including interrupt handlers, and with a waiting list locked), so the waker must be safe to call there too (the executor's are).
+    pub fn wake(&self) -> bool {
+        let Some(waker) = self.waker.lock().take() else { return false };
+        self.notified.store(true, Ordering::Release);
+        waker.wake();
+        true
+    }
+    /// Replace the waker (as the future may have been moved to a different executor task since it was last polled). Returns false if it has already been woken (or given up).
+    pub fn update_waker(&self, waker: &Waker) -> bool {
+        let mut current = self.waker.lock();
+        match current.as_mut() {
+            Some(current) => { if !current.will_wake(waker) { *current = waker.clone(); } true },
+            None => false,
+        }
+    }
+    /// Stop waiting. Returns false if it had already been woken.
+    pub fn cancel(&self) -> bool {
+        self.waker.lock().take().is_some()
+    }
+    /// Returns true if the future has been woken (as opposed to still waiting, or having given up)
+    pub fn is_notified(&self) -> bool {
+        self.notified.load(Ordering::Acquire)
+    }
 }
 
 /// A scheduler-based waiting list
-/// Tasks here will sleep until woken by a corresponding notify() call.
-pub struct WaitingList(YMutex<VecDeque<WaitingListEntry>>);
+/// Tasks here will sleep until woken by a corresponding notify() call. Futures may also wait here (see wait_until_try_async), and are woken in turn with the tasks.
+/// notify_one() and notify_all() may also be called from interrupt handlers (or anywhere else where yielding isn't possible).
+/// If the list is locked at the time, the notification is deferred, and is carried out by whoever holds the lock before they release it.
+pub struct WaitingList {
+    list: YMutex<VecDeque<WaitingListEntry>>,
+    /// notify_one() calls that could not be performed immediately
+    deferred_notify_one: AtomicUsize,
+    /// Whether a notify_all() call could not be performed immediately
+    deferred_notify_all: AtomicBool,
+}
+/// A lock on a waiting list's queue. When dropped, any deferred notifications are carried out before the list is unlocked.
+pub struct WaitingListGuard<'a> {
+    waitlist: &'a WaitingList,
+    guard: Option<super::YMutexGuard<'a,VecDeque<WaitingListEntry>>>,
+}
+impl core::ops::Deref for WaitingListGuard<'_> {
+    type Target = VecDeque<WaitingListEntry>;
+    fn deref(&self) -> &Self::Target { self.guard.as_ref().unwrap() }
+}
+impl core::ops::DerefMut for WaitingListGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target { self.guard.as_mut().unwrap() }
+}
+impl core::ops::Drop for WaitingListGuard<'_> {
+    fn drop(&mut self) {
+        let mut guard = self.guard.take().unwrap();
+        loop {
+            self.waitlist.perform_deferred(&mut guard);
+            drop(guard);
+            // If someone deferred a notification in between us checking and unlocking, and nobody else has locked it since, then it's up to us
+            if !self.waitlist.has_deferred() { return; }
+            match self.waitlist.list.try_lock() {
+                Some(g) => guard = g,
+                None => return,  // whoever has it locked now will perform them
+            }
+        }
+    }
+}
 impl WaitingList {
     pub const fn new() -> Self {
-        Self(YMutex::new(VecDeque::new()))
+        Self {
+            list: YMutex::new(VecDeque::new()),
+            deferred_notify_one: AtomicUsize::new(0),
+            deferred_notify_all: AtomicBool::new(false),
+        }
+    }
+    
+    fn lock(&self) -> WaitingListGuard<'_> {
+        WaitingListGuard { waitlist: self, guard: Some(self.list.lock()) }
+    }
+    fn try_lock(&self) -> Option<WaitingListGuard<'_>> {
+        self.list.try_lock().map(|g|WaitingListGuard { waitlist: self, guard: Some(g) })
+    }
+    fn has_deferred(&self) -> bool {
+        self.deferred_notify_one.load(Ordering::SeqCst) > 0 || self.deferred_notify_all.load(Ordering::SeqCst)
+    }
+    fn perform_deferred(&self, list: &mut super::YMutexGuard<'_,VecDeque<WaitingListEntry>>) {
+        if self.deferred_notify_all.swap(false, Ordering::SeqCst) {
+            self.deferred_notify_one.store(0, Ordering::SeqCst);
+            while Self::notify_inner(list){}
+        }
+        let n = self.deferred_notify_one.swap(0, Ordering::SeqCst);
+        for _ in 0..n { Self::notify_inner(list); }
     }
     
-    fn wait_inner(&self, list: super::YMutexGuard<'_,VecDeque<WaitingListEntry>>) {
+    fn wait_inner(&self, list: WaitingListGuard<'_>) {
         // The scheduler takes ownership of the lock and drops it after pushing
         scheduler::yield_to_scheduler(scheduler::SchedulerCommand::PushToWaitingList(core::cell::Cell::new(Some(list))));
     }
+    /// Same as wait_inner, but gives up after the given number of scheduler ticks. Returns true if it timed out.
+    fn wait_inner_timed(&self, list: WaitingListGuard<'_>, ticks: usize) -> bool {
+        let waiter = Arc::new(TimedWaiter::new());
+        scheduler::yield_to_scheduler(scheduler::SchedulerCommand::PushToWaitingListTimed(core::cell::Cell::new(Some(list)), ticks, Arc::clone(&waiter)));
+        if !waiter.timed_out() { return false; }
+        // Our entry is still in the list, so remove it (otherwise lists that are rarely notified would fill up with them)
+        self.lock().retain(|entry|!matches!(entry, WaitingListEntry::Timed(w) if Arc::ptr_eq(w, &waiter)));
+        true
+    }
     /// Yield to the scheduler, and wait until the thread is notified
     /// Note: This makes no guarantee that a notify hasn't happened in between you checking the predicate and calling wait()
     ///       For more robust behaviour, consider using wait_ifnt or wait_until instead.
     pub fn wait(&self) {
-        let list = self.0.lock();
+        let list = self.lock();
         self.wait_inner(list);
     }
     
@@ -32,7 +176,7 @@ impl WaitingList {
     /// This method guarantees that notify() has not been called between checking the predicate and suspending the thread
     /// Returns true if the thread was suspended, false if the predicate returned true early.
     pub fn wait_ifnt(&self, predicate: impl FnOnce()->bool) -> bool {
-        let list = self.0.lock();
+        let list = self.lock();
         if predicate() { return false; }  // Predicate returned true, so return early
         // The scheduler takes ownership of the lock and drops it after pushing
         self.wait_inner(list);
@@ -46,31 +190,169 @@ impl WaitingList {
     /// A version of wait_until that calls the predicate. If it returns Some(x), returns x. If it returns None, waits and then tries again.
     pub fn wait_until_try<R>(&self, predicate: impl Fn()->Option<R>) -> R {
         loop {
-            let list = self.0.lock();
+            let list = self.lock();
             if let Some(value) = predicate() { return value; }
             self.wait_inner(list);
         }
     }
     
-    fn notify_inner(&self, list: &mut super::YMutexGuard<'_,VecDeque<WaitingListEntry>>) -> bool {
-        match list.pop_front() {
-            Some(entry) => {
-                scheduler::push_task_to(entry.cpu, entry.task);
-                true
-            },
-            None => { false },
+    /// Lock the list, call release() (e.g. to unlock a mutex), and then wait until notified.
+    /// As the list is locked before release() is called, any notification sent after it (e.g. by someone who took the mutex afterwards) is guaranteed to wake us. (see Condvar)
+    pub fn wait_releasing(&self, release: impl FnOnce()) {
+        let list = self.lock();
+        release();
+        self.wait_inner(list);
+    }
+    /// Same as wait_releasing, but gives up after the given timeout. Returns false if it timed out.
+    pub fn wait_releasing_timeout(&self, release: impl FnOnce(), timeout: Duration) -> bool {
+        self.wait_releasing_deadline(release, scheduler::get_scheduler_ticks() + scheduler::duration_to_ticks(timeout))
+    }
+    /// Same as wait_releasing_timeout, but gives up at the given deadline (in scheduler ticks, see get_scheduler_ticks), so that several waits can share one timeout.
+    pub fn wait_releasing_deadline(&self, release: impl FnOnce(), deadline: usize) -> bool {
+        let list = self.lock();
+        release();
+        let remaining = deadline.saturating_sub(scheduler::get_scheduler_ticks());
+        if remaining == 0 { return false; }
+        !self.wait_inner_timed(list, remaining)
+    }
+    
+    /// A version of wait_until that gives up after the given timeout. Returns true if the predicate returned true, or false if it timed out.
+    pub fn wait_until_timeout(&self, predicate: impl Fn()->bool, timeout: Duration) -> bool {
+        self.wait_until_try_timeout(||predicate().then_some(()), timeout).is_some()
+    }
+    /// A version of wait_until_try that gives up after the given timeout, returning None.
+    pub fn wait_until_try_timeout<R>(&self, predicate: impl Fn()->Option<R>, timeout: Duration) -> Option<R> {
+        let deadline = scheduler::get_scheduler_ticks() + scheduler::duration_to_ticks(timeout);
+        loop {
+            let list = self.lock();
+            if let Some(value) = predicate() { return Some(value); }
+            let remaining = deadline.saturating_sub(scheduler::get_scheduler_ticks());
+            if remaining == 0 { return None; }
+            self.wait_inner_timed(list, remaining);
         }
     }
+    
+    /// The asynchronous version of wait_until_try: a future which checks the predicate (with the list locked, so no notification can be missed), and waits to be notified if it returns None.
+    /// The future must be polled and dropped in task context (as the list's lock may yield).
+    pub fn wait_until_try_async<R,F:Fn()->Option<R>>(&self, predicate: F) -> WaitUntilTry<'_,R,F> {
+        WaitUntilTry { list: self, predicate, waiter: None }
+    }
+    /// The asynchronous version of wait_until (see wait_until_try_async)
+    pub async fn wait_until_async(&self, predicate: impl Fn()->bool) {
+        self.wait_until_try_async(||predicate().then_some(())).await
+    }
+    
+    fn notify_inner(list: &mut VecDeque<WaitingListEntry>) -> bool {
+        loop {
+            match list.pop_front() {
+                Some(WaitingListEntry::Task { task, cpu }) => {
+                    scheduler::wake_task(cpu, task);
+                    return true;
+                },
+                Some(WaitingListEntry::Timed(waiter)) => {
+                    // (if it has already timed out, move on to the next one)
+                    let Some((task, cpu)) = waiter.take_notified() else { continue };
+                    scheduler::wake_task(cpu, task);
+                    return true;
+                },
+                Some(WaitingListEntry::Async(waiter)) => {
+                    // (if it has already given up, move on to the next one)
+                    if waiter.wake() { return true; }
+                },
+                None => return false,
+            }
+        }
+    }
+    /// Lock the list for notifying. If we can't wait for it (e.g. in an interrupt handler), None is returned if it's already locked.
+    fn lock_for_notify(&self) -> Option<WaitingListGuard<'_>> {
+        if is_sched_yield_disabled() { self.try_lock() }
+        else { Some(self.lock()) }
+    }
     /// Wake up one thread waiting on this list
-    /// Returns true if one was waiting, false otherwise
+    /// Returns true if one was waiting, false otherwise (or if the notification had to be deferred)
     pub fn notify_one(&self) -> bool {
-        let mut list = self.0.lock();
-        self.notify_inner(&mut list)
+        match self.lock_for_notify() {
+            Some(mut list) => Self::notify_inner(&mut list),
+            None => {
+                self.deferred_notify_one.fetch_add(1, Ordering::SeqCst);
+                // (in case it was unlocked in the meantime)
+                drop(self.try_lock());
+                false
+            }
+        }
+    }
+    /// Lock the list, call update(), and then wake up the first thread waiting on it, before unlocking it again.
+    /// As the predicates of wait_until etc. are checked with the list locked, every waiter either sees the state from before update() (and is in the list to be woken), or from after it.
+    /// Unlike notify_one, this must be called from task context (as it waits for the list's lock). Returns true if one was waiting.
+    pub fn notify_one_after(&self, update: impl FnOnce()) -> bool {
+        let mut list = self.lock();
+        update();
+        Self::notify_inner(&mut list)
     }
     /// Wake up all threads waiting on this list
     pub fn notify_all(&self) {
-        let mut list = self.0.lock();
-        // As notify_inner returns true on each success, we can just do this.
-        while self.notify_inner(&mut list){}
+        match self.lock_for_notify() {
+            // As notify_inner returns true on each success, we can just do this.
+            Some(mut list) => while Self::notify_inner(&mut list){},
+            None => {
+                self.deferred_notify_all.store(true, Ordering::SeqCst);
+                drop(self.try_lock());
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Returned by WaitingList::wait_until_try_async
+pub struct WaitUntilTry<'a,R,F:Fn()->Option<R>> {
+    list: &'a WaitingList,
+    predicate: F,
+    /// Our entry in the list, once we've had to wait
+    waiter: Option<Arc<AsyncWaiter>>,
+}
+// (no fields are ever pinned)
+impl<R,F:Fn()->Option<R>> Unpin for WaitUntilTry<'_,R,F> {}
+impl<R,F:Fn()->Option<R>> Future for WaitUntilTry<'_,R,F> {
+    type Output = R;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let this = self.get_mut();
+        let mut list = this.list.lock();
+        if let Some(value) = (this.predicate)() {
+            // Done. Take our entry out of the list if it's still there (if we were notified, it's already gone)
+            if let Some(waiter) = this.waiter.take() {
+                if waiter.cancel() { list.retain(|entry|!matches!(entry, WaitingListEntry::Async(w) if Arc::ptr_eq(w, &waiter))); }
+            }
+            return Poll::Ready(value);
+        }
+        match &this.waiter {
+            Some(waiter) if waiter.update_waker(cx.waker()) => {},  // still queued
+            _ => {
+                let waiter = Arc::new(AsyncWaiter::new(cx.waker().clone()));
+                list.push_back(WaitingListEntry::Async(Arc::clone(&waiter)));
+                this.waiter = Some(waiter);
+            },
+        }
+        Poll::Pending
+    }
+}
+impl<R,F:Fn()->Option<R>> WaitUntilTry<'_,R,F> {
+    /// Stop waiting, without passing on a notification we may have been sent (unlike dropping the future).
+    /// update() is called with the list locked, and if it returns true, the first waiter is woken (as with notify_one_after). This is for waiters who hand on whatever they were woken for themselves (see wlock's TicketLock).
+    pub fn give_up_and(&mut self, update: impl FnOnce()->bool) {
+        let mut list = self.list.lock();
+        if let Some(waiter) = self.waiter.take() {
+            if waiter.cancel() { list.retain(|entry|!matches!(entry, WaitingListEntry::Async(w) if Arc::ptr_eq(w, &waiter))); }
+        }
+        if update() { WaitingList::notify_inner(&mut list); }
+    }
+}
+impl<R,F:Fn()->Option<R>> core::ops::Drop for WaitUntilTry<'_,R,F> {
+    fn drop(&mut self) {
+        let Some(waiter) = self.waiter.take() else { return };
+        if waiter.cancel() {
+            self.list.lock().retain(|entry|!matches!(entry, WaitingListEntry::Async(w) if Arc::ptr_eq(w, &waiter)));
+        } else {
+            // We were notified, but gave up before we could act on it, so pass it on to whoever's next
+            self.list.notify_one();
+        }
+    }
+}
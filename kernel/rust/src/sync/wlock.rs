@@ -0,0 +1,459 @@
+//! WaitLocks: sleeping locks for tasks, built on WaitingLists.
+//! Unlike YMutex (which spins, yielding between attempts), contending tasks are suspended until it's their turn, and the lock is handed to them in the order they asked for it (using tickets), so nobody is starved.
+//! Each lock also keeps statistics on how contended it is (see LockStatsExt).
+//! These may only be used in task context (not in interrupt handlers or the scheduler, as they may suspend the current task).
+//! Futures can wait for them too (see asynclocks), and take their turn in the same queue as tasks. A future that's dropped while waiting gives up its place in the queue.
+//! Note: killing a task that's waiting for one of these locks leaves the lock held forever once it reaches the task (same as killing a task that holds a lock).
+
+use lock_api::{RawMutex,RawRwLock,GuardSend};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context,Poll};
+use core::sync::atomic::*;
+use super::WaitingList;
+use super::waitlist::WaitUntilTry;
+use super::kspin::KMutex;
+use super::asynclocks::{RawMutexAsync,RawRwLockAsync};
+use crate::multitasking::arch::idle::read_timestamp;
+
+/// How contended a lock has been (see LockStatsExt::contention_stats)
+#[derive(Debug,Clone,Copy,Default)]
+pub struct ContentionStats {
+    /// The number of times the lock has been taken
+    pub acquisitions: usize,
+    /// The number of those where the task had to wait
+    pub contended: usize,
+    /// The number of tasks waiting for the lock right now
+    pub waiting: usize,
+    /// Timestamp counter cycles spent waiting for the lock, in total and at most at once
+    pub wait_cycles: u64,
+    pub max_wait_cycles: u64,
+}
+impl ContentionStats {
+    /// The average number of cycles each contended acquisition waited for
+    pub fn average_wait_cycles(&self) -> u64 {
+        if self.contended == 0 { 0 } else { self.wait_cycles / self.contended as u64 }
+    }
+}
+
+struct LockStats {
+    acquisitions: AtomicUsize,
+    contended: AtomicUsize,
+    waiting: AtomicUsize,
+    wait_cycles: AtomicU64,
+    max_wait_cycles: AtomicU64,
+}
+impl LockStats {
+    const fn new() -> Self {
+        Self { acquisitions: AtomicUsize::new(0), contended: AtomicUsize::new(0), waiting: AtomicUsize::new(0), wait_cycles: AtomicU64::new(0), max_wait_cycles: AtomicU64::new(0) }
+    }
+    #[inline]
+    fn record_acquired(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+    /* Wait using the given function, counting the time spent waiting */
+    fn record_wait<R>(&self, wait: impl FnOnce()->R) -> R {
+        let start = self.begin_wait();
+        let result = wait();
+        self.end_wait(start);
+        result
+    }
+    /* Count a wait that's starting, returning the timestamp to pass to end_wait once it's over */
+    fn begin_wait(&self) -> u64 {
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        read_timestamp()
+    }
+    fn end_wait(&self, start: u64) {
+        let waited = read_timestamp().saturating_sub(start);
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.wait_cycles.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_cycles.fetch_max(waited, Ordering::Relaxed);
+    }
+    fn snapshot(&self) -> ContentionStats {
+        ContentionStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            waiting: self.waiting.load(Ordering::Relaxed),
+            wait_cycles: self.wait_cycles.load(Ordering::Relaxed),
+            max_wait_cycles: self.max_wait_cycles.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A ticket lock whose waiters sleep on a waiting list.
+/// Tickets are only taken (when waiting) and served with the list locked, so the list is always in ticket order, and unlocking wakes exactly the task (or future) whose turn it is.
+struct TicketLock {
+    next_ticket: AtomicUsize,
+    /// The ticket of the current holder. The lock is free when this is equal to next_ticket.
+    now_serving: AtomicUsize,
+    waiters: WaitingList,
+    /// Tickets whose futures were dropped before their turn came. They're skipped over when the lock is handed on.
+    /// (now_serving is only advanced with this locked, so a ticket can't be abandoned just as its turn comes)
+    abandoned: KMutex<Vec<usize>>,
+}
+/// TicketWait's ticket before it has taken one
+const NO_TICKET: usize = usize::MAX;
+impl TicketLock {
+    const fn new() -> Self {
+        Self { next_ticket: AtomicUsize::new(0), now_serving: AtomicUsize::new(0), waiters: WaitingList::new(), abandoned: KMutex::new(Vec::new()) }
+    }
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+    #[inline]
+    fn try_lock(&self) -> bool {
+        // (only succeeds if nobody holds it or is waiting for it)
+        let serving = self.now_serving.load(Ordering::SeqCst);
+        self.next_ticket.compare_exchange(serving, serving.wrapping_add(1), Ordering::SeqCst, Ordering::Relaxed).is_ok()
+    }
+    fn lock(&self, stats: &LockStats) {
+        if !self.try_lock() {
+            // (the ticket is taken the first time the predicate is checked, so that it's taken with the list locked)
+            let my_ticket = Cell::new(None);
+            stats.record_wait(||self.waiters.wait_until(||{
+                let ticket = match my_ticket.get() {
+                    Some(ticket) => ticket,
+                    None => { let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst); my_ticket.set(Some(ticket)); ticket },
+                };
+                self.now_serving.load(Ordering::SeqCst) == ticket
+            }));
+        }
+        stats.record_acquired();
+    }
+    /* The asynchronous version of lock(). The future takes a ticket the first time it's polled, and waits in the list like a task would. */
+    fn lock_async<'a>(&'a self, stats: &'a LockStats) -> TicketWait<'a, impl Fn()->Option<()>+'a> {
+        let ticket = Arc::new(AtomicUsize::new(NO_TICKET));
+        let my_ticket = Arc::clone(&ticket);
+        let wait = self.waiters.wait_until_try_async(move ||{
+            let ticket = match my_ticket.load(Ordering::Relaxed) {
+                NO_TICKET => { let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst); my_ticket.store(ticket, Ordering::Relaxed); ticket },
+                ticket => ticket,
+            };
+            (self.now_serving.load(Ordering::SeqCst) == ticket).then_some(())
+        });
+        TicketWait { lock: self, stats, ticket, wait, waiting_since: None, done: false }
+    }
+    /* SAFETY: The lock must be held by the caller */
+    unsafe fn unlock(&self) {
+        // Hand the lock to the next ticket, and wake its holder (which is first in the list, if they're waiting)
+        self.waiters.notify_one_after(||self.advance(&mut self.abandoned.lock()));
+    }
+    /* Move on to the next ticket that hasn't been abandoned. Called with the list locked. */
+    fn advance(&self, abandoned: &mut Vec<usize>) {
+        loop {
+            let serving = self.now_serving.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+            let Some(index) = abandoned.iter().position(|t|*t == serving) else { return };
+            abandoned.swap_remove(index);
+        }
+    }
+    /* Give up the given ticket, whose future has been dropped. Called with the list locked (and the future's entry already taken out of it).
+        Returns true if its turn had already come, in which case the lock is handed on, and the next waiter must be woken. */
+    fn abandon(&self, ticket: usize) -> bool {
+        let mut abandoned = self.abandoned.lock();
+        if self.now_serving.load(Ordering::SeqCst) == ticket {
+            self.advance(&mut abandoned);
+            true
+        } else {
+            abandoned.push(ticket);
+            false
+        }
+    }
+}
+/// Returned by TicketLock::lock_async
+struct TicketWait<'a,F:Fn()->Option<()>> {
+    lock: &'a TicketLock,
+    stats: &'a LockStats,
+    ticket: Arc<AtomicUsize>,
+    wait: WaitUntilTry<'a,(),F>,
+    /// When we first had to wait (for the lock's stats)
+    waiting_since: Option<u64>,
+    /// Set once we've got the lock
+    done: bool,
+}
+impl<F:Fn()->Option<()>> Future for TicketWait<'_,F> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.wait).poll(cx) {
+            Poll::Ready(()) => {
+                this.done = true;
+                if let Some(start) = this.waiting_since.take() { this.stats.end_wait(start); }
+                this.stats.record_acquired();
+                Poll::Ready(())
+            },
+            Poll::Pending => {
+                if this.waiting_since.is_none() { this.waiting_since = Some(this.stats.begin_wait()); }
+                Poll::Pending
+            },
+        }
+    }
+}
+impl<F:Fn()->Option<()>> core::ops::Drop for TicketWait<'_,F> {
+    fn drop(&mut self) {
+        if self.done { return; }
+        if let Some(start) = self.waiting_since.take() { self.stats.end_wait(start); }
+        // (if our turn had come, we were the one woken, so wake whoever's next instead)
+        let ticket = self.ticket.load(Ordering::Relaxed);
+        let lock = self.lock;
+        self.wait.give_up_and(||ticket != NO_TICKET && lock.abandon(ticket));
+    }
+}
+
+pub struct WMutexRaw(TicketLock,LockStats);
+unsafe impl RawMutex for WMutexRaw {
+    type GuardMarker = GuardSend;
+    const INIT: Self = Self(TicketLock::new(), LockStats::new());
+
+    fn try_lock(&self) -> bool {
+        let ok = self.0.try_lock();
+        if ok { self.1.record_acquired(); }
+        ok
+    }
+    fn lock(&self) {
+        self.0.lock(&self.1)
+    }
+    unsafe fn unlock(&self) {
+        self.0.unlock()
+    }
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+impl RawMutexAsync for WMutexRaw {
+    fn lock_async(&self) -> impl Future<Output=()> + Send + '_ {
+        self.0.lock_async(&self.1)
+    }
+}
+pub type WMutex<T> = lock_api::Mutex<WMutexRaw,T>;
+pub type WMutexGuard<'a,T> = lock_api::MutexGuard<'a,WMutexRaw,T>;
+pub type MappedWMutexGuard<'a,T> = lock_api::MappedMutexGuard<'a,WMutexRaw,T>;
+pub type ArcWMutexGuard<T> = lock_api::ArcMutexGuard<WMutexRaw,T>;
+
+/// A fair readers-writer lock. Everyone queues up at a ticket lock (the "gate") in order:
+/// readers pass straight through it (so consecutive readers share the lock), while writers hold on to it (so nobody behind them can get in) until they've waited for the readers in front of them to finish, and they've finished writing.
+pub struct WRwLockRaw {
+    gate: TicketLock,
+    readers: AtomicUsize,
+    /// The writer holding the gate waits here for the readers to finish
+    drain: WaitingList,
+    stats: LockStats,
+}
+impl WRwLockRaw {
+    /// The number of readers currently holding the lock
+    pub fn reader_count(&self) -> usize {
+        self.readers.load(Ordering::Relaxed)
+    }
+    /* Pass through the gate (which must be held) as a reader */
+    #[inline]
+    unsafe fn _enter_shared(&self) {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        self.gate.unlock();
+    }
+}
+unsafe impl RawRwLock for WRwLockRaw {
+    type GuardMarker = GuardSend;
+    const INIT: Self = Self { gate: TicketLock::new(), readers: AtomicUsize::new(0), drain: WaitingList::new(), stats: LockStats::new() };
+
+    fn lock_shared(&self) {
+        self.gate.lock(&self.stats);
+        unsafe { self._enter_shared() }
+    }
+    fn try_lock_shared(&self) -> bool {
+        if !self.gate.try_lock() { return false; }
+        self.stats.record_acquired();
+        unsafe { self._enter_shared() }
+        true
+    }
+    unsafe fn unlock_shared(&self) {
+        // (only the writer holding the gate can be waiting for us)
+        if self.readers.fetch_sub(1, Ordering::SeqCst) == 1 { self.drain.notify_one(); }
+    }
+
+    fn lock_exclusive(&self) {
+        self.gate.lock(&self.stats);
+        if self.readers.load(Ordering::SeqCst) != 0 {
+            self.stats.record_wait(||self.drain.wait_until(||self.readers.load(Ordering::SeqCst) == 0));
+        }
+    }
+    fn try_lock_exclusive(&self) -> bool {
+        if !self.gate.try_lock() { return false; }
+        if self.readers.load(Ordering::SeqCst) != 0 {
+            unsafe { self.gate.unlock() };
+            return false;
+        }
+        self.stats.record_acquired();
+        true
+    }
+    unsafe fn unlock_exclusive(&self) {
+        self.gate.unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.gate.is_locked() || self.reader_count() != 0
+    }
+    fn is_locked_exclusive(&self) -> bool {
+        // (the gate is also briefly held by readers on their way in, and by writers waiting for readers to finish)
+        self.gate.is_locked() && self.reader_count() == 0
+    }
+}
+unsafe impl lock_api::RawRwLockDowngrade for WRwLockRaw {
+    unsafe fn downgrade(&self) {
+        // Become a reader, and let whoever is next through the gate
+        self._enter_shared();
+    }
+}
+impl RawRwLockAsync for WRwLockRaw {
+    fn lock_shared_async(&self) -> impl Future<Output=()> + Send + '_ {
+        async move {
+            self.gate.lock_async(&self.stats).await;
+            unsafe { self._enter_shared() }
+        }
+    }
+    fn lock_exclusive_async(&self) -> impl Future<Output=()> + Send + '_ {
+        async move {
+            self.gate.lock_async(&self.stats).await;
+            // (if we're dropped while waiting for the readers to finish, the gate must be let go of)
+            let gate = GateGuard(&self.gate);
+            if self.readers.load(Ordering::SeqCst) != 0 {
+                let start = self.stats.begin_wait();
+                let stats = StatsGuard(&self.stats, start);
+                self.drain.wait_until_async(||self.readers.load(Ordering::SeqCst) == 0).await;
+                drop(stats);
+            }
+            core::mem::forget(gate);
+        }
+    }
+}
+/// Unlocks the gate when dropped (see lock_exclusive_async)
+struct GateGuard<'a>(&'a TicketLock);
+impl core::ops::Drop for GateGuard<'_> {
+    fn drop(&mut self) { unsafe { self.0.unlock() } }
+}
+/// Ends a wait (see LockStats::end_wait) when dropped
+struct StatsGuard<'a>(&'a LockStats, u64);
+impl core::ops::Drop for StatsGuard<'_> {
+    fn drop(&mut self) { self.0.end_wait(self.1) }
+}
+pub type WRwLock<T> = lock_api::RwLock<WRwLockRaw,T>;
+pub type WRwLockReadGuard<'a,T> = lock_api::RwLockReadGuard<'a,WRwLockRaw,T>;
+pub type WRwLockWriteGuard<'a,T> = lock_api::RwLockWriteGuard<'a,WRwLockRaw,T>;
+pub type MappedWRwLockReadGuard<'a,T> = lock_api::MappedRwLockReadGuard<'a,WRwLockRaw,T>;
+pub type MappedWRwLockWriteGuard<'a,T> = lock_api::MappedRwLockWriteGuard<'a,WRwLockRaw,T>;
+pub type ArcWRwLockReadGuard<T> = lock_api::ArcRwLockReadGuard<WRwLockRaw,T>;
+pub type ArcWRwLockWriteGuard<T> = lock_api::ArcRwLockWriteGuard<WRwLockRaw,T>;
+
+pub trait LockStatsExt {
+    /// How contended the lock has been since it was created
+    fn contention_stats(&self) -> ContentionStats;
+}
+impl<T:?Sized> LockStatsExt for WMutex<T> {
+    fn contention_stats(&self) -> ContentionStats {
+        // SAFETY: We only read the statistics (we don't lock or unlock the raw lock)
+        unsafe { self.raw() }.1.snapshot()
+    }
+}
+impl<T:?Sized> LockStatsExt for WRwLock<T> {
+    fn contention_stats(&self) -> ContentionStats {
+        // SAFETY: We only read the statistics (we don't lock or unlock the raw lock)
+        unsafe { self.raw() }.stats.snapshot()
+    }
+}
+
+pub(crate) mod wlock_test {
+    use super::*;
+    use alloc::vec::Vec;
+    use alloc::format;
+    use crate::multitasking::scheduler::spin_yield;
+    use crate::multitasking::util::def_task_fn;
+    use crate::multitasking::Executor;
+    use crate::sync::MutexAsyncExt;
+    use core::task::Waker;
+
+    const CONTENDERS: usize = 6;
+    static MUTEX: WMutex<Vec<usize>> = WMutex::new(Vec::new());
+    static RWLOCK: WRwLock<Vec<usize>> = WRwLock::new(Vec::new());
+
+    def_task_fn! {
+        task fn mutex_contender(i: usize) {
+            MUTEX.lock().push(i);
+        }
+    }
+    def_task_fn! {
+        task fn rwlock_contender(i: usize) {
+            // (odd contenders write, even ones read - and record that they got in by writing afterwards)
+            if i % 2 == 1 { RWLOCK.write().push(i); }
+            else { drop(RWLOCK.read()); RWLOCK.write().push(i); }
+        }
+    }
+
+    /* Wait until the given number of tasks are waiting for the lock */
+    fn wait_for_waiters(stats: impl Fn()->ContentionStats, count: usize) {
+        while stats().waiting < count { spin_yield(); }
+    }
+
+    pub fn run() -> crate::debugshell::CommandResult {
+        // Mutex: contenders queue up (one at a time, so the order is known) while we hold the lock, and must get it in that order
+        let contended_before = MUTEX.contention_stats().contended;
+        let mut guard = MUTEX.lock();
+        guard.clear();
+        let tasks: Vec<_> = (0..CONTENDERS).map(|i|{ let t = mutex_contender::spawn(i); wait_for_waiters(||MUTEX.contention_stats(), i+1); t }).collect();
+        // (nobody may jump the queue, even with try_lock)
+        if MUTEX.try_lock().is_some() { return Err(format!("try_lock took a WMutex that was held")); }
+        drop(guard);
+        for task in tasks.iter() { task.join().map_err(|e|format!("Contender failed: {:?}", e))?; }
+        let order = MUTEX.lock().clone();
+        if order != (0..CONTENDERS).collect::<Vec<_>>() { return Err(format!("WMutex was taken in the order {:?}", order)); }
+        let stats = MUTEX.contention_stats();
+        if stats.contended - contended_before < CONTENDERS || stats.waiting != 0 { return Err(format!("Unexpected WMutex stats: {:?}", stats)); }
+
+        // RwLock: while we hold a read lock, a writer queues up, and the reader behind it must not overtake it (even though a reader is in)
+        RWLOCK.write().clear();
+        let reader = RWLOCK.read();
+        let writer_task = rwlock_contender::spawn(1);
+        wait_for_waiters(||RWLOCK.contention_stats(), 1);
+        let reader_task = rwlock_contender::spawn(2);
+        wait_for_waiters(||RWLOCK.contention_stats(), 2);
+        if RWLOCK.try_read().is_some() { return Err(format!("A reader overtook a waiting writer")); }
+        drop(reader);
+        writer_task.join().map_err(|e|format!("Writer failed: {:?}", e))?;
+        reader_task.join().map_err(|e|format!("Reader failed: {:?}", e))?;
+        let order = RWLOCK.read().clone();
+        if order != [1, 2] { return Err(format!("WRwLock was taken in the order {:?}", order)); }
+
+        // Futures queue up with tasks, and take their turn in order
+        let mut guard = MUTEX.lock();
+        guard.clear();
+        let first = mutex_contender::spawn(0);
+        wait_for_waiters(||MUTEX.contention_stats(), 1);
+        let executor = Executor::new();
+        let future = executor.spawn(async { MUTEX.lock_async().await.push(1) });
+        let runner = executor.spawn_runner();
+        wait_for_waiters(||MUTEX.contention_stats(), 2);
+        let last = mutex_contender::spawn(2);
+        wait_for_waiters(||MUTEX.contention_stats(), 3);
+        drop(guard);
+        for task in [first, last] { task.join().map_err(|e|format!("Contender failed: {:?}", e))?; }
+        runner.join().map_err(|e|format!("Executor failed: {:?}", e))?;
+        if future.try_get().is_err() { return Err(format!("lock_async never finished")); }
+        let order = MUTEX.lock().clone();
+        if order != [0, 1, 2] { return Err(format!("WMutex was taken by tasks and futures in the order {:?}", order)); }
+
+        // Dropping a future that's waiting gives up its place (both before and after its turn has come), rather than leaving the lock stuck
+        let mut context = Context::from_waker(Waker::noop());
+        for turn_came in [false, true] {
+            let guard = MUTEX.lock();
+            let mut future = alloc::boxed::Box::pin(MUTEX.lock_async());
+            if future.as_mut().poll(&mut context).is_ready() { return Err(format!("lock_async took a WMutex that was held")); }
+            if turn_came { drop(guard); drop(future); }
+            else { drop(future); drop(guard); }
+            if MUTEX.try_lock().is_none() { return Err(format!("WMutex was left locked after a waiting future was dropped (turn_came={})", turn_came)); }
+        }
+        if MUTEX.contention_stats().waiting != 0 { return Err(format!("Unexpected WMutex stats: {:?}", MUTEX.contention_stats())); }
+        Ok(())
+    }
+}
@@ -8,6 +8,9 @@ impl spin::relax::RelaxStrategy for SchedulerYield {
         spin_yield()
     }
 }
+impl super::spinlocks::LockRelaxStrategy for SchedulerYield {
+    const YIELDS: bool = true;
+}
 pub type YieldSpin = SchedulerYield;
 
 pub type YMutex<T> = super::spinlocks::BaseSpinMutex<T,YieldSpin>;
@@ -0,0 +1,361 @@
+#!/usr/bin/env python3
+"""Decode binary kernel log records (from a kernel built with the klog_binary feature) back into readable text.
+
+Usage:
+    tools/klogdecode.py [FILE ...]      decode the given serial logs (e.g. logs/*.log)
+    tools/klogdecode.py                 decode stdin as it arrives (e.g. qemu ... -serial stdio | tools/klogdecode.py)
+
+Anything in the stream that isn't a valid record (e.g. emergency_kernel_log output during a panic) is passed through unchanged.
+See kernel/rust/src/logging/binary.rs for the format.
+"""
+import re
+import struct
+import sys
+
+FRAME_MAGIC = b"\x1eK"
+HEADER_LEN = 5
+MAX_PAYLOAD = 480
+STREAM_VERSION = 2
+LEVEL_NAMES = ["DBG", "INFO", "WARN", "SEVERE", "CRITICAL", "FATAL ERROR"]
+
+FRAME_STREAM_START = 0
+FRAME_DEFINITION = 1
+FRAME_RECORD = 2
+
+ARG_UNSIGNED, ARG_SIGNED, ARG_BOOL, ARG_CHAR, ARG_STR, ARG_FLOAT, ARG_TEXT, ARG_MISSING = range(8)
+
+
+class Truncated(Exception):
+    pass
+
+
+class Reader:
+    def __init__(self, data):
+        self.data = data
+        self.pos = 0
+
+    def u8(self):
+        if self.pos >= len(self.data):
+            raise Truncated()
+        self.pos += 1
+        return self.data[self.pos - 1]
+
+    def bytes(self, n):
+        if self.pos + n > len(self.data):
+            raise Truncated()
+        self.pos += n
+        return self.data[self.pos - n:self.pos]
+
+    def varint(self):
+        result, shift = 0, 0
+        while True:
+            b = self.u8()
+            result |= (b & 0x7F) << shift
+            shift += 7
+            if not b & 0x80:
+                return result
+
+    def str(self):
+        return self.bytes(self.varint()).decode("utf-8", errors="replace")
+
+
+class Text:
+    """An argument that was already formatted by the kernel"""
+    def __init__(self, text):
+        self.text = text
+
+
+class Raw:
+    """An argument that was sent raw, and must be formatted according to its format spec"""
+    def __init__(self, kind, value):
+        self.kind = kind
+        self.value = value
+
+
+# FORMATTING
+SPEC_RE = re.compile(r"^(?:(?P<fill>.)?(?P<align>[<^>]))?(?P<sign>[+-])?(?P<alt>#)?(?P<zero>0)?(?P<width>\d+)?(?:\.(?P<precision>\d+))?(?P<type>\?|x\?|X\?|[xXobeEp])?$")
+
+
+def parse_template(template):
+    """Split a Rust format string into a list of literal strings and (argument, spec) placeholders"""
+    parts = []
+    literal = ""
+    i = 0
+    next_arg = 0
+    while i < len(template):
+        c = template[i]
+        if c == "{" and template[i + 1:i + 2] == "{":
+            literal += "{"
+            i += 2
+        elif c == "}" and template[i + 1:i + 2] == "}":
+            literal += "}"
+            i += 2
+        elif c == "{":
+            end = template.index("}", i)
+            arg, _, spec = template[i + 1:end].partition(":")
+            if arg.isdigit():
+                arg = int(arg)
+            else:
+                arg = next_arg  # (named arguments are not supported by klog!, so treat them as positional)
+                next_arg += 1
+            if literal:
+                parts.append(literal)
+                literal = ""
+            parts.append((arg, spec))
+            i = end + 1
+        else:
+            literal += c
+            i += 1
+    if literal:
+        parts.append(literal)
+    return parts
+
+
+def rust_debug_str(s, quote='"'):
+    out = quote
+    for c in s:
+        if c == quote or c == "\\":
+            out += "\\" + c
+        elif c == "\n":
+            out += "\\n"
+        elif c == "\r":
+            out += "\\r"
+        elif c == "\t":
+            out += "\\t"
+        elif ord(c) < 0x20 or ord(c) == 0x7F:
+            out += "\\u{%x}" % ord(c)
+        else:
+            out += c
+    return out + quote
+
+
+def rust_float(x, debug):
+    if x != x:
+        return "NaN"
+    if x in (float("inf"), float("-inf")):
+        return "inf" if x > 0 else "-inf"
+    if x == int(x) and abs(x) < 1e16:
+        return "%d.0" % x if debug else "%d" % x
+    return repr(x)
+
+
+def format_raw(arg, spec):
+    m = SPEC_RE.match(spec)
+    if m is None:
+        # Something we don't understand - give up and use a sensible default
+        m = SPEC_RE.match("")
+    ty = m.group("type") or ""
+    if ty in ("x?", "X?"):
+        ty = ty[0]
+    width = int(m.group("width")) if m.group("width") else 0
+    precision = int(m.group("precision")) if m.group("precision") is not None else None
+    kind, value = arg.kind, arg.value
+
+    if kind in (ARG_UNSIGNED, ARG_SIGNED) and ty in ("x", "X", "o", "b"):
+        if kind == ARG_SIGNED and value < 0:
+            value &= (1 << 64) - 1  # rust formats negative numbers in hex/binary/octal as two's complement
+        pyspec = "%s%s%s%s%s%s" % ((m.group("fill") or "") + (m.group("align") or ""), m.group("sign") or "", m.group("alt") or "",
+                                 m.group("zero") or "", m.group("width") or "", ty)
+        return format(value, pyspec)
+    if kind in (ARG_UNSIGNED, ARG_SIGNED):
+        pyspec = "%s%s%s%s" % ((m.group("fill") or "") + (m.group("align") or ""), m.group("sign") or "", m.group("zero") or "", m.group("width") or "")
+        return format(value, pyspec + "d")
+    if kind == ARG_FLOAT:
+        if precision is not None:
+            text = "%.*f" % (precision, value)
+        elif ty in ("e", "E"):
+            text = ("%e" if ty == "e" else "%E") % value
+        else:
+            text = rust_float(value, ty == "?")
+    elif kind == ARG_BOOL:
+        text = "true" if value else "false"
+    elif kind == ARG_CHAR:
+        text = rust_debug_str(value, "'") if ty == "?" else value
+    elif kind == ARG_STR:
+        text = rust_debug_str(value) if ty == "?" else value
+        if precision is not None and ty != "?":
+            text = text[:precision]
+    else:
+        text = str(value)
+
+    if width > len(text):
+        fill = m.group("fill") or " "
+        align = m.group("align") or ("<" if kind in (ARG_STR, ARG_CHAR, ARG_BOOL) else ">")
+        pad = width - len(text)
+        if align == "<":
+            text = text + fill * pad
+        elif align == ">":
+            text = fill * pad + text
+        else:
+            text = fill * (pad // 2) + text + fill * (pad - pad // 2)
+    return text
+
+
+def format_message(definition, args):
+    if definition["verbatim"]:
+        return definition["template"]
+    out = ""
+    for part in definition["parts"]:
+        if isinstance(part, str):
+            out += part
+            continue
+        index, spec = part
+        if index >= len(args):
+            out += "<?>"
+        elif isinstance(args[index], Text):
+            out += args[index].text
+        elif args[index] is None:
+            out += "<?>"
+        else:
+            out += format_raw(args[index], spec)
+    return out
+
+
+# DECODING
+class Decoder:
+    def __init__(self, out):
+        self.out = out
+        self.buffer = b""
+        self.definitions = {}
+        # (assume the current version until we see a StreamStart frame, in case we've joined partway through)
+        self.version = STREAM_VERSION
+
+    def feed(self, data):
+        self.buffer += data
+        while self.buffer:
+            start = self.buffer.find(FRAME_MAGIC)
+            if start < 0:
+                # No frames in here, but we may have half of the magic at the end
+                keep = 1 if self.buffer.endswith(FRAME_MAGIC[:1]) else 0
+                self.passthrough(self.buffer[:len(self.buffer) - keep])
+                self.buffer = self.buffer[len(self.buffer) - keep:]
+                return
+            self.passthrough(self.buffer[:start])
+            self.buffer = self.buffer[start:]
+            if len(self.buffer) < HEADER_LEN:
+                return
+            frame_type = self.buffer[2]
+            (length,) = struct.unpack_from("<H", self.buffer, 3)
+            if length > MAX_PAYLOAD or frame_type > FRAME_RECORD:
+                # Not a real frame
+                self.passthrough(self.buffer[:1])
+                self.buffer = self.buffer[1:]
+                continue
+            if len(self.buffer) < HEADER_LEN + length + 1:
+                return
+            payload = self.buffer[HEADER_LEN:HEADER_LEN + length]
+            checksum = self.buffer[HEADER_LEN + length]
+            if sum(payload) & 0xFF != checksum:
+                self.passthrough(self.buffer[:1])
+                self.buffer = self.buffer[1:]
+                continue
+            self.buffer = self.buffer[HEADER_LEN + length + 1:]
+            self.handle_frame(frame_type, payload)
+
+    def finish(self):
+        self.passthrough(self.buffer)
+        self.buffer = b""
+        self.out.flush()
+
+    def passthrough(self, data):
+        if data:
+            self.out.write(data.decode("utf-8", errors="replace"))
+            self.out.flush()
+
+    def handle_frame(self, frame_type, payload):
+        r = Reader(payload)
+        if frame_type == FRAME_STREAM_START:
+            # The kernel has (re)started
+            self.definitions = {}
+            try:
+                self.version = r.u8()
+            except Truncated:
+                pass
+        elif frame_type == FRAME_DEFINITION:
+            try:
+                d = {"id": r.varint(), "level": r.u8(), "verbatim": bool(r.u8() & 1), "component": r.str(), "file": r.str(),
+                     "line": r.varint(), "column": r.varint(), "template": r.str()}
+            except Truncated:
+                return
+            d["parts"] = [] if d["verbatim"] else parse_template(d["template"])
+            self.definitions[d["id"]] = d
+        elif frame_type == FRAME_RECORD:
+            self.handle_record(r)
+
+    def handle_record(self, r):
+        try:
+            site, cpu, task = r.varint(), r.varint(), r.varint()
+            # (version 1 streams didn't include task names)
+            task_name = r.str() if task and self.version >= 2 else ""
+            ticks = r.varint()
+        except Truncated:
+            return
+        args = []
+        try:
+            for _ in range(r.u8()):
+                tag = r.u8()
+                if tag == ARG_UNSIGNED:
+                    args.append(Raw(tag, r.varint()))
+                elif tag == ARG_SIGNED:
+                    z = r.varint()
+                    args.append(Raw(tag, (z >> 1) ^ -(z & 1)))
+                elif tag == ARG_BOOL:
+                    args.append(Raw(tag, bool(r.u8())))
+                elif tag == ARG_CHAR:
+                    args.append(Raw(tag, chr(r.varint())))
+                elif tag == ARG_STR:
+                    args.append(Raw(tag, r.str()))
+                elif tag == ARG_FLOAT:
+                    args.append(Raw(tag, struct.unpack("<d", r.bytes(8))[0]))
+                elif tag == ARG_TEXT:
+                    args.append(Text(r.str()))
+                else:
+                    args.append(None)
+        except Truncated:
+            pass  # the record was truncated by the kernel, so some arguments are missing
+
+        # (same layout as ExecutionContext's Display impl)
+        if not task:
+            context = "%dt: CPU%d SCHED" % (ticks, cpu)
+        elif task_name:
+            context = "%dt: CPU%d TASK %d (%s)" % (ticks, cpu, task - 1, task_name)
+        else:
+            context = "%dt: CPU%d TASK %d" % (ticks, cpu, task - 1)
+        d = self.definitions.get(site)
+        if d is None:
+            self.out.write("[???] %s: <unknown log site #%d (definition missing from stream)>\n" % (context, site))
+            return
+        level = LEVEL_NAMES[d["level"]] if d["level"] < len(LEVEL_NAMES) else "LVL%d" % d["level"]
+        message = format_message(d, args)
+        # (same layout as DefaultLogFormatter)
+        self.out.write("[%s] %s: %s - %s (%s:%d:%d)\n" % (level, context, d["component"], message, d["file"], d["line"], d["column"]))
+
+
+def main(argv):
+    out = sys.stdout
+    if len(argv) > 1 and argv[1] in ("-h", "--help"):
+        out.write(__doc__)
+        return 0
+    if len(argv) > 1:
+        for path in argv[1:]:
+            decoder = Decoder(out)
+            with open(path, "rb") as f:
+                decoder.feed(f.read())
+            decoder.finish()
+    else:
+        decoder = Decoder(out)
+        stdin = sys.stdin.buffer
+        while True:
+            data = stdin.read1(4096) if hasattr(stdin, "read1") else stdin.read(4096)
+            if not data:
+                break
+            decoder.feed(data)
+        decoder.finish()
+    return 0
+
+
+if __name__ == "__main__":
+    try:
+        sys.exit(main(sys.argv))
+    except (KeyboardInterrupt, BrokenPipeError):
+        pass